@@ -0,0 +1,385 @@
+#[doc="
+
+    Module: bgzf
+
+    This module reads BGZF, the blocked gzip format behind BAM
+    files and tabix indexed VCFs. A BGZF file is a series of gzip
+    members, called blocks, each holding at most 64K of data, with
+    a BC subfield in FEXTRA giving the block's total size less one.
+    Blocks can be found from their sizes alone, without
+    decompressing anything, and any byte of the data can be named
+    by a 64 bit virtual offset: the file offset of its block, shifted
+    left 16 bits, plus its offset within the decompressed block. A
+    file normally ends with EOF_MARKER, an empty block, so that
+    truncation can be detected.
+
+"]
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+
+use error::{GzError, ErrorKind};
+use header;
+use header::{ExtraField, GZ_MAGIC_BYTES, GZIP_FOOTER_LEN, read_u32_le};
+use stream::{Decompressor, StreamStatus};
+
+/// The empty block that ends a BGZF file
+pub const EOF_MARKER: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00,
+    0x42, 0x43, 0x02, 0x00, 0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00];
+/// The most data a block may hold
+pub const MAX_BLOCK_DATA: usize = 65536;
+// the id of the subfield holding the block size
+const BC_ID: [u8; 2] = [0x42, 0x43];
+// the fixed part of a gzip header, up to and including XLEN
+const FIXED_HEADER_LEN: usize = 12;
+const FEXTRA: u8 = 0x04;
+
+/// Make a virtual offset from the file offset of a block, and an offset
+/// into its decompressed data
+pub fn virtual_offset(block_offset: u64, in_block: u16) -> u64 {
+    block_offset << 16 | in_block as u64
+}
+
+/// Split a virtual offset into the file offset of its block, and the
+/// offset into the decompressed block
+pub fn split_virtual_offset(offset: u64) -> (u64, u16) {
+    (offset >> 16, offset as u16)
+}
+
+/// The total size of a block, from the subfields of its header
+/// returns None if there is no BC subfield
+pub fn block_size(extra: &[ExtraField]) -> Option<usize> {
+    for field in extra.iter() {
+        if field.id == BC_ID && field.data.len() == 2 {
+            return Some((field.data[0] as usize | (field.data[1] as usize) << 8) + 1);
+        }
+    }
+    None
+}
+
+/// Read one whole block from reader, still compressed, or None if reader
+/// is already at its end. offset is the file offset of the block, for
+/// error positions.
+pub fn read_block<R: Read>(reader: &mut R, offset: u64) -> io::Result<Option<Vec<u8>>> {
+    let mut block = vec![0u8; FIXED_HEADER_LEN];
+    let got = try!(read_full(reader, block.as_mut_slice()));
+    if got == 0 {
+        return Ok(None);
+    }
+    if got < FIXED_HEADER_LEN {
+        return Err(error(ErrorKind::UnexpectedEof, offset + got as u64));
+    }
+    if block[0] != GZ_MAGIC_BYTES[0] || block[1] != GZ_MAGIC_BYTES[1] || block[2] != 8 ||
+            block[3] & FEXTRA == 0 {
+        return Err(error(ErrorKind::BadHeader, offset));
+    }
+    let xlen = block[10] as usize | (block[11] as usize) << 8;
+    let mut extra = vec![0u8; xlen];
+    if try!(read_full(reader, extra.as_mut_slice())) < xlen {
+        return Err(error(ErrorKind::UnexpectedEof, offset));
+    }
+    let (fields, _) = header::extra_fields(extra.as_slice());
+    let size = match block_size(fields.as_slice()) {
+        Some(size) if size >= FIXED_HEADER_LEN + xlen + GZIP_FOOTER_LEN => size,
+        _ => return Err(error(ErrorKind::BadHeader, offset))
+    };
+    block.push_all(extra.as_slice());
+    let mut rest = vec![0u8; size - block.len()];
+    if try!(read_full(reader, rest.as_mut_slice())) < rest.len() {
+        return Err(error(ErrorKind::UnexpectedEof, offset));
+    }
+    block.push_all(rest.as_slice());
+    Ok(Some(block))
+}
+
+/// Decompress a whole block read by read_block, checking its CRC and
+/// size. Error positions are relative to the start of the block.
+pub fn inflate_block(block: &[u8]) -> Result<Vec<u8>, GzError> {
+    let len = block.len();
+    if len < FIXED_HEADER_LEN + GZIP_FOOTER_LEN {
+        return Err(GzError::new(ErrorKind::TooShort, len));
+    }
+    let data_len = read_u32_le(&block[len - 4 ..]) as usize;
+    if data_len > MAX_BLOCK_DATA {
+        return Err(GzError::new(ErrorKind::SizeMismatch, len - 4));
+    }
+    // a byte to spare, so running out of room means the size is wrong
+    let mut out = vec![0u8; data_len + 1];
+    let mut decompressor = Decompressor::new();
+    let (used, written, status) = try!(decompressor.decompress(block, out.as_mut_slice()));
+    match status {
+        StreamStatus::MemberEnd if used == len => {},
+        StreamStatus::MemberEnd => return Err(GzError::new(ErrorKind::CorruptData, used)),
+        StreamStatus::NeedInput => return Err(GzError::new(ErrorKind::UnexpectedEof, len)),
+        _ => return Err(GzError::new(ErrorKind::SizeMismatch, len - 4))
+    }
+    out.truncate(written);
+    Ok(out)
+}
+
+/// Reads the data in a BGZF file a block at a time
+pub struct BgzfReader<R> {
+    reader: R,
+    // the decompressed data of the current block, and how much has been read
+    block: Vec<u8>,
+    pos: usize,
+    // the file offsets of the current block, and of the one after it
+    block_offset: u64,
+    next_offset: u64,
+    eof_marker: bool
+}
+
+impl<R: Read> BgzfReader<R> {
+    /// Read the BGZF file starting at the current position of reader
+    pub fn new(reader: R) -> BgzfReader<R> {
+        BgzfReader {
+            reader: reader,
+            block: Vec::new(),
+            pos: 0,
+            block_offset: 0,
+            next_offset: 0,
+            eof_marker: false
+        }
+    }
+
+    /// The virtual offset of the next byte to be read
+    pub fn virtual_offset(&self) -> u64 {
+        virtual_offset(self.block_offset, self.pos as u16)
+    }
+
+    /// Whether the last block read was the EOF marker. Once reading has
+    /// reached the end, false means the file may have been truncated.
+    pub fn saw_eof_marker(&self) -> bool {
+        self.eof_marker
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Read and decompress the next block, returning false at the end of
+    /// the file
+    fn next_block(&mut self) -> io::Result<bool> {
+        let offset = self.next_offset;
+        let block = match try!(read_block(&mut self.reader, offset)) {
+            Some(block) => block,
+            None => return Ok(false)
+        };
+        self.block = match inflate_block(block.as_slice()) {
+            Ok(data) => data,
+            Err(e) => return Err(GzError::new(e.kind, offset as usize + e.position).to_io_error())
+        };
+        self.eof_marker = block.as_slice() == EOF_MARKER.as_slice();
+        self.pos = 0;
+        self.block_offset = offset;
+        self.next_offset = offset + block.len() as u64;
+        Ok(true)
+    }
+}
+
+impl<R: Read + Seek> BgzfReader<R> {
+    /// Move to a virtual offset, as given by virtual_offset()
+    pub fn seek_virtual(&mut self, offset: u64) -> io::Result<()> {
+        let (block_offset, in_block) = split_virtual_offset(offset);
+        try!(self.reader.seek(SeekFrom::Start(block_offset)));
+        self.next_offset = block_offset;
+        self.block.clear();
+        self.pos = 0;
+        self.block_offset = block_offset;
+        if !try!(self.next_block()) && in_block == 0 {
+            // the very end of the file
+            return Ok(());
+        }
+        if in_block as usize > self.block.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "virtual offset past the end of its block"));
+        }
+        self.pos = in_block as usize;
+        Ok(())
+    }
+
+    /// Whether the file ends with the EOF marker, as it should. The
+    /// position in the file is left where it was.
+    pub fn has_eof_marker(&mut self) -> io::Result<bool> {
+        let here = try!(self.reader.seek(SeekFrom::Current(0)));
+        let end = try!(self.reader.seek(SeekFrom::End(0)));
+        let mut tail = [0u8; 28];
+        let mut found = false;
+        if end >= tail.len() as u64 {
+            try!(self.reader.seek(SeekFrom::End(-(tail.len() as i64))));
+            found = try!(read_full(&mut self.reader, &mut tail)) == tail.len() &&
+                tail.as_slice() == EOF_MARKER.as_slice();
+        }
+        try!(self.reader.seek(SeekFrom::Start(here)));
+        Ok(found)
+    }
+}
+
+impl<R: Read> Read for BgzfReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if out.len() == 0 {
+            return Ok(0);
+        }
+        // empty blocks, such as the EOF marker, are skipped over
+        while self.pos == self.block.len() {
+            if !try!(self.next_block()) {
+                return Ok(0);
+            }
+        }
+        let n = if out.len() < self.block.len() - self.pos {
+            out.len()
+        } else {
+            self.block.len() - self.pos
+        };
+        for i in 0 .. n {
+            out[i] = self.block[self.pos + i];
+        }
+        self.pos += n;
+        if self.pos == self.block.len() {
+            // the next byte is at the start of the next block
+            self.block.clear();
+            self.pos = 0;
+            self.block_offset = self.next_offset;
+        }
+        Ok(n)
+    }
+}
+
+/// Read until buf is full or the input ends, returning how much was read
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut done = 0;
+    while done < buf.len() {
+        match reader.read(&mut buf[done ..]) {
+            Ok(0) => break,
+            Ok(n) => done += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e)
+        }
+    }
+    Ok(done)
+}
+
+fn error(kind: ErrorKind, offset: u64) -> io::Error {
+    GzError::new(kind, offset as usize).to_io_error()
+}
+
+#[cfg(test)]
+mod bgzf_tests {
+    use super::{BgzfReader, EOF_MARKER, virtual_offset, split_virtual_offset,
+                read_block, inflate_block};
+    use encoder::compress_with_header;
+    use header::HeaderOptions;
+    use read::GzDecoder;
+    use std::io::{Cursor, Read};
+    use testing::{text, bgzf};
+
+    #[test]
+    fn test_virtual_offset() {
+        assert_eq!(virtual_offset(0x1234, 0x5678), 0x12345678);
+        assert_eq!(split_virtual_offset(0x12345678), (0x1234, 0x5678));
+        assert_eq!(split_virtual_offset(virtual_offset(1 << 40, 65535)), (1 << 40, 65535));
+    }
+
+    #[test]
+    fn test_eof_marker() {
+        // the marker is a valid, empty gzip member
+        let mut out = Vec::new();
+        GzDecoder::new(EOF_MARKER.as_slice()).read_to_end(&mut out).unwrap();
+        assert_eq!(out.len(), 0);
+        let mut reader = Cursor::new(EOF_MARKER.as_slice());
+        let block = read_block(&mut reader, 0).unwrap().unwrap();
+        assert_eq!(block.as_slice(), EOF_MARKER.as_slice());
+        assert_eq!(inflate_block(block.as_slice()).unwrap().len(), 0);
+        assert!(read_block(&mut reader, 28).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_read() {
+        let data = text(10000);
+        let file = bgzf(data.as_slice(), 65280);
+        let mut reader = BgzfReader::new(file.as_slice());
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).unwrap();
+        assert!(out == data);
+        assert!(reader.saw_eof_marker());
+        // as gzip, the blocks are just members one after another
+        let mut out = Vec::new();
+        GzDecoder::new(file.as_slice()).read_to_end(&mut out).unwrap();
+        assert!(out == data);
+    }
+
+    #[test]
+    fn test_seek_virtual() {
+        let data = text(10000);
+        let file = bgzf(data.as_slice(), 10000);
+        let mut reader = BgzfReader::new(Cursor::new(file.as_slice()));
+        // note the virtual offset of every 1000th byte
+        let mut offsets = Vec::new();
+        let mut buf = [0u8; 1000];
+        loop {
+            let offset = reader.virtual_offset();
+            let n = reader.read(&mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            offsets.push(offset);
+        }
+        assert!(offsets.len() > 10);
+        // every 1000 bytes falls in a block of 10000
+        assert_eq!(split_virtual_offset(offsets[25]).1, 5000);
+        for i in (0 .. offsets.len()).rev() {
+            reader.seek_virtual(offsets[i]).unwrap();
+            let n = reader.read(&mut buf[.. 10]).unwrap();
+            assert!(&buf[.. n] == &data[i * 1000 .. i * 1000 + n]);
+            assert!(n > 0);
+        }
+        // the end of the file
+        let end = virtual_offset(file.len() as u64, 0);
+        reader.seek_virtual(end).unwrap();
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+        assert!(reader.seek_virtual(offsets[1] + 20000).is_err());
+    }
+
+    #[test]
+    fn test_has_eof_marker() {
+        let data = text(10000);
+        let file = bgzf(data.as_slice(), 65280);
+        let mut reader = BgzfReader::new(Cursor::new(file.as_slice()));
+        assert!(reader.has_eof_marker().unwrap());
+        let mut buf = [0u8; 100];
+        assert_eq!(reader.read(&mut buf).unwrap(), 100);
+        assert!(&buf[..] == &data[.. 100]);
+        let truncated = &file[.. file.len() - 28];
+        let mut reader = BgzfReader::new(Cursor::new(truncated));
+        assert!(!reader.has_eof_marker().unwrap());
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).unwrap();
+        assert!(!reader.saw_eof_marker());
+    }
+
+    #[test]
+    fn test_not_bgzf() {
+        // an ordinary gzip member has no BC subfield
+        let file = compress_with_header(b"plain", 6, &HeaderOptions::new()).unwrap();
+        let mut out = Vec::new();
+        let err = BgzfReader::new(file.as_slice()).read_to_end(&mut out).unwrap_err();
+        assert_eq!(format!("{}", err), "invalid or unsupported gzip header at byte 0");
+    }
+
+    #[test]
+    fn test_corrupt_block() {
+        let data = text(10000);
+        let mut file = bgzf(data.as_slice(), 65280);
+        file[100] ^= 0x55;
+        let mut out = Vec::new();
+        assert!(BgzfReader::new(file.as_slice()).read_to_end(&mut out).is_err());
+        let mut truncated = bgzf(data.as_slice(), 65280);
+        truncated.truncate(1000);
+        assert!(BgzfReader::new(truncated.as_slice()).read_to_end(&mut out).is_err());
+    }
+}
@@ -0,0 +1,1111 @@
+#[doc="
+
+    Program: rgunzip
+
+    A gunzip work-alike built on rgzip. It takes the flags scripts
+    usually pass to gunzip, prints the same messages, and exits with
+    the same codes: 0 when all went well, 1 on an error, and 2 when
+    there was only a warning, such as an output file that already
+    exists or trailing garbage after the data.
+
+"]
+extern crate rgzip;
+extern crate libc;
+
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write, BufRead, BufReader, BufWriter};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use rgzip::{GzBufDecoder, GzListing, GzError, ErrorKind, read_header, list_members, os_name};
+
+const PROGRAM: &'static str = "rgunzip";
+
+const EXIT_OK: i32 = 0;
+const EXIT_ERROR: i32 = 1;
+const EXIT_WARNING: i32 = 2;
+
+// the suffixes gunzip takes off compressed file names, and what replaces them
+const SUFFIXES: [(&'static str, &'static str); 8] = [
+    (".gz", ""), ("-gz", ""), (".z", ""), ("-z", ""), ("_z", ""), (".Z", ""),
+    (".tgz", ".tar"), (".taz", ".tar")
+];
+// how much of the start of a file to read for its header
+const HEADER_PREFIX_LEN: u64 = 262144;
+const GZIP_FOOTER_LEN: u64 = 8;
+const COPY_BUF_SIZE: usize = 65536;
+
+// what gzip -lv shows for a time it can't convert
+const UNKNOWN_TIME: &'static str = "??? ?? ??:??";
+static MONTHS: [&'static str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"
+];
+
+const USAGE: &'static str = "Usage: rgunzip [OPTION]... [FILE]...
+Decompress FILEs, replacing each with its contents.
+
+  -c, --stdout      write to standard output, keeping the input files
+  -f, --force       overwrite existing output files, and with -c pass
+                    through data that isn't compressed
+  -k, --keep        keep the input files
+  -l, --list        list the sizes of compressed files
+      --members     with -l, list each member of a file as well
+  -N, --name        restore the name and time stamp saved in the file
+  -n, --no-name     don't restore the saved name and time stamp
+  -q, --quiet       suppress warnings
+  -r, --recursive   decompress the files in directories
+  -t, --test        check that the files decompress
+  -v, --verbose     report on each file
+  -h, --help        show this help
+
+With no FILE, or when FILE is -, read standard input.
+";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let code = run(args.as_slice(), &mut io::stdout(), &mut io::stderr());
+    process::exit(code);
+}
+
+/// Run with the given arguments, writing output and messages to the
+/// given streams, and return the exit code
+fn run(args: &[String], out: &mut Write, err: &mut Write) -> i32 {
+    let (options, files) = match parse_args(args) {
+        Ok(parsed) => parsed,
+        Err(message) => {
+            let _ = write!(err, "{}: {}\nTry '{} --help' for more information.\n",
+                           PROGRAM, message, PROGRAM);
+            return EXIT_ERROR;
+        }
+    };
+    if options.help {
+        let _ = out.write_all(USAGE.as_bytes());
+        return EXIT_OK;
+    }
+    let mut gunzip = Gunzip {
+        options: options,
+        out: out,
+        err: err,
+        status: EXIT_OK,
+        listed: 0,
+        total_in: 0,
+        total_out: 0,
+        total_deflated: 0
+    };
+    if files.len() == 0 {
+        gunzip.stdin();
+    }
+    for file in files.iter() {
+        if file.as_slice() == "-" {
+            gunzip.stdin();
+        } else {
+            gunzip.path(Path::new(file.as_slice()));
+        }
+    }
+    if gunzip.listed > 1 {
+        gunzip.list_totals();
+    }
+    if gunzip.out.flush().is_err() {
+        gunzip.status = EXIT_ERROR;
+    }
+    gunzip.status
+}
+
+/// What the command line asked for
+struct Options {
+    stdout: bool,
+    force: bool,
+    keep: bool,
+    list: bool,
+    members: bool,
+    name: bool,
+    quiet: bool,
+    recursive: bool,
+    test: bool,
+    verbose: bool,
+    help: bool
+}
+
+/// Split the arguments into options and file names
+fn parse_args(args: &[String]) -> Result<(Options, Vec<String>), String> {
+    let mut options = Options {
+        stdout: false,
+        force: false,
+        keep: false,
+        list: false,
+        members: false,
+        name: false,
+        quiet: false,
+        recursive: false,
+        test: false,
+        verbose: false,
+        help: false
+    };
+    let mut files = Vec::new();
+    let mut only_files = false;
+    for arg in args.iter() {
+        let arg = arg.as_slice();
+        if only_files || arg == "-" || !arg.starts_with("-") {
+            files.push(arg.to_string());
+        } else if arg == "--" {
+            only_files = true;
+        } else if arg == "--members" {
+            options.members = true;
+        } else if arg.starts_with("--") {
+            let flag = match &arg[2 ..] {
+                "stdout" | "to-stdout" => 'c',
+                "decompress" | "uncompress" => 'd',
+                "force" => 'f',
+                "help" => 'h',
+                "keep" => 'k',
+                "list" => 'l',
+                "name" => 'N',
+                "no-name" => 'n',
+                "quiet" => 'q',
+                "recursive" => 'r',
+                "test" => 't',
+                "verbose" => 'v',
+                _ => return Err(format!("unrecognized option '{}'", arg))
+            };
+            try!(set_flag(&mut options, flag));
+        } else {
+            for flag in arg[1 ..].chars() {
+                try!(set_flag(&mut options, flag));
+            }
+        }
+    }
+    Ok((options, files))
+}
+
+fn set_flag(options: &mut Options, flag: char) -> Result<(), String> {
+    match flag {
+        'c' => options.stdout = true,
+        // decompressing is all this does
+        'd' => {},
+        'f' => options.force = true,
+        'h' => options.help = true,
+        'k' => options.keep = true,
+        'l' => options.list = true,
+        'N' => options.name = true,
+        'n' => options.name = false,
+        'q' => {
+            options.quiet = true;
+            options.verbose = false;
+        },
+        'r' => options.recursive = true,
+        't' => options.test = true,
+        'v' => {
+            options.verbose = true;
+            options.quiet = false;
+        },
+        _ => return Err(format!("invalid option -- '{}'", flag))
+    }
+    Ok(())
+}
+
+/// Works through the files named on the command line
+struct Gunzip<'a> {
+    options: Options,
+    out: &'a mut (Write + 'a),
+    err: &'a mut (Write + 'a),
+    status: i32,
+    // running totals for a listing of several files
+    listed: usize,
+    total_in: u64,
+    total_out: u64,
+    total_deflated: u64
+}
+
+impl<'a> Gunzip<'a> {
+    /// Decompress, test or list a file, or the files in a directory
+    fn path(&mut self, path: &Path) {
+        let name = path.display().to_string();
+        let metadata = match fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(e) => return self.error(format!("{}: {}", name, os_message(&e)))
+        };
+        if metadata.is_dir() {
+            if self.options.recursive {
+                self.directory(path, &name);
+            } else {
+                self.warning(format!("{} is a directory -- ignored", name));
+            }
+        } else if self.options.list {
+            self.list_file(path, &name);
+        } else if self.options.test || self.options.stdout {
+            self.to_stdout(path, &name);
+        } else {
+            self.to_file(path, &name, &metadata);
+        }
+    }
+
+    /// Go through the entries of a directory, in order of name
+    fn directory(&mut self, path: &Path, name: &str) {
+        let entries = match fs::read_dir(path) {
+            Ok(entries) => entries,
+            Err(e) => return self.error(format!("{}: {}", name, os_message(&e)))
+        };
+        let mut paths: Vec<PathBuf> = Vec::new();
+        for entry in entries {
+            match entry {
+                Ok(entry) => paths.push(entry.path()),
+                Err(e) => return self.error(format!("{}: {}", name, os_message(&e)))
+            }
+        }
+        paths.sort();
+        for path in paths.iter() {
+            self.path(path.as_path());
+        }
+    }
+
+    /// Decompress, test or list standard input
+    fn stdin(&mut self) {
+        let stdin = io::stdin();
+        let mut input = stdin.lock();
+        if self.options.list {
+            match list_members(&mut input) {
+                Ok(listing) => self.list("stdin", "stdout", &listing),
+                Err(e) => self.list_failed("stdin", e)
+            }
+        } else {
+            self.decompress_to_stdout(&mut input, "stdin");
+        }
+    }
+
+    /// Decompress a file to standard output, or test it
+    fn to_stdout(&mut self, path: &Path, name: &str) {
+        match File::open(path) {
+            Ok(file) => self.decompress_to_stdout(&mut BufReader::new(file), name),
+            Err(e) => self.error(format!("{}: {}", name, os_message(&e)))
+        }
+    }
+
+    fn decompress_to_stdout<R: BufRead>(&mut self, input: &mut R, name: &str) {
+        if self.options.force && !self.options.test {
+            // with -f, data that isn't gzip is passed through as it is
+            let compressed = match input.fill_buf() {
+                Ok(start) => start.len() == 0 || start[0] == 0x1f,
+                Err(e) => return self.error(format!("{}: {}", name, os_message(&e)))
+            };
+            if !compressed {
+                if let Err(e) = io::copy(input, &mut *self.out) {
+                    self.error(format!("{}: {}", name, os_message(&e)));
+                }
+                return;
+            }
+        }
+        self.decompress_stream(input, name);
+    }
+
+    fn decompress_stream<R: BufRead>(&mut self, input: &mut R, name: &str) {
+        let result = if self.options.test {
+            gunzip(input, &mut io::sink())
+        } else {
+            gunzip(input, &mut *self.out)
+        };
+        match result {
+            Ok(decoded) => {
+                if self.options.verbose {
+                    let report = if self.options.test {
+                        format!("{}:\t OK\n", name)
+                    } else {
+                        format!("{}:\t{} -- replaced with stdout\n", name, decoded.ratio())
+                    };
+                    let _ = self.err.write_all(report.as_bytes());
+                }
+                if decoded.trailing_garbage {
+                    self.garbage_warning(name);
+                }
+            },
+            Err(failure) => self.failed(name, failure)
+        }
+    }
+
+    /// Decompress a file into the file named by taking its suffix off
+    fn to_file(&mut self, path: &Path, name: &str, metadata: &fs::Metadata) {
+        let mut out_path = match output_path(path) {
+            Some(out_path) => out_path,
+            None => return self.warning(format!("{}: unknown suffix -- ignored", name))
+        };
+        let (atime, mut mtime) = file_times(metadata);
+        if self.options.name {
+            if let Some((stored_name, stored_mtime)) = stored_name_and_time(path) {
+                if let Some(stored_name) = stored_name {
+                    out_path = path.with_file_name(stored_name.as_slice());
+                }
+                if stored_mtime != 0 {
+                    mtime = stored_mtime as i64;
+                }
+            }
+        }
+        // creating the output would truncate the input, the only copy
+        if out_path == path {
+            return self.warning(format!("{}: stored name is the file's own -- ignored", name));
+        }
+        let out_name = out_path.display().to_string();
+        if !self.options.force && fs::metadata(&out_path).is_ok() {
+            return self.warning(format!("{} already exists;\tnot overwritten", out_name));
+        }
+
+        let input = match File::open(path) {
+            Ok(input) => input,
+            Err(e) => return self.error(format!("{}: {}", name, os_message(&e)))
+        };
+        let output = match File::create(&out_path) {
+            Ok(output) => output,
+            Err(e) => return self.error(format!("{}: {}", out_name, os_message(&e)))
+        };
+        let result = {
+            let mut writer = BufWriter::new(output);
+            match gunzip(&mut BufReader::new(input), &mut writer) {
+                Ok(decoded) => match writer.flush() {
+                    Ok(()) => Ok(decoded),
+                    Err(e) => Err(Failure::Write(e))
+                },
+                Err(failure) => Err(failure)
+            }
+        };
+        let decoded = match result {
+            Ok(decoded) => decoded,
+            Err(failure) => {
+                // don't leave half a file behind
+                let _ = fs::remove_file(&out_path);
+                return self.failed(name, failure);
+            }
+        };
+
+        // the output takes on the input's permissions and times
+        let _ = fs::set_permissions(&out_path, metadata.permissions());
+        set_times(&out_path, atime, mtime);
+        if !self.options.keep {
+            if let Err(e) = fs::remove_file(path) {
+                self.error(format!("{}: {}", name, os_message(&e)));
+            }
+        }
+        if self.options.verbose {
+            let action = if self.options.keep { "created" } else { "replaced with" };
+            let report = format!("{}:\t{} -- {} {}\n", name, decoded.ratio(), action, out_name);
+            let _ = self.err.write_all(report.as_bytes());
+        }
+        if decoded.trailing_garbage {
+            self.garbage_warning(name);
+        }
+    }
+
+    /// List the sizes of a file, walking all its members
+    fn list_file(&mut self, path: &Path, name: &str) {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) => return self.error(format!("{}: {}", name, os_message(&e)))
+        };
+        match list_members(file) {
+            Ok(listing) => {
+                let out_name = match output_path(path) {
+                    Some(out_path) => out_path.display().to_string(),
+                    None => name.to_string()
+                };
+                self.list(name, out_name.as_slice(), &listing);
+            },
+            Err(e) => self.list_failed(name, e)
+        }
+    }
+
+    /// Print a file's line of the listing, and with --members a line for
+    /// each of its members before it. Where gzip shows the last member's
+    /// size alone, the sizes here are those of all the members together.
+    fn list(&mut self, name: &str, out_name: &str, listing: &GzListing) {
+        let (first, last) = match (listing.members.first(), listing.members.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return self.data_error(name, "unexpected end of file")
+        };
+        let shown_name = match first.name {
+            Some(ref fname) if self.options.name => fname.clone(),
+            _ => out_name.to_string()
+        };
+        if self.listed == 0 {
+            let heading = if self.options.members {
+                member_row("member", "offset", "method", "crc", "date  time", "os",
+                           "compressed", "uncompressed", " ratio", "uncompressed_name")
+            } else if self.options.verbose {
+                "method  crc     date  time           compressed        uncompressed  ratio uncompressed_name\n".to_string()
+            } else {
+                "         compressed        uncompressed  ratio uncompressed_name\n".to_string()
+            };
+            let _ = self.out.write_all(heading.as_bytes());
+        }
+        let len = listing.compressed_len();
+        let uncompressed = listing.uncompressed_len();
+        let deflated = listing.deflated_len();
+        let mut lines = String::new();
+        if self.options.members {
+            for (i, member) in listing.members.iter().enumerate() {
+                let stored_name = match member.name {
+                    Some(ref fname) => fname.as_slice(),
+                    None => "-"
+                };
+                lines.push_str(member_row(
+                    (i + 1).to_string().as_slice(), member.offset.to_string().as_slice(), "defla",
+                    format!("{:08x}", member.crc).as_slice(), format_time(member.mtime as i64).as_slice(),
+                    os_name(member.os), member.compressed_len.to_string().as_slice(),
+                    member.uncompressed_len.to_string().as_slice(),
+                    ratio(member.uncompressed_len, member.deflated_len()).as_slice(),
+                    stored_name).as_slice());
+            }
+            lines.push_str(member_row(
+                "file", "", "defla", format!("{:08x}", last.crc).as_slice(),
+                format_time(first.mtime as i64).as_slice(), os_name(first.os),
+                len.to_string().as_slice(), uncompressed.to_string().as_slice(),
+                ratio(uncompressed, deflated).as_slice(), shown_name.as_slice()).as_slice());
+        } else {
+            if self.options.verbose {
+                lines.push_str(format!("defla {:08x} {} ", last.crc,
+                                       format_time(first.mtime as i64)).as_slice());
+            }
+            lines.push_str(format!("{:>19} {:>19} {} {}\n", len, uncompressed,
+                                   ratio(uncompressed, deflated), shown_name).as_slice());
+        }
+        let _ = self.out.write_all(lines.as_bytes());
+        self.listed += 1;
+        self.total_in += len;
+        self.total_out += uncompressed;
+        self.total_deflated += deflated;
+    }
+
+    /// Report why a file couldn't be listed
+    fn list_failed(&mut self, name: &str, e: io::Error) {
+        match data_error_kind(&e) {
+            Some(kind) => self.data_error(name, data_message(kind)),
+            None => self.error(format!("{}: {}", name, os_message(&e)))
+        }
+    }
+
+    fn list_totals(&mut self) {
+        let ratio = ratio(self.total_out, self.total_deflated);
+        let line = if self.options.members {
+            member_row("", "", "", "", "", "", self.total_in.to_string().as_slice(),
+                       self.total_out.to_string().as_slice(), ratio.as_slice(), "(totals)")
+        } else {
+            let padding = if self.options.verbose { "                            " } else { "" };
+            format!("{}{:>19} {:>19} {} (totals)\n", padding, self.total_in, self.total_out, ratio)
+        };
+        let _ = self.out.write_all(line.as_bytes());
+    }
+
+    /// Report why decompressing a file failed
+    fn failed(&mut self, name: &str, failure: Failure) {
+        match failure {
+            Failure::Data(kind) => self.data_error(name, data_message(kind)),
+            Failure::Read(e) => self.error(format!("{}: {}", name, os_message(&e))),
+            Failure::Write(e) => self.error(format!("write error: {}", os_message(&e)))
+        }
+    }
+
+    fn garbage_warning(&mut self, name: &str) {
+        if !self.options.quiet {
+            let _ = write!(self.err, "\n{}: {}: decompression OK, trailing garbage ignored\n",
+                           PROGRAM, name);
+        }
+        self.warned();
+    }
+
+    /// An error in the compressed data, reported after a blank line as
+    /// gzip does
+    fn data_error(&mut self, name: &str, message: &str) {
+        let _ = write!(self.err, "\n{}: {}: {}\n", PROGRAM, name, message);
+        self.status = EXIT_ERROR;
+    }
+
+    fn error(&mut self, message: String) {
+        let _ = write!(self.err, "{}: {}\n", PROGRAM, message);
+        self.status = EXIT_ERROR;
+    }
+
+    fn warning(&mut self, message: String) {
+        if !self.options.quiet {
+            let _ = write!(self.err, "{}: {}\n", PROGRAM, message);
+        }
+        self.warned();
+    }
+
+    /// A warning only counts if there hasn't been an error
+    fn warned(&mut self) {
+        if self.status == EXIT_OK {
+            self.status = EXIT_WARNING;
+        }
+    }
+}
+
+/// Why decompressing a file failed
+enum Failure {
+    Data(ErrorKind),
+    Read(io::Error),
+    Write(io::Error)
+}
+
+/// What decompressing a file came to
+struct Decoded {
+    // the compressed bytes, not counting headers and footers
+    deflated: u64,
+    uncompressed: u64,
+    trailing_garbage: bool
+}
+
+impl Decoded {
+    fn ratio(&self) -> String {
+        ratio(self.uncompressed, self.deflated)
+    }
+}
+
+/// Decompress every member read from input into out, as gunzip does:
+/// trailing zeros are ignored, and anything else after the last member
+/// is reported as garbage
+fn gunzip<R: BufRead>(input: &mut R, out: &mut Write) -> Result<Decoded, Failure> {
+    let mut input = Counter { inner: input, count: 0 };
+    let mut decoded = Decoded { deflated: 0, uncompressed: 0, trailing_garbage: false };
+    let mut header_bytes = 0;
+    let mut buf = vec![0u8; COPY_BUF_SIZE];
+    let mut first = true;
+    loop {
+        let mut decoder = GzBufDecoder::new(&mut input);
+        loop {
+            let n = match decoder.read(buf.as_mut_slice()) {
+                Ok(n) => n,
+                Err(e) => {
+                    return match data_error_kind(&e) {
+                        // after the first member, what isn't gzip is garbage
+                        Some(ErrorKind::BadHeader) if !first => {
+                            decoded.trailing_garbage = true;
+                            Ok(decoded)
+                        },
+                        Some(kind) => Err(Failure::Data(kind)),
+                        None => Err(Failure::Read(e))
+                    };
+                }
+            };
+            if n == 0 {
+                break;
+            }
+            if let Err(e) = out.write_all(&buf[.. n]) {
+                return Err(Failure::Write(e));
+            }
+            decoded.uncompressed += n as u64;
+        }
+        header_bytes += decoder.header().map_or(0, |header| header.header_len as u64) +
+            GZIP_FOOTER_LEN;
+        first = false;
+        let counter = decoder.into_inner();
+        // counted at the end of each member, so that trailing garbage,
+        // whether or not it looks like a header, is left out
+        decoded.deflated = counter.count - header_bytes;
+        match skip_zeros(counter) {
+            Ok(Next::End) => break,
+            Ok(Next::Member) => {},
+            Ok(Next::Garbage) => {
+                decoded.trailing_garbage = true;
+                break;
+            },
+            Err(e) => return Err(Failure::Read(e))
+        }
+    }
+    Ok(decoded)
+}
+
+/// What follows a member
+enum Next {
+    End,
+    Member,
+    Garbage
+}
+
+/// Look at what follows a member, skipping any zeros at the end
+fn skip_zeros<R: BufRead>(input: &mut R) -> io::Result<Next> {
+    let mut zeros = false;
+    loop {
+        let len = {
+            let buf = try!(input.fill_buf());
+            if buf.len() == 0 {
+                return Ok(Next::End);
+            }
+            if !zeros && buf[0] == 0x1f {
+                return Ok(Next::Member);
+            }
+            if buf.iter().any(|&b| b != 0) {
+                return Ok(Next::Garbage);
+            }
+            buf.len()
+        };
+        input.consume(len);
+        zeros = true;
+    }
+}
+
+/// Counts the bytes taken from a BufRead
+struct Counter<R> {
+    inner: R,
+    count: u64
+}
+
+impl<R: BufRead> Read for Counter<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = try!(self.inner.read(buf));
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for Counter<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.count += amt as u64;
+        self.inner.consume(amt)
+    }
+}
+
+/// Which decompression error an io::Error carries, if it carries one
+fn data_error_kind(e: &io::Error) -> Option<ErrorKind> {
+    e.get_ref()
+        .and_then(|inner| inner.downcast_ref::<GzError>())
+        .map(|error| error.kind)
+}
+
+/// What gzip says about each kind of bad data
+fn data_message(kind: ErrorKind) -> &'static str {
+    match kind {
+        ErrorKind::UnexpectedEof => "unexpected end of file",
+        ErrorKind::CrcMismatch => "invalid compressed data--crc error",
+        ErrorKind::SizeMismatch => "invalid compressed data--length error",
+        ErrorKind::CorruptData => "invalid compressed data--format violated",
+        _ => "not in gzip format"
+    }
+}
+
+/// An io::Error's message, without the error number
+fn os_message(e: &io::Error) -> String {
+    let message = format!("{}", e);
+    match message.find(" (os error") {
+        Some(end) => message[.. end].to_string(),
+        None => message
+    }
+}
+
+/// The name a compressed file decompresses to, if it has a known suffix
+fn output_path(path: &Path) -> Option<PathBuf> {
+    let name = match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => name,
+        None => return None
+    };
+    for &(suffix, replacement) in SUFFIXES.iter() {
+        if name.len() > suffix.len() && name.ends_with(suffix) {
+            let stem = &name[.. name.len() - suffix.len()];
+            return Some(path.with_file_name(format!("{}{}", stem, replacement).as_slice()));
+        }
+    }
+    None
+}
+
+/// The file name and modification time saved in a file's header. Only
+/// the last part of the name is used, so it can't point elsewhere.
+fn stored_name_and_time(path: &Path) -> Option<(Option<String>, u32)> {
+    let mut prefix = Vec::new();
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(..) => return None
+    };
+    if file.take(HEADER_PREFIX_LEN).read_to_end(&mut prefix).is_err() {
+        return None;
+    }
+    let header = match read_header(prefix.as_slice()) {
+        Ok(header) => header,
+        Err(..) => return None
+    };
+    let name = header.fname.as_ref()
+        .and_then(|fname| Path::new(fname.as_slice()).file_name())
+        .and_then(|name| name.to_str())
+        .map(|name| name.to_string());
+    Some((name, header.mtime))
+}
+
+/// A line of the --members listing
+fn member_row(member: &str, offset: &str, method: &str, crc: &str, time: &str, os: &str,
+              compressed: &str, uncompressed: &str, ratio: &str, name: &str) -> String {
+    format!("{:>6} {:>19} {:<6} {:<8} {:<12} {:<7} {:>19} {:>19} {} {}\n", member, offset,
+            method, crc, time, os, compressed, uncompressed, ratio, name)
+}
+
+/// The percentage by which compression shrank the data, as gzip prints it
+fn ratio(uncompressed: u64, deflated: u64) -> String {
+    let saved = if uncompressed == 0 {
+        0.0
+    } else {
+        100.0 * (uncompressed as f64 - deflated as f64) / uncompressed as f64
+    };
+    format!("{:5.1}%", saved)
+}
+
+/// The access and modification times of a file, in seconds since the epoch
+#[cfg(unix)]
+fn file_times(metadata: &fs::Metadata) -> (i64, i64) {
+    use std::os::unix::fs::MetadataExt;
+    (metadata.atime() as i64, metadata.mtime() as i64)
+}
+
+#[cfg(not(unix))]
+fn file_times(_metadata: &fs::Metadata) -> (i64, i64) {
+    // set_times can't use them here anyway
+    (0, 0)
+}
+
+/// Set the access and modification times of a file, as best we can
+#[cfg(unix)]
+fn set_times(path: &Path, atime: i64, mtime: i64) {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = match CString::new(path.as_os_str().as_bytes()) {
+        Ok(path) => path,
+        Err(..) => return
+    };
+    let times = libc::utimbuf { actime: atime as libc::time_t, modtime: mtime as libc::time_t };
+    unsafe {
+        libc::utime(path.as_ptr(), &times);
+    }
+}
+
+#[cfg(not(unix))]
+fn set_times(_path: &Path, _atime: i64, _mtime: i64) {}
+
+/// A time as gzip -lv shows it, like "Oct  8 13:44", in local time
+#[cfg(unix)]
+fn format_time(time: i64) -> String {
+    let time = time as libc::time_t;
+    let mut tm: libc::tm = unsafe { mem::zeroed() };
+    if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        return UNKNOWN_TIME.to_string();
+    }
+    format!("{} {:>2} {:02}:{:02}", MONTHS[tm.tm_mon as usize % 12], tm.tm_mday,
+            tm.tm_hour, tm.tm_min)
+}
+
+#[cfg(not(unix))]
+fn format_time(_time: i64) -> String {
+    UNKNOWN_TIME.to_string()
+}
+
+#[cfg(all(test, unix))]
+mod rgunzip_tests {
+    use super::{run, output_path, EXIT_OK, EXIT_ERROR, EXIT_WARNING};
+    use libc;
+    use rgzip::{compress_with_header, HeaderOptions};
+    use std::env;
+    use std::fs;
+    use std::fs::File;
+    use std::io::{Read, Write};
+    use std::os::unix::fs::MetadataExt;
+    use std::path::{Path, PathBuf};
+    use std::process::Command;
+
+    /// An empty directory for a test to work in
+    fn scratch_dir(test: &str) -> PathBuf {
+        let pid = unsafe { libc::getpid() };
+        let dir = env::temp_dir().join(format!("rgunzip-{}-{}", pid, test));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write_file(path: &Path, data: &[u8]) {
+        File::create(path).unwrap().write_all(data).unwrap();
+    }
+
+    fn read_file(path: &Path) -> Vec<u8> {
+        let mut data = Vec::new();
+        File::open(path).unwrap().read_to_end(&mut data).unwrap();
+        data
+    }
+
+    fn text(lines: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        for i in 0 .. lines {
+            data.push_all(format!("line {} of the test file, {}\n", i, i * 7919 % 10007).as_bytes());
+        }
+        data
+    }
+
+    /// Run the system gzip in dir, returning its exit code and output
+    fn gzip(dir: &Path, args: &[&str]) -> (i32, Vec<u8>) {
+        let output = Command::new("gzip").args(args).current_dir(dir).output()
+            .ok().expect("gzip must be installed to run these tests");
+        (output.status.code().unwrap(), output.stdout)
+    }
+
+    /// Run rgunzip, returning its exit code, output and messages
+    fn rgunzip(args: &[String]) -> (i32, Vec<u8>, String) {
+        let mut out = Vec::new();
+        let mut err = Vec::new();
+        let code = run(args, &mut out, &mut err);
+        (code, out, String::from_utf8(err).unwrap())
+    }
+
+    fn arg(path: &Path) -> String {
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_output_path() {
+        for &(name, out) in [("a.gz", Some("a")), ("a.Z", Some("a")), ("a-z", Some("a")),
+                             ("a.tgz", Some("a.tar")), ("a.taz", Some("a.tar")),
+                             (".gz", None), ("a.txt", None)].iter() {
+            let expected = out.map(|out| Path::new("dir").join(out));
+            assert_eq!(output_path(&Path::new("dir").join(name)), expected);
+        }
+    }
+
+    #[test]
+    fn test_decompress() {
+        let dir = scratch_dir("decompress");
+        let data = text(5000);
+        write_file(&dir.join("a"), data.as_slice());
+        gzip(&dir, &["-9", "a"]);
+        let gz = dir.join("a.gz");
+        let mtime = fs::metadata(&gz).unwrap().mtime();
+        let (code, out, err) = rgunzip(&[arg(&gz)]);
+        assert_eq!((code, out.len(), err.as_slice()), (EXIT_OK, 0, ""));
+        assert!(read_file(&dir.join("a")) == data);
+        assert!(fs::metadata(&gz).is_err());
+        assert_eq!(fs::metadata(&dir.join("a")).unwrap().mtime(), mtime);
+    }
+
+    #[test]
+    fn test_keep_and_stdout() {
+        let dir = scratch_dir("keep");
+        let data = text(100);
+        write_file(&dir.join("b"), data.as_slice());
+        gzip(&dir, &["-k", "b"]);
+        fs::remove_file(&dir.join("b")).unwrap();
+        let gz = arg(&dir.join("b.gz"));
+
+        let (code, out, _) = rgunzip(&["-c".to_string(), gz.clone()]);
+        assert_eq!(code, EXIT_OK);
+        assert!(out == data);
+        assert!(fs::metadata(&dir.join("b")).is_err());
+
+        let (code, _, err) = rgunzip(&["-kv".to_string(), gz.clone()]);
+        assert_eq!(code, EXIT_OK);
+        assert!(read_file(&dir.join("b")) == data);
+        assert!(fs::metadata(&dir.join("b.gz")).is_ok());
+        assert!(err.contains("% -- created "), "{}", err);
+
+        // the output exists now, so it takes -f
+        let (code, _, err) = rgunzip(&[gz.clone()]);
+        assert_eq!(code, EXIT_WARNING);
+        assert!(err.contains("already exists;\tnot overwritten"));
+        write_file(&dir.join("b"), b"old");
+        assert_eq!(rgunzip(&["-f".to_string(), gz.clone()]).0, EXIT_OK);
+        assert!(read_file(&dir.join("b")) == data);
+    }
+
+    #[test]
+    fn test_exit_codes_match_gzip() {
+        let dir = scratch_dir("codes");
+        write_file(&dir.join("good"), text(2000).as_slice());
+        gzip(&dir, &["good"]);
+        let good = read_file(&dir.join("good.gz"));
+        let len = good.len();
+        let mut cases: Vec<(&str, Vec<u8>)> = Vec::new();
+        cases.push(("good", good.clone()));
+        cases.push(("truncated", good[.. len - 20].to_vec()));
+        let mut crc = good.clone();
+        crc[len - 8] ^= 1;
+        cases.push(("crc", crc));
+        let mut size = good.clone();
+        size[len - 1] ^= 1;
+        cases.push(("size", size));
+        let mut garbage = good.clone();
+        garbage.push_all(b"junk at the end");
+        cases.push(("garbage", garbage));
+        let mut zeros = good.clone();
+        zeros.push_all(&[0u8; 100]);
+        cases.push(("zeros", zeros));
+        let mut members = good.clone();
+        members.push_all(good.as_slice());
+        cases.push(("members", members));
+        cases.push(("plain", b"not compressed at all".to_vec()));
+
+        for &(name, ref data) in cases.iter() {
+            let file = dir.join(format!("{}.gz", name));
+            write_file(&file, data.as_slice());
+            let (expect, _) = gzip(&dir, &["-t", file.to_str().unwrap()]);
+            let (code, _, _) = rgunzip(&["-t".to_string(), arg(&file)]);
+            assert_eq!(code, expect, "testing {}", name);
+            let (expect, expect_out) = gzip(&dir, &["-dc", file.to_str().unwrap()]);
+            let (code, out, _) = rgunzip(&["-c".to_string(), arg(&file)]);
+            assert_eq!(code, expect, "decompressing {}", name);
+            if code != EXIT_ERROR {
+                assert!(out == expect_out, "output of {}", name);
+            }
+        }
+        let (_, _, err) = rgunzip(&["-t".to_string(), arg(&dir.join("crc.gz"))]);
+        assert_eq!(err.as_slice(), format!("\nrgunzip: {}: invalid compressed data--crc error\n",
+                                           arg(&dir.join("crc.gz"))).as_slice());
+        // a failed decompression leaves the input alone and no output
+        assert_eq!(rgunzip(&[arg(&dir.join("truncated.gz"))]).0, EXIT_ERROR);
+        assert!(fs::metadata(&dir.join("truncated.gz")).is_ok());
+        assert!(fs::metadata(&dir.join("truncated")).is_err());
+
+        // garbage that starts like a header isn't counted in the ratio
+        let mut fake = good.clone();
+        fake.push_all(b"\x1fjunk that starts like a header");
+        write_file(&dir.join("fake.gz"), fake.as_slice());
+        let ratio = |name: &str| {
+            let (_, _, err) = rgunzip(&["-cv".to_string(), arg(&dir.join(name))]);
+            let report = err.lines().next().unwrap().to_string();
+            report[report.find('\t').unwrap() + 1 .. report.find(" -- ").unwrap()].to_string()
+        };
+        assert_eq!(ratio("fake.gz"), ratio("good.gz"));
+    }
+
+    #[test]
+    fn test_list_matches_gzip() {
+        let dir = scratch_dir("list");
+        write_file(&dir.join("one"), text(3000).as_slice());
+        write_file(&dir.join("two"), b"");
+        gzip(&dir, &["one", "two"]);
+        for flags in ["-l", "-lv"].iter() {
+            let (code, out, _) = rgunzip(&[flags.to_string(), arg(&dir.join("one.gz"))]);
+            let (expect_code, expect) = gzip(&dir, &[*flags, "one.gz"]);
+            assert_eq!(code, expect_code);
+            // gzip was given a relative name, so compare from the numbers on
+            let out = String::from_utf8(out).unwrap();
+            let expect = String::from_utf8(expect).unwrap();
+            assert_eq!(out.lines().next(), expect.lines().next());
+            let row = out.lines().nth(1).unwrap();
+            let expect_row = expect.lines().nth(1).unwrap();
+            let name_at = row.rfind(' ').unwrap();
+            assert_eq!(&row[.. name_at], &expect_row[.. expect_row.rfind(' ').unwrap()]);
+            assert_eq!(&row[name_at + 1 ..], arg(&dir.join("one")).as_slice());
+        }
+        let (code, out, _) = rgunzip(&["-l".to_string(), arg(&dir.join("one.gz")),
+                                       arg(&dir.join("two.gz"))]);
+        assert_eq!(code, EXIT_OK);
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out.lines().count(), 4);
+        assert!(out.lines().last().unwrap().ends_with(" (totals)"));
+        assert!(out.contains("                  0   0.0% "));
+    }
+
+    #[test]
+    fn test_list_members() {
+        let dir = scratch_dir("members");
+        write_file(&dir.join("a"), text(2000).as_slice());
+        write_file(&dir.join("b"), b"short");
+        gzip(&dir, &["a", "b"]);
+        let mut both = read_file(&dir.join("a.gz"));
+        let a_len = both.len();
+        both.push_all(read_file(&dir.join("b.gz")).as_slice());
+        write_file(&dir.join("both.gz"), both.as_slice());
+        let uncompressed = text(2000).len() + 5;
+
+        // gzip would give the last member's size alone
+        let (code, out, _) = rgunzip(&["-l".to_string(), arg(&dir.join("both.gz"))]);
+        assert_eq!(code, EXIT_OK);
+        let out = String::from_utf8(out).unwrap();
+        let row = format!("{:>19} {:>19} ", both.len(), uncompressed);
+        assert!(out.lines().nth(1).unwrap().starts_with(row.as_slice()));
+
+        let (code, out, _) = rgunzip(&["-l".to_string(), "--members".to_string(), arg(&dir.join("both.gz"))]);
+        assert_eq!(code, EXIT_OK);
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("member              offset method crc"));
+        assert!(lines[1].starts_with(format!("{:>6} {:>19} defla ", 1, 0).as_slice()));
+        assert!(lines[2].starts_with(format!("{:>6} {:>19} defla ", 2, a_len).as_slice()));
+        assert!(lines[1].ends_with(" a") && lines[2].ends_with(" b"));
+        assert!(lines[3].starts_with("  file "));
+        assert!(lines[3].contains(row.as_slice()));
+
+        write_file(&dir.join("empty.gz"), b"");
+        let (code, _, err) = rgunzip(&["-l".to_string(), arg(&dir.join("empty.gz"))]);
+        assert_eq!(code, EXIT_ERROR);
+        assert!(err.ends_with("empty.gz: unexpected end of file\n"));
+    }
+
+    #[test]
+    fn test_restore_name() {
+        let dir = scratch_dir("name");
+        let data = text(10);
+        write_file(&dir.join("original.txt"), data.as_slice());
+        Command::new("touch").args(&["-d", "2001-02-03 04:05:06", "original.txt"])
+            .current_dir(&dir).status().unwrap();
+        let stamp = fs::metadata(&dir.join("original.txt")).unwrap().mtime();
+        gzip(&dir, &["-N", "original.txt"]);
+        fs::rename(&dir.join("original.txt.gz"), &dir.join("renamed.gz")).unwrap();
+
+        let (code, _, _) = rgunzip(&["-kN".to_string(), arg(&dir.join("renamed.gz"))]);
+        assert_eq!(code, EXIT_OK);
+        assert!(read_file(&dir.join("original.txt")) == data);
+        assert_eq!(fs::metadata(&dir.join("original.txt")).unwrap().mtime(), stamp);
+        // without -N the name comes from the file name
+        assert_eq!(rgunzip(&[arg(&dir.join("renamed.gz"))]).0, EXIT_OK);
+        assert!(read_file(&dir.join("renamed")) == data);
+
+        // a stored name that is the file's own must not overwrite it
+        let mut options = HeaderOptions::new();
+        options.fname = Some("same.gz".to_string());
+        let same = compress_with_header(data.as_slice(), 6, &options).unwrap();
+        write_file(&dir.join("same.gz"), same.as_slice());
+        let (code, _, err) = rgunzip(&["-Nf".to_string(), arg(&dir.join("same.gz"))]);
+        assert_eq!(code, EXIT_WARNING);
+        assert!(err.contains("same.gz: stored name is the file's own -- ignored"), "{}", err);
+        assert!(read_file(&dir.join("same.gz")) == same);
+    }
+
+    #[test]
+    fn test_recursive() {
+        let dir = scratch_dir("recursive");
+        fs::create_dir_all(&dir.join("sub/deeper")).unwrap();
+        write_file(&dir.join("sub/x"), b"x data\n");
+        write_file(&dir.join("sub/deeper/y"), b"y data\n");
+        gzip(&dir, &["-r", "sub"]);
+        write_file(&dir.join("sub/plain"), b"not compressed\n");
+
+        let (code, _, err) = rgunzip(&[arg(&dir.join("sub"))]);
+        assert_eq!(code, EXIT_WARNING);
+        assert!(err.contains("is a directory -- ignored"));
+
+        let (code, _, err) = rgunzip(&["-r".to_string(), arg(&dir.join("sub"))]);
+        assert_eq!(code, EXIT_WARNING);
+        assert!(err.contains("plain: unknown suffix -- ignored"));
+        assert_eq!(read_file(&dir.join("sub/x")).as_slice(), b"x data\n");
+        assert_eq!(read_file(&dir.join("sub/deeper/y")).as_slice(), b"y data\n");
+        assert!(fs::metadata(&dir.join("sub/deeper/y.gz")).is_err());
+    }
+
+    #[test]
+    fn test_arguments() {
+        let dir = scratch_dir("arguments");
+        let (code, _, err) = rgunzip(&["-x".to_string()]);
+        assert_eq!(code, EXIT_ERROR);
+        assert!(err.starts_with("rgunzip: invalid option -- 'x'"));
+        assert_eq!(rgunzip(&["--help".to_string()]).0, EXIT_OK);
+        let (code, _, err) = rgunzip(&[arg(&dir.join("missing.gz"))]);
+        assert_eq!(code, EXIT_ERROR);
+        assert!(err.ends_with(": No such file or directory\n"), "{}", err);
+        // an error outweighs a warning
+        write_file(&dir.join("plain"), b"plain");
+        let (code, _, _) = rgunzip(&[arg(&dir.join("plain")), arg(&dir.join("missing.gz"))]);
+        assert_eq!(code, EXIT_ERROR);
+        let (code, _, err) = rgunzip(&["-q".to_string(), arg(&dir.join("plain"))]);
+        assert_eq!((code, err.as_slice()), (EXIT_WARNING, ""));
+        // -cf passes through what isn't compressed
+        let (code, out, _) = rgunzip(&["-cf".to_string(), arg(&dir.join("plain"))]);
+        assert_eq!((code, out.as_slice()), (EXIT_OK, &b"plain"[..]));
+    }
+}
@@ -0,0 +1,183 @@
+#[doc="
+
+    Program: rgzinspect
+
+    Shows the DEFLATE blocks inside gzip files, for working out why
+    a file fails to decompress or compresses badly. Each file is
+    described as text, or with --json as a JSON object on a line of
+    its own. Exits with 0 if every file could be walked to its end,
+    and 1 if one could not be read or its data is damaged.
+
+"]
+extern crate rgzip;
+#[cfg(test)]
+extern crate libc;
+
+use std::env;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::process;
+
+use rgzip::inspect;
+
+const PROGRAM: &'static str = "rgzinspect";
+
+const EXIT_OK: i32 = 0;
+const EXIT_ERROR: i32 = 1;
+
+const USAGE: &'static str = "Usage: rgzinspect [OPTION]... [FILE]...
+Show the DEFLATE blocks inside gzip FILEs: where each one starts and
+ends, its type, the output it makes, and the code lengths of dynamic
+blocks.
+
+      --json        write a JSON object for each file, one to a line
+  -h, --help        show this help
+
+With no FILE, or when FILE is -, read standard input.
+";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let code = run(args.as_slice(), &mut io::stdout(), &mut io::stderr());
+    process::exit(code);
+}
+
+/// Run with the given arguments, writing output and messages to the
+/// given streams, and return the exit code
+fn run(args: &[String], out: &mut Write, err: &mut Write) -> i32 {
+    let mut json = false;
+    let mut files = Vec::new();
+    for arg in args.iter() {
+        match arg.as_slice() {
+            "--json" => json = true,
+            "-h" | "--help" => {
+                let _ = out.write_all(USAGE.as_bytes());
+                return EXIT_OK;
+            },
+            "-" => files.push(arg.as_slice()),
+            option if option.starts_with("-") => {
+                let _ = write!(err, "{}: unrecognized option '{}'\nTry '{} --help' for more information.\n",
+                               PROGRAM, option, PROGRAM);
+                return EXIT_ERROR;
+            },
+            file => files.push(file)
+        }
+    }
+    if files.len() == 0 {
+        files.push("-");
+    }
+    let mut status = EXIT_OK;
+    for &file in files.iter() {
+        let name = if file == "-" { "stdin" } else { file };
+        let mut data = Vec::new();
+        let read = if file == "-" {
+            io::stdin().read_to_end(&mut data)
+        } else {
+            File::open(file).and_then(|mut f| f.read_to_end(&mut data))
+        };
+        if let Err(e) = read {
+            let _ = write!(err, "{}: {}: {}\n", PROGRAM, name, e);
+            status = EXIT_ERROR;
+            continue;
+        }
+        let inspection = inspect(data.as_slice());
+        if inspection.error.is_some() {
+            status = EXIT_ERROR;
+        }
+        let written = if json {
+            write!(out, "{{\"file\":{},\"inspection\":{}}}\n", json_string(name), inspection.to_json())
+        } else {
+            write!(out, "{}:\n{}", name, inspection.to_text())
+        };
+        if written.is_err() {
+            return EXIT_ERROR;
+        }
+    }
+    status
+}
+
+/// A string as a quoted JSON string
+fn json_string(s: &str) -> String {
+    let mut quoted = "\"".to_string();
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if (c as u32) < 0x20 => quoted.push_str(format!("\\u{:04x}", c as u32).as_slice()),
+            c => quoted.push(c)
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod rgzinspect_tests {
+    use super::{run, json_string, EXIT_OK, EXIT_ERROR};
+    use libc;
+    use rgzip::compress;
+    use std::env;
+    use std::fs::File;
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    /// A path in the temporary directory no other run of the tests uses
+    fn temp_path(name: &str) -> PathBuf {
+        let pid = unsafe { libc::getpid() };
+        env::temp_dir().join(format!("rgzinspect-{}-{}", pid, name))
+    }
+
+    /// Write data to a file for a test to inspect, returning its name
+    fn write_file(name: &str, data: &[u8]) -> String {
+        let path = temp_path(name);
+        File::create(&path).unwrap().write_all(data).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    /// Run rgzinspect, returning its exit code, output and messages
+    fn rgzinspect(args: &[String]) -> (i32, String, String) {
+        let mut out = Vec::new();
+        let mut err = Vec::new();
+        let code = run(args, &mut out, &mut err);
+        (code, String::from_utf8(out).unwrap(), String::from_utf8(err).unwrap())
+    }
+
+    #[test]
+    fn test_text_and_json() {
+        let file = write_file("good.gz", compress(b"inspect this, inspect this", 6).as_slice());
+        let (code, out, _) = rgzinspect(&[file.clone()]);
+        assert_eq!(code, EXIT_OK);
+        assert!(out.starts_with(format!("{}:\nmember 1 at byte 0: header 10 bytes", file).as_slice()));
+        assert!(out.contains("  block 1 (last): "));
+        let (code, out, _) = rgzinspect(&["--json".to_string(), file.clone(), file.clone()]);
+        assert_eq!(code, EXIT_OK);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 2);
+        let start = format!("{{\"file\":{},\"inspection\":{{\"members\":[{{\"offset\":0,",
+                            json_string(file.as_slice()));
+        assert!(lines[0].starts_with(start.as_slice()));
+        assert!(lines[0].ends_with("\"error\":null}}"));
+        assert_eq!(json_string("a \"b\"\\\n"), "\"a \\\"b\\\"\\\\\\u000a\"");
+    }
+
+    #[test]
+    fn test_failures() {
+        let mut data = compress(b"inspect this, inspect this", 6);
+        data.truncate(15);
+        let file = write_file("truncated.gz", data.as_slice());
+        let (code, out, _) = rgzinspect(&[file]);
+        assert_eq!(code, EXIT_ERROR);
+        assert!(out.lines().last().unwrap().starts_with("error: "));
+        let missing = temp_path("missing.gz").to_str().unwrap().to_string();
+        let (code, _, err) = rgzinspect(&[missing.clone()]);
+        assert_eq!(code, EXIT_ERROR);
+        assert!(err.starts_with(format!("rgzinspect: {}: ", missing).as_slice()));
+        let (code, _, err) = rgzinspect(&["--bogus".to_string()]);
+        assert_eq!(code, EXIT_ERROR);
+        assert!(err.starts_with("rgzinspect: unrecognized option '--bogus'"));
+        let (code, out, _) = rgzinspect(&["--help".to_string()]);
+        assert_eq!(code, EXIT_OK);
+        assert!(out.starts_with("Usage: rgzinspect"));
+    }
+}
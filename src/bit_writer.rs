@@ -0,0 +1,176 @@
+#[doc="
+    Module: bit_writer
+
+    This module provides the inverse of gz_reader: a 'bit stream'
+    that values are written to, and that packs them into bytes,
+    least significant bit first, as DEFLATE expects.
+
+"]
+use std::mem;
+
+pub struct BitWriter {
+    out: Vec<u8>,
+    // how many bytes have been taken from out
+    taken: usize,
+    // bits waiting to be written, the oldest in the low bits
+    acc: u64,
+    count: u32
+}
+
+/// Write to a byte buffer bit by bit
+impl BitWriter {
+    pub fn new() -> BitWriter {
+        BitWriter { out: Vec::new(), taken: 0, acc: 0, count: 0 }
+    }
+
+    /// The position of the next bit to be written, counted in bits from
+    /// the start of the stream
+    pub fn bit_position(&self) -> usize {
+        (self.taken + self.out.len()) * 8 + self.count as usize
+    }
+
+    /// writes the low count bits of value in least to most significant
+    /// order, the mirror of GzBitReader::read_bits
+    pub fn write_bits(&mut self, value: u32, count: u32) {
+        assert!(count <= 32);
+        let mask = (1u64 << count) - 1;
+        self.acc |= (value as u64 & mask) << self.count;
+        self.count += count;
+        if self.count >= 32 {
+            for _ in 0 .. 4 {
+                self.out.push(self.acc as u8);
+                self.acc >>= 8;
+            }
+            self.count -= 32;
+        }
+    }
+
+    /// writes the low count bits of value in most to least significant
+    /// order, as Huffman codes are, the mirror of GzBitReader::read_bits_rev
+    pub fn write_bits_rev(&mut self, value: u32, count: u32) {
+        let mut reversed = 0;
+        for i in 0 .. count {
+            reversed |= ((value >> i) & 1) << (count - 1 - i);
+        }
+        self.write_bits(reversed, count);
+    }
+
+    /// Pad with zero bits to the next byte boundary, if not already on one
+    pub fn align_to_byte(&mut self) {
+        let pad = (8 - self.count % 8) % 8;
+        self.write_bits(0, pad);
+        self.flush_bytes();
+    }
+
+    /// Write whole bytes. The stream must be on a byte boundary.
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        assert!(self.count % 8 == 0);
+        self.flush_bytes();
+        self.out.push_all(bytes);
+    }
+
+    /// Take the bytes that are complete so far. Bits of a byte that is
+    /// only partly written stay behind.
+    pub fn take_bytes(&mut self) -> Vec<u8> {
+        self.flush_bytes();
+        self.taken += self.out.len();
+        mem::replace(&mut self.out, Vec::new())
+    }
+
+    /// Move the complete bytes in the accumulator to the buffer
+    fn flush_bytes(&mut self) {
+        while self.count >= 8 {
+            self.out.push(self.acc as u8);
+            self.acc >>= 8;
+            self.count -= 8;
+        }
+    }
+}
+
+#[cfg(test)]
+mod bit_writer_tests {
+    use super::BitWriter;
+    use cvec::CVec;
+    use gz_reader::GzBitReader;
+    use testing::Lcg;
+
+    /// Write one byte past the end, as GzBitReader needs a byte to start
+    fn finish(mut writer: BitWriter) -> Vec<u8> {
+        writer.align_to_byte();
+        writer.write_bytes(&[0xff]);
+        writer.take_bytes()
+    }
+
+    #[test]
+    fn test_write_bits() {
+        let mut writer = BitWriter::new();
+        writer.write_bits(1, 9);
+        writer.write_bits(385, 9);
+        writer.write_bits(0, 14);
+        assert_eq!(writer.bit_position(), 32);
+        // matches gz_reader_tests::test_read_bits
+        assert_eq!(writer.take_bytes(), vec![1, 2, 3, 0]);
+    }
+
+    #[test]
+    fn test_write_bits_rev() {
+        let mut writer = BitWriter::new();
+        writer.write_bits_rev(256, 9);
+        writer.write_bits_rev(259, 9);
+        writer.align_to_byte();
+        assert_eq!(writer.take_bytes(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_align_and_position() {
+        let mut writer = BitWriter::new();
+        writer.align_to_byte();
+        assert_eq!(writer.bit_position(), 0);
+        writer.write_bits(0x5, 3);
+        assert_eq!(writer.bit_position(), 3);
+        writer.align_to_byte();
+        assert_eq!(writer.bit_position(), 8);
+        writer.write_bytes(&[0xab, 0xcd]);
+        writer.write_bits(0x1, 1);
+        assert_eq!(writer.bit_position(), 25);
+        // the partial byte stays behind
+        assert_eq!(writer.take_bytes(), vec![0x5, 0xab, 0xcd]);
+        assert_eq!(writer.bit_position(), 25);
+        writer.write_bits(0, 7);
+        assert_eq!(writer.take_bytes(), vec![0x1]);
+    }
+
+    #[test]
+    fn test_read_back() {
+        // random runs of writes, read back through GzBitReader
+        let mut random = Lcg::new(12345);
+        let mut next = || random.next();
+        for _ in 0 .. 50 {
+            let mut writer = BitWriter::new();
+            let mut ops = Vec::new();
+            for _ in 0 .. 200 {
+                let op = next() % 8;
+                let count = next() % 33;
+                let value = next() ^ (next() << 24);
+                let value = if count == 32 { value } else { value & ((1 << count) - 1) };
+                match op {
+                    0 => writer.align_to_byte(),
+                    1 => writer.write_bits_rev(value, count),
+                    _ => writer.write_bits(value, count)
+                }
+                ops.push((op, value, count, writer.bit_position()));
+            }
+            let bytes = finish(writer);
+            let buffer = unsafe { CVec::from_raw_buf(bytes.as_ptr(), bytes.len()) }.unwrap();
+            let mut reader = GzBitReader::new(buffer.iter()).unwrap();
+            for &(op, value, count, position) in ops.iter() {
+                match op {
+                    0 => reader.align_to_byte(),
+                    1 => assert_eq!(reader.read_bits_rev(count), Some(value)),
+                    _ => assert_eq!(reader.read_bits(count), Some(value))
+                }
+                assert_eq!(reader.bit_position(), position);
+            }
+        }
+    }
+}
@@ -0,0 +1,163 @@
+#[doc="
+
+    Module: bufread
+
+    This module provides GzBufDecoder, for gzip data embedded in
+    a larger stream. It decompresses a single member from a
+    std::io::BufRead, consuming exactly the bytes that belong to
+    it, footer included, so whatever follows the member is left
+    in the underlying reader.
+
+"]
+use std::io;
+use std::io::{Read, BufRead};
+
+use error::{GzError, ErrorKind};
+use header::GZHeader;
+use stream::{Decompressor, StreamStatus};
+
+/// Decompresses one gzip member read from R, leaving R positioned just
+/// after the member's footer
+pub struct GzBufDecoder<R> {
+    reader: R,
+    decompressor: Decompressor,
+    done: bool
+}
+
+impl<R: BufRead> GzBufDecoder<R> {
+    pub fn new(reader: R) -> GzBufDecoder<R> {
+        GzBufDecoder {
+            reader: reader,
+            decompressor: Decompressor::new(),
+            done: false
+        }
+    }
+
+    /// The member's header, once it has been parsed
+    pub fn header(&self) -> Option<&GZHeader> {
+        self.decompressor.header()
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    /// Give back the inner reader. Once read() has returned 0, it is
+    /// positioned just after the member.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R: BufRead> Read for GzBufDecoder<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if out.len() == 0 || self.done {
+            return Ok(0);
+        }
+        loop {
+            let (used, written, status, eof) = {
+                let input = match self.reader.fill_buf() {
+                    Ok(input) => input,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e)
+                };
+                match self.decompressor.decompress(input, out) {
+                    Ok((used, written, status)) => (used, written, status, input.len() == 0),
+                    Err(e) => return Err(e.to_io_error())
+                }
+            };
+            self.reader.consume(used);
+            match status {
+                StreamStatus::OutputFull => return Ok(written),
+                StreamStatus::MemberEnd => {
+                    self.done = true;
+                    return Ok(written);
+                },
+                StreamStatus::NeedInput => {
+                    // output first, then the error on the next call
+                    if eof && written == 0 {
+                        let position = self.decompressor.total_in();
+                        return Err(GzError::new(ErrorKind::UnexpectedEof, position).to_io_error());
+                    }
+                }
+            }
+            if written > 0 {
+                return Ok(written);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod gz_buf_decoder_tests {
+    use super::GzBufDecoder;
+    use std::io;
+    use std::io::{Read, BufReader};
+    use testing::{COMPRESSED, EXPECTED};
+    static TRAILING: &'static [u8] = b"HTTP/1.1 200 OK\r\n";
+
+    fn read_all<R: Read>(reader: &mut R, step: usize) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        let mut buf = vec![0u8; step];
+        loop {
+            let n = try!(reader.read(buf.as_mut_slice()));
+            if n == 0 {
+                return Ok(out);
+            }
+            out.push_all(&buf[.. n]);
+        }
+    }
+
+    #[test]
+    fn test_leaves_trailing_data() {
+        let mut input = COMPRESSED.to_vec();
+        input.push_all(TRAILING);
+        let mut rest = input.as_slice();
+        {
+            let mut decoder = GzBufDecoder::new(&mut rest);
+            assert_eq!(read_all(&mut decoder, 4096).unwrap().as_slice(), EXPECTED);
+        }
+        assert_eq!(rest, TRAILING);
+    }
+
+    #[test]
+    fn test_small_buffers() {
+        let mut input = COMPRESSED.to_vec();
+        input.push_all(TRAILING);
+        for &capacity in [1, 2, 5, 8, 13].iter() {
+            let reader = BufReader::with_capacity(capacity, input.as_slice());
+            let mut decoder = GzBufDecoder::new(reader);
+            assert_eq!(read_all(&mut decoder, 3).unwrap().as_slice(), EXPECTED);
+            let mut reader = decoder.into_inner();
+            assert_eq!(read_all(&mut reader, 100).unwrap().as_slice(), TRAILING);
+        }
+    }
+
+    #[test]
+    fn test_one_member_only() {
+        let mut input = COMPRESSED.to_vec();
+        input.push_all(COMPRESSED);
+        let mut rest = input.as_slice();
+        {
+            let mut decoder = GzBufDecoder::new(&mut rest);
+            assert_eq!(read_all(&mut decoder, 7).unwrap().as_slice(), EXPECTED);
+            assert_eq!(decoder.header().unwrap().header_len, 10);
+        }
+        assert_eq!(rest, COMPRESSED);
+    }
+
+    #[test]
+    fn test_truncated() {
+        let mut rest = &COMPRESSED[.. 72];
+        let mut decoder = GzBufDecoder::new(&mut rest);
+        let err = read_all(&mut decoder, 4096).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        // without its footer, the whole output comes before the error
+        let mut rest = &COMPRESSED[.. COMPRESSED.len() - 8];
+        let mut decoder = GzBufDecoder::new(&mut rest);
+        let mut buf = [0u8; 4096];
+        assert_eq!(decoder.read(&mut buf).unwrap(), EXPECTED.len());
+        assert_eq!(&buf[.. EXPECTED.len()], EXPECTED);
+        assert_eq!(decoder.read(&mut buf).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -2,7 +2,8 @@
 
     Module: crc32
 
-    This module handles verifying the CRC in the GZip file
+    This module handles verifying the CRC in the GZip file,
+    and combining the CRCs of pieces compressed separately
 
 "]
 use cvec;
@@ -10,14 +11,15 @@ use cvec;
 const IEEE: u32 = 0xedb88320;
 
 /// Cyclic Redundancy Check
-struct Crc32 {
+/// Can be fed data a piece at a time with update()
+pub struct Crc32 {
     table: [u32; 256],
     value: u32
 }
 
 impl Crc32 {
     /// Setup the CRC
-    fn new() -> Crc32 {
+    pub fn new() -> Crc32 {
         let mut c = Crc32 { table: [0; 256], value: 0xffffffff };
         for i in 0 .. 256 {
             let mut v = i as u32;
@@ -41,6 +43,19 @@ impl Crc32 {
         }
         self.value ^ 0xffffffff
     }
+
+    /// Add the given bytes to the CRC
+    pub fn update(&mut self, buf: &[u8]) {
+        for &i in buf.iter() {
+            self.value = self.table[((self.value ^ (i as u32)) & 0xFF) as usize] ^
+                (self.value >> 8);
+        }
+    }
+
+    /// The CRC of all the bytes added so far
+    pub fn crc(&self) -> u32 {
+        self.value ^ 0xffffffff
+    }
 }
 
 /// Public interface for using the CRC
@@ -48,3 +63,104 @@ pub fn sum(buf: cvec::Iter<u8>) -> u32 {
     let mut c = Crc32::new();
     c.sum(buf)
 }
+
+/// The CRC of two pieces of data one after the other, from the CRC of
+/// each and the length of the second, without going over the data again.
+/// This is zlib's crc32_combine: crc1 is run through len2 zero bytes by
+/// repeatedly squaring the operator for a single zero bit.
+pub fn combine(crc1: u32, crc2: u32, len2: u64) -> u32 {
+    if len2 == 0 {
+        return crc1;
+    }
+    let mut even = [0u32; 32];
+    let mut odd = [0u32; 32];
+
+    // the operator for one zero bit
+    odd[0] = IEEE;
+    for n in 1 .. 32 {
+        odd[n] = 1 << (n - 1);
+    }
+    // then two zero bits, and four
+    gf2_matrix_square(&mut even, &odd);
+    gf2_matrix_square(&mut odd, &even);
+
+    // apply len2 zero bytes, starting with the operator for one byte
+    let mut crc1 = crc1;
+    let mut len2 = len2;
+    loop {
+        gf2_matrix_square(&mut even, &odd);
+        if len2 & 1 != 0 {
+            crc1 = gf2_matrix_times(&even, crc1);
+        }
+        len2 >>= 1;
+        if len2 == 0 {
+            break;
+        }
+        gf2_matrix_square(&mut odd, &even);
+        if len2 & 1 != 0 {
+            crc1 = gf2_matrix_times(&odd, crc1);
+        }
+        len2 >>= 1;
+        if len2 == 0 {
+            break;
+        }
+    }
+    crc1 ^ crc2
+}
+
+/// Multiply a vector by a 32x32 matrix over GF(2)
+fn gf2_matrix_times(mat: &[u32; 32], mut vec: u32) -> u32 {
+    let mut sum = 0;
+    let mut i = 0;
+    while vec != 0 {
+        if vec & 1 != 0 {
+            sum ^= mat[i];
+        }
+        vec >>= 1;
+        i += 1;
+    }
+    sum
+}
+
+fn gf2_matrix_square(square: &mut [u32; 32], mat: &[u32; 32]) {
+    for n in 0 .. 32 {
+        square[n] = gf2_matrix_times(mat, mat[n]);
+    }
+}
+
+#[cfg(test)]
+mod crc32_tests {
+    use super::{sum, combine, Crc32};
+    use cvec::CVec;
+
+    #[test]
+    fn test_update_matches_sum() {
+        let data = b"123456789";
+        let mut buf: CVec<u8> = CVec::with_capacity(data.len()).unwrap();
+        for &b in data.iter() {
+            buf.push(b);
+        }
+        let mut crc = Crc32::new();
+        crc.update(&data[..4]);
+        crc.update(&data[4..]);
+        assert_eq!(crc.crc(), 0xcbf43926);
+        assert_eq!(sum(buf.iter()), 0xcbf43926);
+    }
+
+    fn crc_of(data: &[u8]) -> u32 {
+        let mut crc = Crc32::new();
+        crc.update(data);
+        crc.crc()
+    }
+
+    #[test]
+    fn test_combine() {
+        let data: Vec<u8> = (0 .. 100000).map(|i| (i * 7 + i / 13) as u8).collect();
+        let whole = crc_of(data.as_slice());
+        for &split in [0, 1, 9, 1000, 65536, 99999, 100000].iter() {
+            let (a, b) = data.split_at(split);
+            assert_eq!(combine(crc_of(a), crc_of(b), b.len() as u64), whole);
+        }
+        assert_eq!(combine(crc_of(b"1234"), crc_of(b"56789"), 5), 0xcbf43926);
+    }
+}
@@ -4,7 +4,10 @@
 
     This is a slight modification of Vec from the standard library, but uses
     C-style allocation and reallocation so we can safely construct it from a
-    C pointer, or return it as a C pointer. This cannot safely be used on
+    C pointer, or return it as a C pointer. By default memory comes from
+    libc's malloc, but a C caller may supply its own Allocator instead,
+    in which case the returned pointer must be freed with that same
+    Allocator. This cannot safely be used on
     zero-sized types, and will panic if you try.
 
     This CANNOT be used on types that implement Drop, or else we will leak memory
@@ -22,16 +25,60 @@ use self::core::raw::Slice as RawSlice;
 use self::core::num::Int;
 use std::ptr;
 use std::fmt;
+use std::io;
+use std::io::Write;
 
 const DEFAULT_CVEC_CAPACITY: usize = 8;
 
 pub type Buf = CVec<u8>;
 
+/// Memory allocation callbacks used by a CVec, so that C callers can
+/// control where the memory used for them comes from. opaque is passed
+/// back to every call.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct Allocator {
+    pub alloc: extern "C" fn(opaque: *mut c_void, size: size_t) -> *mut c_void,
+    pub realloc: extern "C" fn(opaque: *mut c_void, ptr: *mut c_void, size: size_t)
+        -> *mut c_void,
+    pub free: extern "C" fn(opaque: *mut c_void, ptr: *mut c_void),
+    pub opaque: *mut c_void,
+}
+
+impl Allocator {
+    /// The default Allocator, which uses libc's malloc, realloc and free
+    pub fn libc() -> Allocator {
+        Allocator {
+            alloc: libc_alloc,
+            realloc: libc_realloc,
+            free: libc_free,
+            opaque: ptr::null_mut(),
+        }
+    }
+}
+
+extern "C" fn libc_alloc(_opaque: *mut c_void, size: size_t) -> *mut c_void {
+    unsafe { malloc(size) }
+}
+
+extern "C" fn libc_realloc(_opaque: *mut c_void, ptr: *mut c_void, size: size_t)
+        -> *mut c_void {
+    unsafe { realloc(ptr, size) }
+}
+
+extern "C" fn libc_free(_opaque: *mut c_void, ptr: *mut c_void) {
+    unsafe { free(ptr) }
+}
+
 pub struct CVec<T> {
     ptr: *mut T,
     len: usize,
     cap: usize,
     mutable: bool,
+    owned: bool,
+    // set once the CVec has needed to grow and could not
+    grow_failed: bool,
+    allocator: Allocator,
 }
 
 impl<T> CVec<T> {
@@ -52,13 +99,20 @@ impl<T> CVec<T> {
     /// Constructs a new CVec with given capacity
     /// returns None if the allocation fails
     pub fn with_capacity(capacity: usize) -> Option<CVec<T>> {
+        CVec::<T>::with_capacity_in(capacity, Allocator::libc())
+    }
+
+    /// Constructs a new CVec with given capacity, whose memory is managed
+    /// by the given Allocator
+    /// returns None if the allocation fails
+    pub fn with_capacity_in(capacity: usize, allocator: Allocator) -> Option<CVec<T>> {
         let capacity = if capacity > 0 { capacity } else { DEFAULT_CVEC_CAPACITY } ;
         CVec::<T>::check_type_size();
         let size = capacity.checked_mul(mem::size_of::<T>() as usize);
         if size.is_none() {
             return None;
         }
-        let ptr = unsafe { malloc(size.unwrap() as size_t) } as *mut T;
+        let ptr = (allocator.alloc)(allocator.opaque, size.unwrap() as size_t) as *mut T;
         if ptr.is_null() {
             None
         } else {
@@ -66,7 +120,10 @@ impl<T> CVec<T> {
                 ptr: ptr,
                 len: 0,
                 cap: capacity,
-                mutable: true
+                mutable: true,
+                owned: true,
+                grow_failed: false,
+                allocator: allocator
             })
         }
     }
@@ -82,13 +139,38 @@ impl<T> CVec<T> {
                 ptr: ptr as *mut T,
                 len: buf_size,
                 cap: buf_size,
-                mutable: false
+                mutable: false,
+                owned: false,
+                grow_failed: false,
+                allocator: Allocator::libc()
+            })
+        }
+    }
+
+    /// Constructs an empty CVec on top of a caller-owned buffer with room
+    /// for buf_size elements, without copying.
+    /// If the input pointer is null, then None is returned
+    /// The returned CVec can be written to, but will never grow past
+    /// buf_size, and the buffer is NOT freed when the CVec is dropped.
+    pub unsafe fn from_raw_buf_mut(ptr: *mut T, buf_size: usize) -> Option<CVec<T>> {
+        if ptr.is_null() {
+            None
+        } else {
+            Some(CVec {
+                ptr: ptr,
+                len: 0,
+                cap: buf_size,
+                mutable: true,
+                owned: false,
+                grow_failed: false,
+                allocator: Allocator::libc()
             })
         }
     }
 
     /// Converts this CVec to a raw pointer. The CVec cannot be used after this
-    /// is called. The raw pointer must be freed by the caller.
+    /// is called. The raw pointer must be freed by the caller, using the
+    /// Allocator the CVec was created with.
     pub fn into_raw_buf(self) -> (*mut T, usize) {
         let ret = (self.ptr, self.len);
         unsafe { mem::forget(self); }
@@ -100,19 +182,39 @@ impl<T> CVec<T> {
         self.len
     }
 
+    /// Return the number of elements the CVec can hold before it has to grow
+    pub fn capacity(&self) -> usize {
+        self.cap
+    }
+
+    /// The Allocator the CVec's memory comes from
+    pub fn allocator(&self) -> Allocator {
+        self.allocator
+    }
+
+    /// Whether the CVec has needed more room than it could get, since an
+    /// allocation failed or it does not own its buffer
+    pub fn grow_failed(&self) -> bool {
+        self.grow_failed
+    }
+
     /// Effect: doubles the CVec's capacity
-    /// returns None if the allocation failed
+    /// returns None if the allocation failed, or if the CVec does not own
+    /// its buffer and so cannot grow
     pub fn double_capacity(&mut self) -> Option<()> {
         assert!(self.mutable);
         let old_size = self.cap * mem::size_of::<T>();
         let size = old_size * 2;
-        if old_size > size {
-            mem::drop(self);
+        if !self.owned || old_size > size {
+            self.grow_failed = true;
             return None;
         }
         unsafe {
-            let new_ptr = realloc(self.ptr as *mut c_void, size as size_t);
+            let new_ptr = (self.allocator.realloc)(self.allocator.opaque,
+                                                   self.ptr as *mut c_void,
+                                                   size as size_t);
             if new_ptr.is_null() {
+                self.grow_failed = true;
                 return None;
             }
             self.ptr = new_ptr as *mut T;
@@ -167,6 +269,17 @@ impl<T> CVec<T> {
         }
     }
 
+    /// Get a mutable reference to the element at the given index
+    /// Does bounds checking to ensure that index < len
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut T> {
+        assert!(self.mutable);
+        if index < self.len {
+            Some(unsafe { &mut *self.ptr.offset(index as isize) })
+        } else {
+            None
+        }
+    }
+
     /// gets sizeof(U) bytes at the given index into the CVec
     pub fn get_wide<U>(&self, index: usize) -> Option<U> {
         let size = mem::size_of::<U>();
@@ -211,16 +324,22 @@ impl<T> CVec<T> {
 
 impl<T: Clone> CVec<T> {
     /// Add to the CVec length bytes from distance bytes from the end
-    pub fn copy_back_pointer(&mut self, distance: usize, length: usize) {
+    /// returns None if distance points before the start of the CVec, or
+    /// if we had to reallocate and it failed
+    pub fn copy_back_pointer(&mut self, distance: usize, length: usize) -> Option<()> {
+        if distance >= self.len {
+            return None;
+        }
         let mut back_ptr  = self.len - distance - 1;
         let mut length = length;
         let mut c;
         while length > 0 {
             c = self[back_ptr].clone();
-            self.push(c);
+            try_opt!(self.push(c));
             back_ptr += 1;
             length -= 1;
         }
+        Some(())
     }
 }
 
@@ -235,12 +354,18 @@ impl<T> Index<usize> for CVec<T> {
 
 }
 
+/// View a slice as a Buf, without copying, or None if it is empty
+/// The Buf must not outlive the slice
+pub fn slice_buf(data: &[u8]) -> Option<Buf> {
+    unsafe { CVec::from_raw_buf(data.as_ptr(), data.len()) }
+}
+
 #[unsafe_destructor]
 impl<T> Drop for CVec<T> {
     fn drop(&mut self) {
-        if self.mutable {
+        if self.owned {
             self.clear();
-            unsafe { free(self.ptr as *mut c_void); }
+            (self.allocator.free)(self.allocator.opaque, self.ptr as *mut c_void);
         }
     }
 }
@@ -256,6 +381,28 @@ impl<T> AsSlice<T> for CVec<T> {
     }
 }
 
+impl<T: PartialEq> PartialEq for CVec<T> {
+    fn eq(&self, other: &CVec<T>) -> bool {
+        self.as_slice() == other.as_slice()
+    }
+}
+
+impl Write for CVec<u8> {
+    /// Fails if the CVec has to grow and can't
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        for &byte in data.iter() {
+            if self.push(byte).is_none() {
+                return Err(io::Error::new(io::ErrorKind::Other, "out of memory"));
+            }
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
 impl<T: fmt::Show> fmt::Show for CVec<T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         fmt::Show::fmt(self.as_slice(), f)
@@ -305,6 +452,16 @@ impl<'a, T> Iter<'a, T> {
         self.index
     }
 
+    /// Whether next() has been called more times than there were elements
+    pub fn overrun(&self) -> bool {
+        let len = self.cvec.len();
+        let end = match self.limit {
+            Some(limit) if limit < len => limit,
+            _ => len
+        };
+        self.index > end
+    }
+
     #[inline]
     #[allow(dead_code)]
     pub fn skip(&self, n: usize) -> Iter<'a, T> {
@@ -340,7 +497,9 @@ impl<'a, T> Iterator for Iter<'a, T> {
 
 #[cfg(test)]
 mod cvec_tests {
-    use super::CVec;
+    use super::{CVec, Allocator};
+    use libc::{c_void, size_t};
+    use libc::funcs::c95::stdlib::{malloc, realloc, free};
 
     fn setup() -> CVec<u8> {
         let mut v: CVec<u8> = CVec::new().unwrap();
@@ -394,6 +553,102 @@ mod cvec_tests {
         v.push(42);
         assert_eq!(v[v.len() - 1], 42);
     }
+
+    #[test]
+    fn test_from_raw_buf_mut() {
+        let mut backing = [0u8; 4];
+        {
+            let mut v = unsafe { CVec::from_raw_buf_mut(backing.as_mut_ptr(), 4).unwrap() };
+            for i in 0 .. 4 {
+                assert_eq!(v.push(i + 1), Some(()));
+            }
+            assert!(!v.grow_failed());
+            // full, and not allowed to grow
+            assert_eq!(v.push(5), None);
+            assert_eq!(v.len(), 4);
+            assert!(v.grow_failed());
+        }
+        assert_eq!(backing, [1, 2, 3, 4]);
+    }
+
+    // opaque points at a [allocs, reallocs, frees] counter
+    extern "C" fn counting_alloc(opaque: *mut c_void, size: size_t) -> *mut c_void {
+        unsafe {
+            (*(opaque as *mut [usize; 3]))[0] += 1;
+            malloc(size)
+        }
+    }
+
+    extern "C" fn counting_realloc(opaque: *mut c_void, ptr: *mut c_void, size: size_t)
+            -> *mut c_void {
+        unsafe {
+            (*(opaque as *mut [usize; 3]))[1] += 1;
+            realloc(ptr, size)
+        }
+    }
+
+    extern "C" fn counting_free(opaque: *mut c_void, ptr: *mut c_void) {
+        unsafe {
+            (*(opaque as *mut [usize; 3]))[2] += 1;
+            free(ptr)
+        }
+    }
+
+    #[test]
+    fn test_custom_allocator() {
+        let mut counts = [0usize; 3];
+        {
+            let allocator = Allocator {
+                alloc: counting_alloc,
+                realloc: counting_realloc,
+                free: counting_free,
+                opaque: &mut counts as *mut [usize; 3] as *mut c_void
+            };
+            let mut v: CVec<u8> = CVec::with_capacity_in(2, allocator).unwrap();
+            for i in 0 .. 5 {
+                v.push(i);
+            }
+            assert_eq!(v[4], 4);
+        }
+        // 2 -> 4 -> 8
+        assert_eq!(counts, [1, 2, 1]);
+    }
+
+    extern "C" fn failing_realloc(_opaque: *mut c_void, _ptr: *mut c_void, _size: size_t)
+            -> *mut c_void {
+        0 as *mut c_void
+    }
+
+    #[test]
+    fn test_failed_realloc() {
+        let mut counts = [0usize; 3];
+        {
+            let allocator = Allocator {
+                alloc: counting_alloc,
+                realloc: failing_realloc,
+                free: counting_free,
+                opaque: &mut counts as *mut [usize; 3] as *mut c_void
+            };
+            let mut v: CVec<u8> = CVec::with_capacity_in(2, allocator).unwrap();
+            assert_eq!(v.push(1), Some(()));
+            assert_eq!(v.push(2), Some(()));
+            assert!(!v.grow_failed());
+            assert_eq!(v.push(3), None);
+            assert!(v.grow_failed());
+            assert_eq!(v.len(), 2);
+        }
+        assert_eq!(counts, [1, 0, 1]);
+    }
+
+    #[test]
+    fn test_copy_back_pointer() {
+        let mut v = setup();
+        assert_eq!(v.copy_back_pointer(1, 3), Some(()));
+        assert_eq!(v.pop(), Some(8));
+        assert_eq!(v.pop(), Some(9));
+        assert_eq!(v.pop(), Some(8));
+        assert_eq!(v.copy_back_pointer(v.len(), 1), None);
+    }
 }
 
 
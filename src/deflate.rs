@@ -0,0 +1,692 @@
+#[doc="
+
+    Module: deflate
+
+    This module compresses data into a raw DEFLATE stream, the
+    inverse of inflate. Matches are found with hash chains over a
+    32K window, lazily for the higher levels, and each block is
+    written with whichever of dynamic Huffman codes, the fixed
+    codes, or no compression at all comes out smallest. Levels
+    0 to 9 trade speed for size much as they do for gzip.
+
+"]
+use std::cmp;
+
+use bit_writer::BitWriter;
+use huffman::{code_lengths, canonical_codes, encode_code_lengths, code_length_extra};
+use sink::WINDOW_SIZE;
+
+// These constants are defined by the DEFLATE standard
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const WINDOW_MASK: usize = WINDOW_SIZE - 1;
+const MAX_STORED_LEN: usize = 65535;
+const END_OF_BLOCK: usize = 256;
+const NUM_LITERAL_CODES: usize = 286;
+const NUM_DISTANCE_CODES: usize = 30;
+const NUM_CODE_LENGTH_CODES: usize = 19;
+const MAX_CODE_LENGTH: u32 = 15;
+const MAX_CODE_LENGTH_CODE_LENGTH: u32 = 7;
+static CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+static LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115,
+    131, 163, 195, 227, 258];
+static LENGTH_EXTRA: [u32; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+static DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577];
+static DIST_EXTRA: [u32; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+
+const HASH_BITS: usize = 15;
+const HASH_SIZE: usize = 1 << HASH_BITS;
+// keep this much input unprocessed until the end, so matches can be as
+// long as allowed
+const MIN_LOOKAHEAD: usize = MAX_MATCH + MIN_MATCH + 1;
+// a match of MIN_MATCH this far back costs more than the literals
+const TOO_FAR: usize = 4096;
+// the most symbols to put in one block
+const BLOCK_TOKENS: usize = 16384;
+
+/// How hard to look for matches at each level, as in zlib
+#[derive(Copy, Clone)]
+struct Config {
+    /// Look less hard once a match this long has been found
+    good_length: usize,
+    /// Don't look for a better match than one this long. For the greedy
+    /// levels, don't bother hashing inside matches longer than this
+    max_lazy: usize,
+    /// Stop looking once a match is this long
+    nice_length: usize,
+    /// How many hash chain links to follow
+    max_chain: usize,
+    /// Whether to try the next position for a longer match
+    lazy: bool
+}
+
+static CONFIGS: [Config; 10] = [
+    Config { good_length: 0, max_lazy: 0, nice_length: 0, max_chain: 0, lazy: false },
+    Config { good_length: 4, max_lazy: 4, nice_length: 8, max_chain: 4, lazy: false },
+    Config { good_length: 4, max_lazy: 5, nice_length: 16, max_chain: 8, lazy: false },
+    Config { good_length: 4, max_lazy: 6, nice_length: 32, max_chain: 32, lazy: false },
+    Config { good_length: 4, max_lazy: 4, nice_length: 16, max_chain: 16, lazy: true },
+    Config { good_length: 8, max_lazy: 16, nice_length: 32, max_chain: 32, lazy: true },
+    Config { good_length: 8, max_lazy: 16, nice_length: 128, max_chain: 128, lazy: true },
+    Config { good_length: 8, max_lazy: 32, nice_length: 128, max_chain: 256, lazy: true },
+    Config { good_length: 32, max_lazy: 128, nice_length: 258, max_chain: 1024, lazy: true },
+    Config { good_length: 32, max_lazy: 258, nice_length: 258, max_chain: 4096, lazy: true }];
+
+/// A literal byte, or a length and distance back
+#[derive(Copy, Clone, PartialEq, Show)]
+enum Token {
+    Literal(u8),
+    Match(u16, u16)
+}
+
+/////////////////////////////////////////////////////////////////////
+//                     Building the codes                          //
+/////////////////////////////////////////////////////////////////////
+
+/// The length code for a match length, counted from 257
+fn length_code(length: usize) -> usize {
+    let mut code = 0;
+    while code + 1 < LENGTH_BASE.len() && LENGTH_BASE[code + 1] as usize <= length {
+        code += 1;
+    }
+    code
+}
+
+/// The distance code for a match distance
+fn distance_code(distance: usize) -> usize {
+    let mut code = 0;
+    while code + 1 < DIST_BASE.len() && DIST_BASE[code + 1] as usize <= distance {
+        code += 1;
+    }
+    code
+}
+
+/// The code lengths of the fixed literals and distances codes
+fn fixed_lengths() -> (Vec<u32>, Vec<u32>) {
+    let mut literals = vec![8u32; 288];
+    for i in 144 .. 256 {
+        literals[i] = 9;
+    }
+    for i in 256 .. 280 {
+        literals[i] = 7;
+    }
+    (literals, vec![5u32; NUM_DISTANCE_CODES])
+}
+
+/////////////////////////////////////////////////////////////////////
+//                          Deflater                               //
+/////////////////////////////////////////////////////////////////////
+
+/// Compresses data given to it a piece at a time into a raw DEFLATE
+/// stream
+pub struct Deflater {
+    config: Config,
+    level: u32,
+    // input, starting with the window that matches can refer back to
+    buf: Vec<u8>,
+    // the position in the whole input of buf[0]
+    offset: usize,
+    // the index into buf of the next byte to compress
+    pos: usize,
+    // the index into buf of the first byte of the current block
+    block_start: usize,
+    // the latest position + 1 with each hash, or 0 for none
+    head: Vec<usize>,
+    // the previous position + 1 with the same hash as each position
+    prev: Vec<usize>,
+    // a match found at the previous position, for lazy matching
+    prev_length: usize,
+    prev_distance: usize,
+    match_available: bool,
+    tokens: Vec<Token>,
+    out: BitWriter,
+    finished: bool
+}
+
+impl Deflater {
+    /// Create a Deflater for the given level, from 0 (no compression) to
+    /// 9 (smallest). Levels above 9 are treated as 9.
+    pub fn new(level: u32) -> Deflater {
+        let level = cmp::min(level, 9);
+        Deflater {
+            config: CONFIGS[level as usize],
+            level: level,
+            buf: Vec::new(),
+            offset: 0,
+            pos: 0,
+            block_start: 0,
+            head: vec![0; HASH_SIZE],
+            prev: vec![0; WINDOW_SIZE],
+            prev_length: 0,
+            prev_distance: 0,
+            match_available: false,
+            tokens: Vec::with_capacity(BLOCK_TOKENS),
+            out: BitWriter::new(),
+            finished: false
+        }
+    }
+
+    /// Compress data, holding back what is needed to find later matches
+    pub fn write(&mut self, data: &[u8]) {
+        assert!(!self.finished);
+        self.buf.push_all(data);
+        self.compress(false);
+        self.slide();
+    }
+
+    /// Let the first matches refer back into dict, as if it had been
+    /// compressed just before, without it being part of the output. Only
+    /// the last 32K of it can be used. Must be called before any write.
+    pub fn set_dictionary(&mut self, dict: &[u8]) {
+        assert!(self.buf.len() == 0 && !self.finished);
+        let start = if dict.len() > WINDOW_SIZE { dict.len() - WINDOW_SIZE } else { 0 };
+        self.buf.push_all(&dict[start ..]);
+        if self.level > 0 {
+            for p in 0 .. self.buf.len() {
+                self.insert(p);
+            }
+        }
+        self.pos = self.buf.len();
+        self.block_start = self.buf.len();
+    }
+
+    /// Compress everything written so far, and end the output on a byte
+    /// boundary with an empty stored block, as zlib's Z_SYNC_FLUSH does.
+    /// Everything written can then be decompressed from the output, and
+    /// later matches may still refer back to it.
+    pub fn sync_flush(&mut self) {
+        assert!(!self.finished);
+        self.compress(true);
+        if self.tokens.len() > 0 || self.pos > self.block_start {
+            self.flush_block(false);
+        }
+        self.out.write_bits(0, 1);
+        self.out.write_bits(0, 2);
+        self.out.align_to_byte();
+        self.out.write_bits(0, 16);
+        self.out.write_bits(0xffff, 16);
+    }
+
+    /// Compress whatever is left, and end the stream with the last block
+    pub fn finish(&mut self) {
+        if self.finished {
+            return;
+        }
+        self.compress(true);
+        self.flush_block(true);
+        self.out.align_to_byte();
+        self.finished = true;
+    }
+
+    /// Take the compressed bytes written so far
+    pub fn take_output(&mut self) -> Vec<u8> {
+        self.out.take_bytes()
+    }
+
+    /// Find matches and collect tokens, writing blocks as they fill. Unless
+    /// finishing, stop while there is still enough input left for a
+    /// match of any length.
+    fn compress(&mut self, finishing: bool) {
+        let min_lookahead = if finishing { 1 } else { MIN_LOOKAHEAD };
+        if self.level == 0 {
+            while self.buf.len() - self.block_start >= MAX_STORED_LEN ||
+                    (finishing && self.buf.len() > self.block_start) {
+                let len = cmp::min(self.buf.len() - self.block_start, MAX_STORED_LEN);
+                self.write_stored(len, false);
+                self.pos = self.block_start;
+            }
+            return;
+        }
+        while self.buf.len() - self.pos >= min_lookahead {
+            if self.config.lazy {
+                self.lazy_step();
+            } else {
+                self.greedy_step();
+            }
+            if self.tokens.len() >= BLOCK_TOKENS {
+                self.flush_block(false);
+            }
+        }
+        if finishing && self.match_available {
+            let c = self.buf[self.pos - 1];
+            self.tokens.push(Token::Literal(c));
+            self.match_available = false;
+            self.prev_length = 0;
+        }
+    }
+
+    /// Take the longest match at pos, or a literal
+    fn greedy_step(&mut self) {
+        let head = self.insert(self.pos);
+        let (length, distance) = self.longest_match(head, MIN_MATCH - 1);
+        if length >= MIN_MATCH {
+            self.tokens.push(Token::Match(length as u16, distance as u16));
+            if length <= self.config.max_lazy {
+                for p in self.pos + 1 .. self.pos + length {
+                    self.insert(p);
+                }
+            }
+            self.pos += length;
+        } else {
+            let c = self.buf[self.pos];
+            self.tokens.push(Token::Literal(c));
+            self.pos += 1;
+        }
+    }
+
+    /// Look for a match at pos, but only take the one found at the
+    /// previous position if this one is no longer
+    fn lazy_step(&mut self) {
+        let head = self.insert(self.pos);
+        let (mut length, mut distance) = (MIN_MATCH - 1, 0);
+        if self.prev_length < self.config.max_lazy {
+            let found = self.longest_match(head, self.prev_length);
+            length = found.0;
+            distance = found.1;
+            if length == MIN_MATCH && distance > TOO_FAR {
+                length = MIN_MATCH - 1;
+            }
+        }
+        if self.prev_length >= MIN_MATCH && length <= self.prev_length {
+            // the match at the previous position wins
+            let prev_length = self.prev_length;
+            self.tokens.push(Token::Match(prev_length as u16, self.prev_distance as u16));
+            for p in self.pos + 1 .. self.pos + prev_length - 1 {
+                self.insert(p);
+            }
+            self.pos += prev_length - 1;
+            self.prev_length = 0;
+            self.match_available = false;
+        } else {
+            if self.match_available {
+                let c = self.buf[self.pos - 1];
+                self.tokens.push(Token::Literal(c));
+            }
+            self.match_available = true;
+            self.prev_length = length;
+            self.prev_distance = distance;
+            self.pos += 1;
+        }
+    }
+
+    /// Add the string at p to the hash chains, returning the previous
+    /// head of its chain
+    fn insert(&mut self, p: usize) -> usize {
+        if p + MIN_MATCH > self.buf.len() {
+            return 0;
+        }
+        let hash = ((self.buf[p] as usize) << 10 ^ (self.buf[p + 1] as usize) << 5 ^
+                    self.buf[p + 2] as usize) & (HASH_SIZE - 1);
+        let abs = self.offset + p;
+        let head = self.head[hash];
+        self.prev[abs & WINDOW_MASK] = head;
+        self.head[hash] = abs + 1;
+        head
+    }
+
+    /// Follow the hash chain from head for the longest match at pos that
+    /// beats best. Returns its length and distance, or best and 0.
+    fn longest_match(&self, head: usize, best: usize) -> (usize, usize) {
+        let max_length = cmp::min(MAX_MATCH, self.buf.len() - self.pos);
+        let cur_abs = self.offset + self.pos;
+        let mut chain = self.config.max_chain;
+        if best >= self.config.good_length {
+            chain >>= 2;
+        }
+        let mut best_length = best;
+        let mut best_distance = 0;
+        let mut candidate = head;
+        while candidate != 0 && chain > 0 {
+            let cand_abs = candidate - 1;
+            if cand_abs + WINDOW_SIZE < cur_abs || cand_abs < self.offset {
+                break;
+            }
+            let c = cand_abs - self.offset;
+            if best_length < max_length &&
+                    self.buf[c + best_length] == self.buf[self.pos + best_length] {
+                let mut length = 0;
+                while length < max_length && self.buf[c + length] == self.buf[self.pos + length] {
+                    length += 1;
+                }
+                if length > best_length {
+                    best_length = length;
+                    best_distance = cur_abs - cand_abs;
+                    if length >= self.config.nice_length || length == max_length {
+                        break;
+                    }
+                }
+            }
+            let next = self.prev[cand_abs & WINDOW_MASK];
+            // the chain must only go back in time
+            if next >= candidate {
+                break;
+            }
+            candidate = next;
+            chain -= 1;
+        }
+        (best_length, best_distance)
+    }
+
+    /// Drop input that matches can no longer refer to
+    fn slide(&mut self) {
+        if self.pos < 2 * WINDOW_SIZE {
+            return;
+        }
+        let start = cmp::min(self.pos - WINDOW_SIZE, self.block_start);
+        if start < WINDOW_SIZE {
+            return;
+        }
+        let len = self.buf.len();
+        for i in 0 .. len - start {
+            self.buf[i] = self.buf[start + i];
+        }
+        self.buf.truncate(len - start);
+        self.offset += start;
+        self.pos -= start;
+        self.block_start -= start;
+    }
+
+    /// Write the tokens collected since block_start as a block, in
+    /// whichever form is smallest
+    fn flush_block(&mut self, last: bool) {
+        let mut literal_freqs = vec![0u32; NUM_LITERAL_CODES];
+        let mut distance_freqs = vec![0u32; NUM_DISTANCE_CODES];
+        literal_freqs[END_OF_BLOCK] = 1;
+        let mut extra_bits = 0;
+        for token in self.tokens.iter() {
+            match *token {
+                Token::Literal(c) => literal_freqs[c as usize] += 1,
+                Token::Match(length, distance) => {
+                    let lcode = length_code(length as usize);
+                    let dcode = distance_code(distance as usize);
+                    literal_freqs[257 + lcode] += 1;
+                    distance_freqs[dcode] += 1;
+                    extra_bits += (LENGTH_EXTRA[lcode] + DIST_EXTRA[dcode]) as usize;
+                }
+            }
+        }
+        if distance_freqs.iter().all(|&f| f == 0) {
+            // there must be at least one distance code
+            distance_freqs[0] = 1;
+        }
+
+        // dynamic codes
+        let literal_lengths = code_lengths(literal_freqs.as_slice(), MAX_CODE_LENGTH);
+        let distance_lengths = code_lengths(distance_freqs.as_slice(), MAX_CODE_LENGTH);
+        let num_literals = cmp::max(257, last_nonzero(literal_lengths.as_slice()));
+        let num_distances = cmp::max(1, last_nonzero(distance_lengths.as_slice()));
+        let mut all_lengths = literal_lengths[.. num_literals].to_vec();
+        all_lengths.push_all(&distance_lengths[.. num_distances]);
+        let encoded = encode_code_lengths(all_lengths.as_slice());
+        let mut cl_freqs = vec![0u32; NUM_CODE_LENGTH_CODES];
+        for &(symbol, _) in encoded.iter() {
+            cl_freqs[symbol as usize] += 1;
+        }
+        if cl_freqs.iter().filter(|&&f| f > 0).count() < 2 {
+            // inflaters reject an incomplete code length code
+            let unused = if cl_freqs[0] == 0 { 0 } else { 1 };
+            cl_freqs[unused] = 1;
+        }
+        let cl_lengths = code_lengths(cl_freqs.as_slice(), MAX_CODE_LENGTH_CODE_LENGTH);
+        let mut num_cl = NUM_CODE_LENGTH_CODES;
+        while num_cl > 4 && cl_lengths[CODE_LENGTH_ORDER[num_cl - 1]] == 0 {
+            num_cl -= 1;
+        }
+        let mut dynamic_bits = 5 + 5 + 4 + 3 * num_cl + extra_bits;
+        for &(symbol, _) in encoded.iter() {
+            dynamic_bits += (cl_lengths[symbol as usize] + code_length_extra(symbol)) as usize;
+        }
+        dynamic_bits += symbol_bits(&literal_freqs, &literal_lengths) +
+            symbol_bits(&distance_freqs, &distance_lengths);
+
+        // fixed codes
+        let (fixed_literals, fixed_distances) = fixed_lengths();
+        let fixed_bits = extra_bits + symbol_bits(&literal_freqs, &fixed_literals) +
+            symbol_bits(&distance_freqs, &fixed_distances);
+
+        // no compression, including padding to a byte. A literal held back
+        // for lazy matching belongs to the next block.
+        let block_end = if self.match_available { self.pos - 1 } else { self.pos };
+        let block_len = block_end - self.block_start;
+        let num_stored = cmp::max(1, (block_len + MAX_STORED_LEN - 1) / MAX_STORED_LEN);
+        let stored_bits = (block_len + 5 * num_stored) * 8 + 7;
+
+        if stored_bits <= cmp::min(dynamic_bits, fixed_bits) {
+            let mut remaining = block_len;
+            loop {
+                let len = cmp::min(remaining, MAX_STORED_LEN);
+                remaining -= len;
+                self.write_stored(len, last && remaining == 0);
+                if remaining == 0 {
+                    break;
+                }
+            }
+        } else if fixed_bits <= dynamic_bits {
+            self.out.write_bits(last as u32, 1);
+            self.out.write_bits(1, 2);
+            self.write_tokens(fixed_literals.as_slice(), fixed_distances.as_slice());
+        } else {
+            self.out.write_bits(last as u32, 1);
+            self.out.write_bits(2, 2);
+            self.out.write_bits((num_literals - 257) as u32, 5);
+            self.out.write_bits((num_distances - 1) as u32, 5);
+            self.out.write_bits((num_cl - 4) as u32, 4);
+            for &symbol in CODE_LENGTH_ORDER[.. num_cl].iter() {
+                self.out.write_bits(cl_lengths[symbol], 3);
+            }
+            let cl_codes = canonical_codes(cl_lengths.as_slice());
+            for &(symbol, extra) in encoded.iter() {
+                self.out.write_bits(cl_codes[symbol as usize], cl_lengths[symbol as usize]);
+                self.out.write_bits(extra, code_length_extra(symbol));
+            }
+            self.write_tokens(literal_lengths.as_slice(), distance_lengths.as_slice());
+        }
+        self.tokens.clear();
+        self.block_start = block_end;
+    }
+
+    /// Write a stored block holding the len bytes from block_start, moving
+    /// block_start past them
+    fn write_stored(&mut self, len: usize, last: bool) {
+        self.out.write_bits(last as u32, 1);
+        self.out.write_bits(0, 2);
+        self.out.align_to_byte();
+        self.out.write_bits(len as u32, 16);
+        self.out.write_bits(!len as u32 & 0xffff, 16);
+        self.out.write_bytes(&self.buf[self.block_start .. self.block_start + len]);
+        self.block_start += len;
+    }
+
+    /// Write the collected tokens and the end of block code with the given
+    /// code lengths
+    fn write_tokens(&mut self, literal_lengths: &[u32], distance_lengths: &[u32]) {
+        let literal_codes = canonical_codes(literal_lengths);
+        let distance_codes = canonical_codes(distance_lengths);
+        for token in self.tokens.iter() {
+            match *token {
+                Token::Literal(c) => {
+                    self.out.write_bits(literal_codes[c as usize], literal_lengths[c as usize]);
+                },
+                Token::Match(length, distance) => {
+                    let lcode = length_code(length as usize);
+                    let symbol = 257 + lcode;
+                    self.out.write_bits(literal_codes[symbol], literal_lengths[symbol]);
+                    self.out.write_bits((length - LENGTH_BASE[lcode]) as u32, LENGTH_EXTRA[lcode]);
+                    let dcode = distance_code(distance as usize);
+                    self.out.write_bits(distance_codes[dcode], distance_lengths[dcode]);
+                    self.out.write_bits((distance - DIST_BASE[dcode]) as u32, DIST_EXTRA[dcode]);
+                }
+            }
+        }
+        self.out.write_bits(literal_codes[END_OF_BLOCK], literal_lengths[END_OF_BLOCK]);
+    }
+}
+
+/// The number of symbols up to and including the last with a code
+fn last_nonzero(lengths: &[u32]) -> usize {
+    let mut n = lengths.len();
+    while n > 0 && lengths[n - 1] == 0 {
+        n -= 1;
+    }
+    n
+}
+
+/// The bits needed for the symbols with the given frequencies and lengths
+fn symbol_bits(freqs: &Vec<u32>, lengths: &Vec<u32>) -> usize {
+    freqs.iter().zip(lengths.iter()).fold(0, |bits, (&f, &l)| bits + (f * l) as usize)
+}
+
+/// Compress data into a raw DEFLATE stream at the given level
+pub fn deflate(data: &[u8], level: u32) -> Vec<u8> {
+    let mut deflater = Deflater::new(level);
+    deflater.write(data);
+    deflater.finish();
+    deflater.take_output()
+}
+
+#[cfg(test)]
+mod deflate_tests {
+    use super::{deflate, Deflater};
+    use cvec::CVec;
+    use gz_reader::GzBitReader;
+    use inflate::inflate;
+    use sink::VecSink;
+    use std::usize;
+    use testing::{Lcg, text, system_gzip};
+
+    fn inflate_all(compressed: &[u8]) -> Vec<u8> {
+        let buffer = unsafe { CVec::from_raw_buf(compressed.as_ptr(), compressed.len()) }.unwrap();
+        let mut reader = GzBitReader::new(buffer.iter()).unwrap();
+        let mut sink = VecSink::new(0, usize::MAX);
+        inflate(&mut reader, &mut sink).unwrap();
+        sink.into_vec()
+    }
+
+    /// Bytes that do not compress
+    fn noise(len: usize) -> Vec<u8> {
+        let mut random = Lcg::new(12345);
+        (0 .. len).map(|_| random.next() as u8).collect()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let inputs = vec![Vec::new(), b"a".to_vec(), b"abcabcabcabcabc".to_vec(), text(2000),
+                          noise(100000), vec![0u8; 300000]];
+        for input in inputs.iter() {
+            for level in 0 .. 10 {
+                let compressed = deflate(input.as_slice(), level);
+                assert!(inflate_all(compressed.as_slice()) == *input,
+                        "level {} len {}", level, input.len());
+            }
+        }
+    }
+
+    #[test]
+    fn test_levels() {
+        let input = text(2000);
+        let stored = deflate(input.as_slice(), 0).len();
+        let fast = deflate(input.as_slice(), 1).len();
+        let best = deflate(input.as_slice(), 9).len();
+        assert!(stored > input.len());
+        assert!(fast < input.len() / 3);
+        assert!(best <= fast);
+        // each level is within 2% of what gzip makes at the same level.
+        // Its output has a 10 byte header and an 8 byte footer around the
+        // DEFLATE stream.
+        let gzip_fast = system_gzip(input.as_slice(), 1).len() - 18;
+        let gzip_best = system_gzip(input.as_slice(), 9).len() - 18;
+        assert!(fast <= gzip_fast + gzip_fast / 50, "{} against gzip -1's {}", fast, gzip_fast);
+        assert!(best <= gzip_best + gzip_best / 50, "{} against gzip -9's {}", best, gzip_best);
+    }
+
+    #[test]
+    fn test_incompressible() {
+        // falls back to stored blocks, rather than growing much
+        let input = noise(100000);
+        assert!(deflate(input.as_slice(), 6).len() < input.len() + 100);
+    }
+
+    #[test]
+    fn test_streaming() {
+        // writing a piece at a time gives exactly the same stream
+        let mut input = text(2000);
+        input.push_all(noise(70000).as_slice());
+        for &level in [0, 1, 6].iter() {
+            let mut deflater = Deflater::new(level);
+            let mut compressed = Vec::new();
+            for chunk in input.as_slice().chunks(1000) {
+                deflater.write(chunk);
+                compressed.push_all(deflater.take_output().as_slice());
+            }
+            deflater.finish();
+            compressed.push_all(deflater.take_output().as_slice());
+            assert!(compressed == deflate(input.as_slice(), level));
+        }
+    }
+
+    #[test]
+    fn test_sync_flush() {
+        let input = text(2000);
+        let (first, second) = input.split_at(30000);
+        for &level in [0, 1, 6].iter() {
+            let mut deflater = Deflater::new(level);
+            deflater.write(first);
+            deflater.sync_flush();
+            let mut compressed = deflater.take_output();
+            assert!(compressed.ends_with(&[0x00, 0x00, 0xff, 0xff]));
+            deflater.write(second);
+            deflater.sync_flush();
+            deflater.finish();
+            compressed.push_all(deflater.take_output().as_slice());
+            assert!(inflate_all(compressed.as_slice()) == input, "level {}", level);
+        }
+    }
+
+    #[test]
+    fn test_flush_anywhere() {
+        // a flush may land while a match is waiting to be compared with
+        // the next one, which must not carry over to what comes after
+        let input = b"abcdabcdeabcdabcdefabcabcdabcdeabcdabcdefg";
+        for &level in [1, 4, 6, 9].iter() {
+            for split in 0 .. input.len() + 1 {
+                let mut deflater = Deflater::new(level);
+                deflater.write(&input[.. split]);
+                deflater.sync_flush();
+                deflater.write(&input[split ..]);
+                deflater.finish();
+                assert!(inflate_all(deflater.take_output().as_slice()) == input.to_vec(),
+                        "level {} split {}", level, split);
+            }
+        }
+    }
+
+    #[test]
+    fn test_dictionary() {
+        // pieces compressed separately, each with the data before it as
+        // a dictionary, join into one stream
+        let input = text(2000);
+        let (first, second) = input.split_at(50000);
+        for &level in [0, 1, 6, 9].iter() {
+            let mut deflater = Deflater::new(level);
+            deflater.write(first);
+            deflater.sync_flush();
+            let mut compressed = deflater.take_output();
+
+            let mut deflater = Deflater::new(level);
+            deflater.set_dictionary(first);
+            deflater.write(second);
+            deflater.finish();
+            let rest = deflater.take_output();
+            if level > 0 {
+                assert!(rest.len() < deflate(second, level).len());
+            }
+            compressed.push_all(rest.as_slice());
+            assert!(inflate_all(compressed.as_slice()) == input, "level {}", level);
+        }
+    }
+}
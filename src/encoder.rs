@@ -0,0 +1,228 @@
+#[doc="
+
+    Module: encoder
+
+    This module provides GzEncoder, which wraps anything that
+    implements std::io::Write, and compresses whatever is written
+    to it into a single gzip member: a header built from
+    HeaderOptions, the DEFLATE stream, and the CRC32 and size
+    footer. compress() and compress_with_header() do the same for
+    data that is all in memory.
+
+"]
+use std::cmp;
+use std::io;
+use std::io::Write;
+
+use crc32::Crc32;
+use deflate::Deflater;
+use error::{GzError, ErrorKind};
+use header;
+use header::HeaderOptions;
+
+/// Compresses the data written to it into a gzip member written to W.
+/// finish() must be called to end the member and write the footer.
+pub struct GzEncoder<W> {
+    writer: W,
+    deflater: Deflater,
+    // the header, until it has been written
+    header: Vec<u8>,
+    crc: Crc32,
+    len: u64
+}
+
+impl<W: Write> GzEncoder<W> {
+    /// Compress at the given level, from 0 to 9, with a minimal header
+    pub fn new(writer: W, level: u32) -> GzEncoder<W> {
+        GzEncoder::with_header(writer, level, &HeaderOptions::new()).unwrap()
+    }
+
+    /// Compress at the given level, with the header fields in options.
+    /// Fails with InvalidArgument if the header can't be written.
+    pub fn with_header(writer: W, level: u32, options: &HeaderOptions)
+            -> Result<GzEncoder<W>, GzError> {
+        let header = match header::write_header(options, extra_flags(level)) {
+            Some(h) => h,
+            None => return Err(GzError::new(ErrorKind::InvalidArgument, 0))
+        };
+        Ok(GzEncoder {
+            writer: writer,
+            deflater: Deflater::new(level),
+            header: header,
+            crc: Crc32::new(),
+            len: 0
+        })
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    /// End the member, writing the rest of the compressed data and the
+    /// footer, and give back the inner writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.deflater.finish();
+        try!(self.write_output());
+        let mut footer = [0u8; 8];
+        for i in 0 .. 4 {
+            footer[i] = (self.crc.crc() >> (8 * i)) as u8;
+            // the footer only holds the size modulo 2^32
+            footer[i + 4] = (self.len >> (8 * i)) as u8;
+        }
+        try!(self.writer.write_all(&footer));
+        try!(self.writer.flush());
+        Ok(self.writer)
+    }
+
+    /// Write the header, if it hasn't been yet, and whatever the Deflater
+    /// has produced
+    fn write_output(&mut self) -> io::Result<()> {
+        if self.header.len() > 0 {
+            try!(self.writer.write_all(self.header.as_slice()));
+            self.header.clear();
+        }
+        let out = self.deflater.take_output();
+        self.writer.write_all(out.as_slice())
+    }
+}
+
+impl<W: Write> Write for GzEncoder<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.deflater.write(data);
+        self.crc.update(data);
+        self.len += data.len() as u64;
+        try!(self.write_output());
+        Ok(data.len())
+    }
+
+    /// Pass on the compressed data produced so far. The Deflater may
+    /// still be holding back input it hasn't compressed yet.
+    fn flush(&mut self) -> io::Result<()> {
+        try!(self.write_output());
+        self.writer.flush()
+    }
+}
+
+/// The extra flags byte gzip writes for each level: 2 for the slowest
+/// compression, 4 for the fastest
+pub fn extra_flags(level: u32) -> u8 {
+    match cmp::min(level, 9) {
+        9 => 2,
+        0 | 1 => 4,
+        _ => 0
+    }
+}
+
+/// Compress data into a gzip member with a minimal header
+pub fn compress(data: &[u8], level: u32) -> Vec<u8> {
+    compress_with_header(data, level, &HeaderOptions::new()).unwrap()
+}
+
+/// Compress data into a gzip member with the header fields in options
+pub fn compress_with_header(data: &[u8], level: u32, options: &HeaderOptions)
+        -> Result<Vec<u8>, GzError> {
+    let mut encoder = try!(GzEncoder::with_header(Vec::new(), level, options));
+    // writing to a Vec can't fail
+    encoder.write_all(data).unwrap();
+    Ok(encoder.finish().unwrap())
+}
+
+#[cfg(test)]
+mod gz_encoder_tests {
+    use super::{GzEncoder, compress, compress_with_header};
+    use header::{HeaderOptions, ExtraField};
+    use gz::decompress;
+    use read::GzDecoder;
+    use std::io::{Read, Write};
+    use testing::{system_gunzip, EXPECTED};
+
+    #[test]
+    fn test_round_trip() {
+        for level in 0 .. 10 {
+            let compressed = compress(EXPECTED, level);
+            assert_eq!(decompress(compressed.as_slice()).unwrap().as_slice(), EXPECTED);
+        }
+    }
+
+    #[test]
+    fn test_empty() {
+        let compressed = compress(&[], 6);
+        let mut out = Vec::new();
+        GzDecoder::new(compressed.as_slice()).read_to_end(&mut out).unwrap();
+        assert_eq!(out.len(), 0);
+    }
+
+    #[test]
+    fn test_footer() {
+        let compressed = compress(EXPECTED, 9);
+        let len = compressed.len();
+        assert_eq!(&compressed[len - 8 ..], &[0x7b, 0x3f, 0x9f, 0x2f, 0x60, 0x00, 0x00, 0x00]);
+        assert_eq!(compressed[8], 2);
+    }
+
+    #[test]
+    fn test_header_fields() {
+        let options = HeaderOptions {
+            text: true,
+            header_crc: true,
+            mtime: 1234567890,
+            os: 3,
+            extra: Some(vec![ExtraField { id: [0x41, 0x70], data: vec![9, 8, 7] }]),
+            fname: Some("fox.txt".to_string()),
+            comment: Some("two foxes".to_string())
+        };
+        let compressed = compress_with_header(EXPECTED, 6, &options).unwrap();
+        let mut decoder = GzDecoder::new(compressed.as_slice());
+        let mut out = Vec::new();
+        decoder.read_to_end(&mut out).unwrap();
+        assert_eq!(out.as_slice(), EXPECTED);
+        let header = decoder.header().unwrap();
+        assert_eq!(header.mtime, 1234567890);
+        assert_eq!(header.os, 3);
+        assert_eq!(header.fname, options.fname);
+        assert_eq!(header.comment, options.comment);
+        assert_eq!(header.extra_fields(), options.extra);
+        assert!(header.flags.FTEXT);
+    }
+
+    #[test]
+    fn test_system_gunzip() {
+        let mut long = Vec::new();
+        for _ in 0 .. 1000 {
+            long.push_all(EXPECTED);
+        }
+        let options = HeaderOptions {
+            text: true,
+            header_crc: true,
+            mtime: 1234567890,
+            os: 3,
+            extra: Some(vec![ExtraField { id: [0x41, 0x70], data: vec![9, 8, 7] }]),
+            fname: Some("fox.txt".to_string()),
+            comment: Some("two foxes".to_string())
+        };
+        for &data in [&[][..], EXPECTED, long.as_slice()].iter() {
+            for &level in [0, 1, 6, 9].iter() {
+                assert_eq!(system_gunzip(compress(data, level).as_slice()), (0, data.to_vec()));
+                let compressed = compress_with_header(data, level, &options).unwrap();
+                assert_eq!(system_gunzip(compressed.as_slice()), (0, data.to_vec()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_written_in_pieces() {
+        let mut encoder = GzEncoder::new(Vec::new(), 6);
+        for chunk in EXPECTED.chunks(7) {
+            encoder.write_all(chunk).unwrap();
+        }
+        let compressed = encoder.finish().unwrap();
+        assert_eq!(compressed, compress(EXPECTED, 6));
+    }
+
+    #[test]
+    fn test_invalid_header() {
+        let mut options = HeaderOptions::new();
+        options.comment = Some("nul\0".to_string());
+        assert!(compress_with_header(EXPECTED, 6, &options).is_err());
+    }
+}
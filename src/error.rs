@@ -0,0 +1,105 @@
+#[doc="
+
+    Module: error
+
+    This module describes the ways in which decompression can
+    fail, along with the position in the compressed input at
+    which the failure was detected.
+
+"]
+use std::fmt;
+use std::io;
+use std::error::Error;
+
+#[derive(Copy, Clone, PartialEq, Show)]
+pub enum ErrorKind {
+    /// A null pointer or negative length was passed in
+    InvalidArgument,
+    /// The input is too short to be a gzip file
+    TooShort,
+    /// The gzip header is invalid or unsupported
+    BadHeader,
+    /// The compressed data is invalid
+    CorruptData,
+    /// The compressed data ended before the last block did
+    UnexpectedEof,
+    /// The decompressed data does not match the CRC in the footer
+    CrcMismatch,
+    /// The decompressed data does not match the size in the footer
+    SizeMismatch,
+    /// There was no room left to store the decompressed data
+    OutOfMemory,
+    /// A length is too large to be returned through an int
+    Overflow,
+    /// The caller asked for decompression to stop
+    Aborted,
+    /// The decompressed data is larger than the caller allowed
+    LimitExceeded,
+    /// A worker thread panicked, through no fault of the input
+    Internal,
+}
+
+impl ErrorKind {
+    /// A short, human readable description of this kind of error
+    pub fn description(&self) -> &'static str {
+        match *self {
+            ErrorKind::InvalidArgument => "invalid argument",
+            ErrorKind::TooShort => "input too short to be gzip data",
+            ErrorKind::BadHeader => "invalid or unsupported gzip header",
+            ErrorKind::CorruptData => "invalid compressed data",
+            ErrorKind::UnexpectedEof => "unexpected end of compressed data",
+            ErrorKind::CrcMismatch => "CRC32 does not match decompressed data",
+            ErrorKind::SizeMismatch => "size does not match decompressed data",
+            ErrorKind::OutOfMemory => "out of memory for decompressed data",
+            ErrorKind::Overflow => "length too large for an int",
+            ErrorKind::Aborted => "aborted by the output callback",
+            ErrorKind::LimitExceeded => "decompressed data exceeds the output limit",
+            ErrorKind::Internal => "a worker thread failed",
+        }
+    }
+}
+
+/// A decompression failure, and the byte offset into the compressed
+/// input at which it was detected
+#[derive(Copy, Clone, PartialEq, Show)]
+pub struct GzError {
+    pub kind: ErrorKind,
+    pub position: usize,
+}
+
+impl GzError {
+    pub fn new(kind: ErrorKind, position: usize) -> GzError {
+        GzError {
+            kind: kind,
+            position: position
+        }
+    }
+
+    /// Wrap this error up as an io::Error, keeping it as the cause
+    pub fn to_io_error(self) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, self)
+    }
+}
+
+impl fmt::Display for GzError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at byte {}", self.kind.description(), self.position)
+    }
+}
+
+impl Error for GzError {
+    fn description(&self) -> &str {
+        self.kind.description()
+    }
+}
+
+#[cfg(test)]
+mod gz_error_tests {
+    use super::{GzError, ErrorKind};
+
+    #[test]
+    fn test_display() {
+        let err = GzError::new(ErrorKind::CrcMismatch, 1234);
+        assert_eq!(format!("{}", err), "CRC32 does not match decompressed data at byte 1234");
+    }
+}
@@ -4,38 +4,151 @@
 
     This provides the Rust interface to gzip decompression.
     It serves a similar function to lib, except it has no
-    code to interface with C.
+    code to interface with C. The functions taking slices
+    are the ones re-exported for other Rust crates.
 
 "]
-use cvec::{CVec, Buf, Iter};
+use cvec;
+use cvec::{CVec, Buf, Iter, Allocator};
 use libc::c_uint;
+use std::cmp;
+use std::usize;
 
 use header;
+use header::{GZHeader, GZIP_FOOTER_LEN};
 use crc32;
+use crc32::Crc32;
+use error::{GzError, ErrorKind};
 use gz_reader::GzBitReader;
 use inflate::inflate;
+use sink::{Sink, CallbackSink, VecSink};
 
 // every gzip file is at least 10 bytes, if not, it's invalid
 const GZIP_MIN_LEN: usize = 40;
 const GZIP_FILESIZE_OFFSET: usize = 4;
 const GZIP_CRC_OFFSET: usize = 8;
-const GZIP_FOOTER_LEN: usize = 8;
+// DEFLATE can't make more than this many bytes of output for each byte
+// of input, so no footer telling the truth claims more
+const MAX_RATIO: usize = 1032;
 
 /// Decompress the given compressed buffer
-pub fn decompress_gz(buffer: Buf) -> Option<Buf> {
-    if buffer.len() < GZIP_MIN_LEN {
-        return None;
+pub fn decompress_gz(buffer: Buf) -> Result<Buf, GzError> {
+    decompress_gz_with_allocator(buffer, Allocator::libc())
+}
+
+/// Decompress the given compressed buffer, allocating the output
+/// with the given Allocator
+pub fn decompress_gz_with_allocator(buffer: Buf, allocator: Allocator) -> Result<Buf, GzError> {
+    let out_len = try!(uncompressed_len(&buffer));
+    let capacity = initial_capacity(buffer.len(), out_len);
+    let mut out_buf = match CVec::with_capacity_in(capacity, allocator) {
+        Some(b) => b,
+        None => return Err(GzError::new(ErrorKind::OutOfMemory, 0))
+    };
+    try!(decompress_gz_into(&buffer, &mut out_buf));
+    Ok(out_buf)
+}
+
+/// Decompress the given compressed buffer into out_buf, which should be
+/// empty. If out_buf cannot grow, it must have room for at least
+/// uncompressed_len() bytes, or decompression will fail.
+pub fn decompress_gz_into(buffer: &Buf, out_buf: &mut Buf) -> Result<(), GzError> {
+    let out_len = try!(uncompressed_len(buffer));
+    let crc = get_crc(buffer);
+    try!(decompress_raw(try!(compressed_data(buffer)), out_buf));
+    check_footer(buffer, check_crc(out_buf, crc), out_buf.len(), out_len)
+}
+
+/// Decompress the given compressed buffer, passing the output to the
+/// sink's callback in chunks as it is produced. The output has already
+/// been handed over by the time the CRC is checked, so on failure the
+/// caller should discard whatever it was given.
+pub fn decompress_gz_to_callback<F>(buffer: &Buf, sink: &mut CallbackSink<F>)
+        -> Result<(), GzError>
+        where F: FnMut(&[u8]) -> bool {
+    let out_len = try!(uncompressed_len(buffer));
+    let crc = get_crc(buffer);
+    try!(decompress_raw(try!(compressed_data(buffer)), sink));
+    if sink.finish().is_none() {
+        return Err(GzError::new(ErrorKind::Aborted, buffer.len() - GZIP_FOOTER_LEN));
     }
-    let out_len = get_uncompressed_len(&buffer);
-    let crc = get_crc(&buffer);
-    let header = try_opt!(header::parse_header(&buffer));
-    let mut out_buf = try_opt!(CVec::with_capacity(out_len));
-    decompress_raw(buffer.limit_iter(header.header_len, buffer.len() - GZIP_FOOTER_LEN),
-                   &mut out_buf);
-    if check_crc(&out_buf, crc) {
-        Some(out_buf)
+    check_footer(buffer, sink.crc() == crc, sink.total_len(), out_len)
+}
+
+/// Get the size of the given compressed buffer once decompressed,
+/// as recorded in its footer
+pub fn uncompressed_len(buffer: &Buf) -> Result<usize, GzError> {
+    if buffer.len() <= GZIP_MIN_LEN {
+        Err(GzError::new(ErrorKind::TooShort, buffer.len()))
     } else {
-        None
+        Ok(get_uncompressed_len(buffer))
+    }
+}
+
+/////////////////////////////////////////////////////////////////////
+//                       Slice interface                           //
+/////////////////////////////////////////////////////////////////////
+
+/// Options controlling decompression
+#[derive(Copy, Clone, PartialEq, Show)]
+pub struct DecompressOptions {
+    /// Check the decompressed data against the CRC32 and size in the
+    /// footer. On by default.
+    pub verify: bool,
+    /// Fail with LimitExceeded rather than produce more than this many
+    /// bytes. Unlimited by default.
+    pub max_output_len: Option<usize>,
+}
+
+impl DecompressOptions {
+    pub fn new() -> DecompressOptions {
+        DecompressOptions {
+            verify: true,
+            max_output_len: None
+        }
+    }
+}
+
+/// Decompress a gzip file held in memory
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>, GzError> {
+    decompress_with_options(data, &DecompressOptions::new())
+}
+
+/// Decompress a gzip file held in memory, as controlled by options
+pub fn decompress_with_options(data: &[u8], options: &DecompressOptions)
+        -> Result<Vec<u8>, GzError> {
+    let buffer = try!(slice_buf(data));
+    let out_len = try!(uncompressed_len(&buffer));
+    let limit = options.max_output_len.unwrap_or(usize::MAX);
+    let capacity = cmp::min(initial_capacity(data.len(), out_len), limit);
+    let mut sink = VecSink::new(capacity, limit);
+    try!(decompress_raw(try!(compressed_data(&buffer)), &mut sink));
+    let out = sink.into_vec();
+    if options.verify {
+        let mut crc = Crc32::new();
+        crc.update(out.as_slice());
+        try!(check_footer(&buffer, crc.crc() == get_crc(&buffer), out.len(), out_len));
+    }
+    Ok(out)
+}
+
+/// Decompress a gzip file held in memory, passing the output to callback
+/// in chunks as it is produced, rather than collecting it. The callback
+/// returns false to stop decompression. The CRC can only be checked once
+/// all the output has been passed on, so on failure the caller should
+/// discard whatever it was given.
+pub fn decompress_to_callback<F>(data: &[u8], callback: F) -> Result<(), GzError>
+        where F: FnMut(&[u8]) -> bool {
+    let buffer = try!(slice_buf(data));
+    decompress_gz_to_callback(&buffer, &mut CallbackSink::new(callback))
+}
+
+/// Read the header at the start of a gzip file held in memory
+pub fn read_header(data: &[u8]) -> Result<GZHeader, GzError> {
+    let buffer = try!(slice_buf(data));
+    match header::parse_header(&buffer) {
+        Some(header) => Ok(header),
+        None => Err(GzError::new(ErrorKind::BadHeader, 0))
     }
 }
 
@@ -43,16 +156,64 @@ pub fn decompress_gz(buffer: Buf) -> Option<Buf> {
 //                       Helper functions                          //
 /////////////////////////////////////////////////////////////////////
 
+/// Wrap a slice in a Buf, without copying
+/// The Buf must not outlive the slice
+fn slice_buf(data: &[u8]) -> Result<Buf, GzError> {
+    match cvec::slice_buf(data) {
+        Some(buffer) => Ok(buffer),
+        None => Err(GzError::new(ErrorKind::TooShort, 0))
+    }
+}
+
+/// How much room to make for the output at first: the size in the footer,
+/// unless that is more than the input could decompress to, as the footer
+/// may be lying. The output grows past this if it has to.
+fn initial_capacity(compressed_len: usize, out_len: usize) -> usize {
+    cmp::min(out_len, compressed_len.checked_mul(MAX_RATIO).unwrap_or(usize::MAX))
+}
+
+/// Find the compressed data between the header and the footer
+fn compressed_data(buffer: &Buf) -> Result<Iter<u8>, GzError> {
+    match header::parse_header(buffer) {
+        Some(header) => Ok(buffer.limit_iter(header.header_len, buffer.len() - GZIP_FOOTER_LEN)),
+        None => Err(GzError::new(ErrorKind::BadHeader, 0))
+    }
+}
+
 /// Decompress the buffer into out_buf
 /// Helper function for decompress
-fn decompress_raw(buffer: Iter<u8>, out_buf: &mut Buf) {
+fn decompress_raw<S: Sink>(buffer: Iter<u8>, out_buf: &mut S) -> Result<(), GzError> {
+    let start = buffer.index();
     let mut gz_reader = match GzBitReader::new(buffer) {
         Some(g) => g,
-        None => { return; }
+        None => return Err(GzError::new(ErrorKind::UnexpectedEof, start))
     };
     match inflate(&mut gz_reader, out_buf) {
-        Some(()) => {},
-        None => { out_buf.clear(); }
+        Some(()) => Ok(()),
+        None => {
+            let kind = if gz_reader.is_eof() {
+                ErrorKind::UnexpectedEof
+            } else {
+                match out_buf.failure() {
+                    Some(kind) => kind,
+                    None => ErrorKind::CorruptData
+                }
+            };
+            Err(GzError::new(kind, gz_reader.position()))
+        }
+    }
+}
+
+/// Check the result of decompression against the footer
+fn check_footer(buffer: &Buf, crc_matches: bool, out_len: usize, expected_len: usize)
+        -> Result<(), GzError> {
+    if !crc_matches {
+        Err(GzError::new(ErrorKind::CrcMismatch, buffer.len() - GZIP_CRC_OFFSET))
+    } else if out_len as u32 != expected_len as u32 {
+        // the footer only holds the size modulo 2^32
+        Err(GzError::new(ErrorKind::SizeMismatch, buffer.len() - GZIP_FILESIZE_OFFSET))
+    } else {
+        Ok(())
     }
 }
 
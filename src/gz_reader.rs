@@ -5,13 +5,14 @@
     of a gzip-compressed buffer.
 
 "]
-use cvec::Iter;
+use cvec::{Iter, Buf};
 
 #[derive(Show)]
 pub struct GzBitReader<'a> {
     iter: Iter<'a, u8>,
     buf: u8,
-    mask: u8
+    mask: u8,
+    eof: bool
 }
 
 /// Read the GZIP data bit by bit
@@ -21,15 +22,62 @@ impl<'a> GzBitReader<'a> {
         Some(GzBitReader {
             iter: iter,
             buf: *starting_buf,
-            mask: 0x01
+            mask: 0x01,
+            eof: false
         })
     }
 
+    /// Start reading bit_pos bits into the buffer
+    pub fn at(buffer: &'a Buf, bit_pos: usize) -> Option<GzBitReader<'a>> {
+        let mut reader = try_opt!(GzBitReader::new(buffer.limit_iter(bit_pos / 8, buffer.len())));
+        reader.mask = 1 << (bit_pos % 8);
+        Some(reader)
+    }
+
+    /// The position of the next bit to be read, counted in bits from the
+    /// start of the buffer
+    #[inline]
+    pub fn bit_position(&self) -> usize {
+        if self.mask == 0 {
+            return self.iter.index() * 8;
+        }
+        let mut bit = 0;
+        let mut mask = self.mask;
+        while mask > 1 {
+            mask >>= 1;
+            bit += 1;
+        }
+        (self.iter.index() - 1) * 8 + bit
+    }
+
+    /// Skip the rest of the current byte, unless none of it has been read
+    pub fn align_to_byte(&mut self) {
+        if self.mask != 0x01 {
+            self.mask = 0;
+        }
+    }
+
+    /// The index of the byte currently being read
+    pub fn position(&self) -> usize {
+        self.iter.index() - 1
+    }
+
+    /// Whether reading has failed because the stream ran out of bytes
+    pub fn is_eof(&self) -> bool {
+        self.eof
+    }
+
     #[inline]
     /// Get the next bit from the "stream"
     pub fn next_bit(&mut self) -> Option<u32> {
         if self.mask == 0 {
-            self.buf = *try_opt!(self.iter.next());
+            self.buf = match self.iter.next() {
+                Some(&b) => b,
+                None => {
+                    self.eof = true;
+                    return None;
+                }
+            };
             self.mask = 0x01;
         }
         let bit = if (self.buf & self.mask) > 0 { 1 } else { 0 };
@@ -114,6 +162,47 @@ mod gz_reader_tests {
         for _ in 0..5 {
             assert_eq!(reader.next_bit(), Some(0));
         }
+        assert!(!reader.is_eof());
         assert_eq!(reader.next_bit(), None);
+        assert!(reader.is_eof());
+    }
+
+    #[test]
+    fn test_position() {
+        let bytes = setup();
+        let mut reader = GzBitReader::new(bytes.iter()).unwrap();
+        assert_eq!(reader.position(), 0);
+        reader.read_bits(9);
+        assert_eq!(reader.position(), 1);
+        reader.read_bits(16);
+        assert_eq!(reader.position(), 3);
+    }
+
+    #[test]
+    fn test_bit_position() {
+        let bytes = setup();
+        let mut reader = GzBitReader::new(bytes.iter()).unwrap();
+        assert_eq!(reader.bit_position(), 0);
+        reader.read_bits(9);
+        assert_eq!(reader.bit_position(), 9);
+        reader.read_bits(7);
+        assert_eq!(reader.bit_position(), 16);
+        // picking up where the last reader stopped reads the same bits
+        let mut resumed = GzBitReader::at(&bytes, 9).unwrap();
+        assert_eq!(resumed.read_bits(9), Some(385));
+        assert_eq!(resumed.bit_position(), 18);
+        assert!(GzBitReader::at(&bytes, 32).is_none());
+    }
+
+    #[test]
+    fn test_align_to_byte() {
+        let bytes = setup();
+        let mut reader = GzBitReader::new(bytes.iter()).unwrap();
+        reader.align_to_byte();
+        assert_eq!(reader.bit_position(), 0);
+        reader.next_bit();
+        reader.align_to_byte();
+        assert_eq!(reader.bit_position(), 8);
+        assert_eq!(reader.read_bits(8), Some(2));
     }
 }
@@ -4,16 +4,25 @@
 
     This module handles parsing the header into a
     structure representing the information contained
-    within it.
+    within it, and writing a header from the fields
+    we want in it.
 
 "]
 extern crate core;
 
 use cvec;
 use cvec::{Iter, Buf};
+use crc32::Crc32;
 use self::core::num::Int;
 
-const GZ_MAGIC_BYTES: [u8; 2] = [0x1f, 0x8b];
+pub const GZ_MAGIC_BYTES: [u8; 2] = [0x1f, 0x8b];
+
+/// The length of the footer after each member's data: its CRC32 and the
+/// length of its contents, modulo 2^32
+pub const GZIP_FOOTER_LEN: usize = 8;
+
+/// The OS byte for an unknown operating system
+pub const OS_UNKNOWN: u8 = 255;
 
 /*
 Flags:
@@ -26,14 +35,14 @@ bit 5   reserved
 bit 6   reserved
 bit 7   reserved
 */
-#[derive(PartialEq, Show)]
+#[derive(PartialEq, Show, Clone)]
 #[allow(non_snake_case)]
-struct Flags {
-    FTEXT: bool,
-    FHCRC: bool,
-    FNAME: bool,
-    FEXTRA: bool,
-    FCOMMENT: bool,
+pub struct Flags {
+    pub FTEXT: bool,
+    pub FHCRC: bool,
+    pub FNAME: bool,
+    pub FEXTRA: bool,
+    pub FCOMMENT: bool,
 }
 
 impl Flags {
@@ -48,29 +57,64 @@ impl Flags {
     }
 }
 
+/// A subfield of the FEXTRA field: a two byte id and its data
+#[derive(PartialEq, Show, Clone)]
+pub struct ExtraField {
+    pub id: [u8; 2],
+    pub data: Vec<u8>
+}
+
 /// GZHeader consists of the following fields.
 /// Optional fields are, naturally, Options in the GZHeader.
 /// Whether or not they exist depends on whether it's associated
 /// flag bit is set.
-#[derive(PartialEq, Show)]
-struct GZHeader {
+#[derive(PartialEq, Show, Clone)]
+pub struct GZHeader {
     pub header_len: usize,
     pub compression_method: u8,
     pub flags: Flags,
     pub mtime: u32,
     pub extra_flags: u8,
     pub os: u8,
-    pub extra: Option<(String, Vec<u8>)>,
+    /// The FEXTRA field as it was found, all XLEN bytes of it
+    pub extra: Option<Vec<u8>>,
     pub fname: Option<String>,
     pub comment: Option<String>,
     pub crc: Option<u16>
 }
 
+impl GZHeader {
+    /// The subfields of the FEXTRA field, as far as they can be made out.
+    /// Nothing says FEXTRA has to hold subfields, so any bytes after the
+    /// last whole one are left out rather than failing.
+    pub fn extra_fields(&self) -> Option<Vec<ExtraField>> {
+        self.extra.as_ref().map(|extra| extra_fields(extra.as_slice()).0)
+    }
+}
+
 /// Return a GZIP header structure representing the information
 /// contained in the beginning of the given Buf
 pub fn parse_header(buffer: &cvec::Buf) -> Option<GZHeader> {
     let mut iter = buffer.iter();
+    let header = parse_header_iter(&mut iter);
+    // a header that runs off the end of the buffer is no header at all
+    if iter.overrun() { None } else { header }
+}
+
+/// Like parse_header, but tells a header that is cut short by the end
+/// of the buffer apart from an invalid one. Returns Ok(None) if more
+/// bytes are needed, and Err(()) if the header can never be valid.
+pub fn parse_header_partial(buffer: &cvec::Buf) -> Result<Option<GZHeader>, ()> {
+    let mut iter = buffer.iter();
+    match parse_header_iter(&mut iter) {
+        _ if iter.overrun() => Ok(None),
+        Some(header) => Ok(Some(header)),
+        None => Err(())
+    }
+}
 
+/// Parse a header from the start of iter
+fn parse_header_iter(iter: &mut cvec::Iter<u8>) -> Option<GZHeader> {
     // Header fields
     let mut comp_method: u8;
     let mut flags: Flags;
@@ -91,10 +135,14 @@ pub fn parse_header(buffer: &cvec::Buf) -> Option<GZHeader> {
         os = *try_opt!(iter.next());
 
         // Optional stuff
-        let extra = get_extra(&flags, &mut iter);
-        let name = get_string(flags.FNAME, &mut iter);
-        let comment = get_string(flags.FCOMMENT, &mut iter);
-        let crc = get_crc(&flags, &mut iter);
+        let extra = if flags.FEXTRA {
+            Some(try_opt!(get_extra(iter)))
+        } else {
+            None
+        };
+        let name = get_string(flags.FNAME, iter);
+        let comment = get_string(flags.FCOMMENT, iter);
+        let crc = get_crc(&flags, iter);
 
         Some(GZHeader {
             header_len: iter.index(),
@@ -113,25 +161,39 @@ pub fn parse_header(buffer: &cvec::Buf) -> Option<GZHeader> {
     }
 }
 
-/// Get the values contained in the FEXTRA field of the header buffer
-fn get_extra(flags: &Flags, iter: &mut cvec::Iter<u8>) -> Option<(String, Vec<u8>)> {
-    if_opt!(flags.FEXTRA, {
-        let mut id_bytes = Vec::with_capacity(2);
-        id_bytes.push(*try_opt!(iter.next()));
-        id_bytes.push(*try_opt!(iter.next()));
-        let id = match String::from_utf8(id_bytes) {
-            Ok(string) => string,
-            Err(..) => return None
-        };
-        let mut len: u16 = (*try_opt!(iter.next()) as u16) << 8;
-        len += *try_opt!(iter.next()) as u16;
-        let mut data = Vec::with_capacity(len as usize);
-        for _ in 0..(len as usize) {
-            let byte: u8 = *try_opt!(iter.next());
-            data.push(byte);
+/// Get the contents of the FEXTRA field of the header buffer
+fn get_extra(iter: &mut cvec::Iter<u8>) -> Option<Vec<u8>> {
+    let len: u16 = Int::from_le(try_opt!(iter.next_wide::<u16>()));
+    let mut data = Vec::with_capacity(len as usize);
+    for _ in 0..(len as usize) {
+        let byte: u8 = *try_opt!(iter.next());
+        data.push(byte);
+    }
+    Some(data)
+}
+
+/// Split the contents of an FEXTRA field into its subfields
+/// Each is two id bytes, a two byte little endian length, and the data.
+/// Returns None unless the subfields exactly fill the field.
+pub fn parse_extra_fields(data: &[u8]) -> Option<Vec<ExtraField>> {
+    let (fields, left_over) = extra_fields(data);
+    if_opt!(left_over == 0, fields)
+}
+
+/// Split as many whole subfields as there are from the start of the
+/// contents of an FEXTRA field, returning them and the number of bytes
+/// left over after them
+pub fn extra_fields(mut data: &[u8]) -> (Vec<ExtraField>, usize) {
+    let mut fields = Vec::new();
+    while data.len() >= 4 {
+        let len = data[2] as usize | (data[3] as usize) << 8;
+        if data.len() < 4 + len {
+            break;
         }
-        (id, data)
-    })
+        fields.push(ExtraField { id: [data[0], data[1]], data: data[4 .. 4 + len].to_vec() });
+        data = &data[4 + len ..];
+    }
+    (fields, data.len())
 }
 
 /// Get the String corresponding to the header flag that is given
@@ -155,17 +217,119 @@ fn get_string(flag: bool, iter: &mut cvec::Iter<u8>) -> Option<String> {
 }
 
 /// Retrieve the optional CRC from the header
+/// It is the low 16 bits of the CRC32 of the header bytes before it
 fn get_crc(flags: &Flags, iter: &mut cvec::Iter<u8>) -> Option<u16> {
     if_opt!(flags.FHCRC, {
-        let mut crc: u16 = (*try_opt!(iter.next()) as u16) << 8;
-        crc += *try_opt!(iter.next()) as u16;
+        let crc: u16 = Int::from_le(try_opt!(iter.next_wide::<u16>()));
         crc
     })
 }
 
+/// The fields to put in a gzip header written by write_header.
+/// The optional fields are left out when None, and their flags set
+/// when they are present.
+#[derive(Clone, Show)]
+pub struct HeaderOptions {
+    /// Set FTEXT, marking the data as probably text
+    pub text: bool,
+    /// Write FHCRC, a check on the header bytes
+    pub header_crc: bool,
+    pub mtime: u32,
+    pub os: u8,
+    pub extra: Option<Vec<ExtraField>>,
+    pub fname: Option<String>,
+    pub comment: Option<String>
+}
+
+impl HeaderOptions {
+    /// A minimal header: no optional fields, no mtime and an unknown OS.
+    /// It records nothing about where or when the data was compressed,
+    /// so is the same wherever it is written.
+    pub fn new() -> HeaderOptions {
+        HeaderOptions {
+            text: false,
+            header_crc: false,
+            mtime: 0,
+            os: OS_UNKNOWN,
+            extra: None,
+            fname: None,
+            comment: None
+        }
+    }
+}
+
+/// Write the gzip header described by options, with the given extra
+/// flags byte. Returns None if a string holds a zero byte, which would
+/// end it early, or the subfields don't fit in the FEXTRA field.
+pub fn write_header(options: &HeaderOptions, extra_flags: u8) -> Option<Vec<u8>> {
+    let mut flags = 0u8;
+    if options.text { flags |= 1; }
+    if options.header_crc { flags |= 2; }
+    if options.extra.is_some() { flags |= 4; }
+    if options.fname.is_some() { flags |= 8; }
+    if options.comment.is_some() { flags |= 16; }
+
+    let mut out = Vec::with_capacity(64);
+    out.push_all(&GZ_MAGIC_BYTES);
+    out.push(8);
+    out.push(flags);
+    push_le(&mut out, options.mtime as u64, 4);
+    out.push(extra_flags);
+    out.push(options.os);
+
+    if let Some(ref fields) = options.extra {
+        let mut extra = Vec::new();
+        for field in fields.iter() {
+            if field.data.len() > 0xffff {
+                return None;
+            }
+            extra.push_all(&field.id);
+            push_le(&mut extra, field.data.len() as u64, 2);
+            extra.push_all(field.data.as_slice());
+        }
+        if extra.len() > 0xffff {
+            return None;
+        }
+        push_le(&mut out, extra.len() as u64, 2);
+        out.push_all(extra.as_slice());
+    }
+    try_opt!(put_string(&options.fname, &mut out));
+    try_opt!(put_string(&options.comment, &mut out));
+    if options.header_crc {
+        let mut crc = Crc32::new();
+        crc.update(out.as_slice());
+        push_le(&mut out, (crc.crc() & 0xffff) as u64, 2);
+    }
+    Some(out)
+}
+
+/// Append the low len bytes of value, least significant first
+pub fn push_le(out: &mut Vec<u8>, value: u64, len: usize) {
+    for i in 0 .. len {
+        out.push((value >> (8 * i)) as u8);
+    }
+}
+
+/// Read a little endian u32 from the start of data
+pub fn read_u32_le(data: &[u8]) -> u32 {
+    (data[0] as u32) | (data[1] as u32) << 8 | (data[2] as u32) << 16 | (data[3] as u32) << 24
+}
+
+/// Append a zero terminated string, if there is one
+fn put_string(string: &Option<String>, out: &mut Vec<u8>) -> Option<()> {
+    if let Some(ref string) = *string {
+        if string.as_bytes().contains(&0) {
+            return None;
+        }
+        out.push_all(string.as_bytes());
+        out.push(0);
+    }
+    Some(())
+}
+
 #[cfg(test)]
 mod parse_header_tests {
-    use super::{parse_header, Flags};
+    use super::{parse_header, parse_header_partial, Flags, ExtraField};
     use cvec;
 
     fn create_buf(raw: &[u8]) -> cvec::Buf {
@@ -211,14 +375,14 @@ mod parse_header_tests {
             0x00,
             // OS
             0x07,
-            // extra id + length + extra
-            0x41, 0x70, 0x00, 0x04, 0x12, 0x34, 0x56, 0x78,
+            // extra length, then subfield id + length + data
+            0x08, 0x00, 0x41, 0x70, 0x04, 0x00, 0x12, 0x34, 0x56, 0x78,
             // name
             0x41, 0x42, 0x43, 0x44, 0x45, 0x00,
             // comment
             0x41, 0x41, 0x41, 0x41, 0x41, 0x41, 0x00,
             // CRC
-            0x00, 0x01];
+            0x01, 0x00];
 
         let buffer = create_buf(HEADER_BYTES);
         let results = parse_header(&buffer).unwrap();
@@ -230,11 +394,27 @@ mod parse_header_tests {
         assert_eq!(results.mtime, 2018915346);
         assert_eq!(results.extra_flags, 0);
         assert_eq!(results.os, 7);
-        assert_eq!(results.extra, Some(("Ap".to_string(), vec![0x12, 0x34, 0x56, 0x78])));
+        assert_eq!(results.extra, Some(vec![0x41, 0x70, 0x04, 0x00, 0x12, 0x34, 0x56, 0x78]));
+        assert_eq!(results.extra_fields(), Some(vec![ExtraField {
+            id: [0x41, 0x70], data: vec![0x12, 0x34, 0x56, 0x78]
+        }]));
         assert_eq!(results.fname, Some("ABCDE".to_string()));
         assert_eq!(results.comment, Some("AAAAAA".to_string()));
         assert_eq!(results.crc, Some(1));
-        assert_eq!(results.header_len, 33);
+        assert_eq!(results.header_len, 35);
+    }
+
+    #[test]
+    fn test_unstructured_extra() {
+        // FEXTRA holding bytes that aren't subfields, which gzip accepts
+        static HEADER_BYTES: &'static [u8] = &[
+              0x1f, 0x8b, 0x08, 0x04, 0x12, 0x34, 0x56, 0x78,
+              0x00, 0x07, 0x05, 0x00, 0x41, 0x70, 0x09, 0x00, 0x01];
+        let buffer = create_buf(HEADER_BYTES);
+        let results = parse_header(&buffer).unwrap();
+        assert_eq!(results.extra, Some(vec![0x41, 0x70, 0x09, 0x00, 0x01]));
+        assert_eq!(results.extra_fields(), Some(vec![]));
+        assert_eq!(results.header_len, 17);
     }
 
     #[test]
@@ -257,7 +437,7 @@ mod parse_header_tests {
             // comment
             0x41, 0x41, 0x41, 0x41, 0x41, 0x41, 0x00,
             // CRC
-            0x00, 0x01];
+            0x01, 0x00];
 
         let buffer = create_buf(HEADER_BYTES);
         let results = parse_header(&buffer).unwrap();
@@ -292,4 +472,102 @@ mod parse_header_tests {
         assert_eq!(parse_header(&buffer), None);
     }
 
+    #[test]
+    fn test_truncated_header() {
+        // the name is never terminated
+        static HEADER_BYTES: &'static [u8] = &[
+              0x1f, 0x8b, 0x08, 0x08, 0x12, 0x34, 0x56, 0x78,
+              0x00, 0x07, 0x41, 0x42];
+        let buffer = create_buf(HEADER_BYTES);
+        assert_eq!(parse_header(&buffer), None);
+        assert_eq!(parse_header_partial(&buffer), Ok(None));
+        let buffer = create_buf(&HEADER_BYTES[.. 1]);
+        assert_eq!(parse_header_partial(&buffer), Ok(None));
+    }
+
+    #[test]
+    fn test_partial_parse() {
+        static HEADER_BYTES: &'static [u8] = &[
+              0x1f, 0x8b, 0x08, 0x08, 0x12, 0x34, 0x56, 0x78,
+              0x00, 0x07, 0x41, 0x42, 0x00];
+        let buffer = create_buf(HEADER_BYTES);
+        let results = parse_header_partial(&buffer).unwrap().unwrap();
+        assert_eq!(results.fname, Some("AB".to_string()));
+        assert_eq!(results.header_len, 13);
+        let buffer = create_buf(&[0x1f, 0x8c, 0x08]);
+        assert_eq!(parse_header_partial(&buffer), Err(()));
+    }
+}
+
+#[cfg(test)]
+mod write_header_tests {
+    use super::{write_header, parse_header, parse_extra_fields, extra_fields, HeaderOptions,
+                ExtraField, OS_UNKNOWN};
+    use crc32::Crc32;
+    use cvec;
+
+    fn create_buf(raw: &[u8]) -> cvec::Buf {
+        let mut buffer = cvec::CVec::with_capacity(raw.len()).unwrap();
+        for &byte in raw.iter() {
+            buffer.push(byte);
+        }
+        buffer
+    }
+
+    #[test]
+    fn test_minimal_header() {
+        let header = write_header(&HeaderOptions::new(), 0).unwrap();
+        assert_eq!(header, vec![0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff]);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let options = HeaderOptions {
+            text: true,
+            header_crc: true,
+            mtime: 2018915346,
+            os: 3,
+            extra: Some(vec![ExtraField { id: [0x41, 0x70], data: vec![1, 2, 3] },
+                             ExtraField { id: [0x42, 0x43], data: vec![] }]),
+            fname: Some("name.txt".to_string()),
+            comment: Some("a comment".to_string())
+        };
+        let bytes = write_header(&options, 2).unwrap();
+        let header = parse_header(&create_buf(bytes.as_slice())).unwrap();
+        assert!(header.flags.FTEXT && header.flags.FHCRC && header.flags.FEXTRA);
+        assert_eq!(header.mtime, 2018915346);
+        assert_eq!(header.extra_flags, 2);
+        assert_eq!(header.os, 3);
+        assert_eq!(header.extra_fields(), options.extra);
+        assert_eq!(header.fname, options.fname);
+        assert_eq!(header.comment, options.comment);
+        assert_eq!(header.header_len, bytes.len());
+
+        let mut crc = Crc32::new();
+        crc.update(&bytes[.. bytes.len() - 2]);
+        assert_eq!(header.crc, Some((crc.crc() & 0xffff) as u16));
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        let mut options = HeaderOptions::new();
+        options.fname = Some("a\0b".to_string());
+        assert_eq!(write_header(&options, 0), None);
+        let mut options = HeaderOptions::new();
+        options.extra = Some(vec![ExtraField { id: [1, 2], data: vec![0; 0x10000] }]);
+        assert_eq!(write_header(&options, 0), None);
+        assert_eq!(HeaderOptions::new().os, OS_UNKNOWN);
+    }
+
+    #[test]
+    fn test_parse_extra_fields() {
+        assert_eq!(parse_extra_fields(&[]), Some(vec![]));
+        assert_eq!(parse_extra_fields(&[0x42, 0x43, 0x02, 0x00, 0x1b, 0x00]),
+                   Some(vec![ExtraField { id: [0x42, 0x43], data: vec![0x1b, 0x00] }]));
+        // a subfield that runs past the end
+        assert_eq!(parse_extra_fields(&[0x42, 0x43, 0x03, 0x00, 0x1b, 0x00]), None);
+        assert_eq!(parse_extra_fields(&[0x42, 0x43, 0x00]), None);
+        assert_eq!(extra_fields(&[0x42, 0x43, 0x00, 0x00, 0x41, 0x70, 0x03, 0x00, 0x1b]),
+                   (vec![ExtraField { id: [0x42, 0x43], data: vec![] }], 5));
+    }
 }
@@ -4,11 +4,15 @@
 
     This modules contains code to create huffman trees from ranges
     as defined in the gzip specification, and read values from a
-    bitstream as interpreted by a huffman tree.
+    bitstream as interpreted by a huffman tree. For compression it
+    also goes the other way, from symbol frequencies to length
+    limited code lengths, the canonical codes for them, and the
+    run length encoded lengths a dynamic block header holds.
 
 "]
 use std;
 use self::HuffmanNode::{Node, Leaf};
+use cvec::{CVec, Allocator};
 use gz_reader::GzBitReader;
 
 /////////////////////////////////////////////////////////////////////
@@ -34,24 +38,36 @@ pub struct TreeNode {
     pub label: usize
 }
 
-#[derive(Show, PartialEq)]
+/// A node of a HuffmanTree. A Node holds the indexes in the tree of the
+/// nodes a 0 and a 1 bit lead to.
+#[derive(Copy, Clone, Show, PartialEq)]
 pub enum HuffmanNode {
-    Node(Option<Box<HuffmanNode>>, Option<Box<HuffmanNode>>),
+    Node(Option<usize>, Option<usize>),
     Leaf(u32)
 }
 
-impl HuffmanNode {
-    /// Traverse the Huffman Tree by reading sequential bytes
+/// A Huffman tree, kept as a table of its nodes with the root first, so
+/// that its memory can come from an Allocator
+#[derive(Show, PartialEq)]
+pub struct HuffmanTree {
+    nodes: CVec<HuffmanNode>
+}
+
+impl HuffmanTree {
+    /// Traverse the Huffman Tree by reading sequential bits
     pub fn read(&self, stream: &mut GzBitReader) -> Option<u32> {
-        match self {
-            &Leaf(v) => Some(v),
-            &Node(ref left, ref right) => {
-                let target = match try_opt!(stream.next_bit()) {
-                    0 => try_ref_opt!(left),
-                    1 => try_ref_opt!(right),
-                    _ => { panic!("Bit greater than one, no bueno."); }
-                };
-                target.read(stream)
+        let mut node = self.nodes[0];
+        loop {
+            match node {
+                Leaf(v) => return Some(v),
+                Node(left, right) => {
+                    let target = match try_opt!(stream.next_bit()) {
+                        0 => try_opt!(left),
+                        1 => try_opt!(right),
+                        _ => { panic!("Bit greater than one, no bueno."); }
+                    };
+                    node = self.nodes[target];
+                }
             }
         }
     }
@@ -61,23 +77,56 @@ impl HuffmanNode {
 //                     Building the tree                           //
 /////////////////////////////////////////////////////////////////////
 
-/// Build the Huffman Tree from a set of Huffman Ranges
-pub fn build_huffman_tree(ranges: &[HuffmanRange]) -> Option<HuffmanNode> {
+/// Build the Huffman Tree from a set of Huffman Ranges, with all the
+/// memory it needs coming from allocator
+pub fn build_huffman_tree(ranges: &[HuffmanRange], allocator: Allocator)
+        -> Option<HuffmanTree> {
     let max_bit_length: usize = try_opt!(ranges.iter()
                                          .map(|x| x.bit_length)
                                          .max()) as usize;
-    let bl_count = count_bitlengths(ranges, max_bit_length);
-    let mut next_code = compute_first_codes(&bl_count);
-    let table: Vec<TreeNode> = compute_code_table(&mut next_code, ranges);
-    let tree: HuffmanNode = build_tree(&table);
-    Some(tree)
+    if max_bit_length > 15 {
+        return None;
+    }
+    let bl_count = try_opt!(count_bitlengths(ranges, max_bit_length, allocator));
+    // more codes than the bit patterns can hold would collide in the tree
+    let used = bl_count.iter().enumerate()
+        .fold(0u64, |used, (i, &count)| used + ((count as u64) << (14 - i)));
+    if used > 1 << 15 {
+        return None;
+    }
+    let mut next_code = try_opt!(compute_first_codes(bl_count.as_slice(), allocator));
+    let table = try_opt!(compute_code_table(&mut next_code, ranges, allocator));
+    build_tree(table.as_slice(), allocator)
+}
+
+/// Build the Huffman Tree for a code length for each symbol
+pub fn tree_from_lengths(lengths: &[u32], allocator: Allocator) -> Option<HuffmanTree> {
+    let ranges = try_opt!(lengths_to_ranges(lengths, allocator));
+    build_huffman_tree(ranges.as_slice(), allocator)
+}
+
+/// Whether code lengths of at most 15 bits make a complete prefix code,
+/// one with no unused bit patterns
+pub fn is_complete(lengths: &[u32]) -> bool {
+    let sum = lengths.iter().filter(|&&l| l > 0).fold(0u64, |sum, &l| sum + (1 << (15 - l)));
+    sum == 1 << 15
+}
+
+/// A CVec of len zeros
+fn zeros(len: usize, allocator: Allocator) -> Option<CVec<u32>> {
+    let mut vec = try_opt!(CVec::with_capacity_in(len, allocator));
+    for _ in 0 .. len {
+        try_opt!(vec.push(0));
+    }
+    Some(vec)
 }
 
 /// determine number of codes of each bit-length
 /// returns a vector where the index corresponds to (bit_length - 1)
-fn count_bitlengths(ranges: &[HuffmanRange], max_bit_length: usize) -> Vec<u32> {
-    // Vec of size max_bit_length + 1, initialized to 0
-    let mut bl_count: Vec<u32> = std::iter::repeat(0).take(max_bit_length).collect();
+fn count_bitlengths(ranges: &[HuffmanRange], max_bit_length: usize, allocator: Allocator)
+        -> Option<CVec<u32>> {
+    // CVec of size max_bit_length, initialized to 0
+    let mut bl_count = try_opt!(zeros(max_bit_length, allocator));
 
     let mut range_iter = ranges.iter();
     let mut old_range: &HuffmanRange = range_iter.next().unwrap();
@@ -95,12 +144,13 @@ fn count_bitlengths(ranges: &[HuffmanRange], max_bit_length: usize) -> Vec<u32>
         }
         old_range = range;
     }
-    bl_count
+    Some(bl_count)
 }
 
 #[cfg(test)]
 mod count_bitlengths_tests {
     use super::{HuffmanRange, count_bitlengths};
+    use cvec::Allocator;
 
     macro_rules! range {
         ( $( ($x:expr, $y:expr) ),* ) => {{
@@ -121,7 +171,8 @@ mod count_bitlengths_tests {
                             (14, 5), (18, 6), (21, 4),
                             (26, 6)];
         let expect = vec![0, 0, 0, 7, 8, 12];
-        assert_eq!(count_bitlengths(ranges.as_slice(), 6), expect);
+        let bl_count = count_bitlengths(ranges.as_slice(), 6, Allocator::libc()).unwrap();
+        assert_eq!(bl_count.as_slice(), expect.as_slice());
     }
 }
 
@@ -129,64 +180,69 @@ mod count_bitlengths_tests {
 /// This is one more than the last code of the previous bit length,
 /// left-shifted once. Returns a vector where the index corresponds
 /// to (bit_length - 1)
-fn compute_first_codes(bl_count: &Vec<u32>) -> Vec<u32> {
-    let mut ret = Vec::new();
+fn compute_first_codes(bl_count: &[u32], allocator: Allocator) -> Option<CVec<u32>> {
+    let mut ret = try_opt!(CVec::with_capacity_in(bl_count.len(), allocator));
     let mut code: u32 = 0;
     // from the RFC
     for bits in (0 .. bl_count.len()) {
         if bits > 0 {
             code = ( code + bl_count[bits - 1] ) << 1;
         }
-        ret.push(if bl_count[bits] > 0 { code } else { 0 });
+        try_opt!(ret.push(if bl_count[bits] > 0 { code } else { 0 }));
     }
-    ret
+    Some(ret)
 }
 
 #[cfg(test)]
 mod compute_first_codes_tests {
     use super::compute_first_codes;
+    use cvec::Allocator;
 
     #[test]
     fn test_compute_codes() {
         let input = vec![0, 0, 0, 7, 8, 12];
         let expect = vec![0, 0, 0, 0, 14, 44];
-        assert_eq!(compute_first_codes(&input), expect);
+        let codes = compute_first_codes(input.as_slice(), Allocator::libc()).unwrap();
+        assert_eq!(codes.as_slice(), expect.as_slice());
     }
 
     #[test]
     fn test_1_bit_codes() {
         let input = vec![1, 1, 1, 1, 0, 4];
         let expect = vec![0, 2, 6, 14, 0, 60];
-        assert_eq!(compute_first_codes(&input), expect);
+        let codes = compute_first_codes(input.as_slice(), Allocator::libc()).unwrap();
+        assert_eq!(codes.as_slice(), expect.as_slice());
     }
 }
 
 /// Assign codes to each symbol in the each range of a given bitlength
-fn compute_code_table(next_code: &mut Vec<u32>, ranges: &[HuffmanRange])
-        -> Vec<TreeNode> {
-    let mut ret = Vec::new();
-    let mut active_range: usize = 0;
+fn compute_code_table(next_code: &mut CVec<u32>, ranges: &[HuffmanRange],
+                      allocator: Allocator)
+        -> Option<CVec<TreeNode>> {
     let num_entries = ranges.get(ranges.len() - 1).unwrap().end;
+    let mut ret = try_opt!(CVec::with_capacity_in(num_entries as usize + 1, allocator));
+    let mut active_range: usize = 0;
     for n in 0 .. num_entries + 1 {
         if n > ranges[active_range].end {
             active_range += 1;
         }
         let bit_length = ranges[active_range].bit_length as usize;
         if bit_length > 0 {
-            ret.push(TreeNode {
+            try_opt!(ret.push(TreeNode {
                 len: bit_length,
                 bits: next_code[bit_length - 1] as usize,
                 label: n as usize
-            });
+            }));
             *next_code.get_mut(bit_length - 1).unwrap() += 1;
         }
     }
-    ret
+    Some(ret)
 }
 
 #[cfg(test)]
 mod compute_code_table_tests {
-    use super::{TreeNode, HuffmanRange, compute_code_table};
+    use super::{TreeNode, HuffmanRange, compute_first_codes, compute_code_table};
+    use cvec::Allocator;
 
     macro_rules! range {
         ( $( ($x:expr, $y:expr) ),* ) => {{
@@ -219,7 +275,8 @@ mod compute_code_table_tests {
 
     #[test]
     fn test_compute_code_table() {
-        let mut next_code = vec![0, 0, 0, 0, 14, 44];
+        // 0, 0, 0, 0, 14, 44
+        let mut next_code = compute_first_codes(&[0, 0, 0, 7, 8, 12], Allocator::libc()).unwrap();
         let ranges = range![(1, 4), (4, 6), (6, 4),
                             (14, 5), (18, 6), (21, 4),
                             (26, 6)];
@@ -229,26 +286,28 @@ mod compute_code_table_tests {
                             (6, 47), (6, 48), (6, 49), (6, 50), (4, 4),
                             (4, 5), (4, 6), (6, 51), (6, 52), (6, 53),
                             (6, 54), (6, 55)];
-        assert_eq!(compute_code_table(&mut next_code, ranges.as_slice()), expect);
+        let table = compute_code_table(&mut next_code, ranges.as_slice(),
+                                       Allocator::libc()).unwrap();
+        assert_eq!(table.as_slice(), expect.as_slice());
     }
 }
 
 /// Create the Huffman tree from the code table
-fn build_tree(code_table: &Vec<TreeNode>) -> HuffmanNode {
-    let mut root = Node(None, None);
+fn build_tree(code_table: &[TreeNode], allocator: Allocator) -> Option<HuffmanTree> {
+    // a complete code has one fewer internal node than it has leaves
+    let mut nodes = try_opt!(CVec::with_capacity_in(2 * code_table.len(), allocator));
+    try_opt!(nodes.push(Node(None, None)));
     for t_node in code_table.iter() {
-        let bits = t_node.bits;
-        let len = (t_node.len - 1) as isize;
-        let label = t_node.label;
-        make_tree(&mut root, bits, len, label);
+        try_opt!(make_tree(&mut nodes, t_node.bits, t_node.len, t_node.label));
     }
-    root
+    Some(HuffmanTree { nodes: nodes })
 }
 
 #[cfg(test)]
 mod build_tree_tests {
     use super::{build_tree, TreeNode};
     use super::HuffmanNode::{Node, Leaf};
+    use cvec::Allocator;
 
     #[test]
     fn test_build_tree() {
@@ -257,53 +316,50 @@ mod build_tree_tests {
             bits: 5, // 0101
             label: 0
         }];
-        assert_eq!(build_tree(&input), Node(
-            Some(box Node(
-                    None,
-                    Some(box Node(
-                            Some(box Node(None,
-                                      Some(box Leaf(0)))),
-                            None)))),
-            None));
-
+        let tree = build_tree(input.as_slice(), Allocator::libc()).unwrap();
+        let expect = vec![Node(Some(1), None),
+                          Node(None, Some(2)),
+                          Node(Some(3), None),
+                          Node(None, Some(4)),
+                          Leaf(0)];
+        assert_eq!(tree.nodes.as_slice(), expect.as_slice());
     }
 }
 
-/// Helper function for build_tree
-fn make_tree(tree: &mut HuffmanNode, bits: usize, len: isize, label: usize) {
-    match tree {
-        &mut Leaf(_) => {
-            panic!("This shouldn't have happened.");
-        },
-        &mut Node(ref mut left, ref mut right) => {
-            match get_bit(bits, len as usize) {
-                0 => { make_tree_side(left, bits, len - 1, label); },
-                1 => { make_tree_side(right, bits, len - 1, label); },
-                _ => { panic!("A bit was greater than 1, this is bad."); }
+/// Helper function for build_tree: follow the len bits of the code from
+/// the root, adding the nodes that are missing, with a Leaf at the end
+fn make_tree(nodes: &mut CVec<HuffmanNode>, bits: usize, len: usize, label: usize)
+        -> Option<()> {
+    let mut index = 0;
+    for depth in 0 .. len {
+        let bit_index = len - 1 - depth;
+        let bit = get_bit(bits, bit_index);
+        let child = match nodes[index] {
+            Leaf(_) => {
+                panic!("This shouldn't have happened.");
+            },
+            Node(left, right) => {
+                match bit {
+                    0 => left,
+                    1 => right,
+                    _ => { panic!("A bit was greater than 1, this is bad."); }
+                }
             }
-        }
-    }
-}
-
-/// Make one side of the tree
-fn make_tree_side(t_side: &mut Option<Box<HuffmanNode>>, bits: usize, len: isize, value: usize) {
-    match t_side {
-        &mut None => { *t_side = Some(box make_new_tree(bits, len, value)); },
-        &mut Some(ref mut t) => { make_tree(&mut **t, bits, len, value); },
-    };
-}
-
-/// Create a new HuffmanNode based on the next set of bits to read
-fn make_new_tree(bits: usize, len: isize, value: usize) -> HuffmanNode {
-    if len < 0 {
-        Leaf(value as u32)
-    } else {
-        match get_bit(bits, len as usize) {
-            0 => Node(Some(box make_new_tree(bits, len - 1, value)), None),
-            1 => Node(None, Some(box make_new_tree(bits, len - 1, value))),
-            _ => { panic!("A bit was greater than 1, this is bad."); }
-        }
+        };
+        index = match child {
+            Some(child) => child,
+            None => {
+                let child = nodes.len();
+                let node = if bit_index == 0 { Leaf(label as u32) } else { Node(None, None) };
+                try_opt!(nodes.push(node));
+                if let Node(ref mut left, ref mut right) = *nodes.get_mut(index).unwrap() {
+                    if bit == 0 { *left = Some(child); } else { *right = Some(child); }
+                }
+                child
+            }
+        };
     }
+    Some(())
 }
 
 /// gets 'index' bit of input
@@ -323,3 +379,284 @@ mod get_bit_tests {
         assert_eq!(get_bit(0x3, 3), 0);
     }
 }
+
+/////////////////////////////////////////////////////////////////////
+//                    Building codes to write                      //
+/////////////////////////////////////////////////////////////////////
+
+/// Turn a code length for each symbol into ranges for build_huffman_tree
+pub fn lengths_to_ranges(lengths: &[u32], allocator: Allocator) -> Option<CVec<HuffmanRange>> {
+    let mut ranges = try_opt!(CVec::with_capacity_in(lengths.len(), allocator));
+    let mut range = HuffmanRange::new();
+    for i in 0 .. lengths.len() {
+        if i > 0 && lengths[i] != lengths[i-1] {
+            try_opt!(ranges.push(range.clone()));
+        }
+        range.end = i as u32;
+        range.bit_length = lengths[i];
+    }
+    try_opt!(ranges.push(range));
+    Some(ranges)
+}
+
+/// Optimal code lengths for the given symbol frequencies, no longer than
+/// limit, found with the package-merge algorithm. Symbols that never
+/// occur get length 0. There must be no more than 2^limit symbols.
+pub fn code_lengths(freqs: &[u32], limit: u32) -> Vec<u32> {
+    let mut lengths = vec![0u32; freqs.len()];
+    let mut symbols: Vec<usize> = (0 .. freqs.len()).filter(|&s| freqs[s] > 0).collect();
+    if symbols.len() == 0 {
+        return lengths;
+    }
+    if symbols.len() == 1 {
+        lengths[symbols[0]] = 1;
+        return lengths;
+    }
+    assert!(symbols.len() <= 1 << limit);
+    symbols.sort_by(|&a, &b| (freqs[a], a).cmp(&(freqs[b], b)));
+    let n = symbols.len();
+    let leaves: Vec<u64> = symbols.iter().map(|&s| freqs[s] as u64).collect();
+
+    // Each list holds the leaves merged with packages of pairs from the
+    // list before it, lightest first. Only whether each item is a package
+    // needs keeping: the packages and leaves in a list are each in order,
+    // so the items chosen from it are always the first of each.
+    let mut weights = leaves.clone();
+    let mut lists: Vec<Vec<bool>> = vec![vec![false; n]];
+    for _ in 1 .. limit {
+        let packages: Vec<u64> = weights.chunks(2)
+            .filter(|pair| pair.len() == 2)
+            .map(|pair| pair[0] + pair[1])
+            .collect();
+        let mut merged = Vec::with_capacity(n + packages.len());
+        let mut is_package = Vec::with_capacity(n + packages.len());
+        let (mut leaf, mut package) = (0, 0);
+        while leaf < n || package < packages.len() {
+            if package == packages.len() || (leaf < n && leaves[leaf] <= packages[package]) {
+                merged.push(leaves[leaf]);
+                is_package.push(false);
+                leaf += 1;
+            } else {
+                merged.push(packages[package]);
+                is_package.push(true);
+                package += 1;
+            }
+        }
+        weights = merged;
+        lists.push(is_package);
+    }
+
+    // choose the lightest 2n - 2 items from the last list. Every leaf
+    // chosen from a list adds one to that symbol's length, and every
+    // package chosen takes two items from the list before.
+    let mut take = 2 * n - 2;
+    for list in lists.iter().rev() {
+        let packages = list[.. take].iter().filter(|&&p| p).count();
+        for &s in symbols[.. take - packages].iter() {
+            lengths[s] += 1;
+        }
+        take = 2 * packages;
+    }
+    lengths
+}
+
+/// The canonical codes for the given lengths, bit reversed so they can be
+/// written least significant bit first
+pub fn canonical_codes(lengths: &[u32]) -> Vec<u32> {
+    let mut bl_count = [0u32; 16];
+    for &len in lengths.iter() {
+        bl_count[len as usize] += 1;
+    }
+    bl_count[0] = 0;
+    let mut next_code = [0u32; 16];
+    let mut code = 0;
+    for bits in 1 .. 16 {
+        code = (code + bl_count[bits - 1]) << 1;
+        next_code[bits] = code;
+    }
+    lengths.iter().map(|&len| {
+        if len == 0 {
+            return 0;
+        }
+        let code = next_code[len as usize];
+        next_code[len as usize] += 1;
+        reverse_bits(code, len)
+    }).collect()
+}
+
+fn reverse_bits(code: u32, len: u32) -> u32 {
+    let mut result = 0;
+    for i in 0 .. len {
+        result |= ((code >> i) & 1) << (len - 1 - i);
+    }
+    result
+}
+
+/// Run length encode code lengths with the symbols 16, 17 and 18, as
+/// read_huffman_tree in inflate expects them.
+/// Returns (symbol, extra bits value) pairs.
+pub fn encode_code_lengths(lengths: &[u32]) -> Vec<(u32, u32)> {
+    let mut encoded = Vec::new();
+    let mut i = 0;
+    while i < lengths.len() {
+        let len = lengths[i];
+        let mut run = 1;
+        while i + run < lengths.len() && lengths[i + run] == len {
+            run += 1;
+        }
+        i += run;
+        if len == 0 {
+            while run >= 11 {
+                let n = std::cmp::min(run, 138);
+                encoded.push((18, (n - 11) as u32));
+                run -= n;
+            }
+            if run >= 3 {
+                encoded.push((17, (run - 3) as u32));
+                run = 0;
+            }
+        } else {
+            encoded.push((len, 0));
+            run -= 1;
+            while run >= 3 {
+                let n = std::cmp::min(run, 6);
+                encoded.push((16, (n - 3) as u32));
+                run -= n;
+            }
+        }
+        for _ in 0 .. run {
+            encoded.push((len, 0));
+        }
+    }
+    encoded
+}
+
+/// The number of extra bits that follow a code length symbol
+pub fn code_length_extra(symbol: u32) -> u32 {
+    match symbol {
+        16 => 2,
+        17 => 3,
+        18 => 7,
+        _ => 0
+    }
+}
+
+#[cfg(test)]
+mod code_lengths_tests {
+    use super::{code_lengths, canonical_codes, encode_code_lengths, lengths_to_ranges,
+                tree_from_lengths, is_complete};
+    use cvec::{CVec, Allocator};
+    use gz_reader::GzBitReader;
+    use bit_writer::BitWriter;
+
+    /// Write symbols with the canonical codes for lengths, and read them
+    /// back through the tree that tree_from_lengths makes from them
+    fn check_decodes(lengths: &[u32], symbols: &[usize]) {
+        let codes = canonical_codes(lengths);
+        let mut writer = BitWriter::new();
+        for &s in symbols.iter() {
+            writer.write_bits(codes[s], lengths[s]);
+        }
+        writer.align_to_byte();
+        let packed = writer.take_bytes();
+        let buffer = unsafe { CVec::from_raw_buf(packed.as_ptr(), packed.len()) }.unwrap();
+        let mut reader = GzBitReader::new(buffer.iter()).unwrap();
+        let tree = tree_from_lengths(lengths, Allocator::libc()).unwrap();
+        for &s in symbols.iter() {
+            assert_eq!(tree.read(&mut reader), Some(s as u32));
+        }
+    }
+
+    #[test]
+    fn test_code_lengths() {
+        let lengths = code_lengths(&[10, 1, 1, 2, 0], 15);
+        assert_eq!(lengths, vec![1, 3, 3, 2, 0]);
+        assert!(is_complete(lengths.as_slice()));
+        assert_eq!(code_lengths(&[0, 5, 0], 15), vec![0, 1, 0]);
+        assert_eq!(code_lengths(&[0, 0], 15), vec![0, 0]);
+    }
+
+    #[test]
+    fn test_length_limit() {
+        // fibonacci frequencies make the deepest possible tree
+        let mut freqs = vec![1u32, 1];
+        for i in 2 .. 30 {
+            let f = freqs[i - 1] + freqs[i - 2];
+            freqs.push(f);
+        }
+        let lengths = code_lengths(freqs.as_slice(), 15);
+        assert!(lengths.iter().all(|&l| l > 0 && l <= 15));
+        assert!(is_complete(lengths.as_slice()));
+        let lengths = code_lengths(freqs.as_slice(), 7);
+        assert!(lengths.iter().all(|&l| l > 0 && l <= 7));
+        assert!(is_complete(lengths.as_slice()));
+        // more frequent symbols never get longer codes
+        for i in 1 .. freqs.len() {
+            assert!(lengths[i] <= lengths[i - 1]);
+        }
+    }
+
+    #[test]
+    fn test_optimal_when_limited() {
+        // four symbols limited to 2 bits can only be a flat code
+        assert_eq!(code_lengths(&[100, 10, 1, 1], 2), vec![2, 2, 2, 2]);
+        // with room for 3 bits, the limit changes nothing
+        assert_eq!(code_lengths(&[100, 10, 1, 1], 3), vec![1, 2, 3, 3]);
+        // 1, 2, 3, 4, 4 would be best, but the limit makes 1, 3, 3, 3, 3
+        // cheaper than 2, 2, 2, 3, 3
+        assert_eq!(code_lengths(&[8, 4, 2, 1, 1], 3), vec![1, 3, 3, 3, 3]);
+    }
+
+    #[test]
+    fn test_canonical_codes() {
+        // the example from RFC 1951, bit reversed
+        let codes = canonical_codes(&[3, 3, 3, 3, 3, 2, 4, 4]);
+        assert_eq!(codes, vec![0b010, 0b110, 0b001, 0b101, 0b011, 0b00, 0b0111, 0b1111]);
+    }
+
+    #[test]
+    fn test_lengths_to_ranges() {
+        let ranges = lengths_to_ranges(&[3, 3, 0, 2, 2, 2], Allocator::libc()).unwrap();
+        assert_eq!(ranges.len(), 3);
+        let expect = [(1, 3), (2, 0), (5, 2)];
+        for (range, &(end, bit_length)) in ranges.iter().zip(expect.iter()) {
+            assert_eq!((range.end, range.bit_length), (end, bit_length));
+        }
+    }
+
+    #[test]
+    fn test_codes_decode() {
+        check_decodes(&[3, 3, 3, 3, 3, 2, 4, 4], &[0, 5, 7, 6, 1, 2, 3, 4, 5]);
+        let freqs: Vec<u32> = (0 .. 286).map(|i| ((i * 7919) % 1000) as u32).collect();
+        let symbols: Vec<usize> = (0 .. 286).filter(|&s| freqs[s] > 0).collect();
+        for &limit in [9, 15].iter() {
+            let lengths = code_lengths(freqs.as_slice(), limit);
+            assert!(lengths.iter().all(|&l| l <= limit));
+            check_decodes(lengths.as_slice(), symbols.as_slice());
+        }
+    }
+
+    #[test]
+    fn test_oversubscribed() {
+        // three 1 bit codes can't all be told apart
+        assert!(tree_from_lengths(&[1, 1, 1], Allocator::libc()).is_none());
+        assert!(!is_complete(&[1, 1, 1]));
+        // an incomplete code is fine, as a lone distance code may be
+        assert!(tree_from_lengths(&[0, 1], Allocator::libc()).is_some());
+        assert!(!is_complete(&[0, 1]));
+    }
+
+    #[test]
+    fn test_too_long() {
+        // DEFLATE codes are at most 15 bits, even where the code has room
+        assert!(tree_from_lengths(&[1, 2, 3, 16], Allocator::libc()).is_none());
+        assert!(tree_from_lengths(&[1, 2, 3, 15], Allocator::libc()).is_some());
+    }
+
+    #[test]
+    fn test_encode_code_lengths() {
+        let lengths = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 8, 8, 8, 8, 8, 8, 8, 8, 0, 0, 0, 3, 0, 0];
+        assert_eq!(encode_code_lengths(&lengths),
+                   vec![(18, 1), (8, 0), (16, 3), (8, 0), (17, 0), (3, 0), (0, 0), (0, 0)]);
+    }
+}
@@ -0,0 +1,530 @@
+#[doc="
+
+    Module: index
+
+    This module gives random access into gzip files, as zlib's
+    zran example does. Building a GzIndex decompresses the file
+    once, and records a checkpoint at the start of a DEFLATE block
+    about every span bytes of output: where the block starts in the
+    compressed stream, to the bit, and the 32K of output before
+    it, which is all that back pointers in the block can refer to.
+    Reading from any offset can then start at the checkpoint
+    before it, rather than at the start of the file.
+
+    An index can be written out and read back in, so it only has
+    to be built once. The format is little endian throughout: the
+    magic \"RGZI\", a u32 version, then the u64 span, decompressed
+    length and checkpoint count. Each checkpoint follows with its
+    u64 output offset and input offset in bits, then the u32
+    window length, CRC32 of the window and deflated window length,
+    and last the window itself, deflated.
+
+"]
+use std::io;
+use std::io::{Read, Write, Seek, SeekFrom};
+
+use crc32::Crc32;
+use cvec::CVec;
+use deflate::deflate;
+use error::{GzError, ErrorKind};
+use gz_reader::GzBitReader;
+use header::push_le;
+use inflate::inflate;
+use read::Input;
+use sink::{VecSink, WINDOW_SIZE};
+use stream::{Decompressor, StreamStatus, BlockStatus};
+
+/// The default amount of output between checkpoints
+pub const DEFAULT_SPAN: u64 = 1048576;
+// how much output to decompress at a time when output is thrown away
+const SKIP_BUF_SIZE: usize = 32768;
+// how hard to compress windows when writing an index out
+const WINDOW_LEVEL: u32 = 6;
+const INDEX_MAGIC: &'static [u8] = b"RGZI";
+const INDEX_VERSION: u32 = 1;
+
+/// A place in a gzip stream where decompression can pick up
+#[derive(Clone, PartialEq, Show)]
+pub struct Checkpoint {
+    /// Offset into the decompressed data
+    pub out_offset: u64,
+    /// Offset into the gzip stream, in bits, of the block starting here
+    pub bit_offset: u64,
+    /// The output before this point, as far back as back pointers reach
+    pub window: Vec<u8>
+}
+
+/// Checkpoints for random access into a gzip stream
+#[derive(Clone, PartialEq, Show)]
+pub struct GzIndex {
+    span: u64,
+    len: u64,
+    checkpoints: Vec<Checkpoint>
+}
+
+impl GzIndex {
+    /// Decompress the gzip stream read from reader, recording a checkpoint
+    /// at the start of each member, and at the first block start at least
+    /// span bytes of output after the checkpoint before. The footer of
+    /// each member is checked along the way.
+    pub fn build<R: Read>(reader: R, span: u64) -> io::Result<GzIndex> {
+        let mut input = Input::new(reader);
+        let mut decompressor = Decompressor::new();
+        let mut output = vec![0u8; SKIP_BUF_SIZE];
+        let mut out_offset = 0;
+        let mut checkpoints: Vec<Checkpoint> = Vec::new();
+        loop {
+            try!(input.fill());
+            let (used, written, status) = match decompressor.decompress_to_block(
+                    input.data(), output.as_mut_slice()) {
+                Ok(result) => result,
+                Err(e) => return Err(e.to_io_error())
+            };
+            input.consume(used);
+            out_offset += written as u64;
+            match status {
+                BlockStatus::BlockStart => {
+                    let due = match checkpoints.last() {
+                        Some(last) => out_offset - last.out_offset >= span,
+                        None => true
+                    };
+                    // every member starts with an empty window, and needs
+                    // its header read, so starts one too
+                    if due || decompressor.window().len() == 0 {
+                        checkpoints.push(Checkpoint {
+                            out_offset: out_offset,
+                            bit_offset: decompressor.bit_position() as u64,
+                            window: decompressor.window().to_vec()
+                        });
+                    }
+                },
+                BlockStatus::Stream(StreamStatus::MemberEnd) => {
+                    // another member may follow
+                    try!(input.fill());
+                    if input.data().len() == 0 {
+                        break;
+                    }
+                    decompressor.next_member();
+                },
+                BlockStatus::Stream(StreamStatus::NeedInput) => {
+                    if input.eof() {
+                        let position = decompressor.total_in();
+                        return Err(GzError::new(ErrorKind::UnexpectedEof, position).to_io_error());
+                    }
+                },
+                BlockStatus::Stream(StreamStatus::OutputFull) => {}
+            }
+        }
+        Ok(GzIndex {
+            span: span,
+            len: out_offset,
+            checkpoints: checkpoints
+        })
+    }
+
+    /// The span the index was built with
+    pub fn span(&self) -> u64 {
+        self.span
+    }
+
+    /// The length of the decompressed data
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn checkpoints(&self) -> &[Checkpoint] {
+        self.checkpoints.as_slice()
+    }
+
+    /// The last checkpoint at or before offset into the decompressed data
+    pub fn checkpoint_for(&self, offset: u64) -> &Checkpoint {
+        // the first checkpoint is always at offset 0
+        let i = match self.checkpoints.binary_search_by(|c| c.out_offset.cmp(&offset)) {
+            Ok(i) => i,
+            Err(i) => i - 1
+        };
+        &self.checkpoints[i]
+    }
+
+    /// Start reading the decompressed data at offset, from the gzip
+    /// stream the index was built from
+    pub fn reader_at<R: Read + Seek>(&self, mut reader: R, offset: u64)
+            -> io::Result<CheckpointReader<R>> {
+        let checkpoint = self.checkpoint_for(offset);
+        try!(reader.seek(SeekFrom::Start(checkpoint.bit_offset / 8)));
+        let mut checkpoint_reader = CheckpointReader::new(reader, checkpoint);
+        let skip = offset - checkpoint.out_offset;
+        if try!(checkpoint_reader.skip(skip)) < skip {
+            return Err(past_end());
+        }
+        Ok(checkpoint_reader)
+    }
+
+    /// Fill buf with the decompressed data starting at offset, from the
+    /// gzip stream the index was built from. Returns how much was read,
+    /// which is less than buf.len() only at the end of the data.
+    pub fn extract<R: Read + Seek>(&self, reader: R, offset: u64, buf: &mut [u8])
+            -> io::Result<usize> {
+        if offset >= self.len {
+            return Ok(0);
+        }
+        let mut checkpoint_reader = try!(self.reader_at(reader, offset));
+        let mut done = 0;
+        while done < buf.len() {
+            let n = try!(checkpoint_reader.read(&mut buf[done ..]));
+            if n == 0 {
+                break;
+            }
+            done += n;
+        }
+        Ok(done)
+    }
+
+    /// Write the index out in the format described above
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let mut out = INDEX_MAGIC.to_vec();
+        push_le(&mut out, INDEX_VERSION as u64, 4);
+        push_le(&mut out, self.span, 8);
+        push_le(&mut out, self.len, 8);
+        push_le(&mut out, self.checkpoints.len() as u64, 8);
+        for checkpoint in self.checkpoints.iter() {
+            let window = if checkpoint.window.len() > 0 {
+                deflate(checkpoint.window.as_slice(), WINDOW_LEVEL)
+            } else {
+                Vec::new()
+            };
+            push_le(&mut out, checkpoint.out_offset, 8);
+            push_le(&mut out, checkpoint.bit_offset, 8);
+            push_le(&mut out, checkpoint.window.len() as u64, 4);
+            push_le(&mut out, window_crc(checkpoint.window.as_slice()) as u64, 4);
+            push_le(&mut out, window.len() as u64, 4);
+            out.push_all(window.as_slice());
+        }
+        try!(writer.write_all(out.as_slice()));
+        writer.flush()
+    }
+
+    /// Read back an index written by write_to
+    pub fn read_from<R: Read>(mut reader: R) -> io::Result<GzIndex> {
+        let mut data = Vec::new();
+        try!(reader.read_to_end(&mut data));
+        match parse_index(data.as_slice()) {
+            Some(index) => Ok(index),
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid gzip index"))
+        }
+    }
+}
+
+/// Decompresses a gzip stream from a checkpoint onwards, reading the
+/// compressed data from R
+pub struct CheckpointReader<R> {
+    input: Input<R>,
+    decompressor: Decompressor,
+    // the offset into the decompressed data of the next byte read
+    position: u64,
+    done: bool
+}
+
+impl<R: Read> CheckpointReader<R> {
+    /// Decompress from checkpoint, where reader is already at the byte its
+    /// bit offset falls in
+    pub fn new(reader: R, checkpoint: &Checkpoint) -> CheckpointReader<R> {
+        CheckpointReader {
+            input: Input::new(reader),
+            decompressor: Decompressor::resume(checkpoint.bit_offset as usize,
+                                               checkpoint.window.as_slice()),
+            position: checkpoint.out_offset,
+            done: false
+        }
+    }
+
+    /// The offset into the decompressed data of the next byte to be read
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    pub fn into_inner(self) -> R {
+        self.input.into_inner()
+    }
+
+    /// Read and throw away up to n bytes, returning how many there were
+    pub fn skip(&mut self, n: u64) -> io::Result<u64> {
+        let mut buf = [0u8; SKIP_BUF_SIZE];
+        let mut skipped = 0;
+        while skipped < n {
+            let step = if n - skipped < SKIP_BUF_SIZE as u64 {
+                (n - skipped) as usize
+            } else {
+                SKIP_BUF_SIZE
+            };
+            let got = try!(self.read(&mut buf[.. step]));
+            if got == 0 {
+                break;
+            }
+            skipped += got as u64;
+        }
+        Ok(skipped)
+    }
+}
+
+impl<R: Read> Read for CheckpointReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if out.len() == 0 || self.done {
+            return Ok(0);
+        }
+        loop {
+            try!(self.input.fill());
+            let (used, written, status) = match self.decompressor.decompress(
+                    self.input.data(), out) {
+                Ok(result) => result,
+                Err(e) => return Err(e.to_io_error())
+            };
+            self.input.consume(used);
+            self.position += written as u64;
+            match status {
+                StreamStatus::OutputFull => return Ok(written),
+                StreamStatus::MemberEnd => {
+                    // another member may follow
+                    try!(self.input.fill());
+                    if self.input.data().len() == 0 {
+                        self.done = true;
+                        return Ok(written);
+                    }
+                    self.decompressor.next_member();
+                },
+                StreamStatus::NeedInput => {
+                    // output first, then the error on the next call
+                    if self.input.eof() && written == 0 {
+                        let position = self.decompressor.total_in();
+                        return Err(GzError::new(ErrorKind::UnexpectedEof, position).to_io_error());
+                    }
+                }
+            }
+            if written > 0 {
+                return Ok(written);
+            }
+        }
+    }
+}
+
+/// The error for an offset past the end of the decompressed data
+fn past_end() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "offset past the end of the gzip data")
+}
+
+/// Parse an index in the format described above
+/// returns None if it is invalid or an unknown version
+fn parse_index(mut data: &[u8]) -> Option<GzIndex> {
+    if data.len() < INDEX_MAGIC.len() || &data[.. INDEX_MAGIC.len()] != INDEX_MAGIC {
+        return None;
+    }
+    data = &data[INDEX_MAGIC.len() ..];
+    if try_opt!(take_le(&mut data, 4)) != INDEX_VERSION as u64 {
+        return None;
+    }
+    let span = try_opt!(take_le(&mut data, 8));
+    let len = try_opt!(take_le(&mut data, 8));
+    let count = try_opt!(take_le(&mut data, 8));
+    let mut checkpoints: Vec<Checkpoint> = Vec::new();
+    for _ in 0 .. count {
+        let out_offset = try_opt!(take_le(&mut data, 8));
+        let bit_offset = try_opt!(take_le(&mut data, 8));
+        let window_len = try_opt!(take_le(&mut data, 4)) as usize;
+        let crc = try_opt!(take_le(&mut data, 4)) as u32;
+        let deflated_len = try_opt!(take_le(&mut data, 4)) as usize;
+        if window_len > WINDOW_SIZE || deflated_len > data.len() {
+            return None;
+        }
+        let window = try_opt!(inflate_window(&data[.. deflated_len], window_len));
+        data = &data[deflated_len ..];
+        if window_crc(window.as_slice()) != crc {
+            return None;
+        }
+        // checkpoints run forwards through both streams
+        if let Some(last) = checkpoints.last() {
+            if out_offset < last.out_offset || bit_offset <= last.bit_offset {
+                return None;
+            }
+        }
+        if out_offset > len {
+            return None;
+        }
+        checkpoints.push(Checkpoint {
+            out_offset: out_offset,
+            bit_offset: bit_offset,
+            window: window
+        });
+    }
+    if data.len() > 0 || checkpoints.len() == 0 || checkpoints[0].out_offset != 0 {
+        return None;
+    }
+    Some(GzIndex {
+        span: span,
+        len: len,
+        checkpoints: checkpoints
+    })
+}
+
+/// Inflate a window that was deflated by write_to
+/// returns None unless it inflates to exactly len bytes
+fn inflate_window(deflated: &[u8], len: usize) -> Option<Vec<u8>> {
+    if len == 0 {
+        return if_opt!(deflated.len() == 0, Vec::new());
+    }
+    let buffer = try_opt!(unsafe { CVec::from_raw_buf(deflated.as_ptr(), deflated.len()) });
+    let mut stream = try_opt!(GzBitReader::new(buffer.iter()));
+    let mut sink = VecSink::new(len, len);
+    try_opt!(inflate(&mut stream, &mut sink));
+    let window = sink.into_vec();
+    if_opt!(window.len() == len, window)
+}
+
+fn window_crc(window: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(window);
+    crc.crc()
+}
+
+/// Take a len byte little endian value off the front of data
+fn take_le(data: &mut &[u8], len: usize) -> Option<u64> {
+    if data.len() < len {
+        return None;
+    }
+    let mut value = 0;
+    for i in 0 .. len {
+        value |= (data[i] as u64) << (8 * i);
+    }
+    *data = &data[len ..];
+    Some(value)
+}
+
+#[cfg(test)]
+mod gz_index_tests {
+    use super::GzIndex;
+    use encoder::compress;
+    use std::io::{Cursor, Read};
+    use testing::{Lcg, text};
+
+    /// Offsets spread over the data, including both ends
+    fn offsets(len: u64) -> Vec<u64> {
+        let mut random = Lcg::new(4321);
+        let mut offsets = vec![0, len - 1, len];
+        for _ in 0 .. 40 {
+            offsets.push(random.next() as u64 % len);
+        }
+        offsets
+    }
+
+    fn check_extract(index: &GzIndex, compressed: &[u8], expected: &[u8]) {
+        let mut buf = vec![0u8; 5000];
+        for &offset in offsets(expected.len() as u64).iter() {
+            let n = index.extract(Cursor::new(compressed), offset, buf.as_mut_slice()).unwrap();
+            let start = offset as usize;
+            let end = if start + 5000 < expected.len() { start + 5000 } else { expected.len() };
+            assert_eq!(n, end - start);
+            assert!(&buf[.. n] == &expected[start .. end], "offset {}", offset);
+        }
+    }
+
+    #[test]
+    fn test_checkpoints() {
+        let data = text(20000);
+        let compressed = compress(data.as_slice(), 6);
+        let index = GzIndex::build(compressed.as_slice(), 65536).unwrap();
+        assert_eq!(index.len(), data.len() as u64);
+        let checkpoints = index.checkpoints();
+        assert!(checkpoints.len() > 3);
+        assert_eq!(checkpoints[0].out_offset, 0);
+        // the first block starts just after the ten byte header
+        assert_eq!(checkpoints[0].bit_offset, 80);
+        for pair in checkpoints.windows(2) {
+            assert!(pair[1].out_offset - pair[0].out_offset >= 65536);
+            let start = pair[1].out_offset as usize;
+            assert!(pair[1].window.as_slice() == &data[start - 32768 .. start]);
+        }
+        let offset = checkpoints[2].out_offset;
+        assert_eq!(index.checkpoint_for(offset), &checkpoints[2]);
+        assert_eq!(index.checkpoint_for(offset - 1), &checkpoints[1]);
+        check_extract(&index, compressed.as_slice(), data.as_slice());
+    }
+
+    #[test]
+    fn test_stored_blocks() {
+        let data = text(20000);
+        let compressed = compress(data.as_slice(), 0);
+        let index = GzIndex::build(compressed.as_slice(), 100000).unwrap();
+        assert!(index.checkpoints().len() > 3);
+        check_extract(&index, compressed.as_slice(), data.as_slice());
+    }
+
+    #[test]
+    fn test_multiple_members() {
+        let data = text(20000);
+        let (first, second) = data.split_at(300000);
+        let mut compressed = compress(first, 9);
+        compressed.push_all(compress(&[], 6).as_slice());
+        compressed.push_all(compress(second, 1).as_slice());
+        let index = GzIndex::build(compressed.as_slice(), 200000).unwrap();
+        // each member starts with a checkpoint with an empty window
+        let starts = index.checkpoints().iter().filter(|c| c.window.len() == 0).count();
+        assert_eq!(starts, 3);
+        check_extract(&index, compressed.as_slice(), data.as_slice());
+    }
+
+    #[test]
+    fn test_reader_at() {
+        let data = text(20000);
+        let compressed = compress(data.as_slice(), 6);
+        let index = GzIndex::build(compressed.as_slice(), 65536).unwrap();
+        let mut reader = index.reader_at(Cursor::new(compressed.as_slice()), 500000).unwrap();
+        assert_eq!(reader.position(), 500000);
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert!(rest.as_slice() == &data[500000 ..]);
+        assert_eq!(reader.position(), data.len() as u64);
+        assert!(index.reader_at(Cursor::new(compressed.as_slice()), data.len() as u64 + 1).is_err());
+    }
+
+    #[test]
+    fn test_write_and_read_back() {
+        let data = text(20000);
+        let compressed = compress(data.as_slice(), 6);
+        let index = GzIndex::build(compressed.as_slice(), 65536).unwrap();
+        let mut saved = Vec::new();
+        index.write_to(&mut saved).unwrap();
+        assert_eq!(&saved[.. 8], b"RGZI\x01\x00\x00\x00");
+        // deflated windows keep the index small
+        assert!(saved.len() < index.checkpoints().len() * 32768 / 4);
+        let loaded = GzIndex::read_from(saved.as_slice()).unwrap();
+        assert_eq!(loaded, index);
+        check_extract(&loaded, compressed.as_slice(), data.as_slice());
+    }
+
+    #[test]
+    fn test_invalid_index() {
+        let compressed = compress(text(20000).as_slice(), 6);
+        let index = GzIndex::build(compressed.as_slice(), 65536).unwrap();
+        let mut saved = Vec::new();
+        index.write_to(&mut saved).unwrap();
+        let mut bad_version = saved.clone();
+        bad_version[4] = 2;
+        assert!(GzIndex::read_from(bad_version.as_slice()).is_err());
+        let len = saved.len();
+        assert!(GzIndex::read_from(&saved[.. len - 1]).is_err());
+        let mut bad_window = saved.clone();
+        bad_window[len - 3] ^= 0xff;
+        assert!(GzIndex::read_from(bad_window.as_slice()).is_err());
+        let junk: &[u8] = b"not an index";
+        assert!(GzIndex::read_from(junk).is_err());
+    }
+
+    #[test]
+    fn test_corrupt_stream() {
+        let data = text(20000);
+        let mut compressed = compress(data.as_slice(), 6);
+        let len = compressed.len();
+        assert!(GzIndex::build(&compressed[.. len - 100], 65536).is_err());
+        compressed[len - 8] ^= 1;
+        assert!(GzIndex::build(compressed.as_slice(), 65536).is_err());
+    }
+}
@@ -6,12 +6,16 @@
     in decompressing a gzip buffer. It parses the buffer to
     generate the huffman trees embedded in it, and then uses
     those huffman trees to decode the gzip into a buffer.
+    An Inflater does the same for a stream that arrives in
+    pieces, stopping whenever it runs out of input.
 
 "]
+use cvec::{Buf, CVec, Allocator};
 use gz_reader::GzBitReader;
-use cvec::Buf;
-use huffman::{HuffmanNode, HuffmanRange};
-use huffman::build_huffman_tree;
+use sink::{Sink, Window};
+use std::cmp;
+use huffman::{HuffmanTree, HuffmanRange};
+use huffman::{build_huffman_tree, tree_from_lengths};
 
 // These constants are defined by the GZIP standard
 static CODE_LENGTH_OFFSETS: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
@@ -31,44 +35,60 @@ static FIXED_TREE_RANGES: [HuffmanRange; 4] = [
 //                  Tree Reading                                   //
 /////////////////////////////////////////////////////////////////////
 
-/// Builds the first tree from a gzip block header, used to encode
-/// the following literals and distance tree
-fn build_code_length_tree(stream: &mut GzBitReader, hclen: u32)
-    -> Option<HuffmanNode>
-{
-    let mut code_length_ranges = Vec::new();
-    let mut code_lengths = [0u32; 19];
+/// The code lengths a dynamic block header describes
+pub struct DynamicHeader {
+    /// The number of literal/length codes, less 257
+    pub hlit: u32,
+    /// The number of distance codes, less 1
+    pub hdist: u32,
+    /// The number of code length code lengths given, less 4
+    pub hclen: u32,
+    /// The lengths of the code the other lengths are encoded with
+    pub code_length_lengths: CVec<u32>,
+    /// The lengths of the literal/length codes, HLIT + 257 of them
+    pub literal_lengths: CVec<u32>,
+    /// The lengths of the distance codes, HDIST + 1 of them
+    pub distance_lengths: CVec<u32>
+}
+
+/// Reads the lengths of the first tree from a gzip block header, used
+/// to encode the following literals and distance tree
+fn read_code_length_lengths(stream: &mut GzBitReader, hclen: u32, allocator: Allocator)
+        -> Option<CVec<u32>> {
+    let mut code_lengths = try_opt!(copy_lengths(&[0u32; 19], allocator));
 
     for i in 0 .. (hclen + 4) as usize {
-        code_lengths[CODE_LENGTH_OFFSETS[i]] = try_opt!(stream.read_bits(3));
+        *code_lengths.get_mut(CODE_LENGTH_OFFSETS[i]).unwrap() = try_opt!(stream.read_bits(3));
     }
+    Some(code_lengths)
+}
 
-    // make these ranges for the huffman tree routine
-    let mut range = HuffmanRange::new();
-    for i in (0 .. 19) {
-        if i > 0 && code_lengths[i] != code_lengths[i-1] {
-            code_length_ranges.push(range.clone());
-        }
-        range.end = i as u32;
-        range.bit_length = code_lengths[i];
+/// Copy code lengths into a new CVec from allocator
+fn copy_lengths(lengths: &[u32], allocator: Allocator) -> Option<CVec<u32>> {
+    let mut copy = try_opt!(CVec::with_capacity_in(lengths.len(), allocator));
+    for &length in lengths.iter() {
+        try_opt!(copy.push(length));
     }
-    code_length_ranges.push(range.clone());
-    build_huffman_tree(code_length_ranges.as_slice())
+    Some(copy)
 }
 
-/// Reads a huffman tree from a GzBitReader and returns two trees:
-/// the first is the literals tree, and the second is the distances tree
-fn read_huffman_tree(stream: &mut GzBitReader) -> Option<(HuffmanNode, HuffmanNode)> {
+/// Reads the header of a dynamic block, following the block type, and
+/// returns the code lengths it describes, in memory from allocator
+pub fn read_dynamic_header(stream: &mut GzBitReader, allocator: Allocator)
+        -> Option<DynamicHeader> {
     let hlit = try_opt!(stream.read_bits(5));
     let hdist = try_opt!(stream.read_bits(5));
     let hclen = try_opt!(stream.read_bits(4)); // max of 15
 
-    let code_lengths_root = try_opt!(build_code_length_tree(stream, hclen));
+    let code_length_lengths = try_opt!(read_code_length_lengths(stream, hclen, allocator));
+    let code_lengths_root = try_opt!(tree_from_lengths(code_length_lengths.as_slice(),
+                                                       allocator));
 
     // now we read the literal/length alphabet, encoded with the huffman tree
     // we just built
     let mut i = 0;
-    let mut alphabet: Vec<u32> = Vec::new();
+    let mut alphabet = try_opt!(CVec::with_capacity_in((hlit + hdist + 258) as usize,
+                                                       allocator));
     while i < (hlit + hdist + 258) {
         let code = try_opt!(code_lengths_root.read(stream));
         if code > 15 {
@@ -83,101 +103,137 @@ fn read_huffman_tree(stream: &mut GzBitReader) -> Option<(HuffmanNode, HuffmanNo
             } as i32;
             while repeat_length > 0 {
                 if code == 16 {
-                    let prev = *try_opt!(alphabet.get((i-1) as usize));
-                    alphabet.push(prev);
+                    // there must be a length to repeat
+                    let prev = *try_opt!(alphabet.as_slice().last());
+                    try_opt!(alphabet.push(prev));
                 } else {
-                    alphabet.push(0);
+                    try_opt!(alphabet.push(0));
                 }
                 i += 1;
                 repeat_length -= 1;
             }
         } else {
-            alphabet.push(code);
+            try_opt!(alphabet.push(code));
             i += 1;
         }
     }
 
-    // now alphabet lenths have been read, turn these into a range declaration and build
-    // the final huffman code from it
-    let mut range = HuffmanRange::new();
-    let mut literals_ranges = Vec::new();
-    for i in 0 .. (hlit + 257) as usize {
-        if i > 0 && alphabet[i] != alphabet[i-1] {
-            literals_ranges.push(range.clone());
-        }
-        range.end = i as u32;
-        range.bit_length = alphabet[i];
-    };
-    literals_ranges.push(range.clone());
+    let dist_start = (hlit + 257) as usize;
+    let dist_end = dist_start + hdist as usize + 1;
+    Some(DynamicHeader {
+        hlit: hlit,
+        hdist: hdist,
+        hclen: hclen,
+        code_length_lengths: code_length_lengths,
+        literal_lengths: try_opt!(copy_lengths(&alphabet.as_slice()[.. dist_start], allocator)),
+        distance_lengths: try_opt!(copy_lengths(&alphabet.as_slice()[dist_start .. dist_end],
+                                                allocator))
+    })
+}
 
-    let mut distances_ranges = Vec::new();
-    let dist_start = hlit + 257;
-    for i in dist_start as usize .. (hdist + dist_start + 1) as usize {
-        if i > dist_start as usize && alphabet[i] != alphabet[i-1] {
-            distances_ranges.push(range.clone());
-        }
-        range.end = i as u32 - dist_start;
-        range.bit_length = alphabet[i];
-    }
-    distances_ranges.push(range);
+/// Reads a huffman tree from a GzBitReader and returns two trees:
+/// the first is the literals tree, and the second is the distances tree
+fn read_huffman_tree(stream: &mut GzBitReader, allocator: Allocator)
+        -> Option<(HuffmanTree, HuffmanTree)> {
+    let header = try_opt!(read_dynamic_header(stream, allocator));
+    build_dynamic_trees(&header, allocator)
+}
 
-    let literals_root = try_opt!(build_huffman_tree(literals_ranges.as_slice()));
-    let distances_root = try_opt!(build_huffman_tree(distances_ranges.as_slice()));
+/// Builds the trees a dynamic block header describes:
+/// the first is the literals tree, and the second is the distances tree
+fn build_dynamic_trees(header: &DynamicHeader, allocator: Allocator)
+        -> Option<(HuffmanTree, HuffmanTree)> {
+    let literals_root = try_opt!(tree_from_lengths(header.literal_lengths.as_slice(), allocator));
+    let distances_root = try_opt!(tree_from_lengths(header.distance_lengths.as_slice(),
+                                                    allocator));
     Some((literals_root, distances_root))
 }
 
 /// Create the fixed HuffmanTree (per the spec)
-fn build_fixed_huffman_tree() -> Option<HuffmanNode> {
-    build_huffman_tree(&FIXED_TREE_RANGES)
+fn build_fixed_huffman_tree(allocator: Allocator) -> Option<HuffmanTree> {
+    build_huffman_tree(&FIXED_TREE_RANGES, allocator)
 }
 
 /////////////////////////////////////////////////////////////////////
 //                    Inflating the data                           //
 /////////////////////////////////////////////////////////////////////
 
+/// Read the extra bits for a length code, and return the length
+#[inline]
+fn read_length(stream: &mut GzBitReader, code: u32) -> Option<u32> {
+    if code < 265 {
+        Some(code - 254)
+    } else if code < 285 {
+        let extra_bits = try_opt!(stream.read_bits((code - 261) / 4));
+        Some(extra_bits + EXTRA_LENGTH_ADDEND[(code - 265) as usize] as u32)
+    } else {
+        Some(258)
+    }
+}
+
+/// Read the distance that follows a length, minus one
+/// With no distance tree, the fixed distance codes are used
+#[inline]
+fn read_distance(stream: &mut GzBitReader, distances_root: Option<&HuffmanTree>) -> Option<u32> {
+    let mut dist = match distances_root {
+        None => {
+            try_opt!(stream.read_bits_rev(5)) // hardcoded distance
+        },
+        Some(distance_tree) => {
+            try_opt!(distance_tree.read(stream))
+        }
+    };
+
+    if dist > 3 {
+        if dist > 29 {
+            return None;
+        }
+        let extra_dist = try_opt!(stream.read_bits((dist - 2) / 2));
+        dist = extra_dist + EXTRA_DIST_ADDEND[(dist - 4) as usize] as u32;
+    }
+    Some(dist)
+}
+
+/// Read the length of a stored block, which starts at the next byte
+fn read_stored_len(stream: &mut GzBitReader) -> Option<usize> {
+    stream.align_to_byte();
+    let len = try_opt!(stream.read_bits(16));
+    let nlen = try_opt!(stream.read_bits(16));
+    if_opt!(len == !nlen & 0xffff, len as usize)
+}
+
 /// Inflate the data segment based on the given Huffman Trees
 /// Effect: the output will be stored in out
 /// Success on a Some(()) result, failure on a None result
-fn inflate_huffman_codes(stream: &mut GzBitReader,
-                         literals_root: &HuffmanNode,
-                         distances_root: Option<&HuffmanNode>,
-                         out: &mut Buf)
+fn inflate_huffman_codes<S: Sink>(stream: &mut GzBitReader,
+                                  literals_root: &HuffmanTree,
+                                  distances_root: Option<&HuffmanTree>,
+                                  out: &mut S)
         -> Option<()> {
     while let Some(code) = literals_root.read(stream) {
         if code >= 286 {
             return None;
         }
         if code < 256 {
-            out.push(code as u8);
+            try_opt!(out.push(code as u8));
         } else if code == 256 { //stop code
             break;
-        } else if code > 256 {
-            let length = if code < 265 {
-                code - 254
-            } else {
-                if code < 285 {
-                    let extra_bits = try_opt!(stream.read_bits((code - 261) / 4));
-                    extra_bits + EXTRA_LENGTH_ADDEND[((code - 266) + 1) as usize] as u32
-                } else { 258 }
-            };
-
+        } else {
+            let length = try_opt!(read_length(stream, code));
             // now, the length is followed by the distance back
-            let mut dist = match distances_root {
-                None => {
-                    try_opt!(stream.read_bits_rev(5)) // hardcoded distance
-                },
-                Some(distance_tree) => {
-                    try_opt!(distance_tree.read(stream))
-                }
-            };
-
-            if dist > 3 {
-                let extra_dist = try_opt!(stream.read_bits((dist - 2) / 2));
-                dist = extra_dist + EXTRA_DIST_ADDEND[(dist - 4) as usize] as u32;
-
-            }
-            out.copy_back_pointer(dist as usize, length as usize);
+            let dist = try_opt!(read_distance(stream, distances_root));
+            try_opt!(out.copy_back_pointer(dist as usize, length as usize));
         }
+        out.token_end(stream.bit_position());
+    }
+    Some(())
+}
+
+/// Copy a stored block of len bytes into the out buffer
+fn inflate_stored<S: Sink>(stream: &mut GzBitReader, len: usize, out: &mut S) -> Option<()> {
+    for _ in 0 .. len {
+        try_opt!(out.push(try_opt!(stream.read_bits(8)) as u8));
+        out.token_end(stream.bit_position());
     }
     Some(())
 }
@@ -185,32 +241,376 @@ fn inflate_huffman_codes(stream: &mut GzBitReader,
 /// Inflate the given compressed stream into the out buffer
 /// inflate() should be called with a GzBitReader starting at the head
 /// of the first block
-pub fn inflate(stream: &mut GzBitReader, out: &mut Buf) -> Option<()> {
-    let fixed_tree = try_opt!(build_fixed_huffman_tree());
+pub fn inflate<S: Sink>(stream: &mut GzBitReader, out: &mut S) -> Option<()> {
+    inflate_blocks(stream, out, usize::MAX).map(|_| ())
+}
+
+/// Inflate blocks into the out buffer until the last block has been read,
+/// or until a block ends at or past stop_bit, a bit position counted from
+/// the start of the stream's buffer
+/// The sink is told where each block starts and ends, and the Huffman
+/// tables come from its allocator
+/// Returns whether the last block was reached
+pub fn inflate_blocks<S: Sink>(stream: &mut GzBitReader, out: &mut S, stop_bit: usize)
+        -> Option<bool> {
+    let allocator = out.allocator();
+    let fixed_tree = try_opt!(build_fixed_huffman_tree(allocator));
     let mut last_block = 0;
     while { last_block == 0 } {
+        let start = stream.bit_position();
+        if start >= stop_bit {
+            return Some(false);
+        }
         last_block = try_opt!(stream.next_bit());
         let block_format = try_opt!(stream.read_bits(2));
+        let last = last_block == 1;
         match block_format {
             0x00 => {
-                // uncompressed block type, not supported
-                return None;
+                // uncompressed block
+                let len = try_opt!(read_stored_len(stream));
+                out.block_start(start, last, block_format, stream.bit_position());
+                try_opt!(inflate_stored(stream, len, out));
             },
             0x01 => {
                 // fixed tree
+                out.block_start(start, last, block_format, stream.bit_position());
                 try_opt!(inflate_huffman_codes(stream, &fixed_tree, None, out));
             },
             0x02 => {
                 // dynamic tree
-                let (literals_tree, distances_tree) = try_opt!(read_huffman_tree(stream));
+                let dynamic = try_opt!(read_dynamic_header(stream, allocator));
+                let (literals_tree, distances_tree) = try_opt!(build_dynamic_trees(&dynamic,
+                                                                                   allocator));
+                out.dynamic_lengths(dynamic.hclen, dynamic.code_length_lengths.as_slice(),
+                                    dynamic.literal_lengths.as_slice(),
+                                    dynamic.distance_lengths.as_slice());
+                out.block_start(start, last, block_format, stream.bit_position());
                 try_opt!(inflate_huffman_codes(stream, &literals_tree, Some(&distances_tree), out));
             }
             _ => {
-                println!("unsupported block");
-                // unsupported block type
+                // reserved block type, which no encoder writes
+                out.block_start(start, last, block_format, stream.bit_position());
                 return None;
             }
         }
+        out.block_end(stream.bit_position());
+    }
+    Some(true)
+}
+
+/////////////////////////////////////////////////////////////////////
+//                    Resumable inflation                          //
+/////////////////////////////////////////////////////////////////////
+
+/// What a resumable Inflater expects to read next
+enum InflateState {
+    /// The header of a block
+    BlockHeader,
+    /// This many more bytes of a stored block
+    Stored(usize),
+    /// Codes using the fixed tree
+    Fixed,
+    /// Codes using the given literals and distances trees
+    Dynamic(HuffmanTree, HuffmanTree),
+    /// Nothing, the last block has ended
+    Done
+}
+
+/// What to do with the InflateState after a step
+enum Step {
+    Stay,
+    Next(InflateState),
+    BlockEnd
+}
+
+/// Why Inflater::inflate_some stopped
+#[derive(Copy, Clone, PartialEq, Show)]
+pub enum InflateStatus {
+    /// The requested amount of output is waiting to be taken
+    OutputReady,
+    /// The input ran out partway through the stream
+    NeedInput,
+    /// A block has ended and the next one has not begun. Only returned
+    /// when asked for with stop_at_block_ends.
+    BlockEnd,
+    /// The last block has ended
+    Done
+}
+
+/// Inflates a stream that arrives in pieces. It only ever commits to
+/// whole block headers and symbols, so when the input runs out it can
+/// pick up again from the position it returned once more arrives.
+pub struct Inflater {
+    state: InflateState,
+    last_block: bool,
+    stop_at_blocks: bool,
+    fixed_tree: HuffmanTree,
+    window: Window
+}
+
+impl Inflater {
+    pub fn new() -> Inflater {
+        Inflater::with_window(&[])
+    }
+
+    /// Start inflating partway through a stream, at the start of a block,
+    /// where window holds the output before that point
+    pub fn with_window(window: &[u8]) -> Inflater {
+        Inflater {
+            state: InflateState::BlockHeader,
+            last_block: false,
+            stop_at_blocks: false,
+            fixed_tree: build_fixed_huffman_tree(Allocator::libc()).unwrap(),
+            window: Window::with_history(window)
+        }
+    }
+
+    /// Whether inflate_some should also stop at the end of every block
+    pub fn stop_at_block_ends(&mut self, stop: bool) {
+        self.stop_at_blocks = stop;
+    }
+
+    /// The most recent output, taken or not, as much as back pointers can
+    /// refer to
+    pub fn window(&self) -> &[u8] {
+        self.window.history()
+    }
+
+    /// The output that has not been taken yet
+    pub fn output(&self) -> &[u8] {
+        self.window.pending()
+    }
+
+    /// Mark the first n bytes of output as taken
+    pub fn consume(&mut self, n: usize) {
+        self.window.consume(n);
+    }
+
+    /// Whether the last block has ended
+    pub fn is_done(&self) -> bool {
+        match self.state {
+            InflateState::Done => true,
+            _ => false
+        }
+    }
+
+    /// Inflate input, starting bit_pos bits in, until at least want bytes
+    /// of output are waiting, the input runs out, or the last block ends.
+    /// Returns the bit position just past what was used, and why it
+    /// stopped, or None if the stream is corrupt.
+    pub fn inflate_some(&mut self, input: &Buf, bit_pos: usize, want: usize)
+            -> Option<(usize, InflateStatus)> {
+        let mut committed = bit_pos;
+        loop {
+            if self.is_done() {
+                return Some((committed, InflateStatus::Done));
+            }
+            if self.window.pending_len() >= want {
+                return Some((committed, InflateStatus::OutputReady));
+            }
+            let mut stream = match GzBitReader::at(input, committed) {
+                Some(stream) => stream,
+                None => return Some((committed, InflateStatus::NeedInput))
+            };
+            let step = match self.state {
+                InflateState::BlockHeader => {
+                    match read_block_header(&mut stream) {
+                        Some((last_block, state)) => {
+                            self.last_block = last_block;
+                            committed = stream.bit_position();
+                            Some(Step::Next(state))
+                        },
+                        None => None
+                    }
+                },
+                InflateState::Stored(remaining) => {
+                    // stored data is byte aligned, so copy straight from input
+                    let start = committed / 8;
+                    let n = cmp::min(cmp::min(remaining, input.len() - start),
+                                     want - self.window.pending_len());
+                    for i in start .. start + n {
+                        self.window.push(input[i]);
+                    }
+                    committed += n * 8;
+                    if n == remaining {
+                        Some(Step::BlockEnd)
+                    } else {
+                        Some(Step::Next(InflateState::Stored(remaining - n)))
+                    }
+                },
+                InflateState::Fixed => {
+                    inflate_some_codes(&mut stream, &self.fixed_tree, None,
+                                       &mut self.window, want, &mut committed)
+                },
+                InflateState::Dynamic(ref literals_tree, ref distances_tree) => {
+                    inflate_some_codes(&mut stream, literals_tree, Some(distances_tree),
+                                       &mut self.window, want, &mut committed)
+                },
+                InflateState::Done => Some(Step::Stay)
+            };
+            match step {
+                Some(Step::Stay) => {},
+                Some(Step::Next(state)) => self.state = state,
+                Some(Step::BlockEnd) => {
+                    if self.last_block {
+                        self.state = InflateState::Done;
+                    } else {
+                        self.state = InflateState::BlockHeader;
+                        if self.stop_at_blocks {
+                            return Some((committed, InflateStatus::BlockEnd));
+                        }
+                    }
+                },
+                // running out of input partway through is not corruption
+                None if stream.is_eof() => return Some((committed, InflateStatus::NeedInput)),
+                None => return None
+            }
+        }
+    }
+}
+
+/// Read a block header, returning whether it is the last block, and
+/// what to expect next
+fn read_block_header(stream: &mut GzBitReader) -> Option<(bool, InflateState)> {
+    let last_block = try_opt!(stream.next_bit()) == 1;
+    let state = match try_opt!(stream.read_bits(2)) {
+        0x00 => InflateState::Stored(try_opt!(read_stored_len(stream))),
+        0x01 => InflateState::Fixed,
+        0x02 => {
+            let (literals_tree, distances_tree) = try_opt!(read_huffman_tree(stream,
+                                                                             Allocator::libc()));
+            InflateState::Dynamic(literals_tree, distances_tree)
+        },
+        _ => return None
+    };
+    Some((last_block, state))
+}
+
+/// Inflate codes into the window until at least want bytes are waiting
+/// or the block ends, moving committed past each whole symbol
+fn inflate_some_codes(stream: &mut GzBitReader,
+                      literals_root: &HuffmanTree,
+                      distances_root: Option<&HuffmanTree>,
+                      window: &mut Window,
+                      want: usize,
+                      committed: &mut usize)
+        -> Option<Step> {
+    while window.pending_len() < want {
+        let code = try_opt!(literals_root.read(stream));
+        if code >= 286 {
+            return None;
+        }
+        if code < 256 {
+            window.push(code as u8);
+        } else if code == 256 {
+            *committed = stream.bit_position();
+            return Some(Step::BlockEnd);
+        } else {
+            let length = try_opt!(read_length(stream, code));
+            let dist = try_opt!(read_distance(stream, distances_root));
+            try_opt!(window.copy_back_pointer(dist as usize, length as usize));
+        }
+        *committed = stream.bit_position();
+    }
+    Some(Step::Stay)
+}
+
+#[cfg(test)]
+mod read_huffman_tree_tests {
+    use super::{read_huffman_tree, read_dynamic_header, CODE_LENGTH_OFFSETS};
+    use huffman::{code_lengths, canonical_codes, encode_code_lengths, code_length_extra,
+                  tree_from_lengths};
+    use cvec::{CVec, Allocator};
+    use gz_reader::GzBitReader;
+    use bit_writer::BitWriter;
+
+    #[test]
+    fn test_encoded_tree() {
+        // a literal code with long runs of equal and zero lengths
+        let mut literal_freqs = vec![0u32; 286];
+        for i in 97 .. 123 {
+            literal_freqs[i] = 100 + i as u32;
+        }
+        for i in (0 .. 256).filter(|i| i % 40 == 0) {
+            literal_freqs[i] = 1;
+        }
+        literal_freqs[256] = 1;
+        literal_freqs[270] = 30;
+        let literal_lengths = code_lengths(literal_freqs.as_slice(), 15);
+        let distance_lengths = code_lengths(&[5, 0, 0, 9, 9, 9, 9, 9, 9, 9, 1], 15);
+        let mut all_lengths = literal_lengths[.. 271].to_vec();
+        all_lengths.push_all(distance_lengths.as_slice());
+
+        let encoded = encode_code_lengths(all_lengths.as_slice());
+        let mut cl_freqs = vec![0u32; 19];
+        for &(symbol, _) in encoded.iter() {
+            cl_freqs[symbol as usize] += 1;
+        }
+        let cl_lengths = code_lengths(cl_freqs.as_slice(), 7);
+        let cl_codes = canonical_codes(cl_lengths.as_slice());
+
+        let mut writer = BitWriter::new();
+        writer.write_bits(271 - 257, 5);
+        writer.write_bits(11 - 1, 5);
+        writer.write_bits(19 - 4, 4);
+        for &offset in CODE_LENGTH_OFFSETS.iter() {
+            writer.write_bits(cl_lengths[offset], 3);
+        }
+        for &(symbol, extra) in encoded.iter() {
+            writer.write_bits(cl_codes[symbol as usize], cl_lengths[symbol as usize]);
+            writer.write_bits(extra, code_length_extra(symbol));
+        }
+        writer.align_to_byte();
+        let packed = writer.take_bytes();
+        let buffer = unsafe { CVec::from_raw_buf(packed.as_ptr(), packed.len()) }.unwrap();
+        let mut reader = GzBitReader::new(buffer.iter()).unwrap();
+
+        let (literals, distances) = read_huffman_tree(&mut reader, Allocator::libc()).unwrap();
+        assert_eq!(literals, tree_from_lengths(&literal_lengths[.. 271], Allocator::libc()).unwrap());
+        assert_eq!(distances,
+                   tree_from_lengths(distance_lengths.as_slice(), Allocator::libc()).unwrap());
+    }
+
+    /// Read a dynamic header of one literal and one distance code, with
+    /// four code length code lengths, from the bits written by code_lengths
+    fn read_header<F>(code_lengths: F) -> bool where F: Fn(&mut BitWriter) {
+        let mut writer = BitWriter::new();
+        writer.write_bits(0, 5);
+        writer.write_bits(0, 5);
+        writer.write_bits(0, 4);
+        code_lengths(&mut writer);
+        // zero bits for whatever else is read
+        for _ in 0 .. 16 {
+            writer.write_bits(0, 32);
+        }
+        let packed = writer.take_bytes();
+        let buffer = unsafe { CVec::from_raw_buf(packed.as_ptr(), packed.len()) }.unwrap();
+        let mut reader = GzBitReader::new(buffer.iter()).unwrap();
+        read_dynamic_header(&mut reader, Allocator::libc()).is_some()
+    }
+
+    #[test]
+    fn test_oversubscribed_header() {
+        // four 1 bit code length codes
+        assert!(!read_header(|writer| {
+            for _ in 0 .. 4 {
+                writer.write_bits(1, 3);
+            }
+        }));
+    }
+
+    #[test]
+    fn test_repeat_first() {
+        // codes 16 and 0 get 1 bit each, and 16 comes first, with nothing
+        // before it to repeat
+        for &(first, ok) in [(1, false), (0, true)].iter() {
+            assert_eq!(read_header(|writer| {
+                for &length in [1, 0, 0, 1].iter() {
+                    writer.write_bits(length, 3);
+                }
+                writer.write_bits(first, 1);
+                writer.write_bits(0, 2);
+            }), ok);
+        }
     }
-    Some(())
 }
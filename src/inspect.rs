@@ -0,0 +1,444 @@
+#[doc="
+
+    Module: inspect
+
+    This module looks inside the DEFLATE streams of a gzip file,
+    for when a file fails to decompress or compresses badly.
+    inspect() finds and checks the members with Members, as list
+    and verify do, and runs inflate's own block loop over each of
+    them again with a sink that only counts the output and takes
+    note of where each block starts and ends. Each block is
+    reported with BFINAL, BTYPE, the bits it spans in the file and
+    the bytes of output it makes, and a dynamic block also with
+    HLIT, HDIST, HCLEN and its code lengths. Inspection stops at
+    the first damage verify() would report. An Inspection can be
+    written out as text for people or as JSON for other tools.
+    inspect_members() walks with any sink that records blocks as
+    BlockSink does, so the tokens module can build on it.
+
+"]
+use cvec::slice_buf;
+use error::{GzError, ErrorKind};
+use gz_reader::GzBitReader;
+use inflate::inflate_blocks;
+use members::Members;
+use sink::{Sink, WINDOW_SIZE};
+use std::mem;
+use std::usize;
+
+/// The code lengths given in the header of a dynamic block
+#[derive(Clone, Show)]
+pub struct DynamicInfo {
+    pub hlit: u32,
+    pub hdist: u32,
+    pub hclen: u32,
+    /// The lengths of the code length code, in symbol order
+    pub code_length_lengths: Vec<u32>,
+    pub literal_lengths: Vec<u32>,
+    pub distance_lengths: Vec<u32>
+}
+
+/// One DEFLATE block. Bit positions are counted from the start of the
+/// file, and output positions from the start of the member's output.
+#[derive(Clone, Show)]
+pub struct BlockInfo {
+    /// BFINAL, set on the last block of a member
+    pub last: bool,
+    /// BTYPE: 0 for stored, 1 for fixed codes, 2 for dynamic codes
+    pub block_type: u32,
+    /// Where the block starts, at its BFINAL bit
+    pub start_bit: u64,
+    /// Where its codes or stored bytes start, after its header
+    pub data_start_bit: u64,
+    /// Where the block ends, or None if it could not be decoded
+    pub end_bit: Option<u64>,
+    pub output_start: u64,
+    /// Where the block's output ends, or where it got to if it failed
+    pub output_end: u64,
+    pub dynamic: Option<DynamicInfo>
+}
+
+impl BlockInfo {
+    /// The length of the block header, including BFINAL and BTYPE
+    pub fn header_bits(&self) -> u64 {
+        self.data_start_bit - self.start_bit
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self.block_type {
+            0 => "stored",
+            1 => "fixed",
+            2 => "dynamic",
+            _ => "reserved"
+        }
+    }
+}
+
+/// The blocks of one gzip member
+#[derive(Clone, Show)]
+pub struct MemberBlocks {
+    /// Where the member starts in the file
+    pub offset: u64,
+    pub header_len: u64,
+    pub blocks: Vec<BlockInfo>,
+    /// Where the member's footer is, if its data could be decoded
+    pub footer_offset: Option<u64>
+}
+
+/// What inspect() found in a gzip file
+#[derive(Clone, Show)]
+pub struct Inspection {
+    pub members: Vec<MemberBlocks>,
+    /// The number of bytes after the last member that are not another
+    /// member
+    pub trailing_len: u64,
+    /// Why inspection stopped early, if it did
+    pub error: Option<GzError>
+}
+
+/// A sink that records the blocks of each member, as BlockSink does, for
+/// inspect_members to walk with
+pub trait BlockRecorder: Sink {
+    /// Hand over the blocks of the member that has just been walked, and
+    /// get ready for the next one
+    fn take_blocks(&mut self) -> Vec<BlockInfo>;
+}
+
+/// Walk the blocks of every member of a gzip file
+pub fn inspect(data: &[u8]) -> Inspection {
+    inspect_members(data, &mut BlockSink::new())
+}
+
+/// Walk the blocks of every member of a gzip file with sink
+pub fn inspect_members<S: BlockRecorder>(data: &[u8], sink: &mut S) -> Inspection {
+    let mut inspection = Inspection { members: Vec::new(), trailing_len: 0, error: None };
+    let buffer = match slice_buf(data) {
+        Some(buffer) => buffer,
+        None => {
+            inspection.error = Some(GzError::new(ErrorKind::TooShort, 0));
+            return inspection;
+        }
+    };
+    let mut members = Members::new(data);
+    for member in members.by_ref() {
+        let member = member.ok().expect("a slice can always be read");
+        let header_len = match member.header {
+            Some(ref header) => header.header_len,
+            None => {
+                inspection.error = member.error;
+                break;
+            }
+        };
+        let offset = member.offset as usize;
+        // Members has checked the member, so this only walks it again for
+        // its blocks
+        let end = match GzBitReader::at(&buffer, (offset + header_len) * 8) {
+            Some(mut stream) => inflate_blocks(&mut stream, sink, usize::MAX)
+                .map(|_| (stream.bit_position() + 7) / 8),
+            None => None
+        };
+        let footer_offset = match (end, member.footer) {
+            (Some(end), Some(..)) => Some(end as u64),
+            _ => None
+        };
+        inspection.members.push(MemberBlocks {
+            offset: member.offset,
+            header_len: header_len as u64,
+            blocks: sink.take_blocks(),
+            footer_offset: footer_offset
+        });
+        inspection.error = member.error;
+    }
+    inspection.trailing_len = members.trailing_len();
+    inspection
+}
+
+impl Inspection {
+    /// A description for people to read, a line for each member and block
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for (i, member) in self.members.iter().enumerate() {
+            text.push_str(format!("member {} at byte {}: header {} bytes", i + 1, member.offset,
+                                  member.header_len).as_slice());
+            match member.footer_offset {
+                Some(footer) => text.push_str(format!(", footer at byte {}\n", footer).as_slice()),
+                None => text.push_str("\n")
+            }
+            for (j, block) in member.blocks.iter().enumerate() {
+                let end = match block.end_bit {
+                    Some(end) => end.to_string(),
+                    None => "?".to_string()
+                };
+                let last = if block.last { " (last)" } else { "" };
+                text.push_str(format!("  block {}{}: {}, bits {}-{} (header {} bits), output {}-{}\n",
+                                      j + 1, last, block.type_name(), block.start_bit, end,
+                                      block.header_bits(), block.output_start,
+                                      block.output_end).as_slice());
+                if let Some(ref dynamic) = block.dynamic {
+                    text.push_str(format!("    HLIT {}, HDIST {}, HCLEN {}\n", dynamic.hlit,
+                                          dynamic.hdist, dynamic.hclen).as_slice());
+                    let lengths = [("code length", &dynamic.code_length_lengths),
+                                   ("literal/length", &dynamic.literal_lengths),
+                                   ("distance", &dynamic.distance_lengths)];
+                    for &(code, lengths) in lengths.iter() {
+                        text.push_str(format!("    {} code lengths: {}\n", code,
+                                              join(lengths.as_slice(), " ")).as_slice());
+                    }
+                }
+            }
+        }
+        if self.trailing_len > 0 {
+            text.push_str(format!("{} bytes of trailing data\n", self.trailing_len).as_slice());
+        }
+        if let Some(ref error) = self.error {
+            text.push_str(format!("error: {}\n", error).as_slice());
+        }
+        text
+    }
+
+    /// The same as a JSON object, with members, trailing_len and error
+    /// keys. error is null, or has a message and the byte position.
+    pub fn to_json(&self) -> String {
+        let members: Vec<String> = self.members.iter().map(|member| {
+            let blocks: Vec<String> = member.blocks.iter().map(block_json).collect();
+            format!("{{\"offset\":{},\"header_len\":{},\"footer_offset\":{},\"blocks\":[{}]}}",
+                    member.offset, member.header_len, json_option(member.footer_offset),
+                    blocks.connect(","))
+        }).collect();
+        let error = match self.error {
+            Some(ref error) => format!("{{\"message\":\"{}\",\"position\":{}}}",
+                                       error.kind.description(), error.position),
+            None => "null".to_string()
+        };
+        format!("{{\"members\":[{}],\"trailing_len\":{},\"error\":{}}}", members.connect(","),
+                self.trailing_len, error)
+    }
+}
+
+fn block_json(block: &BlockInfo) -> String {
+    let mut json = format!("{{\"last\":{},\"block_type\":{},\"type\":\"{}\",\"start_bit\":{},\
+                            \"data_start_bit\":{},\"end_bit\":{},\"header_bits\":{},\
+                            \"output_start\":{},\"output_end\":{}",
+                           block.last, block.block_type, block.type_name(), block.start_bit,
+                           block.data_start_bit, json_option(block.end_bit), block.header_bits(),
+                           block.output_start, block.output_end);
+    if let Some(ref dynamic) = block.dynamic {
+        json.push_str(format!(",\"hlit\":{},\"hdist\":{},\"hclen\":{},\"code_length_lengths\":[{}],\
+                               \"literal_lengths\":[{}],\"distance_lengths\":[{}]",
+                              dynamic.hlit, dynamic.hdist, dynamic.hclen,
+                              join(dynamic.code_length_lengths.as_slice(), ","),
+                              join(dynamic.literal_lengths.as_slice(), ","),
+                              join(dynamic.distance_lengths.as_slice(), ",")).as_slice());
+    }
+    json.push_str("}");
+    json
+}
+
+fn json_option(value: Option<u64>) -> String {
+    match value {
+        Some(value) => value.to_string(),
+        None => "null".to_string()
+    }
+}
+
+fn join(values: &[u32], separator: &str) -> String {
+    let strings: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+    strings.connect(separator)
+}
+
+/// Counts the output instead of keeping it, and records the blocks
+pub struct BlockSink {
+    blocks: Vec<BlockInfo>,
+    out_len: u64,
+    // the code lengths of the dynamic block about to start
+    dynamic: Option<DynamicInfo>
+}
+
+impl BlockSink {
+    pub fn new() -> BlockSink {
+        BlockSink { blocks: Vec::new(), out_len: 0, dynamic: None }
+    }
+
+    /// The blocks of the current member so far
+    pub fn blocks(&self) -> &[BlockInfo] {
+        self.blocks.as_slice()
+    }
+
+    /// The length of the current member's output so far
+    pub fn out_len(&self) -> u64 {
+        self.out_len
+    }
+}
+
+impl Sink for BlockSink {
+    fn push(&mut self, _value: u8) -> Option<()> {
+        self.out_len += 1;
+        Some(())
+    }
+
+    fn copy_back_pointer(&mut self, distance: usize, length: usize) -> Option<()> {
+        if distance >= WINDOW_SIZE || distance as u64 >= self.out_len {
+            return None;
+        }
+        self.out_len += length as u64;
+        Some(())
+    }
+
+    fn failure(&self) -> Option<ErrorKind> {
+        None
+    }
+
+    fn dynamic_lengths(&mut self, hclen: u32, code_length_lengths: &[u32],
+                       literal_lengths: &[u32], distance_lengths: &[u32]) {
+        self.dynamic = Some(DynamicInfo {
+            hlit: (literal_lengths.len() - 257) as u32,
+            hdist: (distance_lengths.len() - 1) as u32,
+            hclen: hclen,
+            code_length_lengths: code_length_lengths.to_vec(),
+            literal_lengths: literal_lengths.to_vec(),
+            distance_lengths: distance_lengths.to_vec()
+        });
+    }
+
+    fn block_start(&mut self, start: usize, last: bool, block_type: u32, data_start: usize) {
+        self.blocks.push(BlockInfo {
+            last: last,
+            block_type: block_type,
+            start_bit: start as u64,
+            data_start_bit: data_start as u64,
+            end_bit: None,
+            output_start: self.out_len,
+            output_end: self.out_len,
+            dynamic: self.dynamic.take()
+        });
+    }
+
+    fn block_end(&mut self, end: usize) {
+        if let Some(block) = self.blocks.last_mut() {
+            block.end_bit = Some(end as u64);
+            block.output_end = self.out_len;
+        }
+    }
+}
+
+impl BlockRecorder for BlockSink {
+    fn take_blocks(&mut self) -> Vec<BlockInfo> {
+        // a block that failed gets as far as its output did
+        if let Some(block) = self.blocks.last_mut() {
+            block.output_end = self.out_len;
+        }
+        self.out_len = 0;
+        mem::replace(&mut self.blocks, Vec::new())
+    }
+}
+
+#[cfg(test)]
+mod inspect_tests {
+    use super::inspect;
+    use encoder::compress;
+    use error::{GzError, ErrorKind};
+    use testing::{COMPRESSED, text};
+
+    #[test]
+    fn test_fixed() {
+        let inspection = inspect(COMPRESSED);
+        assert!(inspection.error.is_none());
+        assert_eq!(inspection.members.len(), 1);
+        let member = &inspection.members[0];
+        assert_eq!((member.offset, member.header_len), (0, 10));
+        assert_eq!(member.footer_offset, Some(COMPRESSED.len() as u64 - 8));
+        assert_eq!(member.blocks.len(), 1);
+        let block = &member.blocks[0];
+        assert!(block.last && block.dynamic.is_none());
+        assert_eq!((block.block_type, block.type_name()), (1, "fixed"));
+        assert_eq!((block.start_bit, block.header_bits()), (80, 3));
+        assert_eq!((block.output_start, block.output_end), (0, 96));
+        let end = block.end_bit.unwrap();
+        assert_eq!((end + 7) / 8, COMPRESSED.len() as u64 - 8);
+        assert_eq!(inspection.to_text(), format!(
+            "member 1 at byte 0: header 10 bytes, footer at byte 67\n  \
+             block 1 (last): fixed, bits 80-{} (header 3 bits), output 0-96\n", end));
+    }
+
+    #[test]
+    fn test_dynamic_blocks() {
+        let contents = text(20000);
+        let data = compress(contents.as_slice(), 6);
+        let inspection = inspect(data.as_slice());
+        assert!(inspection.error.is_none());
+        let blocks = &inspection.members[0].blocks;
+        assert!(blocks.len() > 1);
+        assert_eq!(blocks[0].start_bit, 80);
+        for pair in blocks.windows(2) {
+            assert_eq!(pair[0].end_bit, Some(pair[1].start_bit));
+            assert_eq!(pair[0].output_end, pair[1].output_start);
+            assert!(!pair[0].last);
+        }
+        let last = blocks.last().unwrap();
+        assert!(last.last);
+        assert_eq!(last.output_end, contents.len() as u64);
+        for block in blocks.iter().filter(|block| block.block_type == 2) {
+            let dynamic = block.dynamic.as_ref().unwrap();
+            assert_eq!(dynamic.literal_lengths.len(), dynamic.hlit as usize + 257);
+            assert_eq!(dynamic.distance_lengths.len(), dynamic.hdist as usize + 1);
+            assert_eq!(dynamic.code_length_lengths.len(), 19);
+            assert!(block.header_bits() > 3 + 14 + 3 * (dynamic.hclen as u64 + 4));
+        }
+        assert!(blocks.iter().any(|block| block.block_type == 2));
+    }
+
+    #[test]
+    fn test_stored_and_members() {
+        let stored = compress(b"stored as it is", 0);
+        let mut data = stored.clone();
+        data.push_all(COMPRESSED);
+        data.push_all(&[0u8; 3]);
+        let inspection = inspect(data.as_slice());
+        assert!(inspection.error.is_none());
+        assert_eq!(inspection.members.len(), 2);
+        assert_eq!(inspection.members[1].offset, stored.len() as u64);
+        assert_eq!(inspection.trailing_len, 3);
+        let block = &inspection.members[0].blocks[0];
+        assert_eq!(block.type_name(), "stored");
+        // BFINAL and BTYPE, padding to the byte, then LEN and NLEN
+        assert_eq!(block.header_bits(), 8 + 32);
+        assert_eq!(block.output_end, 15);
+        assert!(inspection.to_text().ends_with("3 bytes of trailing data\n"));
+    }
+
+    #[test]
+    fn test_errors() {
+        // BTYPE 3 is reserved
+        let mut reserved = COMPRESSED.to_vec();
+        reserved[10] |= 0x06;
+        let inspection = inspect(reserved.as_slice());
+        let block = &inspection.members[0].blocks[0];
+        assert_eq!((block.type_name(), block.end_bit), ("reserved", None));
+        assert_eq!(inspection.members[0].footer_offset, None);
+        assert_eq!(inspection.error.unwrap().kind, ErrorKind::CorruptData);
+        assert!(inspection.to_text().contains("bits 80-? (header 3 bits)"));
+        let inspection = inspect(&COMPRESSED[.. 40]);
+        assert_eq!(inspection.error.unwrap().kind, ErrorKind::UnexpectedEof);
+        assert!(inspection.members[0].blocks[0].output_end > 0);
+        assert_eq!(inspect(b"not gzip").error, Some(GzError::new(ErrorKind::BadHeader, 0)));
+        assert_eq!(inspect(&[]).error, Some(GzError::new(ErrorKind::TooShort, 0)));
+    }
+
+    #[test]
+    fn test_json() {
+        let mut reserved = COMPRESSED.to_vec();
+        reserved[10] |= 0x06;
+        let json = inspect(reserved.as_slice()).to_json();
+        assert_eq!(json, format!(
+            "{{\"members\":[{{\"offset\":0,\"header_len\":10,\"footer_offset\":null,\"blocks\":[\
+             {{\"last\":true,\"block_type\":3,\"type\":\"reserved\",\"start_bit\":80,\
+             \"data_start_bit\":83,\"end_bit\":null,\"header_bits\":3,\"output_start\":0,\
+             \"output_end\":0}}]}}],\"trailing_len\":0,\
+             \"error\":{{\"message\":\"{}\",\"position\":10}}}}",
+            ErrorKind::CorruptData.description()));
+        let json = inspect(compress(text(100).as_slice(), 6).as_slice()).to_json();
+        assert!(json.contains("\"type\":\"dynamic\""));
+        assert!(json.contains("\"hlit\":"));
+        assert!(json.ends_with("\"trailing_len\":0,\"error\":null}"));
+    }
+}
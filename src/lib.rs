@@ -12,15 +12,85 @@
     a gzip-compressed buffer, as well as its length.
     This library will return a pointer to a malloc'd
     buffer representing the decompressed contents of the
-    original buffer.
+    original buffer. Callers that manage their own memory
+    may pass an allocator to be used instead of malloc, both
+    for the returned buffer and for the Huffman tables built
+    while decompressing. rgzip_compress() writes its output
+    into memory from the allocator as it is produced.
+
+    When a call fails, the reason is recorded for the
+    calling thread and can be retrieved as a message with
+    rgzip_last_error().
+
+    Rust crates can use the library directly instead:
+    decompress() takes a slice holding a gzip file and
+    returns its contents in a Vec, or a GzError saying what
+    went wrong and where. decompress_with_options() gives
+    more control, decompress_to_callback() streams the
+    output in chunks, and read_header() gives the GZHeader.
+    GzDecoder wraps any std::io::Read holding gzip data, and
+    reads out the decompressed bytes a piece at a time, while
+    GzWriteDecoder accepts gzip data through std::io::Write and
+    writes the decompressed bytes on to another Write.
+    GzBufDecoder reads a single member from a BufRead, leaving
+    whatever follows it unread. deflate() and Deflater go the
+    other way, compressing data into a raw DEFLATE stream, and
+    compress() and GzEncoder wrap that stream in a gzip header
+    and footer. HeaderOptions sets the fields of the header;
+    rgzip_compress() gives C programs the same.
+    compress_parallel() and ParallelGzEncoder spread the
+    compression over several threads, still producing a
+    single gzip member. GzIndex records checkpoints in a gzip
+    file, so that reading can start partway through it, and
+    IndexedGzReader uses one to Read and Seek the decompressed
+    data as if it were an uncompressed file. BgzfReader reads
+    BGZF files, and seeks within them by virtual offset.
+    decompress_parallel() decompresses the members of BGZF and
+    other multi-member files on several threads, and
+    decompress_speculative() splits even a single member between
+    threads by guessing where its blocks start.
+    list_members() walks every member of a file for a listing
+    like gzip -l, without its wrapped sizes. verify() checks a
+    file as gzip -t does, in constant memory, reporting where the
+    first failure was found; rgzip_verify() does the same for C.
+    inspect() reports the DEFLATE blocks inside each member, as
+    text or JSON, for diagnosing files that fail or compress badly.
+    visit_tokens() hands the LZ77 literals and matches of every
+    block to a TokenVisitor, with the bits each one cost.
 
 "]
 
 extern crate libc;
 
-use libc::{c_int, c_uchar, c_void};
+use libc::{c_int, c_uint, c_uchar, c_void, c_char, size_t, ssize_t};
+use std::io;
+use std::io::{Read, Write};
 use std::ptr::null;
-use cvec::CVec;
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use cvec::{CVec, Buf};
+use sink::CallbackSink;
+
+pub use error::{GzError, ErrorKind};
+pub use header::{GZHeader, Flags, ExtraField, HeaderOptions};
+pub use gz::{decompress, decompress_with_options, decompress_to_callback, read_header};
+pub use gz::DecompressOptions;
+pub use cvec::Allocator;
+pub use read::GzDecoder;
+pub use write::GzWriteDecoder;
+pub use bufread::GzBufDecoder;
+pub use deflate::{deflate, Deflater};
+pub use encoder::{compress, compress_with_header, GzEncoder};
+pub use parallel::{compress_parallel, ParallelGzEncoder, ParallelOptions};
+pub use index::{GzIndex, Checkpoint, CheckpointReader};
+pub use seek::IndexedGzReader;
+pub use bgzf::{BgzfReader, virtual_offset, split_virtual_offset};
+pub use parallel_decode::{decompress_parallel, decompress_parallel_to_callback};
+pub use speculative::{decompress_speculative, decompress_speculative_to_callback};
+pub use list::{list_members, os_name, GzListing, MemberInfo};
+pub use verify::{verify, VerifyReport, VerifyFailure};
+pub use inspect::{inspect, Inspection, MemberBlocks, BlockInfo, DynamicInfo};
+pub use tokens::{visit_tokens, Token, TokenVisitor};
 
 #[macro_use]
 mod macros;
@@ -28,14 +98,59 @@ mod cvec;
 mod gz;
 mod header;
 mod crc32;
+mod error;
 mod inflate;
 mod huffman;
 mod gz_reader;
+mod bit_writer;
+mod sink;
+mod deflate;
+mod stream;
+mod read;
+mod write;
+mod bufread;
+mod encoder;
+mod pool;
+mod parallel;
+mod index;
+mod seek;
+mod bgzf;
+mod parallel_decode;
+mod speculative;
+mod members;
+mod list;
+mod verify;
+mod inspect;
+mod tokens;
+#[cfg(test)]
+mod testing;
 
 /////////////////////////////////////////////////////////////////////
 //                   Decompression interface                       //
 /////////////////////////////////////////////////////////////////////
 
+/// Status codes returned by the C interface
+/// Failures are negative, with one code for each ErrorKind
+pub const RGZIP_OK: c_int = 0;
+pub const RGZIP_BUF_TOO_SMALL: c_int = 1;
+pub const RGZIP_ERROR: c_int = -1;
+pub const RGZIP_ERR_TOO_SHORT: c_int = -2;
+pub const RGZIP_ERR_HEADER: c_int = -3;
+pub const RGZIP_ERR_DATA: c_int = -4;
+pub const RGZIP_ERR_EOF: c_int = -5;
+pub const RGZIP_ERR_CRC: c_int = -6;
+pub const RGZIP_ERR_SIZE: c_int = -7;
+pub const RGZIP_ERR_MEMORY: c_int = -8;
+pub const RGZIP_ERR_OVERFLOW: c_int = -9;
+pub const RGZIP_ERR_ABORTED: c_int = -10;
+pub const RGZIP_ERR_LIMIT: c_int = -11;
+pub const RGZIP_ERR_INTERNAL: c_int = -12;
+
+/// Receives a chunk of decompressed data
+/// Returning anything other than 0 stops decompression
+pub type WriteCallback = extern "C" fn(opaque: *mut c_void, buf: *const c_void, len: size_t)
+    -> c_int;
+
 /// The main decompression function
 /// return a null pointer on failure, let the caller clean up
 
@@ -44,12 +159,1344 @@ pub extern "C" fn decompress_gzip_to_heap(buf: *const c_void,
                                           buf_len: c_int,
                                           decompressed_len: *mut c_int)
         -> *mut c_void {
-    let in_vec = try_bail!(unsafe { CVec::from_raw_buf(buf as *const c_uchar, buf_len as usize)});
-    let out_vec = try_bail!(gz::decompress_gz(in_vec));
+    decompress_gzip_to_heap_with_allocator(buf, buf_len, decompressed_len, null::<Allocator>())
+}
+
+/// As decompress_gzip_to_heap, but with size_t lengths, for buffers
+/// over 2GB
+
+#[no_mangle]
+pub extern "C" fn decompress_gzip_to_heap_sz(buf: *const c_void,
+                                             buf_len: size_t,
+                                             decompressed_len: *mut size_t)
+        -> *mut c_void {
+    decompress_gzip_to_heap_with_allocator_sz(buf, buf_len, decompressed_len,
+                                              null::<Allocator>())
+}
+
+/// Decompress using the given allocator for the output buffer.
+/// If allocator is null, malloc is used, exactly as in decompress_gzip_to_heap.
+/// The result must be released with rgzip_free and the same allocator.
+/// return a null pointer on failure, let the caller clean up
+
+#[no_mangle]
+pub extern "C" fn decompress_gzip_to_heap_with_allocator(buf: *const c_void,
+                                                         buf_len: c_int,
+                                                         decompressed_len: *mut c_int,
+                                                         allocator: *const Allocator)
+        -> *mut c_void {
+    let allocator = get_allocator(allocator);
+    let buf_len = try_bail!(int_to_size(buf_len));
+    let result = try_bail!(decompress_to_heap(buf, buf_len, allocator));
+    let (out_ptr, out_size) = try_bail!(narrow_heap_result(result, allocator));
+    unsafe { *decompressed_len = out_size; }
+    out_ptr
+}
+
+/// As decompress_gzip_to_heap_with_allocator, but with size_t lengths
+
+#[no_mangle]
+pub extern "C" fn decompress_gzip_to_heap_with_allocator_sz(buf: *const c_void,
+                                                            buf_len: size_t,
+                                                            decompressed_len: *mut size_t,
+                                                            allocator: *const Allocator)
+        -> *mut c_void {
+    let allocator = get_allocator(allocator);
+    let (out_ptr, out_size) = try_bail!(decompress_to_heap(buf, buf_len as usize, allocator));
+    unsafe { *decompressed_len = out_size as size_t; }
+    out_ptr
+}
+
+/// Decompress to a newly allocated buffer, reporting failure through the
+/// return value: RGZIP_OK on success, or one of the negative error codes.
+/// On success out_buf points to the result, which must be released with
+/// rgzip_free and the same allocator, and decompressed_len holds its size.
+/// On failure out_buf is set to null and decompressed_len to 0.
+/// If allocator is null, malloc is used.
+
+#[no_mangle]
+pub extern "C" fn rgzip_decompress(buf: *const c_void,
+                                   buf_len: c_int,
+                                   out_buf: *mut *mut c_void,
+                                   decompressed_len: *mut c_int,
+                                   allocator: *const Allocator)
+        -> c_int {
+    if out_buf.is_null() || decompressed_len.is_null() {
+        return set_last_error(invalid_argument());
+    }
+    unsafe {
+        *out_buf = null::<c_void>() as *mut c_void;
+        *decompressed_len = 0;
+    }
+    let buf_len = try_code!(int_to_size(buf_len));
+    let mut out_size: size_t = 0;
+    let code = rgzip_decompress_sz(buf, buf_len as size_t, out_buf, &mut out_size, allocator);
+    if code != RGZIP_OK {
+        return code;
+    }
+    match size_to_int(out_size as usize) {
+        Ok(size) => {
+            unsafe { *decompressed_len = size; }
+            RGZIP_OK
+        }
+        Err(e) => {
+            unsafe {
+                rgzip_free(*out_buf, allocator);
+                *out_buf = null::<c_void>() as *mut c_void;
+            }
+            set_last_error(e)
+        }
+    }
+}
+
+/// As rgzip_decompress, but with size_t lengths
+
+#[no_mangle]
+pub extern "C" fn rgzip_decompress_sz(buf: *const c_void,
+                                      buf_len: size_t,
+                                      out_buf: *mut *mut c_void,
+                                      decompressed_len: *mut size_t,
+                                      allocator: *const Allocator)
+        -> c_int {
+    if out_buf.is_null() || decompressed_len.is_null() {
+        return set_last_error(invalid_argument());
+    }
+    unsafe {
+        *out_buf = null::<c_void>() as *mut c_void;
+        *decompressed_len = 0;
+    }
+    let allocator = get_allocator(allocator);
+    let (out_ptr, out_size) = try_code!(decompress_to_heap(buf, buf_len as usize, allocator));
     unsafe {
-        let (out_ptr, out_size) = out_vec.into_raw_buf();
-        *decompressed_len = out_size as c_int;
-        out_ptr as *mut c_void
+        *out_buf = out_ptr;
+        *decompressed_len = out_size as size_t;
+    }
+    RGZIP_OK
+}
+
+/// Free a buffer returned by this library, using the allocator it was
+/// created with. If allocator is null, free is used.
+
+#[no_mangle]
+pub extern "C" fn rgzip_free(ptr: *mut c_void, allocator: *const Allocator) {
+    if ptr.is_null() {
+        return;
+    }
+    let allocator = get_allocator(allocator);
+    (allocator.free)(allocator.opaque, ptr);
+}
+
+/// Decompress into a buffer owned by the caller, without allocating
+/// any memory for the output.
+/// On success, returns RGZIP_OK and sets decompressed_len to the number of
+/// bytes written to out_buf. If out_buf is too small, nothing is decoded,
+/// RGZIP_BUF_TOO_SMALL is returned and decompressed_len is set to the size
+/// required. Any other failure returns one of the negative error codes.
+
+#[no_mangle]
+pub extern "C" fn decompress_gzip_to_buffer(buf: *const c_void,
+                                            buf_len: c_int,
+                                            out_buf: *mut c_void,
+                                            out_buf_len: c_int,
+                                            decompressed_len: *mut c_int)
+        -> c_int {
+    if decompressed_len.is_null() {
+        return set_last_error(invalid_argument());
+    }
+    let buf_len = try_code!(int_to_size(buf_len));
+    let out_buf_len = try_code!(int_to_size(out_buf_len));
+    let mut len: size_t = 0;
+    let code = decompress_gzip_to_buffer_sz(buf, buf_len as size_t, out_buf,
+                                            out_buf_len as size_t, &mut len);
+    if code == RGZIP_OK || code == RGZIP_BUF_TOO_SMALL {
+        unsafe { *decompressed_len = try_code!(size_to_int(len as usize)); }
+    }
+    code
+}
+
+/// As decompress_gzip_to_buffer, but with size_t lengths
+
+#[no_mangle]
+pub extern "C" fn decompress_gzip_to_buffer_sz(buf: *const c_void,
+                                               buf_len: size_t,
+                                               out_buf: *mut c_void,
+                                               out_buf_len: size_t,
+                                               decompressed_len: *mut size_t)
+        -> c_int {
+    if decompressed_len.is_null() {
+        return set_last_error(invalid_argument());
+    }
+    let in_vec = try_code!(input_buf(buf, buf_len as usize));
+    let needed = try_code!(gz::uncompressed_len(&in_vec));
+    if needed > out_buf_len as usize {
+        unsafe { *decompressed_len = needed as size_t; }
+        return RGZIP_BUF_TOO_SMALL;
     }
+    let mut out_vec = match unsafe {
+        CVec::from_raw_buf_mut(out_buf as *mut c_uchar, out_buf_len as usize)
+    } {
+        Some(v) => v,
+        None => return set_last_error(invalid_argument())
+    };
+    try_code!(gz::decompress_gz_into(&in_vec, &mut out_vec));
+    unsafe { *decompressed_len = out_vec.len() as size_t; }
+    RGZIP_OK
 }
 
+/// Decompress without collecting the output in one buffer, instead passing
+/// it to callback in chunks, in order, as it is produced. opaque is passed
+/// back to every call. Returns RGZIP_OK on success, RGZIP_ERR_ABORTED if
+/// the callback stopped decompression, or another negative error code.
+/// The CRC can only be checked after all the output has been passed on, so
+/// on failure the caller should discard whatever it was given.
+
+#[no_mangle]
+pub extern "C" fn decompress_gzip_to_callback(buf: *const c_void,
+                                              buf_len: size_t,
+                                              callback: Option<WriteCallback>,
+                                              opaque: *mut c_void)
+        -> c_int {
+    let callback = match callback {
+        Some(c) => c,
+        None => return set_last_error(invalid_argument())
+    };
+    let in_vec = try_code!(input_buf(buf, buf_len as usize));
+    let mut sink = CallbackSink::new(|chunk: &[u8]| {
+        callback(opaque, chunk.as_ptr() as *const c_void, chunk.len() as size_t) == 0
+    });
+    try_code!(gz::decompress_gz_to_callback(&in_vec, &mut sink));
+    RGZIP_OK
+}
+
+/////////////////////////////////////////////////////////////////////
+//                     Compression interface                       //
+/////////////////////////////////////////////////////////////////////
+
+/// The header fields for rgzip_compress, matching rgzip_header in rgzip.h.
+/// name and comment are nul terminated, and left out when null. extra
+/// holds extra_len bytes of FEXTRA subfields, each two id bytes, a two
+/// byte little endian length and the data, and is left out when null.
+/// text and header_crc are flags, set when non-zero.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct HeaderFields {
+    pub name: *const c_char,
+    pub comment: *const c_char,
+    pub extra: *const c_uchar,
+    pub extra_len: size_t,
+    pub mtime: c_uint,
+    pub os: c_int,
+    pub text: c_int,
+    pub header_crc: c_int,
+}
+
+/// Compress buf into a gzip member in a newly allocated buffer, at a
+/// level from 0 (stored) to 9 (smallest). If header is null, a minimal
+/// header is written. On success, returns RGZIP_OK, out_buf points to the
+/// result, which must be released with rgzip_free and the same allocator,
+/// and compressed_len holds its size. On failure out_buf is set to null,
+/// compressed_len to 0, and a negative error code is returned.
+/// If allocator is null, malloc is used.
+
+#[no_mangle]
+pub extern "C" fn rgzip_compress(buf: *const c_void,
+                                 buf_len: size_t,
+                                 level: c_int,
+                                 header: *const HeaderFields,
+                                 out_buf: *mut *mut c_void,
+                                 compressed_len: *mut size_t,
+                                 allocator: *const Allocator)
+        -> c_int {
+    if out_buf.is_null() || compressed_len.is_null() {
+        return set_last_error(invalid_argument());
+    }
+    unsafe {
+        *out_buf = null::<c_void>() as *mut c_void;
+        *compressed_len = 0;
+    }
+    if level < 0 || level > 9 {
+        return set_last_error(invalid_argument());
+    }
+    let allocator = get_allocator(allocator);
+    let options = try_code!(header_options(header));
+    // empty input is fine to compress, but can't be wrapped in a CVec
+    let in_vec = if buf_len == 0 { None } else { Some(try_code!(input_buf(buf, buf_len as usize))) };
+    let data: &[u8] = match in_vec {
+        Some(ref v) => v.as_slice(),
+        None => &[]
+    };
+    let (out_ptr, out_size) = try_code!(compress_to_heap(data, level as u32, &options,
+                                                         allocator));
+    unsafe {
+        *out_buf = out_ptr;
+        *compressed_len = out_size as size_t;
+    }
+    RGZIP_OK
+}
+
+/////////////////////////////////////////////////////////////////////
+//                     Verification interface                      //
+/////////////////////////////////////////////////////////////////////
+
+/// Reads up to len bytes of compressed input into buf, returning how
+/// many were read, 0 at the end of the input, or a negative number if
+/// reading failed
+pub type ReadCallback = extern "C" fn(opaque: *mut c_void, buf: *mut c_void, len: size_t)
+    -> ssize_t;
+
+/// What rgzip_verify found, matching rgzip_verify_report in rgzip.h.
+/// The fields from failed_member on describe the first failure, and are
+/// only set when status is not RGZIP_OK. has_footer is non-zero if the
+/// failing member's footer was reached, and footer_crc and footer_size
+/// then hold what it says, to set against crc and member_output.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct VerifyResult {
+    pub status: c_int,
+    pub members: size_t,
+    pub compressed_len: u64,
+    pub uncompressed_len: u64,
+    pub trailing_len: u64,
+    pub failed_member: size_t,
+    pub member_offset: u64,
+    pub position: u64,
+    pub member_output: u64,
+    pub crc: c_uint,
+    pub has_footer: c_int,
+    pub footer_crc: c_uint,
+    pub footer_size: c_uint,
+}
+
+/// Check every member of the gzip data in buf, without keeping the
+/// output, and fill in report with what was found. Returns RGZIP_OK if
+/// the data checked out, or the code for the first failure, which is
+/// also left in report->status.
+
+#[no_mangle]
+pub extern "C" fn rgzip_verify(buf: *const c_void,
+                               buf_len: size_t,
+                               report: *mut VerifyResult)
+        -> c_int {
+    if report.is_null() {
+        return set_last_error(invalid_argument());
+    }
+    let in_vec = try_code!(input_buf(buf, buf_len as usize));
+    verify_to_report(in_vec.as_slice(), report)
+}
+
+/// As rgzip_verify, but reading the compressed data through callback,
+/// so that a file of any size can be checked in constant memory. opaque
+/// is passed back to every call. If callback fails, RGZIP_ERR_ABORTED
+/// is returned.
+
+#[no_mangle]
+pub extern "C" fn rgzip_verify_callback(callback: Option<ReadCallback>,
+                                        opaque: *mut c_void,
+                                        report: *mut VerifyResult)
+        -> c_int {
+    let callback = match callback {
+        Some(c) => c,
+        None => return set_last_error(invalid_argument())
+    };
+    if report.is_null() {
+        return set_last_error(invalid_argument());
+    }
+    verify_to_report(CallbackReader { callback: callback, opaque: opaque }, report)
+}
+
+/// Compressed input from a C read callback
+struct CallbackReader {
+    callback: ReadCallback,
+    opaque: *mut c_void
+}
+
+impl Read for CallbackReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = (self.callback)(self.opaque, buf.as_mut_ptr() as *mut c_void, buf.len() as size_t);
+        if n < 0 {
+            Err(io::Error::new(io::ErrorKind::Other, "read callback failed"))
+        } else {
+            Ok(n as usize)
+        }
+    }
+}
+
+/// Verify the data from reader, filling in the caller's report
+fn verify_to_report<R: Read>(reader: R, report: *mut VerifyResult) -> c_int {
+    let found = match verify::verify(reader) {
+        Ok(found) => found,
+        Err(..) => {
+            let status = set_last_error(GzError::new(ErrorKind::Aborted, 0));
+            unsafe { *report = VerifyResult { status: status, ..empty_verify_result() }; }
+            return status;
+        }
+    };
+    let mut result = VerifyResult {
+        members: found.members as size_t,
+        compressed_len: found.compressed_len,
+        uncompressed_len: found.uncompressed_len,
+        trailing_len: found.trailing_len,
+        ..empty_verify_result()
+    };
+    if let Some(failure) = found.failure {
+        result.status = set_last_error(failure.to_gz_error());
+        result.failed_member = failure.member as size_t;
+        result.member_offset = failure.member_offset;
+        result.position = failure.position;
+        result.member_output = failure.member_output;
+        result.crc = failure.crc as c_uint;
+        if let Some((crc, size)) = failure.footer {
+            result.has_footer = 1;
+            result.footer_crc = crc as c_uint;
+            result.footer_size = size as c_uint;
+        }
+    }
+    unsafe { *report = result; }
+    result.status
+}
+
+fn empty_verify_result() -> VerifyResult {
+    VerifyResult {
+        status: RGZIP_OK,
+        members: 0,
+        compressed_len: 0,
+        uncompressed_len: 0,
+        trailing_len: 0,
+        failed_member: 0,
+        member_offset: 0,
+        position: 0,
+        member_output: 0,
+        crc: 0,
+        has_footer: 0,
+        footer_crc: 0,
+        footer_size: 0,
+    }
+}
+
+/////////////////////////////////////////////////////////////////////
+//                       Error reporting                           //
+/////////////////////////////////////////////////////////////////////
+
+thread_local!(static LAST_ERROR: RefCell<Option<GzError>> = RefCell::new(None));
+thread_local!(static LAST_ERROR_MESSAGE: RefCell<CString> = RefCell::new(CString::new("").unwrap()));
+thread_local!(static STRERROR_MESSAGE: RefCell<CString> = RefCell::new(CString::new("").unwrap()));
+
+/// Describe the last failure on the calling thread, including the
+/// position in the compressed input where it was detected.
+/// The returned string is valid until the next call to rgzip_last_error
+/// on the same thread.
+
+#[no_mangle]
+pub extern "C" fn rgzip_last_error() -> *const c_char {
+    let message = LAST_ERROR.with(|e| match *e.borrow() {
+        Some(ref err) => format!("{}", err),
+        None => "no error".to_string()
+    });
+    LAST_ERROR_MESSAGE.with(|m| {
+        *m.borrow_mut() = CString::new(message).unwrap();
+        m.borrow().as_ptr()
+    })
+}
+
+/// Describe a status code returned by this library.
+/// The returned string is valid until the next call to rgzip_strerror
+/// on the same thread.
+
+#[no_mangle]
+pub extern "C" fn rgzip_strerror(code: c_int) -> *const c_char {
+    let message = match code {
+        RGZIP_OK => "success",
+        RGZIP_BUF_TOO_SMALL => "output buffer too small",
+        _ => match error_kind(code) {
+            Some(kind) => kind.description(),
+            None => "unknown error"
+        }
+    };
+    STRERROR_MESSAGE.with(|m| {
+        *m.borrow_mut() = CString::new(message).unwrap();
+        m.borrow().as_ptr()
+    })
+}
+
+/// Record err as the calling thread's last failure, and return its code
+fn set_last_error(err: GzError) -> c_int {
+    LAST_ERROR.with(|e| { *e.borrow_mut() = Some(err); });
+    error_code(err.kind)
+}
+
+fn invalid_argument() -> GzError {
+    GzError::new(ErrorKind::InvalidArgument, 0)
+}
+
+/// The status code for each kind of error
+fn error_code(kind: ErrorKind) -> c_int {
+    match kind {
+        ErrorKind::InvalidArgument => RGZIP_ERROR,
+        ErrorKind::TooShort => RGZIP_ERR_TOO_SHORT,
+        ErrorKind::BadHeader => RGZIP_ERR_HEADER,
+        ErrorKind::CorruptData => RGZIP_ERR_DATA,
+        ErrorKind::UnexpectedEof => RGZIP_ERR_EOF,
+        ErrorKind::CrcMismatch => RGZIP_ERR_CRC,
+        ErrorKind::SizeMismatch => RGZIP_ERR_SIZE,
+        ErrorKind::OutOfMemory => RGZIP_ERR_MEMORY,
+        ErrorKind::Overflow => RGZIP_ERR_OVERFLOW,
+        ErrorKind::Aborted => RGZIP_ERR_ABORTED,
+        ErrorKind::LimitExceeded => RGZIP_ERR_LIMIT,
+        ErrorKind::Internal => RGZIP_ERR_INTERNAL,
+    }
+}
+
+/// The kind of error for each failure status code
+fn error_kind(code: c_int) -> Option<ErrorKind> {
+    match code {
+        RGZIP_ERROR => Some(ErrorKind::InvalidArgument),
+        RGZIP_ERR_TOO_SHORT => Some(ErrorKind::TooShort),
+        RGZIP_ERR_HEADER => Some(ErrorKind::BadHeader),
+        RGZIP_ERR_DATA => Some(ErrorKind::CorruptData),
+        RGZIP_ERR_EOF => Some(ErrorKind::UnexpectedEof),
+        RGZIP_ERR_CRC => Some(ErrorKind::CrcMismatch),
+        RGZIP_ERR_SIZE => Some(ErrorKind::SizeMismatch),
+        RGZIP_ERR_MEMORY => Some(ErrorKind::OutOfMemory),
+        RGZIP_ERR_OVERFLOW => Some(ErrorKind::Overflow),
+        RGZIP_ERR_ABORTED => Some(ErrorKind::Aborted),
+        RGZIP_ERR_LIMIT => Some(ErrorKind::LimitExceeded),
+        RGZIP_ERR_INTERNAL => Some(ErrorKind::Internal),
+        _ => None
+    }
+}
+
+/////////////////////////////////////////////////////////////////////
+//                       Helper functions                          //
+/////////////////////////////////////////////////////////////////////
+
+/// Use the caller's allocator if they gave one, otherwise malloc
+fn get_allocator(allocator: *const Allocator) -> Allocator {
+    if allocator.is_null() {
+        Allocator::libc()
+    } else {
+        unsafe { *allocator }
+    }
+}
+
+/// Convert a length passed to the int interface to a size
+fn int_to_size(len: c_int) -> Result<usize, GzError> {
+    if len < 0 {
+        Err(invalid_argument())
+    } else {
+        Ok(len as usize)
+    }
+}
+
+/// Convert a size to a length that can be returned by the int interface
+fn size_to_int(len: usize) -> Result<c_int, GzError> {
+    if len > std::i32::MAX as usize {
+        Err(GzError::new(ErrorKind::Overflow, 0))
+    } else {
+        Ok(len as c_int)
+    }
+}
+
+/// Narrow the size of a decompressed buffer for the int interface,
+/// freeing the buffer if its size can't be returned
+fn narrow_heap_result(result: (*mut c_void, usize), allocator: Allocator)
+        -> Result<(*mut c_void, c_int), GzError> {
+    let (out_ptr, out_size) = result;
+    match size_to_int(out_size) {
+        Ok(size) => Ok((out_ptr, size)),
+        Err(e) => {
+            (allocator.free)(allocator.opaque, out_ptr);
+            Err(e)
+        }
+    }
+}
+
+/// Wrap the caller's compressed buffer in a CVec, without copying
+fn input_buf(buf: *const c_void, buf_len: usize) -> Result<Buf, GzError> {
+    match unsafe { CVec::from_raw_buf(buf as *const c_uchar, buf_len) } {
+        Some(v) => Ok(v),
+        None => Err(invalid_argument())
+    }
+}
+
+/// Build the HeaderOptions described by the caller's HeaderFields
+fn header_options(header: *const HeaderFields) -> Result<HeaderOptions, GzError> {
+    let mut options = HeaderOptions::new();
+    if header.is_null() {
+        return Ok(options);
+    }
+    let header = unsafe { *header };
+    options.text = header.text != 0;
+    options.header_crc = header.header_crc != 0;
+    options.mtime = header.mtime as u32;
+    if header.os < 0 || header.os > 255 {
+        return Err(invalid_argument());
+    }
+    options.os = header.os as u8;
+    options.fname = try!(c_string(header.name));
+    options.comment = try!(c_string(header.comment));
+    if !header.extra.is_null() {
+        let fields = if header.extra_len == 0 {
+            Some(Vec::new())
+        } else {
+            let extra = try!(input_buf(header.extra as *const c_void, header.extra_len as usize));
+            header::parse_extra_fields(extra.as_slice())
+        };
+        match fields {
+            Some(f) => options.extra = Some(f),
+            None => return Err(invalid_argument())
+        }
+    }
+    Ok(options)
+}
+
+/// Copy a nul terminated string from the caller, which must be UTF-8
+fn c_string(ptr: *const c_char) -> Result<Option<String>, GzError> {
+    if ptr.is_null() {
+        return Ok(None);
+    }
+    let bytes = unsafe { CStr::from_ptr(ptr).to_bytes().to_vec() };
+    match String::from_utf8(bytes) {
+        Ok(s) => Ok(Some(s)),
+        Err(..) => Err(invalid_argument())
+    }
+}
+
+/// Compress to a new buffer from the given allocator, and hand
+/// ownership of it to the caller
+fn compress_to_heap(data: &[u8], level: u32, options: &HeaderOptions, allocator: Allocator)
+        -> Result<(*mut c_void, usize), GzError> {
+    // the buffer grows past this if the data doesn't compress as well
+    let out_vec: Buf = match CVec::with_capacity_in(data.len() / 2 + 64, allocator) {
+        Some(v) => v,
+        None => return Err(GzError::new(ErrorKind::OutOfMemory, 0))
+    };
+    let mut encoder = try!(GzEncoder::with_header(out_vec, level, options));
+    // writing to a Buf only fails when it can't grow
+    if encoder.write_all(data).is_err() {
+        return Err(GzError::new(ErrorKind::OutOfMemory, 0));
+    }
+    let out_vec = match encoder.finish() {
+        Ok(v) => v,
+        Err(..) => return Err(GzError::new(ErrorKind::OutOfMemory, 0))
+    };
+    let (out_ptr, out_size) = out_vec.into_raw_buf();
+    Ok((out_ptr as *mut c_void, out_size))
+}
+
+/// Decompress to a new buffer from the given allocator, and hand
+/// ownership of it to the caller
+fn decompress_to_heap(buf: *const c_void, buf_len: usize, allocator: Allocator)
+        -> Result<(*mut c_void, usize), GzError> {
+    let in_vec = try!(input_buf(buf, buf_len));
+    let out_vec = try!(gz::decompress_gz_with_allocator(in_vec, allocator));
+    let (out_ptr, out_size) = out_vec.into_raw_buf();
+    Ok((out_ptr as *mut c_void, out_size))
+}
+
+#[cfg(test)]
+mod decompress_to_buffer_tests {
+    use super::{decompress_gzip_to_buffer, RGZIP_OK, RGZIP_BUF_TOO_SMALL, RGZIP_ERR_HEADER};
+    use libc::{c_int, c_void};
+    use testing::{COMPRESSED, EXPECTED};
+
+    fn decompress(out: &mut [u8], len: &mut c_int) -> c_int {
+        decompress_gzip_to_buffer(COMPRESSED.as_ptr() as *const c_void,
+                                  COMPRESSED.len() as c_int,
+                                  out.as_mut_ptr() as *mut c_void,
+                                  out.len() as c_int,
+                                  len as *mut c_int)
+    }
+
+    #[test]
+    fn test_exact_buffer() {
+        let mut out = [0u8; 96];
+        let mut len: c_int = 0;
+        assert_eq!(decompress(&mut out, &mut len), RGZIP_OK);
+        assert_eq!(len as usize, EXPECTED.len());
+        assert_eq!(out.as_slice(), EXPECTED);
+    }
+
+    #[test]
+    fn test_large_buffer() {
+        let mut out = [0u8; 256];
+        let mut len: c_int = 0;
+        assert_eq!(decompress(&mut out, &mut len), RGZIP_OK);
+        assert_eq!(len as usize, EXPECTED.len());
+        assert_eq!(&out[..EXPECTED.len()], EXPECTED);
+    }
+
+    #[test]
+    fn test_small_buffer() {
+        let mut out = [0u8; 16];
+        let mut len: c_int = 0;
+        assert_eq!(decompress(&mut out, &mut len), RGZIP_BUF_TOO_SMALL);
+        assert_eq!(len as usize, EXPECTED.len());
+        assert_eq!(out, [0u8; 16]);
+    }
+
+    #[test]
+    fn test_invalid_input() {
+        let mut out = [0u8; 96];
+        let mut len: c_int = 0;
+        let garbage = [0u8; 64];
+        assert_eq!(decompress_gzip_to_buffer(garbage.as_ptr() as *const c_void,
+                                             garbage.len() as c_int,
+                                             out.as_mut_ptr() as *mut c_void,
+                                             out.len() as c_int,
+                                             &mut len as *mut c_int),
+                   RGZIP_ERR_HEADER);
+    }
+}
+
+#[cfg(test)]
+mod allocator_tests {
+    use super::{decompress_gzip_to_heap_with_allocator, rgzip_compress, rgzip_free,
+                HeaderFields, RGZIP_OK};
+    use cvec::Allocator;
+    use gz::decompress;
+    use libc::{c_int, c_void, size_t};
+    use libc::funcs::c95::stdlib::{malloc, realloc, free};
+    use std::ptr::null;
+    use std::slice;
+    use testing::{COMMENTED, EXPECTED};
+
+    /// What a tracking allocator has been asked to do
+    struct Counts {
+        allocs: usize,
+        frees: usize
+    }
+
+    impl Counts {
+        fn new() -> Counts {
+            Counts { allocs: 0, frees: 0 }
+        }
+
+        /// An allocator that counts its calls here
+        fn allocator(&mut self) -> Allocator {
+            Allocator {
+                alloc: tracking_alloc,
+                realloc: tracking_realloc,
+                free: tracking_free,
+                opaque: self as *mut Counts as *mut c_void
+            }
+        }
+
+        fn outstanding(&self) -> usize {
+            self.allocs - self.frees
+        }
+    }
+
+    // opaque points at the Counts
+    extern "C" fn tracking_alloc(opaque: *mut c_void, size: size_t) -> *mut c_void {
+        unsafe {
+            (*(opaque as *mut Counts)).allocs += 1;
+            malloc(size)
+        }
+    }
+
+    extern "C" fn tracking_realloc(_opaque: *mut c_void, ptr: *mut c_void, size: size_t)
+            -> *mut c_void {
+        unsafe { realloc(ptr, size) }
+    }
+
+    extern "C" fn tracking_free(opaque: *mut c_void, ptr: *mut c_void) {
+        unsafe {
+            (*(opaque as *mut Counts)).frees += 1;
+            free(ptr)
+        }
+    }
+
+    #[test]
+    fn test_decompress_with_allocator() {
+        let mut counts = Counts::new();
+        let allocator = counts.allocator();
+        let mut len: c_int = 0;
+        let out = decompress_gzip_to_heap_with_allocator(COMMENTED.as_ptr() as *const c_void,
+                                                         COMMENTED.len() as c_int,
+                                                         &mut len as *mut c_int,
+                                                         &allocator as *const Allocator);
+        assert!(!out.is_null());
+        assert_eq!(len, 64);
+        // the Huffman tables came from the allocator too, and have been
+        // given back to it
+        assert!(counts.allocs > 1);
+        assert_eq!(counts.outstanding(), 1);
+        rgzip_free(out, &allocator as *const Allocator);
+        assert_eq!(counts.outstanding(), 0);
+    }
+
+    #[test]
+    fn test_decompress_failure_frees() {
+        let mut counts = Counts::new();
+        let allocator = counts.allocator();
+        let mut corrupt = COMMENTED.to_vec();
+        let crc_index = corrupt.len() - 8;
+        corrupt[crc_index] ^= 0xff;
+        let mut len: c_int = 0;
+        let out = decompress_gzip_to_heap_with_allocator(corrupt.as_ptr() as *const c_void,
+                                                         corrupt.len() as c_int,
+                                                         &mut len as *mut c_int,
+                                                         &allocator as *const Allocator);
+        assert!(out.is_null());
+        assert!(counts.allocs > 1);
+        assert_eq!(counts.outstanding(), 0);
+    }
+
+    #[test]
+    fn test_compress_with_allocator() {
+        let mut counts = Counts::new();
+        let allocator = counts.allocator();
+        let mut out = null::<c_void>() as *mut c_void;
+        let mut len: size_t = 0;
+        assert_eq!(rgzip_compress(EXPECTED.as_ptr() as *const c_void, EXPECTED.len() as size_t,
+                                  6, null::<HeaderFields>(), &mut out, &mut len,
+                                  &allocator as *const Allocator),
+                   RGZIP_OK);
+        assert_eq!(counts.outstanding(), 1);
+        let compressed = unsafe { slice::from_raw_parts(out as *const u8, len as usize) };
+        assert_eq!(decompress(compressed).unwrap().as_slice(), EXPECTED);
+        rgzip_free(out, &allocator as *const Allocator);
+        assert_eq!(counts.outstanding(), 0);
+    }
+
+    #[test]
+    fn test_free_null() {
+        rgzip_free(null::<c_void>() as *mut c_void, null::<Allocator>());
+    }
+}
+
+#[cfg(test)]
+mod error_reporting_tests {
+    use super::{rgzip_decompress, rgzip_last_error, rgzip_strerror, RGZIP_OK, RGZIP_ERR_CRC,
+                RGZIP_ERR_TOO_SHORT, RGZIP_ERR_INTERNAL};
+    use super::{decompress_gzip_to_heap, rgzip_free};
+    use cvec::Allocator;
+    use libc::{c_int, c_void, c_char};
+    use std::ffi::CStr;
+    use std::ptr::null;
+    use testing::COMMENTED;
+
+    fn message(ptr: *const c_char) -> String {
+        unsafe { String::from_utf8(CStr::from_ptr(ptr).to_bytes().to_vec()).unwrap() }
+    }
+
+    fn decompress(input: &[u8], out: &mut *mut c_void, len: &mut c_int) -> c_int {
+        rgzip_decompress(input.as_ptr() as *const c_void,
+                         input.len() as c_int,
+                         out as *mut *mut c_void,
+                         len as *mut c_int,
+                         null::<Allocator>())
+    }
+
+    #[test]
+    fn test_success() {
+        let mut out = null::<c_void>() as *mut c_void;
+        let mut len: c_int = -1;
+        assert_eq!(decompress(COMMENTED, &mut out, &mut len), RGZIP_OK);
+        assert!(!out.is_null());
+        assert_eq!(len, 64);
+        rgzip_free(out, null::<Allocator>());
+    }
+
+    #[test]
+    fn test_crc_failure() {
+        let mut corrupt = COMMENTED.to_vec();
+        let crc_index = corrupt.len() - 8;
+        corrupt[crc_index] ^= 0xff;
+        let mut out = null::<c_void>() as *mut c_void;
+        let mut len: c_int = -1;
+        assert_eq!(decompress(corrupt.as_slice(), &mut out, &mut len), RGZIP_ERR_CRC);
+        assert!(out.is_null());
+        assert_eq!(len, 0);
+        assert_eq!(message(rgzip_last_error()),
+                   "CRC32 does not match decompressed data at byte 35");
+        assert_eq!(message(rgzip_strerror(RGZIP_ERR_CRC)),
+                   "CRC32 does not match decompressed data");
+    }
+
+    #[test]
+    fn test_heap_failure_sets_last_error() {
+        let mut len: c_int = -1;
+        let out = decompress_gzip_to_heap(COMMENTED.as_ptr() as *const c_void,
+                                          20,
+                                          &mut len as *mut c_int);
+        assert!(out.is_null());
+        assert_eq!(len, -1);
+        assert_eq!(message(rgzip_last_error()), "input too short to be gzip data at byte 20");
+        assert_eq!(message(rgzip_strerror(RGZIP_ERR_TOO_SHORT)),
+                   "input too short to be gzip data");
+    }
+
+    #[test]
+    fn test_strerror() {
+        assert_eq!(message(rgzip_strerror(RGZIP_OK)), "success");
+        assert_eq!(message(rgzip_strerror(RGZIP_ERR_INTERNAL)), "a worker thread failed");
+        assert_eq!(message(rgzip_strerror(-1000)), "unknown error");
+    }
+}
+
+#[cfg(test)]
+mod large_output_tests {
+    use super::{decompress_gzip_to_heap, rgzip_decompress, rgzip_decompress_sz, rgzip_free,
+                rgzip_last_error, narrow_heap_result, size_to_int, RGZIP_OK, RGZIP_ERR_OVERFLOW};
+    use cvec::Allocator;
+    use crc32::Crc32;
+    use error::ErrorKind;
+    use libc::{c_int, c_void, size_t};
+    use libc::funcs::c95::stdlib::{malloc, realloc, free};
+    use std::ffi::CStr;
+    use std::ptr::null;
+
+    // One literal zero, followed by enough (length 258, distance 1)
+    // matches to take the output past i32::MAX
+    const MATCHES: usize = 8323581;
+    const OUT_LEN: usize = 1 + 258 * MATCHES;
+
+    /// Packs bits least significant first, as GzBitReader reads them
+    struct Bits {
+        bytes: Vec<u8>,
+        acc: u32,
+        count: u32
+    }
+
+    impl Bits {
+        fn put(&mut self, value: u32, count: u32) {
+            for i in 0 .. count {
+                self.acc |= ((value >> i) & 1) << self.count;
+                self.count += 1;
+                if self.count == 8 {
+                    self.bytes.push(self.acc as u8);
+                    self.acc = 0;
+                    self.count = 0;
+                }
+            }
+        }
+
+        /// Huffman codes are packed most significant bit first
+        fn put_code(&mut self, code: u32, count: u32) {
+            for i in 0 .. count {
+                self.put((code >> (count - 1 - i)) & 1, 1);
+            }
+        }
+    }
+
+    /// A single fixed-huffman block of OUT_LEN zeroes, wrapped in gzip
+    fn synthetic_gzip() -> Vec<u8> {
+        let mut bits = Bits { bytes: vec![0x1f, 0x8b, 0x08, 0x00, 0, 0, 0, 0, 0x00, 0x03],
+                              acc: 0, count: 0 };
+        bits.put(1, 1); // BFINAL
+        bits.put(1, 2); // fixed huffman
+        bits.put_code(0x30, 8); // literal 0
+        for _ in 0 .. MATCHES {
+            bits.put_code(0xc5, 8); // length 258
+            bits.put_code(0, 5); // distance 1
+        }
+        bits.put_code(0, 7); // end of block
+        bits.put(0, 7);
+        let mut out = bits.bytes;
+
+        let zeroes = [0u8; 65536];
+        let mut crc = Crc32::new();
+        let mut remaining = OUT_LEN;
+        while remaining > 0 {
+            let n = if remaining < zeroes.len() { remaining } else { zeroes.len() };
+            crc.update(&zeroes[..n]);
+            remaining -= n;
+        }
+        for &word in [crc.crc(), OUT_LEN as u32].iter() {
+            for i in 0 .. 4 {
+                out.push((word >> (8 * i)) as u8);
+            }
+        }
+        out
+    }
+
+    // opaque points at a count of frees
+    extern "C" fn counting_alloc(_opaque: *mut c_void, size: size_t) -> *mut c_void {
+        unsafe { malloc(size) }
+    }
+
+    extern "C" fn counting_realloc(_opaque: *mut c_void, ptr: *mut c_void, size: size_t)
+            -> *mut c_void {
+        unsafe { realloc(ptr, size) }
+    }
+
+    extern "C" fn counting_free(opaque: *mut c_void, ptr: *mut c_void) {
+        unsafe {
+            *(opaque as *mut usize) += 1;
+            free(ptr);
+        }
+    }
+
+    #[test]
+    fn test_narrow_lengths() {
+        let max = ::std::i32::MAX as usize;
+        assert_eq!(size_to_int(max), Ok(::std::i32::MAX));
+        assert_eq!(size_to_int(max + 1).unwrap_err().kind, ErrorKind::Overflow);
+
+        // a small buffer standing in for one whose length doesn't fit
+        let mut frees = 0usize;
+        let allocator = Allocator {
+            alloc: counting_alloc,
+            realloc: counting_realloc,
+            free: counting_free,
+            opaque: &mut frees as *mut usize as *mut c_void
+        };
+        let buf = (allocator.alloc)(allocator.opaque, 16);
+        let (out, len) = narrow_heap_result((buf, max), allocator).unwrap();
+        assert_eq!((out, len), (buf, ::std::i32::MAX));
+        assert_eq!(frees, 0);
+        let error = narrow_heap_result((buf, max + 1), allocator).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Overflow);
+        // the buffer whose length couldn't be returned was freed
+        assert_eq!(frees, 1);
+    }
+
+    // Decompresses over 2GB, so only run when asked for; test_narrow_lengths
+    // covers the length checks without it
+    #[test]
+    #[ignore]
+    fn test_output_larger_than_int() {
+        assert!(OUT_LEN > ::std::i32::MAX as usize);
+        let input = synthetic_gzip();
+
+        // the int interface has to fail cleanly
+        let mut int_len: c_int = -1;
+        let out = decompress_gzip_to_heap(input.as_ptr() as *const c_void,
+                                          input.len() as c_int,
+                                          &mut int_len as *mut c_int);
+        assert!(out.is_null());
+        assert_eq!(int_len, -1);
+        let message = unsafe { CStr::from_ptr(rgzip_last_error()).to_bytes().to_vec() };
+        assert_eq!(String::from_utf8(message).unwrap(), "length too large for an int at byte 0");
+
+        let mut out = null::<c_void>() as *mut c_void;
+        assert_eq!(rgzip_decompress(input.as_ptr() as *const c_void,
+                                    input.len() as c_int,
+                                    &mut out as *mut *mut c_void,
+                                    &mut int_len as *mut c_int,
+                                    null::<Allocator>()),
+                   RGZIP_ERR_OVERFLOW);
+        assert!(out.is_null());
+
+        // and the size_t interface has to succeed
+        let mut len: size_t = 0;
+        assert_eq!(rgzip_decompress_sz(input.as_ptr() as *const c_void,
+                                       input.len() as size_t,
+                                       &mut out as *mut *mut c_void,
+                                       &mut len as *mut size_t,
+                                       null::<Allocator>()),
+                   RGZIP_OK);
+        assert_eq!(len as usize, OUT_LEN);
+        unsafe {
+            let bytes = out as *const u8;
+            assert_eq!(*bytes, 0);
+            assert_eq!(*bytes.offset((OUT_LEN - 1) as isize), 0);
+        }
+        rgzip_free(out, null::<Allocator>());
+    }
+}
+
+#[cfg(test)]
+mod callback_tests {
+    use super::{decompress_gzip_to_callback, WriteCallback, RGZIP_OK, RGZIP_ERR_ABORTED,
+                RGZIP_ERROR};
+    use libc::{c_int, c_void, size_t};
+    use std::slice;
+    use testing::{COMPRESSED, EXPECTED};
+
+    // opaque points at a Vec<u8> to append to
+    extern "C" fn collect(opaque: *mut c_void, buf: *const c_void, len: size_t) -> c_int {
+        unsafe {
+            let out = &mut *(opaque as *mut Vec<u8>);
+            out.push_all(slice::from_raw_parts(buf as *const u8, len as usize));
+        }
+        0
+    }
+
+    extern "C" fn refuse(_opaque: *mut c_void, _buf: *const c_void, _len: size_t) -> c_int {
+        1
+    }
+
+    fn decompress(callback: Option<WriteCallback>, out: &mut Vec<u8>) -> c_int {
+        decompress_gzip_to_callback(COMPRESSED.as_ptr() as *const c_void,
+                                    COMPRESSED.len() as size_t,
+                                    callback,
+                                    out as *mut Vec<u8> as *mut c_void)
+    }
+
+    #[test]
+    fn test_callback() {
+        let mut out = Vec::new();
+        assert_eq!(decompress(Some(collect), &mut out), RGZIP_OK);
+        assert_eq!(out.as_slice(), EXPECTED);
+    }
+
+    #[test]
+    fn test_abort() {
+        let mut out = Vec::new();
+        assert_eq!(decompress(Some(refuse), &mut out), RGZIP_ERR_ABORTED);
+    }
+
+    #[test]
+    fn test_null_callback() {
+        let mut out = Vec::new();
+        assert_eq!(decompress(None, &mut out), RGZIP_ERROR);
+    }
+}
+
+#[cfg(test)]
+mod compress_tests {
+    use super::{rgzip_compress, rgzip_decompress_sz, rgzip_free, read_header, HeaderFields,
+                RGZIP_OK, RGZIP_ERROR};
+    use cvec::Allocator;
+    use libc::{c_char, c_uchar, c_void, size_t};
+    use std::ptr::null;
+    use std::slice;
+    use testing::EXPECTED;
+
+    fn compress(input: &[u8], level: i32, header: *const HeaderFields) -> (i32, Vec<u8>) {
+        let mut out = null::<c_void>() as *mut c_void;
+        let mut len: size_t = 0;
+        let code = rgzip_compress(input.as_ptr() as *const c_void, input.len() as size_t,
+                                  level, header, &mut out, &mut len, null::<Allocator>());
+        if code != RGZIP_OK {
+            assert!(out.is_null());
+            return (code, Vec::new());
+        }
+        let compressed = unsafe { slice::from_raw_parts(out as *const u8, len as usize).to_vec() };
+        rgzip_free(out, null::<Allocator>());
+        (code, compressed)
+    }
+
+    fn decompress(input: &[u8]) -> Vec<u8> {
+        let mut out = null::<c_void>() as *mut c_void;
+        let mut len: size_t = 0;
+        assert_eq!(rgzip_decompress_sz(input.as_ptr() as *const c_void, input.len() as size_t,
+                                       &mut out, &mut len, null::<Allocator>()),
+                   RGZIP_OK);
+        let result = unsafe { slice::from_raw_parts(out as *const u8, len as usize).to_vec() };
+        rgzip_free(out, null::<Allocator>());
+        result
+    }
+
+    #[test]
+    fn test_compress() {
+        for level in 0 .. 10 {
+            let (code, compressed) = compress(EXPECTED, level, null::<HeaderFields>());
+            assert_eq!(code, RGZIP_OK);
+            assert_eq!(decompress(compressed.as_slice()).as_slice(), EXPECTED);
+        }
+    }
+
+    #[test]
+    fn test_compress_with_header() {
+        let name = b"fox.txt\0";
+        let extra = [0x41u8, 0x70, 0x02, 0x00, 0x01, 0x02];
+        let fields = HeaderFields {
+            name: name.as_ptr() as *const c_char,
+            comment: null::<c_char>(),
+            extra: extra.as_ptr() as *const c_uchar,
+            extra_len: extra.len() as size_t,
+            mtime: 1234567890,
+            os: 3,
+            text: 1,
+            header_crc: 1
+        };
+        let (code, compressed) = compress(EXPECTED, 6, &fields);
+        assert_eq!(code, RGZIP_OK);
+        let header = read_header(compressed.as_slice()).unwrap();
+        assert_eq!(header.fname, Some("fox.txt".to_string()));
+        assert_eq!(header.comment, None);
+        assert_eq!(header.extra_fields().unwrap()[0].data, vec![1, 2]);
+        assert_eq!(header.mtime, 1234567890);
+        assert!(header.flags.FTEXT && header.flags.FHCRC);
+        assert_eq!(decompress(compressed.as_slice()).as_slice(), EXPECTED);
+    }
+
+    #[test]
+    fn test_invalid_arguments() {
+        assert_eq!(compress(EXPECTED, 10, null::<HeaderFields>()).0, RGZIP_ERROR);
+        assert_eq!(compress(EXPECTED, -1, null::<HeaderFields>()).0, RGZIP_ERROR);
+        // the subfield claims more data than there is
+        let extra = [0x41u8, 0x70, 0x05, 0x00, 0x01];
+        let fields = HeaderFields {
+            name: null::<c_char>(),
+            comment: null::<c_char>(),
+            extra: extra.as_ptr() as *const c_uchar,
+            extra_len: extra.len() as size_t,
+            mtime: 0,
+            os: 255,
+            text: 0,
+            header_crc: 0
+        };
+        assert_eq!(compress(EXPECTED, 6, &fields).0, RGZIP_ERROR);
+    }
+}
+
+#[cfg(test)]
+mod rust_api_tests {
+    use super::{decompress, decompress_with_options, decompress_to_callback, read_header};
+    use super::{DecompressOptions, ErrorKind, GzError};
+    use testing::{COMPRESSED, EXPECTED};
+
+    #[test]
+    fn test_decompress() {
+        assert_eq!(decompress(COMPRESSED).unwrap().as_slice(), EXPECTED);
+    }
+
+    #[test]
+    fn test_decompress_stored() {
+        // a single stored block
+        static STORED: &'static [u8] = &[
+            0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x01, 0x1e,
+            0x00, 0xe1, 0xff, 0x73, 0x74, 0x6f, 0x72, 0x65, 0x64, 0x2c, 0x20, 0x6e,
+            0x6f, 0x74, 0x20, 0x63, 0x6f, 0x6d, 0x70, 0x72, 0x65, 0x73, 0x73, 0x65,
+            0x64, 0x20, 0x61, 0x74, 0x20, 0x61, 0x6c, 0x6c, 0x0a, 0x7e, 0x17, 0xe9,
+            0xb9, 0x1e, 0x00, 0x00, 0x00];
+        assert_eq!(decompress(STORED).unwrap().as_slice(), b"stored, not compressed at all\n");
+    }
+
+    #[test]
+    fn test_decompress_errors() {
+        assert_eq!(decompress(&[]), Err(GzError::new(ErrorKind::TooShort, 0)));
+        let mut corrupt = COMPRESSED.to_vec();
+        corrupt[30] ^= 0x55;
+        assert!(decompress(corrupt.as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_options() {
+        let mut corrupt = COMPRESSED.to_vec();
+        let crc_index = corrupt.len() - 8;
+        corrupt[crc_index] ^= 0xff;
+        assert_eq!(decompress(corrupt.as_slice()).unwrap_err().kind, ErrorKind::CrcMismatch);
+
+        let mut options = DecompressOptions::new();
+        options.verify = false;
+        assert_eq!(decompress_with_options(corrupt.as_slice(), &options).unwrap().as_slice(),
+                   EXPECTED);
+
+        options.max_output_len = Some(10);
+        assert_eq!(decompress_with_options(COMPRESSED, &options).unwrap_err().kind,
+                   ErrorKind::LimitExceeded);
+        options.max_output_len = Some(EXPECTED.len());
+        assert_eq!(decompress_with_options(COMPRESSED, &options).unwrap().as_slice(), EXPECTED);
+    }
+
+    #[test]
+    fn test_lying_footer() {
+        // a footer claiming 4GB must not have that much reserved for it
+        let mut lying = COMPRESSED.to_vec();
+        let len = lying.len();
+        for i in len - 4 .. len {
+            lying[i] = 0xff;
+        }
+        assert_eq!(decompress(lying.as_slice()),
+                   Err(GzError::new(ErrorKind::SizeMismatch, len - 4)));
+    }
+
+    #[test]
+    fn test_decompress_to_callback() {
+        let mut out = Vec::new();
+        decompress_to_callback(COMPRESSED, |chunk: &[u8]| {
+            out.push_all(chunk);
+            true
+        }).unwrap();
+        assert_eq!(out.as_slice(), EXPECTED);
+    }
+
+    #[test]
+    fn test_read_header() {
+        let header = read_header(COMPRESSED).unwrap();
+        assert_eq!(header.header_len, 10);
+        assert_eq!(header.os, 3);
+        assert_eq!(header.fname, None);
+    }
+}
+
+#[cfg(test)]
+mod verify_interface_tests {
+    use super::{rgzip_verify, rgzip_verify_callback, rgzip_last_error, VerifyResult,
+                RGZIP_OK, RGZIP_ERROR, RGZIP_ERR_CRC, RGZIP_ERR_ABORTED};
+    use super::empty_verify_result;
+    use libc::{c_void, size_t, ssize_t};
+    use std::cmp;
+    use std::ffi::CStr;
+    use std::slice;
+    use testing::COMPRESSED;
+
+    const EXPECTED_LEN: u64 = 96;
+
+    // opaque points at the rest of the input, a &[u8], handed out a few
+    // bytes at a time
+    extern "C" fn read_some(opaque: *mut c_void, buf: *mut c_void, len: size_t) -> ssize_t {
+        unsafe {
+            let input = &mut *(opaque as *mut &[u8]);
+            let n = cmp::min(cmp::min(len as usize, input.len()), 7);
+            let out = slice::from_raw_parts_mut(buf as *mut u8, n);
+            for i in 0 .. n {
+                out[i] = input[i];
+            }
+            *input = &input[n ..];
+            n as ssize_t
+        }
+    }
+
+    extern "C" fn fail(_opaque: *mut c_void, _buf: *mut c_void, _len: size_t) -> ssize_t {
+        -1
+    }
+
+    fn verify_buf(data: &[u8]) -> (i32, VerifyResult) {
+        let mut report = empty_verify_result();
+        let code = rgzip_verify(data.as_ptr() as *const c_void, data.len() as size_t, &mut report);
+        (code, report)
+    }
+
+    #[test]
+    fn test_verify() {
+        let mut data = COMPRESSED.to_vec();
+        data.push_all(COMPRESSED);
+        let (code, report) = verify_buf(data.as_slice());
+        assert_eq!((code, report.status), (RGZIP_OK, RGZIP_OK));
+        assert_eq!(report.members, 2);
+        assert_eq!(report.compressed_len, data.len() as u64);
+        assert_eq!(report.uncompressed_len, 2 * EXPECTED_LEN);
+    }
+
+    #[test]
+    fn test_verify_failure() {
+        let mut data = COMPRESSED.to_vec();
+        data.push_all(COMPRESSED);
+        let len = data.len();
+        data[len - 6] ^= 1;
+        let (code, report) = verify_buf(data.as_slice());
+        assert_eq!((code, report.status), (RGZIP_ERR_CRC, RGZIP_ERR_CRC));
+        assert_eq!((report.members, report.failed_member), (1, 1));
+        assert_eq!(report.member_offset, COMPRESSED.len() as u64);
+        assert_eq!(report.position, len as u64 - 8);
+        assert_eq!(report.member_output, EXPECTED_LEN);
+        assert!(report.has_footer != 0);
+        assert_eq!(report.footer_crc, report.crc ^ 0x10000);
+        assert_eq!(report.footer_size as u64, EXPECTED_LEN);
+        let message = unsafe { CStr::from_ptr(rgzip_last_error()).to_bytes().to_vec() };
+        let message = String::from_utf8(message).unwrap();
+        assert!(message.ends_with(format!("at byte {}", len - 8).as_slice()));
+    }
+
+    #[test]
+    fn test_verify_callback() {
+        let mut input: &[u8] = COMPRESSED;
+        let mut report = empty_verify_result();
+        let code = rgzip_verify_callback(Some(read_some), &mut input as *mut &[u8] as *mut c_void,
+                                         &mut report);
+        assert_eq!(code, RGZIP_OK);
+        assert_eq!((report.members, report.uncompressed_len), (1, EXPECTED_LEN));
+        assert_eq!(rgzip_verify_callback(Some(fail), 0 as *mut c_void, &mut report),
+                   RGZIP_ERR_ABORTED);
+        assert_eq!(report.status, RGZIP_ERR_ABORTED);
+        assert_eq!(rgzip_verify_callback(None, 0 as *mut c_void, &mut report), RGZIP_ERROR);
+        assert_eq!(rgzip_verify(COMPRESSED.as_ptr() as *const c_void, 10, 0 as *mut VerifyResult),
+                   RGZIP_ERROR);
+    }
+}
@@ -0,0 +1,236 @@
+#[doc="
+
+    Module: list
+
+    This module lists the members of a gzip file, for what gzip -l
+    gets wrong. gzip -l reads the first header and the last footer
+    and nothing else, so a multi-member file is reported as if its
+    last member were all of it, and the size of anything of 4GB or
+    more comes out modulo 2^32, as that is all the footer holds.
+    list_members() walks every member instead, giving its offset,
+    sizes, stored name, mtime, OS and CRC. Where a member ends can
+    only be found by inflating it, unless it is a BGZF block whose
+    header gives its size, so ordinary members are decompressed and
+    counted exactly, with their CRCs checked. BGZF blocks are taken
+    from their headers and footers alone: a block holds at most 64K,
+    so the size in its footer can't have wrapped, and decompressing
+    it would give nothing more exact.
+
+"]
+use std::io;
+use std::io::{Read, BufReader};
+
+use header::GZIP_FOOTER_LEN;
+use members::Members;
+
+/// What a listing found out about one member
+#[derive(Clone, Show)]
+pub struct MemberInfo {
+    /// Where the member starts in the file
+    pub offset: u64,
+    /// The length of the whole member, header and footer included
+    pub compressed_len: u64,
+    pub header_len: u64,
+    /// The length of the member's contents. Counted if the member was
+    /// decompressed, and otherwise taken from the footer of its BGZF block.
+    pub uncompressed_len: u64,
+    /// Whether the member was decompressed, so that its CRC has been
+    /// checked
+    pub decompressed: bool,
+    /// The CRC32 of the contents, from the footer
+    pub crc: u32,
+    pub name: Option<String>,
+    pub mtime: u32,
+    pub os: u8
+}
+
+impl MemberInfo {
+    /// The length of the compressed data alone, without header or footer
+    pub fn deflated_len(&self) -> u64 {
+        self.compressed_len - self.header_len - GZIP_FOOTER_LEN as u64
+    }
+
+    /// How much compression saved, as a percentage of the contents
+    pub fn ratio(&self) -> f64 {
+        ratio(self.uncompressed_len, self.deflated_len())
+    }
+}
+
+/// The members of a gzip file, and whatever follows them
+#[derive(Clone, Show)]
+pub struct GzListing {
+    pub members: Vec<MemberInfo>,
+    /// The number of bytes after the last member that are not another
+    /// member, such as the zeros some tape formats pad with
+    pub trailing_len: u64
+}
+
+impl GzListing {
+    /// The length of the whole file
+    pub fn compressed_len(&self) -> u64 {
+        self.members.iter().fold(self.trailing_len, |total, m| total + m.compressed_len)
+    }
+
+    /// The length of the compressed data in all the members, without
+    /// their headers and footers
+    pub fn deflated_len(&self) -> u64 {
+        self.members.iter().fold(0, |total, m| total + m.deflated_len())
+    }
+
+    /// The length of the contents of all the members together
+    pub fn uncompressed_len(&self) -> u64 {
+        self.members.iter().fold(0, |total, m| total + m.uncompressed_len)
+    }
+
+    /// How much compression saved, as a percentage of the contents
+    pub fn ratio(&self) -> f64 {
+        ratio(self.uncompressed_len(), self.deflated_len())
+    }
+}
+
+/// List every member of the gzip file read from reader. Fails if reader
+/// holds no member at all, or if a member is damaged, at the file offset
+/// where the damage was found.
+pub fn list_members<R: Read>(reader: R) -> io::Result<GzListing> {
+    let mut members = Members::new(BufReader::new(reader));
+    members.skip_bgzf_blocks(true);
+    let mut listing = GzListing { members: Vec::new(), trailing_len: 0 };
+    for member in members.by_ref() {
+        let member = try!(member);
+        if let Some(error) = member.error {
+            return Err(error.to_io_error());
+        }
+        let header = member.header.expect("a member ended without a header");
+        listing.members.push(MemberInfo {
+            offset: member.offset,
+            compressed_len: member.compressed_len,
+            header_len: header.header_len as u64,
+            uncompressed_len: member.uncompressed_len,
+            decompressed: member.decompressed,
+            crc: member.crc,
+            name: header.fname,
+            mtime: header.mtime,
+            os: header.os
+        });
+    }
+    listing.trailing_len = members.trailing_len();
+    Ok(listing)
+}
+
+/// The name gzip gives the OS byte of a header
+pub fn os_name(os: u8) -> &'static str {
+    match os {
+        0 => "FAT",
+        1 => "Amiga",
+        2 => "VMS",
+        3 => "Unix",
+        4 => "VM/CMS",
+        5 => "Atari",
+        6 => "HPFS",
+        7 => "Mac",
+        8 => "Z-System",
+        9 => "CP/M",
+        10 => "TOPS-20",
+        11 => "NTFS",
+        12 => "QDOS",
+        13 => "Acorn",
+        _ => "unknown"
+    }
+}
+
+/// The percentage saved by compressing uncompressed bytes into deflated,
+/// worked out as gzip -l does
+fn ratio(uncompressed: u64, deflated: u64) -> f64 {
+    if uncompressed == 0 {
+        0.0
+    } else {
+        100.0 * (uncompressed as f64 - deflated as f64) / uncompressed as f64
+    }
+}
+
+#[cfg(test)]
+mod list_tests {
+    use super::{list_members, os_name};
+    use encoder::{compress, compress_with_header};
+    use error::ErrorKind;
+    use header::{HeaderOptions, read_u32_le};
+    use testing::bgzf;
+
+    fn error_message(data: &[u8]) -> String {
+        format!("{}", list_members(data).unwrap_err())
+    }
+
+    #[test]
+    fn test_members() {
+        let first = b"the first member, repeated, repeated, repeated".to_vec();
+        let second = b"and a second".to_vec();
+        let mut options = HeaderOptions::new();
+        options.fname = Some("first.txt".to_string());
+        options.mtime = 1234567890;
+        let mut data = compress_with_header(first.as_slice(), 6, &options).unwrap();
+        let first_len = data.len() as u64;
+        data.push_all(compress(second.as_slice(), 6).as_slice());
+        let listing = list_members(data.as_slice()).unwrap();
+        assert_eq!(listing.members.len(), 2);
+        let (a, b) = (&listing.members[0], &listing.members[1]);
+        assert_eq!((a.offset, a.compressed_len), (0, first_len));
+        assert_eq!((b.offset, b.compressed_len), (first_len, data.len() as u64 - first_len));
+        assert_eq!(a.uncompressed_len, first.len() as u64);
+        assert_eq!(b.uncompressed_len, second.len() as u64);
+        assert_eq!(a.name, Some("first.txt".to_string()));
+        assert_eq!((a.mtime, b.name.clone()), (1234567890, None));
+        assert!(a.decompressed && b.decompressed);
+        assert_eq!(a.crc, read_u32_le(&data[first_len as usize - 8 ..]));
+        assert_eq!(listing.compressed_len(), data.len() as u64);
+        assert_eq!(listing.uncompressed_len(), (first.len() + second.len()) as u64);
+        assert!(a.ratio() > 0.0 && b.ratio() < 0.0);
+        assert_eq!(listing.trailing_len, 0);
+        assert_eq!(os_name(3), "Unix");
+    }
+
+    #[test]
+    fn test_trailing() {
+        let mut data = compress(b"padded", 6);
+        let len = data.len() as u64;
+        data.push_all(&[0u8; 100]);
+        let listing = list_members(data.as_slice()).unwrap();
+        assert_eq!(listing.members.len(), 1);
+        assert_eq!(listing.trailing_len, 100);
+        assert_eq!(listing.compressed_len(), len + 100);
+        // not a header, though it starts like one
+        let mut data = compress(b"padded", 6);
+        data.push_all(b"\x1fjunk");
+        let listing = list_members(data.as_slice()).unwrap();
+        assert_eq!(listing.members.len(), 1);
+        assert_eq!(listing.trailing_len, 5);
+    }
+
+    #[test]
+    fn test_bgzf() {
+        let contents: Vec<u8> = (0 .. 200000).map(|i| (i % 251) as u8).collect();
+        let data = bgzf(contents.as_slice(), 65536);
+        let listing = list_members(data.as_slice()).unwrap();
+        // the blocks come from their sizes and footers, without inflating
+        assert!(listing.members.iter().all(|m| !m.decompressed));
+        assert_eq!(listing.uncompressed_len(), contents.len() as u64);
+        assert_eq!(listing.compressed_len(), data.len() as u64);
+        assert_eq!(listing.members.last().unwrap().uncompressed_len, 0);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(error_message(&[]), format!("{} at byte 0", ErrorKind::UnexpectedEof.description()));
+        assert_eq!(error_message(b"plain text"), format!("{} at byte 0", ErrorKind::BadHeader.description()));
+        let mut data = compress(b"some data that will be cut short", 6);
+        let first_len = data.len();
+        data.push_all(compress(b"cut short", 6).as_slice());
+        let len = data.len();
+        let message = error_message(&data[.. len - 3]);
+        assert!(message.starts_with(ErrorKind::UnexpectedEof.description()));
+        // a damaged footer is reported where it is in the file
+        data[len - 5] ^= 1;
+        assert_eq!(error_message(data.as_slice()),
+                   format!("{} at byte {}", ErrorKind::CrcMismatch.description(), len - 8));
+        assert!(len - 8 > first_len);
+    }
+}
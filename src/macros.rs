@@ -27,8 +27,21 @@ pub macro_rules! if_opt {
 #[macro_export]
 pub macro_rules! try_bail {
     ($expr: expr) => (match $expr {
-        Option::Some(v) => v,
-        Option::None => { bail!() },
+        Result::Ok(v) => v,
+        Result::Err(e) => {
+            set_last_error(e);
+            bail!()
+        },
+    })
+}
+
+#[macro_export]
+pub macro_rules! try_code {
+    ($expr: expr) => (match $expr {
+        Result::Ok(v) => v,
+        Result::Err(e) => {
+            return set_last_error(e);
+        },
     })
 }
 
@@ -0,0 +1,325 @@
+#[doc="
+
+    Module: members
+
+    This module walks the members of a gzip file one after another,
+    for list, verify and inspect. Members decompresses each member
+    with a Decompressor into the same buffer over and over, keeping
+    only the length and CRC of its output, and gives where it starts
+    and ends in the file and what its header and footer hold. It
+    stops at the first damaged member, with how far it got into it,
+    and after the last member counts the bytes that follow which
+    are not another member, even if they start like one, as gunzip
+    treats them. BGZF blocks can be stepped over using
+    the sizes in their headers, reading only their footers.
+
+"]
+use std::io;
+use std::io::{Read, BufRead};
+
+use bgzf;
+use cvec::slice_buf;
+use error::{GzError, ErrorKind};
+use header;
+use header::{GZHeader, GZ_MAGIC_BYTES, GZIP_FOOTER_LEN, read_u32_le};
+use stream::{Decompressor, StreamStatus};
+
+// the output of every member goes through this much memory
+const OUTPUT_BUFFER_SIZE: usize = 32768;
+
+/// One member of a gzip file, or as much of it as could be read
+#[derive(Clone, Show)]
+pub struct Member {
+    /// Where the member starts in the file
+    pub offset: u64,
+    /// The number of bytes read for the member: all of it, header and
+    /// footer included, unless it failed
+    pub compressed_len: u64,
+    /// The member's header, unless it could not be read
+    pub header: Option<GZHeader>,
+    /// The length of the member's output, or of as much as was decoded.
+    /// Taken from the footer if the member was stepped over, which only
+    /// holds it modulo 2^32.
+    pub uncompressed_len: u64,
+    /// The CRC32 of that output, or the footer's if the member was
+    /// stepped over
+    pub crc: u32,
+    /// The CRC and size held in the footer, once it has been read
+    pub footer: Option<(u32, u32)>,
+    /// Whether the member was decompressed rather than stepped over
+    pub decompressed: bool,
+    /// What was wrong with the member, at its position in the file. A
+    /// member with an error is the last one given.
+    pub error: Option<GzError>
+}
+
+/// An iterator over the members of a gzip file read from a BufRead.
+/// Fails only if the input can't be read; damage is given in the last
+/// Member. Input with no member at all gives a single Member with an
+/// error and no header.
+pub struct Members<R> {
+    input: R,
+    output: Vec<u8>,
+    // the number of bytes consumed from input
+    offset: u64,
+    members: usize,
+    skip_blocks: bool,
+    trailing_len: u64,
+    done: bool
+}
+
+impl<R: BufRead> Members<R> {
+    pub fn new(input: R) -> Members<R> {
+        Members {
+            input: input,
+            output: vec![0u8; OUTPUT_BUFFER_SIZE],
+            offset: 0,
+            members: 0,
+            skip_blocks: false,
+            trailing_len: 0,
+            done: false
+        }
+    }
+
+    /// Whether to step over BGZF blocks using the sizes in their headers,
+    /// taking their lengths and CRCs from their footers rather than
+    /// decompressing them
+    pub fn skip_bgzf_blocks(&mut self, skip: bool) {
+        self.skip_blocks = skip;
+    }
+
+    /// The number of bytes after the last member that are not another
+    /// member, such as the zeros some tape formats pad with, or anything
+    /// without a valid header. Only known once the iterator has ended.
+    pub fn trailing_len(&self) -> u64 {
+        self.trailing_len
+    }
+
+    fn read_member(&mut self) -> io::Result<Option<Member>> {
+        let next = try!(self.input.fill_buf()).first().cloned();
+        let kind = match next {
+            Some(byte) if byte == GZ_MAGIC_BYTES[0] => None,
+            None if self.members == 0 => Some(ErrorKind::UnexpectedEof),
+            Some(_) if self.members == 0 => Some(ErrorKind::BadHeader),
+            None => return Ok(None),
+            Some(_) => {
+                self.trailing_len = try!(io::copy(&mut self.input, &mut io::sink()));
+                return Ok(None);
+            }
+        };
+        let mut member = Member {
+            offset: self.offset,
+            compressed_len: 0,
+            header: None,
+            uncompressed_len: 0,
+            crc: 0,
+            footer: None,
+            decompressed: false,
+            error: kind.map(|kind| GzError::new(kind, 0))
+        };
+        if member.error.is_none() {
+            match try!(self.block_header()) {
+                Some((header, size)) if self.skip_blocks =>
+                    try!(self.skip_block(&mut member, header, size)),
+                _ => try!(self.inflate_member(&mut member))
+            }
+        }
+        // after the first member, what doesn't have a valid header is
+        // trailing data rather than a damaged member, as for gunzip
+        let bad_header = match member.error {
+            Some(ref error) => error.kind == ErrorKind::BadHeader,
+            None => false
+        };
+        if bad_header && self.members > 0 {
+            let rest = try!(io::copy(&mut self.input, &mut io::sink()));
+            self.trailing_len = self.offset - member.offset + rest;
+            return Ok(None);
+        }
+        member.compressed_len = self.offset - member.offset;
+        self.members += 1;
+        Ok(Some(member))
+    }
+
+    /// The header of the member about to be read, and its size, if it is
+    /// a BGZF block. Only looks at what is already buffered, so a header
+    /// too long for the buffer is treated as an ordinary member.
+    fn block_header(&mut self) -> io::Result<Option<(GZHeader, u64)>> {
+        let buffered = try!(self.input.fill_buf());
+        let header = match slice_buf(buffered) {
+            Some(buffer) => header::parse_header(&buffer),
+            None => None
+        };
+        let header = match header {
+            Some(header) => header,
+            None => return Ok(None)
+        };
+        let size = match header.extra_fields() {
+            Some(fields) => bgzf::block_size(fields.as_slice()),
+            None => None
+        };
+        match size {
+            Some(size) if size as u64 >= (header.header_len + GZIP_FOOTER_LEN) as u64 =>
+                Ok(Some((header, size as u64))),
+            _ => Ok(None)
+        }
+    }
+
+    /// Step over a BGZF block of the given size, reading only its footer
+    fn skip_block(&mut self, member: &mut Member, header: GZHeader, size: u64)
+                  -> io::Result<()> {
+        member.header = Some(header);
+        let data_len = size - GZIP_FOOTER_LEN as u64;
+        let skipped = try!(io::copy(&mut self.input.by_ref().take(data_len), &mut io::sink()));
+        self.offset += skipped;
+        let mut footer = [0u8; 8];
+        let mut got = 0;
+        while skipped == data_len && got < footer.len() {
+            match try!(self.input.read(&mut footer[got ..])) {
+                0 => break,
+                n => got += n
+            }
+        }
+        self.offset += got as u64;
+        if got < footer.len() {
+            member.error = Some(GzError::new(ErrorKind::UnexpectedEof, self.offset as usize));
+            return Ok(());
+        }
+        let (crc, size) = (read_u32_le(&footer[.. 4]), read_u32_le(&footer[4 ..]));
+        member.footer = Some((crc, size));
+        member.crc = crc;
+        member.uncompressed_len = size as u64;
+        Ok(())
+    }
+
+    /// Decompress a member, counting its output. Its footer is checked on
+    /// the way, so the CRC and length counted are the ones it holds.
+    fn inflate_member(&mut self, member: &mut Member) -> io::Result<()> {
+        member.decompressed = true;
+        let mut decompressor = Decompressor::new();
+        loop {
+            let (result, eof) = {
+                let buffered = try!(self.input.fill_buf());
+                (decompressor.decompress(buffered, self.output.as_mut_slice()),
+                 buffered.len() == 0)
+            };
+            let error = match result {
+                Ok((used, _, status)) => {
+                    self.input.consume(used);
+                    self.offset += used as u64;
+                    match status {
+                        StreamStatus::MemberEnd => None,
+                        StreamStatus::NeedInput if eof =>
+                            Some(GzError::new(ErrorKind::UnexpectedEof, self.offset as usize)),
+                        _ => continue
+                    }
+                },
+                Err(e) => Some(GzError::new(e.kind, member.offset as usize + e.position))
+            };
+            member.header = decompressor.header().map(|header| header.clone());
+            member.uncompressed_len = decompressor.member_len();
+            member.crc = decompressor.crc();
+            member.footer = decompressor.footer();
+            member.error = error;
+            return Ok(());
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for Members<R> {
+    type Item = io::Result<Member>;
+
+    fn next(&mut self) -> Option<io::Result<Member>> {
+        if self.done {
+            return None;
+        }
+        match self.read_member() {
+            Ok(Some(member)) => {
+                self.done = member.error.is_some();
+                Some(Ok(member))
+            },
+            Ok(None) => {
+                self.done = true;
+                None
+            },
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod members_tests {
+    use super::Members;
+    use encoder::compress;
+    use error::{GzError, ErrorKind};
+    use testing::bgzf;
+
+    #[test]
+    fn test_members() {
+        let first = compress(b"the first member, the first member", 6);
+        let mut data = first.clone();
+        data.push_all(compress(b"second", 6).as_slice());
+        data.push_all(&[0u8; 5]);
+        let mut members = Members::new(data.as_slice());
+        let found: Vec<_> = members.by_ref().map(|member| member.unwrap()).collect();
+        assert_eq!(found.len(), 2);
+        assert_eq!((found[0].offset, found[0].compressed_len), (0, first.len() as u64));
+        assert_eq!(found[1].offset, first.len() as u64);
+        assert_eq!(found[0].uncompressed_len, 34);
+        assert_eq!(found[1].footer, Some((found[1].crc, 6)));
+        assert!(found.iter().all(|m| m.decompressed && m.error.is_none() && m.header.is_some()));
+        assert_eq!(members.trailing_len(), 5);
+        // as is anything that starts like a member but has no valid header
+        let mut data = first.clone();
+        data.push_all(b"\x1f\x8b\x07 not deflate");
+        let mut members = Members::new(data.as_slice());
+        assert_eq!(members.by_ref().filter(|m| m.as_ref().unwrap().error.is_none()).count(), 1);
+        assert_eq!(members.trailing_len(), 15);
+    }
+
+    #[test]
+    fn test_skip_blocks() {
+        let contents: Vec<u8> = (0 .. 100000).map(|i| (i % 251) as u8).collect();
+        let data = bgzf(contents.as_slice(), 65536);
+        let mut members = Members::new(data.as_slice());
+        members.skip_bgzf_blocks(true);
+        let skipped: Vec<_> = members.map(|member| member.unwrap()).collect();
+        let inflated: Vec<_> = Members::new(data.as_slice()).map(|member| member.unwrap()).collect();
+        assert!(skipped.iter().all(|m| !m.decompressed));
+        assert_eq!(skipped.len(), inflated.len());
+        for (a, b) in skipped.iter().zip(inflated.iter()) {
+            assert_eq!((a.offset, a.compressed_len, a.uncompressed_len, a.crc),
+                       (b.offset, b.compressed_len, b.uncompressed_len, b.crc));
+        }
+        let mut members = Members::new(&data[.. data.len() - 3]);
+        members.skip_bgzf_blocks(true);
+        let last = members.last().unwrap().unwrap();
+        assert_eq!(last.error, Some(GzError::new(ErrorKind::UnexpectedEof, data.len() - 3)));
+    }
+
+    #[test]
+    fn test_damage() {
+        let data = compress(b"a member to damage, a member to damage", 6);
+        let mut damaged = data.clone();
+        damaged.push_all(data.as_slice());
+        let len = damaged.len();
+        damaged[len - 5] ^= 1;
+        let found: Vec<_> = Members::new(damaged.as_slice()).map(|member| member.unwrap()).collect();
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[1].error, Some(GzError::new(ErrorKind::CrcMismatch, len - 8)));
+        assert!(found[1].footer.is_some());
+        let cut = &data[.. data.len() - 3];
+        let found: Vec<_> = Members::new(cut).map(|member| member.unwrap()).collect();
+        assert_eq!(found[0].error, Some(GzError::new(ErrorKind::UnexpectedEof, cut.len())));
+        let inputs = [(b"".as_slice(), ErrorKind::UnexpectedEof),
+                      (b"plain text".as_slice(), ErrorKind::BadHeader)];
+        for &(data, kind) in inputs.iter() {
+            let found: Vec<_> = Members::new(data).map(|member| member.unwrap()).collect();
+            assert_eq!(found.len(), 1);
+            assert!(found[0].header.is_none());
+            assert_eq!(found[0].error, Some(GzError::new(kind, 0)));
+        }
+    }
+}
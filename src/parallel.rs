@@ -0,0 +1,395 @@
+#[doc="
+
+    Module: parallel
+
+    This module compresses on several threads at once, as pigz
+    does. The input is cut into chunks, and each chunk is deflated
+    on a worker thread with the 32K of input before it as a
+    dictionary, so matches can still reach back across the cut.
+    Every chunk but the last ends with a sync flush, leaving it on
+    a byte boundary, so the compressed chunks join up, in order,
+    into one DEFLATE stream inside a single gzip member. The CRC
+    of the whole input is combined from the CRC of each chunk.
+
+"]
+use std::collections::BTreeMap;
+use std::io;
+use std::io::Write;
+use std::mem;
+
+use crc32;
+use crc32::Crc32;
+use deflate::Deflater;
+use encoder;
+use error::{GzError, ErrorKind};
+use header;
+use header::HeaderOptions;
+use pool::{Pool, worker_failed};
+
+/// The default amount of input compressed as one piece
+pub const DEFAULT_CHUNK_SIZE: usize = 131072;
+// how much input before a chunk can be used as its dictionary
+const DICT_SIZE: usize = 32768;
+
+/// Options controlling parallel compression
+#[derive(Clone, Show)]
+pub struct ParallelOptions {
+    /// From 0 (no compression) to 9 (smallest)
+    pub level: u32,
+    /// How many worker threads to compress on
+    pub threads: usize,
+    /// How much input to compress as one piece. Smaller chunks share
+    /// the work out more evenly but compress a little less well.
+    pub chunk_size: usize,
+    pub header: HeaderOptions
+}
+
+impl ParallelOptions {
+    /// Options whose output is reproducible, depending only on the input,
+    /// the level and the chunk size. The header holds no name, a zero
+    /// mtime and an unknown OS, and chunks are cut at multiples of the
+    /// chunk size, so the number of threads, the order chunks finish in,
+    /// how the input is split between writes and the platform make no
+    /// difference. A fixed mtime can be set in the header without
+    /// losing this.
+    pub fn new() -> ParallelOptions {
+        ParallelOptions {
+            level: 6,
+            threads: 4,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            header: HeaderOptions::new()
+        }
+    }
+}
+
+/// A chunk of input for a worker, with the input before it
+struct Job {
+    index: usize,
+    dict: Vec<u8>,
+    data: Vec<u8>,
+    last: bool
+}
+
+/// What a worker made of a Job
+struct Chunk {
+    index: usize,
+    compressed: Vec<u8>,
+    crc: u32,
+    len: usize
+}
+
+/// Compresses the data written to it into a gzip member written to W,
+/// using a pool of worker threads. finish() must be called to end the
+/// member and write the footer.
+pub struct ParallelGzEncoder<W> {
+    writer: W,
+    chunk_size: usize,
+    // how many chunks may be compressing or waiting to be written
+    max_in_flight: usize,
+    // the header, until it has been written
+    header: Vec<u8>,
+    pool: Option<Pool<Job, Chunk>>,
+    // input not yet handed to a worker, and the input just before it
+    pending: Vec<u8>,
+    dict: Vec<u8>,
+    // the index of the next chunk to hand out, and to write
+    next_job: usize,
+    next_write: usize,
+    // chunks that finished ahead of one before them
+    finished: BTreeMap<usize, Chunk>,
+    crc: u32,
+    len: u64
+}
+
+impl<W: Write> ParallelGzEncoder<W> {
+    /// Start the worker threads. Fails with InvalidArgument if there are
+    /// no threads, the chunk size is 0, or the header can't be written.
+    pub fn new(writer: W, options: &ParallelOptions) -> Result<ParallelGzEncoder<W>, GzError> {
+        if options.threads == 0 || options.chunk_size == 0 {
+            return Err(GzError::new(ErrorKind::InvalidArgument, 0));
+        }
+        let header = match header::write_header(&options.header,
+                                                encoder::extra_flags(options.level)) {
+            Some(h) => h,
+            None => return Err(GzError::new(ErrorKind::InvalidArgument, 0))
+        };
+        let level = options.level;
+        let pool = Pool::new(options.threads, move |job| compress_chunk(level, job));
+        Ok(ParallelGzEncoder {
+            writer: writer,
+            chunk_size: options.chunk_size,
+            max_in_flight: 2 * options.threads,
+            header: header,
+            pool: Some(pool),
+            pending: Vec::with_capacity(options.chunk_size),
+            dict: Vec::new(),
+            next_job: 0,
+            next_write: 0,
+            finished: BTreeMap::new(),
+            crc: 0,
+            len: 0
+        })
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    /// Compress the rest of the input, wait for every chunk to be written,
+    /// and end the member with the footer. Gives back the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        try!(self.submit(true));
+        while self.next_write < self.next_job {
+            try!(self.receive());
+        }
+        let mut footer = [0u8; 8];
+        for i in 0 .. 4 {
+            footer[i] = (self.crc >> (8 * i)) as u8;
+            // the footer only holds the size modulo 2^32
+            footer[i + 4] = (self.len >> (8 * i)) as u8;
+        }
+        try!(self.writer.write_all(&footer));
+        try!(self.writer.flush());
+
+        let finished = match self.pool.take() {
+            Some(pool) => pool.finish(),
+            None => false
+        };
+        if !finished {
+            return Err(worker_failed().to_io_error());
+        }
+        Ok(self.writer)
+    }
+
+    /// Hand the pending input to a worker, and write out whatever chunks
+    /// are ready if too many are in flight
+    fn submit(&mut self, last: bool) -> io::Result<()> {
+        let data = mem::replace(&mut self.pending, Vec::with_capacity(self.chunk_size));
+        let job = Job {
+            index: self.next_job,
+            dict: self.dict.clone(),
+            data: data,
+            last: last
+        };
+
+        // the dictionary for the next chunk is the end of the input so far
+        self.dict.push_all(job.data.as_slice());
+        if self.dict.len() > DICT_SIZE {
+            let start = self.dict.len() - DICT_SIZE;
+            self.dict = self.dict[start ..].to_vec();
+        }
+
+        let sent = match self.pool {
+            Some(ref pool) => pool.send(job),
+            None => false
+        };
+        if !sent {
+            return Err(worker_failed().to_io_error());
+        }
+        self.next_job += 1;
+        while self.next_job - self.next_write >= self.max_in_flight {
+            try!(self.receive());
+        }
+        Ok(())
+    }
+
+    /// Wait for a worker to finish a chunk, then write every chunk that is
+    /// next in order
+    fn receive(&mut self) -> io::Result<()> {
+        let chunk = match self.pool.as_ref().and_then(|pool| pool.recv()) {
+            Some(chunk) => chunk,
+            None => return Err(worker_failed().to_io_error())
+        };
+        self.finished.insert(chunk.index, chunk);
+        while let Some(chunk) = self.finished.remove(&self.next_write) {
+            if self.header.len() > 0 {
+                try!(self.writer.write_all(self.header.as_slice()));
+                self.header.clear();
+            }
+            try!(self.writer.write_all(chunk.compressed.as_slice()));
+            self.crc = crc32::combine(self.crc, chunk.crc, chunk.len as u64);
+            self.len += chunk.len as u64;
+            self.next_write += 1;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for ParallelGzEncoder<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let mut used = 0;
+        while used < data.len() {
+            let room = self.chunk_size - self.pending.len();
+            let n = if room < data.len() - used { room } else { data.len() - used };
+            self.pending.push_all(&data[used .. used + n]);
+            used += n;
+            if self.pending.len() == self.chunk_size {
+                try!(self.submit(false));
+            }
+        }
+        Ok(data.len())
+    }
+
+    /// Pass on what has been written so far. Input waiting for its
+    /// chunk to fill, and chunks still being compressed, are not
+    /// waited for.
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Compress one chunk, on a worker thread
+fn compress_chunk(level: u32, job: Job) -> Chunk {
+    let mut deflater = Deflater::new(level);
+    deflater.set_dictionary(job.dict.as_slice());
+    deflater.write(job.data.as_slice());
+    if job.last {
+        deflater.finish();
+    } else {
+        deflater.sync_flush();
+    }
+    let mut crc = Crc32::new();
+    crc.update(job.data.as_slice());
+    Chunk {
+        index: job.index,
+        compressed: deflater.take_output(),
+        crc: crc.crc(),
+        len: job.data.len()
+    }
+}
+
+/// Compress data into a gzip member on several threads
+pub fn compress_parallel(data: &[u8], options: &ParallelOptions) -> Result<Vec<u8>, GzError> {
+    let mut encoder = try!(ParallelGzEncoder::new(Vec::new(), options));
+    // writing to a Vec can't fail, so only a worker panicking can
+    if encoder.write_all(data).is_err() {
+        return Err(worker_failed());
+    }
+    match encoder.finish() {
+        Ok(out) => Ok(out),
+        Err(..) => Err(worker_failed())
+    }
+}
+
+#[cfg(test)]
+mod parallel_tests {
+    use super::{compress_parallel, ParallelGzEncoder, ParallelOptions};
+    use cvec::CVec;
+    use encoder::extra_flags;
+    use gz::{decompress, decompress_gz};
+    use read::GzDecoder;
+    use std::io::{Read, Write};
+    use testing::{system_gunzip, text};
+
+    fn options(level: u32, threads: usize, chunk_size: usize) -> ParallelOptions {
+        let mut options = ParallelOptions::new();
+        options.level = level;
+        options.threads = threads;
+        options.chunk_size = chunk_size;
+        options
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let input = text(5000);
+        for &level in [0, 1, 6, 9].iter() {
+            for &chunk_size in [1000, 40000, 1 << 20].iter() {
+                let compressed = compress_parallel(input.as_slice(),
+                                                   &options(level, 3, chunk_size)).unwrap();
+                assert!(decompress(compressed.as_slice()).unwrap() == input,
+                        "level {} chunk size {}", level, chunk_size);
+            }
+        }
+    }
+
+    #[test]
+    fn test_system_gunzip() {
+        let input = text(5000);
+        for &(level, threads, chunk_size) in [(0, 2, 1000), (6, 3, 10000), (9, 4, 1 << 20)].iter() {
+            let compressed = compress_parallel(input.as_slice(),
+                                               &options(level, threads, chunk_size)).unwrap();
+            let (status, out) = system_gunzip(compressed.as_slice());
+            assert_eq!(status, 0);
+            assert!(out == input, "level {} chunk size {}", level, chunk_size);
+        }
+        let (status, out) = system_gunzip(compress_parallel(b"", &options(6, 2, 100))
+                                          .unwrap().as_slice());
+        assert_eq!((status, out.len()), (0, 0));
+    }
+
+    #[test]
+    fn test_decompress_gz() {
+        let input = text(5000);
+        let compressed = compress_parallel(input.as_slice(), &options(6, 4, 10000)).unwrap();
+        let mut buffer = CVec::with_capacity(compressed.len()).unwrap();
+        for &byte in compressed.iter() {
+            buffer.push(byte);
+        }
+        let out = decompress_gz(buffer).unwrap();
+        assert!(out.as_slice() == input.as_slice());
+    }
+
+    #[test]
+    fn test_dictionary_helps() {
+        // chunks that can match into the chunk before compress better
+        // than ones compressed on their own
+        let input = text(5000);
+        let compressed = compress_parallel(input.as_slice(), &options(6, 2, 8192)).unwrap();
+        let mut separate = 0;
+        for chunk in input.as_slice().chunks(8192) {
+            separate += compress_parallel(chunk, &options(6, 1, 8192)).unwrap().len();
+        }
+        assert!(compressed.len() < separate);
+    }
+
+    #[test]
+    fn test_empty_and_small() {
+        for input in [&b""[..], &b"a"[..]].iter() {
+            let compressed = compress_parallel(*input, &options(6, 2, 100)).unwrap();
+            let mut out = Vec::new();
+            GzDecoder::new(compressed.as_slice()).read_to_end(&mut out).unwrap();
+            assert_eq!(out.as_slice(), *input);
+        }
+    }
+
+    #[test]
+    fn test_written_in_pieces() {
+        let input = text(5000);
+        let mut encoder = ParallelGzEncoder::new(Vec::new(), &options(6, 3, 5000)).unwrap();
+        for piece in input.as_slice().chunks(777) {
+            encoder.write_all(piece).unwrap();
+        }
+        let compressed = encoder.finish().unwrap();
+        assert!(compressed == compress_parallel(input.as_slice(), &options(6, 3, 5000)).unwrap());
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        assert!(compress_parallel(b"abc", &options(6, 0, 100)).is_err());
+        assert!(compress_parallel(b"abc", &options(6, 1, 0)).is_err());
+    }
+
+    #[test]
+    fn test_reproducible() {
+        // more than two chunks
+        let input = text(5000);
+        for &level in [0, 1, 6].iter() {
+            let mut options = ParallelOptions::new();
+            options.level = level;
+            options.threads = 1;
+            let expect = compress_parallel(input.as_slice(), &options).unwrap();
+            assert_eq!(&expect[.. 10], &[0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00,
+                                         extra_flags(level), 0xff]);
+            for &threads in [2, 3, 8].iter() {
+                options.threads = threads;
+                assert!(compress_parallel(input.as_slice(), &options).unwrap() == expect,
+                        "level {} threads {}", level, threads);
+                let mut encoder = ParallelGzEncoder::new(Vec::new(), &options).unwrap();
+                for piece in input.as_slice().chunks(1000 * threads + 1) {
+                    encoder.write_all(piece).unwrap();
+                }
+                assert!(encoder.finish().unwrap() == expect);
+            }
+        }
+    }
+}
@@ -0,0 +1,341 @@
+#[doc="
+
+    Module: parallel_decode
+
+    This module decompresses gzip files made of many members, such
+    as BGZF files or the output of pigz --independent, on several
+    threads at once. No back pointer reaches from one member into
+    another, so each member can be inflated on its own.
+
+    The input is cut into jobs of whole members. Where a header
+    holds a BGZF BC subfield, the block size says exactly where
+    the member ends. Otherwise the next place that parses as a gzip
+    header is taken as the next member, which is only a guess, as
+    those bytes could also turn up inside compressed data. A job
+    only counts if its members end exactly where it does. The
+    results are put back in order, and wherever a guess was wrong,
+    the members there are decompressed again one after the other,
+    so the output is always what sequential decompression gives.
+    Every member's CRC32 and size are checked either way.
+
+"]
+use std::collections::BTreeMap;
+
+use bgzf;
+use error::{GzError, ErrorKind};
+use gz;
+use header::GZ_MAGIC_BYTES;
+use pool::{Pool, worker_failed};
+use stream::{Decompressor, StreamStatus};
+
+// how much compressed data to put in a job, at least, when members are small
+const JOB_SIZE: usize = 262144;
+// the smallest possible member: a header, an empty block and a footer
+const MIN_MEMBER_LEN: usize = 20;
+// how much output to decompress at a time
+const OUTPUT_STEP: usize = 65536;
+
+/// A run of whole members for a worker to decompress
+struct Job {
+    index: usize,
+    start: usize,
+    data: Vec<u8>
+}
+
+/// What a worker made of a Job: the output, if the members in it ended
+/// exactly at its end
+struct Decoded {
+    index: usize,
+    start: usize,
+    end: usize,
+    out: Option<Vec<u8>>
+}
+
+/// Decompress a gzip file of one or more members on threads worker
+/// threads. The output is the same as decompress() gives, though only
+/// files of many members gain anything from the threads.
+pub fn decompress_parallel(data: &[u8], threads: usize) -> Result<Vec<u8>, GzError> {
+    let mut out = Vec::new();
+    try!(decompress_parallel_to_callback(data, threads, |chunk: &[u8]| {
+        out.push_all(chunk);
+        true
+    }));
+    Ok(out)
+}
+
+/// As decompress_parallel, but passing the output of each run of members
+/// to callback in order, rather than collecting it. The callback returns
+/// false to stop decompression.
+pub fn decompress_parallel_to_callback<F>(data: &[u8], threads: usize, mut callback: F)
+        -> Result<(), GzError>
+        where F: FnMut(&[u8]) -> bool {
+    if threads == 0 {
+        return Err(GzError::new(ErrorKind::InvalidArgument, 0));
+    }
+    if data.len() == 0 {
+        return Err(GzError::new(ErrorKind::UnexpectedEof, 0));
+    }
+    let pool = Pool::new(threads, decode_job);
+
+    let mut collector = Collector {
+        data: data,
+        cursor: 0,
+        next_index: 0,
+        finished: BTreeMap::new()
+    };
+    let max_in_flight = 2 * threads;
+    let mut next_job = 0;
+    let mut pos = 0;
+    let mut result = Ok(());
+    while pos < data.len() && result.is_ok() {
+        let start = pos;
+        while pos < data.len() && pos - start < JOB_SIZE {
+            pos = member_end_guess(data, pos);
+        }
+        let job = Job { index: next_job, start: start, data: data[start .. pos].to_vec() };
+        if !pool.send(job) {
+            result = Err(worker_failed());
+            break;
+        }
+        next_job += 1;
+        while result.is_ok() && next_job - collector.next_index >= max_in_flight {
+            result = collector.receive(&pool, &mut callback);
+        }
+    }
+    while result.is_ok() && collector.next_index < next_job {
+        result = collector.receive(&pool, &mut callback);
+    }
+    if result.is_ok() {
+        // whatever the jobs didn't cover, one member at a time
+        result = collector.catch_up(data.len(), &mut callback);
+    }
+    if !pool.finish() && result.is_ok() {
+        result = Err(worker_failed());
+    }
+    result
+}
+
+/// Puts the results of jobs back in order
+struct Collector<'a> {
+    data: &'a [u8],
+    // everything before this has been decompressed and handed over
+    cursor: usize,
+    // the index of the next job to use
+    next_index: usize,
+    // jobs that finished ahead of one before them
+    finished: BTreeMap<usize, Decoded>
+}
+
+impl<'a> Collector<'a> {
+    /// Wait for a worker to finish a job, then use every job that is
+    /// next in order
+    fn receive<F>(&mut self, pool: &Pool<Job, Decoded>, callback: &mut F)
+            -> Result<(), GzError>
+            where F: FnMut(&[u8]) -> bool {
+        let decoded = match pool.recv() {
+            Some(decoded) => decoded,
+            None => return Err(worker_failed())
+        };
+        self.finished.insert(decoded.index, decoded);
+        while let Some(decoded) = self.finished.remove(&self.next_index) {
+            self.next_index += 1;
+            // members the jobs got wrong come first
+            try!(self.catch_up(decoded.start, callback));
+            if decoded.start != self.cursor {
+                continue;
+            }
+            if let Some(ref out) = decoded.out {
+                if !callback(out.as_slice()) {
+                    return Err(GzError::new(ErrorKind::Aborted, self.cursor));
+                }
+                self.cursor = decoded.end;
+            }
+        }
+        Ok(())
+    }
+
+    /// Decompress members one at a time until the cursor reaches at
+    /// least up to
+    fn catch_up<F>(&mut self, up_to: usize, callback: &mut F) -> Result<(), GzError>
+            where F: FnMut(&[u8]) -> bool {
+        while self.cursor < up_to {
+            let (out, used) = match decode_member(&self.data[self.cursor ..]) {
+                Ok(result) => result,
+                Err(e) => return Err(GzError::new(e.kind, self.cursor + e.position))
+            };
+            if !callback(out.as_slice()) {
+                return Err(GzError::new(ErrorKind::Aborted, self.cursor));
+            }
+            self.cursor += used;
+        }
+        Ok(())
+    }
+}
+
+/// Decompress one job, on a worker thread
+fn decode_job(job: Job) -> Decoded {
+    Decoded {
+        index: job.index,
+        start: job.start,
+        end: job.start + job.data.len(),
+        out: decode_members(job.data.as_slice())
+    }
+}
+
+/// Decompress data, if it is made of whole members and nothing else
+fn decode_members(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        match decode_member(&data[pos ..]) {
+            Ok((member, used)) => {
+                out.push_all(member.as_slice());
+                pos += used;
+            },
+            Err(..) => return None
+        }
+    }
+    Some(out)
+}
+
+/// Decompress the member at the start of data, returning its output and
+/// how many bytes of data it took up
+fn decode_member(data: &[u8]) -> Result<(Vec<u8>, usize), GzError> {
+    let mut decompressor = Decompressor::new();
+    let mut out = Vec::new();
+    let mut buf = vec![0u8; OUTPUT_STEP];
+    let mut pos = 0;
+    loop {
+        let (used, written, status) = try!(decompressor.decompress(&data[pos ..],
+                                                                   buf.as_mut_slice()));
+        pos += used;
+        out.push_all(&buf[.. written]);
+        match status {
+            StreamStatus::MemberEnd => return Ok((out, pos)),
+            StreamStatus::NeedInput => {
+                return Err(GzError::new(ErrorKind::UnexpectedEof, decompressor.total_in()));
+            },
+            _ => {}
+        }
+    }
+}
+
+/// Where the member starting at pos ends, if there is a member at pos:
+/// exactly, from the BGZF block size if there is one, and otherwise at
+/// the next place that looks like a gzip header, or the end of data
+fn member_end_guess(data: &[u8], pos: usize) -> usize {
+    if let Ok(header) = gz::read_header(&data[pos ..]) {
+        let size = match header.extra_fields() {
+            Some(fields) => bgzf::block_size(fields.as_slice()),
+            None => None
+        };
+        if let Some(size) = size {
+            if pos + size <= data.len() {
+                return pos + size;
+            }
+        }
+    }
+    let mut next = pos + MIN_MEMBER_LEN;
+    while next + 3 < data.len() {
+        // the magic number, deflate, and no reserved flags
+        if data[next] == GZ_MAGIC_BYTES[0] && data[next + 1] == GZ_MAGIC_BYTES[1] &&
+                data[next + 2] == 8 && data[next + 3] & 0xe0 == 0 &&
+                gz::read_header(&data[next ..]).is_ok() {
+            return next;
+        }
+        next += 1;
+    }
+    data.len()
+}
+
+#[cfg(test)]
+mod parallel_decode_tests {
+    use super::{decompress_parallel, decompress_parallel_to_callback};
+    use encoder::compress;
+    use error::ErrorKind;
+    use read::GzDecoder;
+    use std::io::Read;
+    use testing::{text, bgzf};
+
+    /// Compress data as members of member_len bytes each
+    fn members(data: &[u8], member_len: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        for chunk in data.chunks(member_len) {
+            out.push_all(compress(chunk, 6).as_slice());
+        }
+        out
+    }
+
+    #[test]
+    fn test_bgzf() {
+        let data = text(20000);
+        let file = bgzf(data.as_slice(), 65280);
+        for &threads in [1, 2, 4].iter() {
+            assert!(decompress_parallel(file.as_slice(), threads).unwrap() == data);
+        }
+    }
+
+    #[test]
+    fn test_members() {
+        let data = text(20000);
+        for &member_len in [100, 5000, 70000, 1 << 20].iter() {
+            let file = members(data.as_slice(), member_len);
+            assert!(decompress_parallel(file.as_slice(), 3).unwrap() == data,
+                    "member length {}", member_len);
+        }
+    }
+
+    #[test]
+    fn test_false_headers() {
+        // stored members full of things that look like gzip headers
+        let mut data = Vec::new();
+        for i in 0 .. 50000 {
+            data.push_all(&[0x1f, 0x8b, 0x08, 0x00, i as u8, 0, 0, 0, 0, 3]);
+        }
+        let mut file = Vec::new();
+        for chunk in data.as_slice().chunks(100000) {
+            file.push_all(compress(chunk, 0).as_slice());
+        }
+        let mut expect = Vec::new();
+        GzDecoder::new(file.as_slice()).read_to_end(&mut expect).unwrap();
+        assert!(expect == data);
+        assert!(decompress_parallel(file.as_slice(), 4).unwrap() == data);
+    }
+
+    #[test]
+    fn test_callback_order() {
+        let data = text(20000);
+        let file = bgzf(data.as_slice(), 1000);
+        let mut out = Vec::new();
+        decompress_parallel_to_callback(file.as_slice(), 4, |chunk: &[u8]| {
+            out.push_all(chunk);
+            true
+        }).unwrap();
+        assert!(out == data);
+        let err = decompress_parallel_to_callback(file.as_slice(), 4, |_: &[u8]| false);
+        assert_eq!(err.unwrap_err().kind, ErrorKind::Aborted);
+    }
+
+    #[test]
+    fn test_errors() {
+        let data = text(20000);
+        let file = members(data.as_slice(), 5000);
+        // a bad CRC in the fifth member
+        let fifth_end = data.as_slice().chunks(5000).take(5)
+            .fold(0, |len, chunk| len + compress(chunk, 6).len());
+        let mut bad = file.clone();
+        bad[fifth_end - 7] ^= 1;
+        let err = decompress_parallel(bad.as_slice(), 3).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::CrcMismatch);
+        assert_eq!(err.position, fifth_end - 8);
+        let err = decompress_parallel(&file[.. file.len() - 3], 3).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::UnexpectedEof);
+        let mut garbage = file.clone();
+        garbage.push_all(b"trailing garbage");
+        assert_eq!(decompress_parallel(garbage.as_slice(), 3).unwrap_err().kind,
+                   ErrorKind::BadHeader);
+        assert_eq!(decompress_parallel(&[], 3).unwrap_err().kind, ErrorKind::UnexpectedEof);
+        assert_eq!(decompress_parallel(file.as_slice(), 0).unwrap_err().kind,
+                   ErrorKind::InvalidArgument);
+    }
+}
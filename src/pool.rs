@@ -0,0 +1,208 @@
+#[doc="
+
+    Module: pool
+
+    A pool of worker threads, each running the same work on whatever
+    jobs come along, for the modules that compress or decompress on
+    several threads. Results come back in the order they finish, so
+    callers number their jobs to put them back in order. A worker
+    that panics says so on its way out, rather than leaving the
+    caller waiting for a result that will never come.
+
+"]
+extern crate core;
+
+use self::core::raw::Slice as RawSlice;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::thread;
+
+use error::{GzError, ErrorKind};
+
+/// Worker threads running work on jobs of type J, giving results of type R
+pub struct Pool<J, R> {
+    jobs: Option<Sender<J>>,
+    // None is sent by a worker that panicked
+    results: Receiver<Option<R>>,
+    workers: Vec<thread::JoinHandle<()>>
+}
+
+impl<J: Send + 'static, R: Send + 'static> Pool<J, R> {
+    /// Start threads workers, running work on each job they are sent
+    pub fn new<F>(threads: usize, work: F) -> Pool<J, R>
+            where F: Fn(J) -> R + Send + Sync + 'static {
+        let (job_sender, job_receiver) = channel();
+        let (result_sender, result_receiver) = channel();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+        let work = Arc::new(work);
+        let mut workers = Vec::with_capacity(threads);
+        for _ in 0 .. threads {
+            let work = work.clone();
+            let jobs = job_receiver.clone();
+            let results = result_sender.clone();
+            workers.push(thread::spawn(move || worker(work, jobs, results)));
+        }
+        Pool { jobs: Some(job_sender), results: result_receiver, workers: workers }
+    }
+
+    /// Hand a job to the next free worker. False if every worker is gone.
+    pub fn send(&self, job: J) -> bool {
+        match self.jobs {
+            Some(ref jobs) => jobs.send(job).is_ok(),
+            None => false
+        }
+    }
+
+    /// Wait for a worker to finish a job. None if a worker panicked, in
+    /// which case the job it was working on will never finish.
+    pub fn recv(&self) -> Option<R> {
+        match self.results.recv() {
+            Ok(result) => result,
+            Err(..) => None
+        }
+    }
+
+    /// Wait for the workers to finish the jobs they have been sent, and
+    /// stop them. False if any of them panicked.
+    pub fn finish(mut self) -> bool {
+        self.stop()
+    }
+}
+
+impl<J, R> Pool<J, R> {
+    fn stop(&mut self) -> bool {
+        // hanging up on the workers lets them finish
+        self.jobs = None;
+        let workers = mem::replace(&mut self.workers, Vec::new());
+        let mut ok = true;
+        for worker in workers.into_iter() {
+            if worker.join().is_err() {
+                ok = false;
+            }
+        }
+        ok
+    }
+}
+
+#[unsafe_destructor]
+impl<J, R> Drop for Pool<J, R> {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Input that the workers of a pool read in place, rather than each
+/// being given a copy
+pub struct Borrowed {
+    ptr: *const u8,
+    len: usize
+}
+
+unsafe impl Send for Borrowed {}
+unsafe impl Sync for Borrowed {}
+
+impl Borrowed {
+    /// Lend data to the workers of a pool. Nothing ties the workers to
+    /// the lifetime of data, so the caller must finish or drop the pool,
+    /// which waits for them, before data goes away.
+    pub unsafe fn new(data: &[u8]) -> Borrowed {
+        Borrowed { ptr: data.as_ptr(), len: data.len() }
+    }
+
+    pub fn get(&self) -> &[u8] {
+        unsafe {
+            mem::transmute(RawSlice {
+                data: self.ptr,
+                len: self.len
+            })
+        }
+    }
+}
+
+/// The error for a pool that lost a worker
+pub fn worker_failed() -> GzError {
+    // no position to blame, only a thread that panicked
+    GzError::new(ErrorKind::Internal, 0)
+}
+
+/// Owns a worker's way back to the pool, and uses it to report a panic
+/// as the worker unwinds
+struct PanicAlarm<R> {
+    results: Sender<Option<R>>
+}
+
+#[unsafe_destructor]
+impl<R> Drop for PanicAlarm<R> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            let _ = self.results.send(None);
+        }
+    }
+}
+
+/// Run work on jobs until the pool hangs up
+fn worker<J, R, F>(work: Arc<F>, jobs: Arc<Mutex<Receiver<J>>>, results: Sender<Option<R>>)
+        where F: Fn(J) -> R {
+    let alarm = PanicAlarm { results: results };
+    loop {
+        let job = match jobs.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(..) => return
+        };
+        if alarm.results.send(Some((*work)(job))).is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod pool_tests {
+    use super::{Pool, Borrowed};
+
+    #[test]
+    fn test_results() {
+        let pool = Pool::new(3, |job: u32| job * 2);
+        for job in 0 .. 100 {
+            assert!(pool.send(job));
+        }
+        let mut results: Vec<u32> = (0 .. 100).map(|_| pool.recv().unwrap()).collect();
+        results.sort();
+        assert!(results == (0 .. 100).map(|job| job * 2).collect::<Vec<u32>>());
+        assert!(pool.finish());
+    }
+
+    #[test]
+    fn test_borrowed() {
+        let data: Vec<u8> = (0 .. 200).map(|i| i as u8).collect();
+        let input = unsafe { Borrowed::new(data.as_slice()) };
+        let pool = Pool::new(4, move |job: usize| input.get()[job]);
+        for job in 0 .. data.len() {
+            pool.send(job);
+        }
+        let mut results: Vec<u8> = (0 .. data.len()).map(|_| pool.recv().unwrap()).collect();
+        assert!(pool.finish());
+        results.sort();
+        assert!(results == data);
+    }
+
+    #[test]
+    fn test_panic() {
+        let pool = Pool::new(2, |job: u32| {
+            if job == 5 {
+                panic!("job 5 fails");
+            }
+            job
+        });
+        for job in 0 .. 10 {
+            pool.send(job);
+        }
+        // the result of job 5 never comes, but the caller hears why
+        let mut received = 0;
+        while let Some(..) = pool.recv() {
+            received += 1;
+        }
+        assert!(received < 10);
+        assert!(!pool.finish());
+    }
+}
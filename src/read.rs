@@ -0,0 +1,277 @@
+#[doc="
+
+    Module: read
+
+    This module provides GzDecoder, which wraps anything that
+    implements std::io::Read holding gzip data, and is itself a
+    Read that yields the decompressed bytes as they are asked for.
+    Only a small buffer of compressed data and the 32K window are
+    held in memory, however large the stream is.
+    Input, that small buffer, is shared with the readers in index.
+
+"]
+use std::io;
+use std::io::Read;
+
+use error::{GzError, ErrorKind};
+use header::GZHeader;
+use stream::{Decompressor, StreamStatus};
+
+// how much compressed data to read from the inner reader at a time
+const INPUT_BUF_SIZE: usize = 32768;
+
+/// Decompresses the gzip stream read from R. Members that follow one
+/// another are decompressed one after the other, as gunzip does.
+pub struct GzDecoder<R> {
+    input: Input<R>,
+    decompressor: Decompressor,
+    done: bool
+}
+
+impl<R: Read> GzDecoder<R> {
+    pub fn new(reader: R) -> GzDecoder<R> {
+        GzDecoder {
+            input: Input::new(reader),
+            decompressor: Decompressor::new(),
+            done: false
+        }
+    }
+
+    /// The header of the member currently being read, once it has been
+    /// parsed
+    pub fn header(&self) -> Option<&GZHeader> {
+        self.decompressor.header()
+    }
+
+    pub fn get_ref(&self) -> &R {
+        self.input.get_ref()
+    }
+
+    /// Give back the inner reader. Any compressed data that has been read
+    /// from it but not decompressed is lost.
+    pub fn into_inner(self) -> R {
+        self.input.into_inner()
+    }
+}
+
+impl<R: Read> Read for GzDecoder<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if out.len() == 0 || self.done {
+            return Ok(0);
+        }
+        loop {
+            try!(self.input.fill());
+            let (used, written, status) = match self.decompressor.decompress(
+                    self.input.data(), out) {
+                Ok(result) => result,
+                Err(e) => return Err(e.to_io_error())
+            };
+            self.input.consume(used);
+            match status {
+                StreamStatus::OutputFull => return Ok(written),
+                StreamStatus::MemberEnd => {
+                    // another member may follow
+                    try!(self.input.fill());
+                    if self.input.data().len() == 0 {
+                        self.done = true;
+                        return Ok(written);
+                    }
+                    self.decompressor.next_member();
+                },
+                StreamStatus::NeedInput => {
+                    // what was decompressed is handed out first, and the
+                    // error given on the next call
+                    if self.input.eof() && written == 0 {
+                        let position = self.decompressor.total_in();
+                        return Err(GzError::new(ErrorKind::UnexpectedEof, position).to_io_error());
+                    }
+                }
+            }
+            if written > 0 {
+                return Ok(written);
+            }
+        }
+    }
+}
+
+/// Compressed data read from R a buffer at a time, for the readers that
+/// hand it to a Decompressor
+pub struct Input<R> {
+    reader: R,
+    buf: Vec<u8>,
+    // the data waiting to be used is buf[pos .. len]
+    pos: usize,
+    len: usize,
+    eof: bool
+}
+
+impl<R: Read> Input<R> {
+    pub fn new(reader: R) -> Input<R> {
+        Input {
+            reader: reader,
+            buf: vec![0u8; INPUT_BUF_SIZE],
+            pos: 0,
+            len: 0,
+            eof: false
+        }
+    }
+
+    /// The data read but not yet used
+    pub fn data(&self) -> &[u8] {
+        &self.buf[self.pos .. self.len]
+    }
+
+    pub fn consume(&mut self, n: usize) {
+        self.pos += n;
+    }
+
+    /// Whether the reader has run out
+    pub fn eof(&self) -> bool {
+        self.eof
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    /// Give back the reader. Data read from it but not used is lost.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Read more data, once what was read before is used up
+    pub fn fill(&mut self) -> io::Result<()> {
+        if self.pos < self.len || self.eof {
+            return Ok(());
+        }
+        self.pos = 0;
+        self.len = 0;
+        loop {
+            match self.reader.read(self.buf.as_mut_slice()) {
+                Ok(0) => {
+                    self.eof = true;
+                    return Ok(());
+                },
+                Ok(n) => {
+                    self.len = n;
+                    return Ok(());
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod gz_decoder_tests {
+    use super::GzDecoder;
+    use std::io;
+    use std::io::Read;
+    use testing::{COMPRESSED, EXPECTED};
+
+    // a single stored block
+    static STORED: &'static [u8] = &[
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x01, 0x1e,
+        0x00, 0xe1, 0xff, 0x73, 0x74, 0x6f, 0x72, 0x65, 0x64, 0x2c, 0x20, 0x6e,
+        0x6f, 0x74, 0x20, 0x63, 0x6f, 0x6d, 0x70, 0x72, 0x65, 0x73, 0x73, 0x65,
+        0x64, 0x20, 0x61, 0x74, 0x20, 0x61, 0x6c, 0x6c, 0x0a, 0x7e, 0x17, 0xe9,
+        0xb9, 0x1e, 0x00, 0x00, 0x00];
+
+    /// Hands out its data one byte per read
+    struct Trickle<'a> {
+        data: &'a [u8]
+    }
+
+    impl<'a> Read for Trickle<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.data.len() == 0 || buf.len() == 0 {
+                return Ok(0);
+            }
+            buf[0] = self.data[0];
+            self.data = &self.data[1 ..];
+            Ok(1)
+        }
+    }
+
+    fn read_all<R: Read>(mut decoder: GzDecoder<R>, step: usize) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        let mut buf = vec![0u8; step];
+        loop {
+            let n = try!(decoder.read(buf.as_mut_slice()));
+            if n == 0 {
+                return Ok(out);
+            }
+            out.push_all(&buf[.. n]);
+        }
+    }
+
+    #[test]
+    fn test_read() {
+        let out = read_all(GzDecoder::new(COMPRESSED), 4096).unwrap();
+        assert_eq!(out.as_slice(), EXPECTED);
+    }
+
+    #[test]
+    fn test_trickle() {
+        let out = read_all(GzDecoder::new(Trickle { data: COMPRESSED }), 1).unwrap();
+        assert_eq!(out.as_slice(), EXPECTED);
+    }
+
+    #[test]
+    fn test_stored() {
+        let out = read_all(GzDecoder::new(Trickle { data: STORED }), 5).unwrap();
+        assert_eq!(out.as_slice(), b"stored, not compressed at all\n");
+    }
+
+    #[test]
+    fn test_multiple_members() {
+        let mut input = COMPRESSED.to_vec();
+        input.push_all(STORED);
+        input.push_all(COMPRESSED);
+        let mut expect = EXPECTED.to_vec();
+        expect.push_all(b"stored, not compressed at all\n");
+        expect.push_all(EXPECTED);
+        let out = read_all(GzDecoder::new(input.as_slice()), 10).unwrap();
+        assert_eq!(out, expect);
+    }
+
+    #[test]
+    fn test_header() {
+        let mut decoder = GzDecoder::new(COMPRESSED);
+        assert!(decoder.header().is_none());
+        let mut buf = [0u8; 4];
+        decoder.read(&mut buf).unwrap();
+        assert_eq!(decoder.header().unwrap().os, 3);
+    }
+
+    #[test]
+    fn test_corrupt() {
+        let mut input = COMPRESSED.to_vec();
+        input[67] ^= 0xff;
+        let err = read_all(GzDecoder::new(input.as_slice()), 4096).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(format!("{}", err), "CRC32 does not match decompressed data at byte 67");
+    }
+
+    #[test]
+    fn test_truncated() {
+        let err = read_all(GzDecoder::new(&COMPRESSED[.. 50]), 4096).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = read_all(GzDecoder::new(&COMPRESSED[.. 0]), 4096).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        // without its footer, the whole output comes before the error
+        let mut decoder = GzDecoder::new(&COMPRESSED[.. COMPRESSED.len() - 8]);
+        let mut buf = [0u8; 4096];
+        assert_eq!(decoder.read(&mut buf).unwrap(), EXPECTED.len());
+        assert_eq!(&buf[.. EXPECTED.len()], EXPECTED);
+        assert_eq!(decoder.read(&mut buf).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_trailing_garbage() {
+        let mut input = COMPRESSED.to_vec();
+        input.push_all(b"garbage");
+        assert!(read_all(GzDecoder::new(input.as_slice()), 4096).is_err());
+    }
+}
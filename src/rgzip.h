@@ -3,9 +3,152 @@
 #ifndef __RGZIP_H
 #define __RGZIP_H
 
+#include <stddef.h>
+#include <stdint.h>
+#include <sys/types.h>
+
+#define RGZIP_OK 0
+#define RGZIP_BUF_TOO_SMALL 1
+#define RGZIP_ERROR -1
+#define RGZIP_ERR_TOO_SHORT -2
+#define RGZIP_ERR_HEADER -3
+#define RGZIP_ERR_DATA -4
+#define RGZIP_ERR_EOF -5
+#define RGZIP_ERR_CRC -6
+#define RGZIP_ERR_SIZE -7
+#define RGZIP_ERR_MEMORY -8
+#define RGZIP_ERR_OVERFLOW -9
+#define RGZIP_ERR_ABORTED -10
+#define RGZIP_ERR_LIMIT -11
+#define RGZIP_ERR_INTERNAL -12
+
+/* Where the buffers returned to the caller come from, along with the
+ * Huffman tables built while decompressing. rgzip_compress writes its
+ * output into memory from it as the output is produced. */
+typedef struct rgzip_allocator {
+    void * (*alloc)(void * opaque, size_t size);
+    void * (*realloc)(void * opaque, void * ptr, size_t size);
+    void (*free)(void * opaque, void * ptr);
+    void * opaque;
+} rgzip_allocator;
+
+/* The int-based functions fail with RGZIP_ERR_OVERFLOW if a length does
+ * not fit in an int; the _sz variants take and return size_t lengths. */
+
 void * decompress_gzip_to_heap(const void * buf,
     int buf_len,
     int * new_buf_len);
 
+void * decompress_gzip_to_heap_sz(const void * buf,
+    size_t buf_len,
+    size_t * new_buf_len);
+
+void * decompress_gzip_to_heap_with_allocator(const void * buf,
+    int buf_len,
+    int * new_buf_len,
+    const rgzip_allocator * allocator);
+
+void * decompress_gzip_to_heap_with_allocator_sz(const void * buf,
+    size_t buf_len,
+    size_t * new_buf_len,
+    const rgzip_allocator * allocator);
+
+int rgzip_decompress(const void * buf,
+    int buf_len,
+    void ** out_buf,
+    int * new_buf_len,
+    const rgzip_allocator * allocator);
+
+int rgzip_decompress_sz(const void * buf,
+    size_t buf_len,
+    void ** out_buf,
+    size_t * new_buf_len,
+    const rgzip_allocator * allocator);
+
+void rgzip_free(void * ptr, const rgzip_allocator * allocator);
+
+int decompress_gzip_to_buffer(const void * buf,
+    int buf_len,
+    void * out_buf,
+    int out_buf_len,
+    int * new_buf_len);
+
+int decompress_gzip_to_buffer_sz(const void * buf,
+    size_t buf_len,
+    void * out_buf,
+    size_t out_buf_len,
+    size_t * new_buf_len);
+
+/* Receives each chunk of output; returning non-zero stops decompression */
+typedef int (*rgzip_write_callback)(void * opaque, const void * buf, size_t len);
+
+int decompress_gzip_to_callback(const void * buf,
+    size_t buf_len,
+    rgzip_write_callback callback,
+    void * opaque);
+
+/* Header fields for rgzip_compress. Null strings and a null extra are
+ * left out. extra holds FEXTRA subfields: two id bytes, a two byte
+ * little endian length, then the data, for each one. */
+typedef struct rgzip_header {
+    const char * name;
+    const char * comment;
+    const unsigned char * extra;
+    size_t extra_len;
+    unsigned int mtime;
+    int os;
+    int text;
+    int header_crc;
+} rgzip_header;
+
+/* level is 0 (stored) to 9 (smallest); header may be null */
+int rgzip_compress(const void * buf,
+    size_t buf_len,
+    int level,
+    const rgzip_header * header,
+    void ** out_buf,
+    size_t * compressed_len,
+    const rgzip_allocator * allocator);
+
+/* What rgzip_verify found. The fields from failed_member on describe
+ * the first failure, and are only set when status is not RGZIP_OK:
+ * the member it was in, counting from 0, where that member starts,
+ * where the failure was found, and how much of the member's output was
+ * decoded before it, with that output's CRC. If the member's footer was
+ * reached, has_footer is non-zero and footer_crc and footer_size hold
+ * what it says. */
+typedef struct rgzip_verify_report {
+    int status;
+    size_t members;
+    uint64_t compressed_len;
+    uint64_t uncompressed_len;
+    uint64_t trailing_len;
+    size_t failed_member;
+    uint64_t member_offset;
+    uint64_t position;
+    uint64_t member_output;
+    unsigned int crc;
+    int has_footer;
+    unsigned int footer_crc;
+    unsigned int footer_size;
+} rgzip_verify_report;
+
+/* Check every member as gzip -t does, keeping none of the output */
+int rgzip_verify(const void * buf,
+    size_t buf_len,
+    rgzip_verify_report * report);
+
+/* Reads up to len bytes into buf; returns the number read, 0 at the end
+ * of the input, or a negative number on failure */
+typedef ssize_t (*rgzip_read_callback)(void * opaque, void * buf, size_t len);
+
+/* As rgzip_verify, reading the input through callback in constant memory */
+int rgzip_verify_callback(rgzip_read_callback callback,
+    void * opaque,
+    rgzip_verify_report * report);
+
+const char * rgzip_last_error(void);
+
+const char * rgzip_strerror(int code);
 
 #endif
@@ -0,0 +1,278 @@
+#[doc="
+
+    Module: seek
+
+    This module provides IndexedGzReader, which reads the
+    decompressed contents of a gzip file through std::io::Read and
+    std::io::Seek, as if it were the uncompressed file. Seeking is
+    cheap: it only records the new position. The next read picks up
+    from there, either by carrying on decompressing when the new
+    position is a little way ahead, or by starting again from the
+    GzIndex checkpoint before it.
+
+"]
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+
+use index::{GzIndex, CheckpointReader};
+
+/// Reads and seeks within the decompressed contents of the gzip file
+/// read from R, using an index of it
+pub struct IndexedGzReader<R> {
+    index: GzIndex,
+    // exactly one of these holds the inner reader
+    reader: Option<R>,
+    current: Option<CheckpointReader<R>>,
+    // the offset into the decompressed data of the next byte to read
+    pos: u64
+}
+
+impl<R: Read + Seek> IndexedGzReader<R> {
+    /// Read the gzip file in reader using an index already built for it,
+    /// or loaded from disk
+    pub fn new(reader: R, index: GzIndex) -> IndexedGzReader<R> {
+        IndexedGzReader {
+            index: index,
+            reader: Some(reader),
+            current: None,
+            pos: 0
+        }
+    }
+
+    /// Build an index of the whole of reader, with a checkpoint about
+    /// every span bytes of output, and read through it
+    pub fn build(mut reader: R, span: u64) -> io::Result<IndexedGzReader<R>> {
+        try!(reader.seek(SeekFrom::Start(0)));
+        let index = try!(GzIndex::build(&mut reader, span));
+        Ok(IndexedGzReader::new(reader, index))
+    }
+
+    /// The index, to be written out and used again later
+    pub fn index(&self) -> &GzIndex {
+        &self.index
+    }
+
+    pub fn into_inner(self) -> R {
+        match self.current {
+            Some(current) => current.into_inner(),
+            None => self.reader.unwrap()
+        }
+    }
+
+    /// Get the decompression under way to the current position, starting
+    /// again from a checkpoint if that is nearer
+    fn catch_up(&mut self) -> io::Result<()> {
+        let restart = match self.current {
+            Some(ref current) => {
+                current.position() > self.pos ||
+                    self.index.checkpoint_for(self.pos).out_offset > current.position()
+            },
+            None => true
+        };
+        if restart {
+            let mut reader = match self.current.take() {
+                Some(current) => current.into_inner(),
+                None => self.reader.take().unwrap()
+            };
+            let checkpoint = self.index.checkpoint_for(self.pos);
+            if let Err(e) = reader.seek(SeekFrom::Start(checkpoint.bit_offset / 8)) {
+                self.reader = Some(reader);
+                return Err(e);
+            }
+            self.current = Some(CheckpointReader::new(reader, checkpoint));
+        }
+        let current = self.current.as_mut().unwrap();
+        let behind = self.pos - current.position();
+        if try!(current.skip(behind)) < behind {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "gzip data is shorter than its index"));
+        }
+        Ok(())
+    }
+
+    /// Drop the decompression under way, after an error
+    fn reset(&mut self) {
+        if let Some(current) = self.current.take() {
+            self.reader = Some(current.into_inner());
+        }
+    }
+}
+
+impl<R: Read + Seek> Read for IndexedGzReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if out.len() == 0 || self.pos >= self.index.len() {
+            return Ok(0);
+        }
+        let result = match self.catch_up() {
+            Ok(()) => self.current.as_mut().unwrap().read(out),
+            Err(e) => Err(e)
+        };
+        match result {
+            Ok(n) => {
+                self.pos += n as u64;
+                Ok(n)
+            },
+            Err(e) => {
+                self.reset();
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<R: Read + Seek> Seek for IndexedGzReader<R> {
+    /// Move to a new offset in the decompressed data. As with files,
+    /// seeking past the end is allowed, and reads there return nothing.
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => add_signed(self.index.len(), delta),
+            SeekFrom::Current(delta) => add_signed(self.pos, delta)
+        };
+        match target {
+            Some(target) => {
+                self.pos = target;
+                Ok(target)
+            },
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                       "seek to before the start of the data"))
+        }
+    }
+}
+
+/// base + delta, or None if that is negative or too large
+fn add_signed(base: u64, delta: i64) -> Option<u64> {
+    if delta >= 0 {
+        base.checked_add(delta as u64)
+    } else {
+        base.checked_sub((-(delta + 1)) as u64 + 1)
+    }
+}
+
+#[cfg(test)]
+mod indexed_gz_reader_tests {
+    use super::IndexedGzReader;
+    use encoder::compress;
+    use index::GzIndex;
+    use std::io::{Cursor, Read, Seek, SeekFrom};
+    use testing::{Lcg, text};
+
+    fn read_exactly<R: Read>(reader: &mut R, len: usize) -> Vec<u8> {
+        let mut out = vec![0u8; len];
+        let mut done = 0;
+        while done < len {
+            let n = reader.read(&mut out[done ..]).unwrap();
+            if n == 0 {
+                break;
+            }
+            done += n;
+        }
+        out.truncate(done);
+        out
+    }
+
+    #[test]
+    fn test_random_seeks() {
+        let data = text(20000);
+        let compressed = compress(data.as_slice(), 6);
+        let mut reader = IndexedGzReader::build(Cursor::new(compressed.as_slice()), 65536).unwrap();
+        let len = data.len() as u64;
+        let mut random = Lcg::new(777);
+        for i in 0 .. 100 {
+            let offset = random.next() as u64 % len;
+            let pos = match i % 3 {
+                0 => SeekFrom::Start(offset),
+                1 => SeekFrom::End(offset as i64 - len as i64),
+                _ => {
+                    let current = reader.seek(SeekFrom::Current(0)).unwrap();
+                    SeekFrom::Current(offset as i64 - current as i64)
+                }
+            };
+            assert_eq!(reader.seek(pos).unwrap(), offset);
+            let step = (random.next() % 3000) as usize;
+            let got = read_exactly(&mut reader, step);
+            let end = if offset as usize + step < data.len() { offset as usize + step } else { data.len() };
+            assert!(got.as_slice() == &data[offset as usize .. end], "offset {}", offset);
+        }
+    }
+
+    #[test]
+    fn test_sequential_read() {
+        let data = text(20000);
+        let compressed = compress(data.as_slice(), 9);
+        let mut reader = IndexedGzReader::build(Cursor::new(compressed.as_slice()), 100000).unwrap();
+        reader.seek(SeekFrom::Start(12345)).unwrap();
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert!(rest.as_slice() == &data[12345 ..]);
+        // and back to the start
+        reader.seek(SeekFrom::Start(0)).unwrap();
+        assert!(read_exactly(&mut reader, 100).as_slice() == &data[.. 100]);
+    }
+
+    #[test]
+    fn test_saved_index() {
+        let data = text(20000);
+        let compressed = compress(data.as_slice(), 6);
+        let mut saved = Vec::new();
+        GzIndex::build(compressed.as_slice(), 65536).unwrap().write_to(&mut saved).unwrap();
+        let index = GzIndex::read_from(saved.as_slice()).unwrap();
+        let mut reader = IndexedGzReader::new(Cursor::new(compressed.as_slice()), index);
+        reader.seek(SeekFrom::End(-10)).unwrap();
+        assert!(read_exactly(&mut reader, 100).as_slice() == &data[data.len() - 10 ..]);
+    }
+
+    #[test]
+    fn test_binary_search() {
+        // find a line by the number it starts with, as by timestamp in a log
+        let data = text(20000);
+        let compressed = compress(data.as_slice(), 6);
+        let mut reader = IndexedGzReader::build(Cursor::new(compressed.as_slice()), 65536).unwrap();
+        let wanted = b"00031416";
+        let (mut low, mut high) = (0, data.len() as u64);
+        while high - low > 1 {
+            let mid = (low + high) / 2;
+            reader.seek(SeekFrom::Start(mid)).unwrap();
+            // the first whole line after mid
+            let chunk = read_exactly(&mut reader, 100);
+            let start = chunk.iter().position(|&b| b == b'\n').unwrap() + 1;
+            if &chunk[start .. start + 8] <= wanted {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+        reader.seek(SeekFrom::Start(low)).unwrap();
+        let chunk = read_exactly(&mut reader, 100);
+        let start = chunk.iter().position(|&b| b == b'\n').unwrap() + 1;
+        assert_eq!(&chunk[start .. start + 8], wanted);
+    }
+
+    #[test]
+    fn test_past_end() {
+        let data = text(20000);
+        let compressed = compress(data.as_slice(), 6);
+        let mut reader = IndexedGzReader::build(Cursor::new(compressed.as_slice()), 65536).unwrap();
+        let len = data.len() as u64;
+        assert_eq!(reader.seek(SeekFrom::End(10)).unwrap(), len + 10);
+        assert_eq!(read_exactly(&mut reader, 10).len(), 0);
+        assert!(reader.seek(SeekFrom::End(-(len as i64) - 1)).is_err());
+        assert!(reader.seek(SeekFrom::Current(-1)).is_ok());
+        assert_eq!(reader.seek(SeekFrom::Current(0)).unwrap(), len + 9);
+    }
+
+    #[test]
+    fn test_truncated_file() {
+        let data = text(20000);
+        let compressed = compress(data.as_slice(), 6);
+        let index = GzIndex::build(compressed.as_slice(), 65536).unwrap();
+        let short = &compressed[.. compressed.len() / 2];
+        let mut reader = IndexedGzReader::new(Cursor::new(short), index);
+        reader.seek(SeekFrom::End(-100)).unwrap();
+        let mut buf = [0u8; 100];
+        assert!(reader.read(&mut buf).is_err());
+        // the start can still be read
+        reader.seek(SeekFrom::Start(0)).unwrap();
+        assert!(read_exactly(&mut reader, 100).as_slice() == &data[.. 100]);
+    }
+}
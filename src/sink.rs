@@ -0,0 +1,458 @@
+#[doc="
+
+    Module: sink
+
+    This module provides the places inflate can send decompressed
+    data. A Buf or VecSink simply holds all of it, while a Window
+    only keeps what back pointers can refer to and what has not been
+    taken yet. A CallbackSink hands the output in its Window to a
+    callback in chunks as it is produced. A sink is also told where
+    each block starts and ends, and where each token ends, which
+    most ignore. Inflating into a Buf takes its working memory from
+    the Buf's own Allocator.
+
+"]
+use cvec::{Buf, Allocator};
+use std::cmp;
+use crc32::Crc32;
+use error::ErrorKind;
+
+// back pointers can reach at most this far into the past
+pub const WINDOW_SIZE: usize = 32768;
+// how much output to collect before passing it on
+const CHUNK_SIZE: usize = 65536;
+
+/// Somewhere to put decompressed bytes
+pub trait Sink {
+    /// Add a byte to the output
+    /// returns None if the byte could not be stored
+    fn push(&mut self, value: u8) -> Option<()>;
+
+    /// Add length bytes, copied from distance + 1 bytes back from the end
+    /// of the output
+    /// returns None if distance is too far back, or the bytes could not be
+    /// stored
+    fn copy_back_pointer(&mut self, distance: usize, length: usize) -> Option<()>;
+
+    /// Why the sink refused output, if it did
+    fn failure(&self) -> Option<ErrorKind>;
+
+    /// A block is starting at bit start, with the given BFINAL and BTYPE,
+    /// and its codes or stored bytes start at bit data_start. Bit positions
+    /// are counted from the start of the stream's buffer. Only sinks that
+    /// look at the structure of the stream need to do anything.
+    fn block_start(&mut self, _start: usize, _last: bool, _block_type: u32, _data_start: usize) {}
+
+    /// The block about to start is a dynamic one, whose header gives
+    /// hclen + 4 code length code lengths and these code lengths
+    fn dynamic_lengths(&mut self, _hclen: u32, _code_length_lengths: &[u32],
+                       _literal_lengths: &[u32], _distance_lengths: &[u32]) {}
+
+    /// The block that last started has ended, just before bit end
+    fn block_end(&mut self, _end: usize) {}
+
+    /// The byte or back pointer just given to the sink was the whole of
+    /// a token, which ended just before bit end. Called by inflate_blocks,
+    /// for sinks that look at the LZ77 tokens themselves.
+    fn token_end(&mut self, _end: usize) {}
+
+    /// Where inflate_blocks should get the memory it works in
+    fn allocator(&self) -> Allocator {
+        Allocator::libc()
+    }
+}
+
+impl Sink for Buf {
+    #[inline]
+    fn push(&mut self, value: u8) -> Option<()> {
+        self.push(value)
+    }
+
+    fn copy_back_pointer(&mut self, distance: usize, length: usize) -> Option<()> {
+        self.copy_back_pointer(distance, length)
+    }
+
+    fn failure(&self) -> Option<ErrorKind> {
+        if_opt!(self.grow_failed(), ErrorKind::OutOfMemory)
+    }
+
+    fn allocator(&self) -> Allocator {
+        self.allocator()
+    }
+}
+
+/// Collects output in a Vec, refusing to grow past a limit
+pub struct VecSink {
+    buf: Vec<u8>,
+    limit: usize,
+    // set once output has been refused for going past the limit
+    refused: bool
+}
+
+impl VecSink {
+    pub fn new(capacity: usize, limit: usize) -> VecSink {
+        VecSink {
+            buf: Vec::with_capacity(capacity),
+            limit: limit,
+            refused: false
+        }
+    }
+
+    pub fn into_vec(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+impl Sink for VecSink {
+    #[inline]
+    fn push(&mut self, value: u8) -> Option<()> {
+        if self.buf.len() >= self.limit {
+            self.refused = true;
+            return None;
+        }
+        self.buf.push(value);
+        Some(())
+    }
+
+    fn copy_back_pointer(&mut self, distance: usize, length: usize) -> Option<()> {
+        let len = self.buf.len();
+        if distance >= len {
+            return None;
+        }
+        let mut back_ptr = len - distance - 1;
+        for _ in 0 .. length {
+            let c = self.buf[back_ptr];
+            try_opt!(self.push(c));
+            back_ptr += 1;
+        }
+        Some(())
+    }
+
+    fn failure(&self) -> Option<ErrorKind> {
+        if_opt!(self.refused, ErrorKind::LimitExceeded)
+    }
+}
+
+/// Holds output until it is taken, along with as much of the output
+/// before it as back pointers can reach
+pub struct Window {
+    buf: Vec<u8>,
+    // index into buf of the first byte not yet taken
+    pending: usize
+}
+
+impl Window {
+    pub fn new() -> Window {
+        Window {
+            buf: Vec::with_capacity(WINDOW_SIZE + CHUNK_SIZE),
+            pending: 0
+        }
+    }
+
+    /// A window that already holds history, the output before this
+    /// point, for back pointers to refer to
+    pub fn with_history(history: &[u8]) -> Window {
+        let mut window = Window::new();
+        window.buf.push_all(history);
+        window.pending = history.len();
+        window
+    }
+
+    /// The most recent output, taken or not, as far back as back pointers
+    /// can reach
+    pub fn history(&self) -> &[u8] {
+        let len = self.buf.len();
+        &self.buf[len - cmp::min(len, WINDOW_SIZE) ..]
+    }
+
+    /// The output that has not been taken yet
+    pub fn pending(&self) -> &[u8] {
+        &self.buf[self.pending ..]
+    }
+
+    pub fn pending_len(&self) -> usize {
+        self.buf.len() - self.pending
+    }
+
+    /// Mark the first n pending bytes as taken
+    pub fn consume(&mut self, n: usize) {
+        assert!(n <= self.pending_len());
+        self.pending += n;
+        if self.pending >= WINDOW_SIZE + CHUNK_SIZE {
+            self.slide();
+        }
+    }
+
+    /// The byte distance + 1 bytes back from the end of the output
+    #[inline]
+    fn back(&self, distance: usize) -> Option<u8> {
+        let len = self.buf.len();
+        if_opt!(distance < len, self.buf[len - distance - 1])
+    }
+
+    /// Drop taken output that back pointers can no longer reach
+    fn slide(&mut self) {
+        let start = self.pending - WINDOW_SIZE;
+        let len = self.buf.len();
+        for i in 0 .. len - start {
+            self.buf[i] = self.buf[start + i];
+        }
+        self.buf.truncate(len - start);
+        self.pending = WINDOW_SIZE;
+    }
+}
+
+impl Sink for Window {
+    #[inline]
+    fn push(&mut self, value: u8) -> Option<()> {
+        self.buf.push(value);
+        Some(())
+    }
+
+    fn copy_back_pointer(&mut self, distance: usize, length: usize) -> Option<()> {
+        for _ in 0 .. length {
+            let c = try_opt!(self.back(distance));
+            self.buf.push(c);
+        }
+        Some(())
+    }
+
+    fn failure(&self) -> Option<ErrorKind> {
+        None
+    }
+}
+
+/// Passes output to a callback in chunks of at most CHUNK_SIZE bytes.
+/// The callback returns false to stop decompression.
+pub struct CallbackSink<F> {
+    callback: F,
+    window: Window,
+    crc: Crc32,
+    total_len: usize,
+    aborted: bool
+}
+
+impl<F: FnMut(&[u8]) -> bool> CallbackSink<F> {
+    pub fn new(callback: F) -> CallbackSink<F> {
+        CallbackSink {
+            callback: callback,
+            window: Window::new(),
+            crc: Crc32::new(),
+            total_len: 0,
+            aborted: false
+        }
+    }
+
+    /// Pass any remaining output to the callback
+    /// returns None if the callback asked to stop
+    pub fn finish(&mut self) -> Option<()> {
+        self.flush()
+    }
+
+    /// The CRC of all the output passed to the callback
+    pub fn crc(&self) -> u32 {
+        self.crc.crc()
+    }
+
+    /// The number of bytes passed to the callback
+    pub fn total_len(&self) -> usize {
+        self.total_len
+    }
+
+    /// Pass pending output to the callback
+    fn flush(&mut self) -> Option<()> {
+        if self.aborted {
+            return None;
+        }
+        let len = self.window.pending_len();
+        if len > 0 {
+            let chunk = self.window.pending();
+            self.crc.update(chunk);
+            self.total_len += len;
+            if !(self.callback)(chunk) {
+                self.aborted = true;
+                return None;
+            }
+        }
+        self.window.consume(len);
+        Some(())
+    }
+}
+
+impl<F: FnMut(&[u8]) -> bool> Sink for CallbackSink<F> {
+    #[inline]
+    fn push(&mut self, value: u8) -> Option<()> {
+        if self.window.pending_len() == CHUNK_SIZE {
+            try_opt!(self.flush());
+        }
+        self.window.push(value)
+    }
+
+    fn copy_back_pointer(&mut self, distance: usize, length: usize) -> Option<()> {
+        for _ in 0 .. length {
+            let c = try_opt!(self.window.back(distance));
+            try_opt!(self.push(c));
+        }
+        Some(())
+    }
+
+    fn failure(&self) -> Option<ErrorKind> {
+        if_opt!(self.aborted, ErrorKind::Aborted)
+    }
+}
+
+#[cfg(test)]
+mod buf_sink_tests {
+    use super::Sink;
+    use cvec::CVec;
+    use error::ErrorKind;
+
+    #[test]
+    fn test_failure() {
+        let mut backing = [0u8; 2];
+        let mut buf = unsafe { CVec::from_raw_buf_mut(backing.as_mut_ptr(), 2).unwrap() };
+        Sink::push(&mut buf, 1).unwrap();
+        Sink::push(&mut buf, 2).unwrap();
+        // full, but a bad back pointer is not a lack of room
+        assert_eq!(Sink::copy_back_pointer(&mut buf, 2, 1), None);
+        assert_eq!(buf.failure(), None);
+        assert_eq!(Sink::push(&mut buf, 3), None);
+        assert_eq!(buf.failure(), Some(ErrorKind::OutOfMemory));
+    }
+}
+
+#[cfg(test)]
+mod vec_sink_tests {
+    use super::{Sink, VecSink};
+    use error::ErrorKind;
+
+    #[test]
+    fn test_limit() {
+        let mut sink = VecSink::new(0, 4);
+        sink.push(1).unwrap();
+        sink.push(2).unwrap();
+        assert_eq!(sink.copy_back_pointer(1, 2), Some(()));
+        // at the limit, but nothing has been refused yet
+        assert_eq!(sink.failure(), None);
+        // so a bad back pointer is not blamed on the limit
+        assert_eq!(sink.copy_back_pointer(4, 1), None);
+        assert_eq!(sink.failure(), None);
+        assert_eq!(sink.push(3), None);
+        assert_eq!(sink.failure(), Some(ErrorKind::LimitExceeded));
+        assert_eq!(sink.into_vec(), vec![1, 2, 1, 2]);
+    }
+}
+
+#[cfg(test)]
+mod callback_sink_tests {
+    use super::{Sink, CallbackSink, CHUNK_SIZE, WINDOW_SIZE};
+    use crc32::Crc32;
+    use error::ErrorKind;
+
+    #[test]
+    fn test_chunks() {
+        let mut expect = Vec::new();
+        let mut chunks: Vec<Vec<u8>> = Vec::new();
+        {
+            let mut sink = CallbackSink::new(|chunk: &[u8]| {
+                chunks.push(chunk.to_vec());
+                true
+            });
+            for i in 0 .. 100000 {
+                sink.push((i % 251) as u8).unwrap();
+                expect.push((i % 251) as u8);
+            }
+            // reach all the way back across a flush
+            sink.copy_back_pointer(WINDOW_SIZE - 1, 1000).unwrap();
+            for i in 0 .. 1000 {
+                let c = expect[100000 - WINDOW_SIZE + i];
+                expect.push(c);
+            }
+            sink.finish().unwrap();
+            let mut crc = Crc32::new();
+            crc.update(expect.as_slice());
+            assert_eq!(sink.crc(), crc.crc());
+            assert_eq!(sink.total_len(), 101000);
+        }
+        assert!(chunks.iter().all(|c| c.len() <= CHUNK_SIZE));
+        let mut got = Vec::new();
+        for chunk in chunks.iter() {
+            got.push_all(chunk.as_slice());
+        }
+        assert_eq!(got, expect);
+    }
+
+    #[test]
+    fn test_back_pointer_out_of_range() {
+        let mut sink = CallbackSink::new(|_: &[u8]| true);
+        for i in 0 .. 10 {
+            sink.push(i).unwrap();
+        }
+        assert_eq!(sink.copy_back_pointer(9, 1), Some(()));
+        assert_eq!(sink.copy_back_pointer(11, 1), None);
+    }
+
+    #[test]
+    fn test_abort() {
+        let mut sink = CallbackSink::new(|_: &[u8]| false);
+        assert_eq!(sink.failure(), None);
+        let mut result = Some(());
+        for _ in 0 .. CHUNK_SIZE + 1 {
+            result = sink.push(0);
+            if result.is_none() {
+                break;
+            }
+        }
+        assert_eq!(result, None);
+        assert_eq!(sink.failure(), Some(ErrorKind::Aborted));
+        assert_eq!(sink.finish(), None);
+    }
+}
+
+#[cfg(test)]
+mod window_tests {
+    use super::{Sink, Window, WINDOW_SIZE, CHUNK_SIZE};
+
+    #[test]
+    fn test_pending() {
+        let mut window = Window::new();
+        window.push(1).unwrap();
+        window.push(2).unwrap();
+        window.copy_back_pointer(1, 3).unwrap();
+        assert_eq!(window.pending(), [1, 2, 1, 2, 1].as_slice());
+        window.consume(2);
+        assert_eq!(window.pending(), [1, 2, 1].as_slice());
+        // taken output can still be referred to
+        window.copy_back_pointer(4, 1).unwrap();
+        assert_eq!(window.pending(), [1, 2, 1, 1].as_slice());
+        assert_eq!(window.copy_back_pointer(6, 1), None);
+    }
+
+    #[test]
+    fn test_slide() {
+        let mut window = Window::new();
+        let total = WINDOW_SIZE + CHUNK_SIZE + 10;
+        for i in 0 .. total {
+            window.push((i % 251) as u8).unwrap();
+        }
+        window.consume(total - 5);
+        assert_eq!(window.pending_len(), 5);
+        window.copy_back_pointer(WINDOW_SIZE + 4, 1).unwrap();
+        let expect = ((total - WINDOW_SIZE - 5) % 251) as u8;
+        assert_eq!(window.pending()[5], expect);
+    }
+
+    #[test]
+    fn test_history() {
+        let mut window = Window::with_history(&[7, 8, 9]);
+        assert_eq!(window.pending_len(), 0);
+        window.copy_back_pointer(2, 2).unwrap();
+        assert_eq!(window.pending(), [7, 8].as_slice());
+        assert_eq!(window.history(), [7, 8, 9, 7, 8].as_slice());
+        for i in 0 .. WINDOW_SIZE {
+            window.push(i as u8).unwrap();
+        }
+        assert_eq!(window.history().len(), WINDOW_SIZE);
+        assert_eq!(window.history()[0], 0);
+    }
+}
@@ -0,0 +1,605 @@
+#[doc="
+
+    Module: speculative
+
+    This module decompresses a single gzip member on several threads
+    at once. Unlike the members of a multi-member file, the blocks of
+    one DEFLATE stream don't say where they start, and back pointers
+    reach into the output of the blocks before them, so the work can
+    only be split by guessing.
+
+    The compressed data is cut into chunks of a fixed size. A worker
+    looks for the first place at or after the start of its chunk
+    that parses as a stored block, or as a dynamic block whose codes
+    are all complete, and inflates from there until a block ends past
+    the start of the next chunk. Fixed blocks are too easily mistaken
+    to be worth looking for. The output before the chunk isn't known
+    yet, so back pointers into it are recorded as markers, each
+    naming a byte of the 32K window before the chunk.
+
+    The chunks are then taken in order. Where a chunk was inflated
+    from exactly where the one before it stopped, the guess was right,
+    and its markers are replaced with bytes from the window. Anywhere
+    else, the chunk is inflated again the ordinary way, from where the
+    one before it stopped and with the window it left. Either way the
+    output is what sequential decompression gives, and the CRC32 and
+    size in the footer are checked. Members after the first, if any,
+    are decompressed the same way in turn.
+
+"]
+use std::cmp;
+use std::collections::BTreeMap;
+use std::usize;
+
+use crc32::Crc32;
+use cvec::{Buf, Allocator, slice_buf};
+use error::{GzError, ErrorKind};
+use gz_reader::GzBitReader;
+use header;
+use header::GZIP_FOOTER_LEN;
+use huffman::is_complete;
+use inflate::{inflate_blocks, read_dynamic_header};
+use pool::{Pool, Borrowed, worker_failed};
+use sink::{Sink, Window, WINDOW_SIZE};
+
+// how much compressed data each worker starts from
+const CHUNK_SIZE: usize = 2097152;
+// output symbols from here up stand for a byte of the window before a chunk
+const MARKER_BASE: u16 = 256;
+
+/// A chunk of a member for a worker to inflate
+struct Job {
+    // the byte offset of the member the chunk belongs to
+    member: usize,
+    index: usize,
+    // the bit to start from, or to start looking for a block from
+    from: usize,
+    // inflate until a block ends at or past this bit
+    stop: usize,
+    // whether a block is known to start at from
+    exact: bool
+}
+
+/// What a worker made of a Job
+struct Chunk {
+    member: usize,
+    index: usize,
+    decoded: Option<Decoded>
+}
+
+/// Output inflated from a guessed block start
+struct Decoded {
+    // the bits inflated
+    start: usize,
+    end: usize,
+    // whether the last block of the member was among them
+    last: bool,
+    // the output, with markers for bytes from before start
+    symbols: Vec<u16>
+}
+
+/// Decompress a gzip file on threads worker threads, guessing where
+/// blocks start so that even a single member can be split between them.
+/// The output is the same as decompress() gives.
+pub fn decompress_speculative(data: &[u8], threads: usize) -> Result<Vec<u8>, GzError> {
+    let mut out = Vec::new();
+    try!(decompress_speculative_to_callback(data, threads, |chunk: &[u8]| {
+        out.push_all(chunk);
+        true
+    }));
+    Ok(out)
+}
+
+/// As decompress_speculative, but passing the output of each chunk to
+/// callback in order, rather than collecting it. The callback returns
+/// false to stop decompression.
+pub fn decompress_speculative_to_callback<F>(data: &[u8], threads: usize, callback: F)
+        -> Result<(), GzError>
+        where F: FnMut(&[u8]) -> bool {
+    decompress_in_chunks(data, threads, CHUNK_SIZE, callback).map(|_| ())
+}
+
+/// Decompress data with workers starting every chunk_size bytes, and
+/// return how many chunks were used as the workers inflated them
+fn decompress_in_chunks<F>(data: &[u8], threads: usize, chunk_size: usize, mut callback: F)
+        -> Result<usize, GzError>
+        where F: FnMut(&[u8]) -> bool {
+    if threads == 0 || chunk_size == 0 {
+        return Err(GzError::new(ErrorKind::InvalidArgument, 0));
+    }
+    let buffer = match slice_buf(data) {
+        Some(buffer) => buffer,
+        None => return Err(GzError::new(ErrorKind::UnexpectedEof, 0))
+    };
+    // the pool is finished before data can go away
+    let input = unsafe { Borrowed::new(data) };
+    let workers = Workers {
+        pool: Pool::new(threads, move |job| inflate_job(input.get(), job)),
+        max_in_flight: 2 * threads,
+        chunk_size: chunk_size
+    };
+    let mut speculated = 0;
+    let mut pos = 0;
+    let mut result = Ok(());
+    while pos < data.len() && result.is_ok() {
+        result = match decompress_member(&buffer, pos, &workers, &mut callback) {
+            Ok((end, used)) => {
+                pos = end;
+                speculated += used;
+                Ok(())
+            },
+            Err(e) => Err(e)
+        };
+    }
+    if !workers.pool.finish() && result.is_ok() {
+        result = Err(worker_failed());
+    }
+    result.map(|_| speculated)
+}
+
+/// The way to the workers and back
+struct Workers {
+    pool: Pool<Job, Chunk>,
+    max_in_flight: usize,
+    chunk_size: usize
+}
+
+/// Decompress the member starting at byte start, returning where it ends
+/// and how many of its chunks were used as the workers inflated them
+fn decompress_member<F>(buffer: &Buf, start: usize, workers: &Workers, callback: &mut F)
+        -> Result<(usize, usize), GzError>
+        where F: FnMut(&[u8]) -> bool {
+    let header_len = match header_len(buffer, start) {
+        Some(len) => len,
+        None => return Err(GzError::new(ErrorKind::BadHeader, start))
+    };
+    let body = start + header_len;
+    let chunk_size = workers.chunk_size;
+    let count = cmp::max(1, (buffer.len() - body + chunk_size - 1) / chunk_size);
+    let first_bit = |index: usize| (body + index * chunk_size) * 8;
+    let stop_bit = |index: usize| if index + 1 < count { first_bit(index + 1) } else { usize::MAX };
+
+    let mut member = Member {
+        buffer: buffer,
+        cursor: body * 8,
+        window: Vec::new(),
+        crc: Crc32::new(),
+        len: 0,
+        last: false,
+        speculated: 0
+    };
+    let mut finished = BTreeMap::new();
+    let mut sent = 0;
+    let mut next = 0;
+    while next < count && !member.last {
+        while sent < count && sent - next < workers.max_in_flight {
+            let job = Job {
+                member: start,
+                index: sent,
+                from: first_bit(sent),
+                stop: stop_bit(sent),
+                exact: sent == 0
+            };
+            if !workers.pool.send(job) {
+                return Err(worker_failed());
+            }
+            sent += 1;
+        }
+        let chunk = match workers.pool.recv() {
+            Some(chunk) => chunk,
+            None => return Err(worker_failed())
+        };
+        // left over from the member before
+        if chunk.member != start {
+            continue;
+        }
+        finished.insert(chunk.index, chunk.decoded);
+        while let Some(decoded) = finished.remove(&next) {
+            try!(member.use_chunk(decoded, stop_bit(next), callback));
+            next += 1;
+            if member.last {
+                break;
+            }
+        }
+    }
+    member.check_footer().map(|end| (end, member.speculated))
+}
+
+/// Puts the output of the chunks of a member together, in order
+struct Member<'a> {
+    buffer: &'a Buf,
+    // everything before this bit has been decompressed and handed over
+    cursor: usize,
+    // the last of the output, for back pointers and markers to refer to
+    window: Vec<u8>,
+    crc: Crc32,
+    len: u64,
+    // whether the last block has been decompressed
+    last: bool,
+    speculated: usize
+}
+
+impl<'a> Member<'a> {
+    /// Carry on from the cursor up to the first block to end at or past
+    /// stop, using what a worker inflated if it started in the right place
+    fn use_chunk<F>(&mut self, decoded: Option<Decoded>, stop: usize, callback: &mut F)
+            -> Result<(), GzError>
+            where F: FnMut(&[u8]) -> bool {
+        if self.last || self.cursor >= stop {
+            // a long block carried the chunk before past this one
+            return Ok(());
+        }
+        let resolved = match decoded {
+            Some(ref decoded) if self.starts_here(decoded.start) => {
+                resolve(decoded.symbols.as_slice(), self.window.as_slice())
+                    .map(|out| (out, decoded.end, decoded.last))
+            },
+            _ => None
+        };
+        let (out, end, last) = match resolved {
+            Some(result) => {
+                self.speculated += 1;
+                result
+            },
+            None => try!(self.inflate_until(stop))
+        };
+        if !callback(out.as_slice()) {
+            return Err(GzError::new(ErrorKind::Aborted, self.cursor / 8));
+        }
+        self.crc.update(out.as_slice());
+        self.len += out.len() as u64;
+        if out.len() >= WINDOW_SIZE {
+            self.window = out[out.len() - WINDOW_SIZE ..].to_vec();
+        } else {
+            self.window.push_all(out.as_slice());
+            let excess = self.window.len() - cmp::min(self.window.len(), WINDOW_SIZE);
+            if excess > 0 {
+                self.window = self.window[excess ..].to_vec();
+            }
+        }
+        self.cursor = end;
+        self.last = last;
+        Ok(())
+    }
+
+    /// Whether inflating from start gives what inflating from the cursor
+    /// would. The header of a stored block can start anywhere in the zero
+    /// padding before its length, so a guess may be a bit or two out.
+    fn starts_here(&self, start: usize) -> bool {
+        start == self.cursor || match stored_header(self.buffer, start) {
+            Some(header) => stored_header(self.buffer, self.cursor) == Some(header),
+            None => false
+        }
+    }
+
+    /// Inflate from the cursor the ordinary way, returning the output,
+    /// where it stopped, and whether the last block was reached
+    fn inflate_until(&self, stop: usize) -> Result<(Vec<u8>, usize, bool), GzError> {
+        let mut stream = match GzBitReader::at(self.buffer, self.cursor) {
+            Some(stream) => stream,
+            None => return Err(GzError::new(ErrorKind::UnexpectedEof, self.buffer.len()))
+        };
+        let mut window = Window::with_history(self.window.as_slice());
+        match inflate_blocks(&mut stream, &mut window, stop) {
+            Some(last) => Ok((window.pending().to_vec(), stream.bit_position(), last)),
+            None => {
+                let kind = if stream.is_eof() {
+                    ErrorKind::UnexpectedEof
+                } else {
+                    match window.failure() {
+                        Some(kind) => kind,
+                        None => ErrorKind::CorruptData
+                    }
+                };
+                Err(GzError::new(kind, stream.position()))
+            }
+        }
+    }
+
+    /// Check the footer that follows the last block, returning where it ends
+    fn check_footer(&self) -> Result<usize, GzError> {
+        let footer = (self.cursor + 7) / 8;
+        if !self.last || footer + GZIP_FOOTER_LEN > self.buffer.len() {
+            return Err(GzError::new(ErrorKind::UnexpectedEof, self.buffer.len()));
+        }
+        if self.buffer.get_wide::<u32>(footer) != Some(self.crc.crc()) {
+            Err(GzError::new(ErrorKind::CrcMismatch, footer))
+        } else if self.buffer.get_wide::<u32>(footer + 4) != Some(self.len as u32) {
+            // the footer only holds the size modulo 2^32
+            Err(GzError::new(ErrorKind::SizeMismatch, footer + 4))
+        } else {
+            Ok(footer + GZIP_FOOTER_LEN)
+        }
+    }
+}
+
+/// Replace the markers in symbols with bytes from the window before them,
+/// or None if one refers to further back than the window goes
+fn resolve(symbols: &[u16], window: &[u8]) -> Option<Vec<u8>> {
+    // markers count from the start of a full window
+    let missing = WINDOW_SIZE - window.len();
+    let mut out = Vec::with_capacity(symbols.len());
+    for &symbol in symbols.iter() {
+        if symbol < MARKER_BASE {
+            out.push(symbol as u8);
+        } else {
+            let index = (symbol - MARKER_BASE) as usize;
+            if index < missing {
+                return None;
+            }
+            out.push(window[index - missing]);
+        }
+    }
+    Some(out)
+}
+
+/// Inflate one chunk of data, on a worker thread
+fn inflate_job(data: &[u8], job: Job) -> Chunk {
+    // data was checked not to be empty before the pool started
+    let buffer = slice_buf(data).unwrap();
+    let decoded = if job.exact {
+        decode_from(&buffer, job.from, job.stop)
+    } else {
+        search_from(&buffer, job.from, job.stop)
+    };
+    Chunk { member: job.member, index: job.index, decoded: decoded }
+}
+
+/// Inflate from the first place between from and stop that looks like a
+/// block start and inflates without error
+fn search_from(buffer: &Buf, from: usize, stop: usize) -> Option<Decoded> {
+    let end = cmp::min(stop, buffer.len() * 8);
+    for bit in from .. end {
+        if let Some((len_bit, true)) = stored_header(buffer, bit) {
+            // the final bit may really be the last bit of the block before
+            if stored_header(buffer, bit + 1) == Some((len_bit, false)) {
+                continue;
+            }
+        }
+        if looks_like_block(buffer, bit) {
+            if let Some(decoded) = decode_from(buffer, bit, stop) {
+                return Some(decoded);
+            }
+        }
+    }
+    None
+}
+
+/// Inflate from a block starting at start, until a block ends at or past
+/// stop, without knowing what came before
+fn decode_from(buffer: &Buf, start: usize, stop: usize) -> Option<Decoded> {
+    let mut stream = try_opt!(GzBitReader::at(buffer, start));
+    let mut sink = MarkerSink { symbols: Vec::new() };
+    let last = try_opt!(inflate_blocks(&mut stream, &mut sink, stop));
+    Some(Decoded {
+        start: start,
+        end: stream.bit_position(),
+        last: last,
+        symbols: sink.symbols
+    })
+}
+
+/// Whether a stored or dynamic block plausibly starts at bit
+fn looks_like_block(buffer: &Buf, bit: usize) -> bool {
+    let mut stream = match GzBitReader::at(buffer, bit) {
+        Some(stream) => stream,
+        None => return false
+    };
+    match (stream.next_bit(), stream.read_bits(2)) {
+        (Some(_), Some(0)) => stored_header(buffer, bit).is_some(),
+        (Some(_), Some(2)) => is_dynamic_header(buffer, stream.bit_position()),
+        _ => false
+    }
+}
+
+/// If a stored block plausibly starts at bit, where its length is and
+/// whether it is the last block
+fn stored_header(buffer: &Buf, bit: usize) -> Option<(usize, bool)> {
+    let mut stream = try_opt!(GzBitReader::at(buffer, bit));
+    let last = try_opt!(stream.next_bit()) == 1;
+    if try_opt!(stream.read_bits(2)) != 0 {
+        return None;
+    }
+    // encoders pad to the byte boundary with zeros
+    while stream.bit_position() % 8 != 0 {
+        if try_opt!(stream.next_bit()) != 0 {
+            return None;
+        }
+    }
+    let len_bit = stream.bit_position();
+    let len = try_opt!(stream.read_bits(16));
+    let nlen = try_opt!(stream.read_bits(16));
+    if_opt!(len == !nlen & 0xffff, (len_bit, last))
+}
+
+/// Whether the header of a dynamic block follows at bit, after the block
+/// type, describing complete codes as an encoder would
+fn is_dynamic_header(buffer: &Buf, bit: usize) -> bool {
+    // most false starts fail on the code length code, so check that
+    // before going to the trouble of reading the rest
+    let mut stream = match GzBitReader::at(buffer, bit) {
+        Some(stream) => stream,
+        None => return false
+    };
+    let (hlit, hdist, hclen) = match (stream.read_bits(5), stream.read_bits(5),
+                                      stream.read_bits(4)) {
+        (Some(hlit), Some(hdist), Some(hclen)) => (hlit, hdist, hclen),
+        _ => return false
+    };
+    if hlit > 29 || hdist > 29 {
+        return false;
+    }
+    let mut code_length_lengths = Vec::with_capacity(19);
+    for _ in 0 .. hclen + 4 {
+        match stream.read_bits(3) {
+            Some(len) => code_length_lengths.push(len),
+            None => return false
+        }
+    }
+    if !is_complete(code_length_lengths.as_slice()) {
+        return false;
+    }
+
+    let mut stream = match GzBitReader::at(buffer, bit) {
+        Some(stream) => stream,
+        None => return false
+    };
+    let header = match read_dynamic_header(&mut stream, Allocator::libc()) {
+        Some(header) => header,
+        None => return false
+    };
+    let distance_codes = header.distance_lengths.iter().filter(|&&len| len > 0).count();
+    header.literal_lengths[256] > 0 &&
+        is_complete(header.literal_lengths.as_slice()) &&
+        // a single distance code, or none at all, is allowed to be incomplete
+        (distance_codes <= 1 || is_complete(header.distance_lengths.as_slice()))
+}
+
+/// Collects output without knowing the output before it. Bytes are kept
+/// as they are, and back pointers to before the start become markers,
+/// MARKER_BASE plus the position of the byte in a 32K window.
+struct MarkerSink {
+    symbols: Vec<u16>
+}
+
+impl Sink for MarkerSink {
+    fn push(&mut self, value: u8) -> Option<()> {
+        self.symbols.push(value as u16);
+        Some(())
+    }
+
+    fn copy_back_pointer(&mut self, distance: usize, length: usize) -> Option<()> {
+        for _ in 0 .. length {
+            let len = self.symbols.len();
+            let symbol = if distance < len {
+                self.symbols[len - distance - 1]
+            } else {
+                // 0 is the last byte of the window
+                let before = distance - len;
+                if before >= WINDOW_SIZE {
+                    return None;
+                }
+                MARKER_BASE + (WINDOW_SIZE - 1 - before) as u16
+            };
+            self.symbols.push(symbol);
+        }
+        Some(())
+    }
+
+    fn failure(&self) -> Option<ErrorKind> {
+        None
+    }
+}
+
+/// The length of the gzip header starting at byte start
+fn header_len(buffer: &Buf, start: usize) -> Option<usize> {
+    if start >= buffer.len() {
+        return None;
+    }
+    let rest = try_opt!(slice_buf(&buffer.as_slice()[start ..]));
+    header::parse_header(&rest).map(|header| header.header_len)
+}
+
+#[cfg(test)]
+mod speculative_tests {
+    use super::{decompress_speculative, decompress_in_chunks};
+    use encoder::compress;
+    use error::ErrorKind;
+    use gz::decompress;
+    use testing::text;
+
+    fn collect(data: &[u8], threads: usize, chunk_size: usize) -> (Vec<u8>, usize) {
+        let mut out = Vec::new();
+        let speculated = decompress_in_chunks(data, threads, chunk_size, |chunk: &[u8]| {
+            out.push_all(chunk);
+            true
+        }).unwrap();
+        (out, speculated)
+    }
+
+    #[test]
+    fn test_matches_sequential() {
+        let data = text(40000);
+        for &level in [1, 6, 9].iter() {
+            let file = compress(data.as_slice(), level);
+            let expect = decompress(file.as_slice()).unwrap();
+            assert!(expect == data);
+            for &threads in [1, 3].iter() {
+                let (out, speculated) = collect(file.as_slice(), threads, 20000);
+                assert!(out == expect, "level {}, {} threads", level, threads);
+                // the first chunk is always used, and guesses at most others
+                assert!(speculated > 1, "level {}: {} chunks used", level, speculated);
+            }
+            assert!(decompress_speculative(file.as_slice(), 2).unwrap() == expect);
+        }
+    }
+
+    #[test]
+    fn test_stored_blocks() {
+        let data = text(40000);
+        let file = compress(&data[.. 300000], 0);
+        let (out, speculated) = collect(file.as_slice(), 4, 50000);
+        assert!(out.as_slice() == &data[.. 300000]);
+        assert!(speculated > 1);
+    }
+
+    #[test]
+    fn test_long_blocks() {
+        // chunks much smaller than a block, so that most are passed over
+        let data = text(40000);
+        let file = compress(data.as_slice(), 6);
+        let (out, _) = collect(file.as_slice(), 4, 1000);
+        assert!(out == data);
+    }
+
+    #[test]
+    fn test_fallback() {
+        // stored data full of what look like short final stored blocks, so
+        // that every guess after the first chunk is wrong
+        let mut data = Vec::new();
+        for i in 0 .. 40000 {
+            data.push_all(&[0x01, 0x04, 0x00, 0xfb, 0xff, i as u8, 0, 0, 0]);
+        }
+        let file = compress(data.as_slice(), 0);
+        assert!(decompress(file.as_slice()).unwrap() == data);
+        let (out, speculated) = collect(file.as_slice(), 3, 30000);
+        assert!(out == data);
+        assert_eq!(speculated, 1);
+    }
+
+    #[test]
+    fn test_members() {
+        let data = text(40000);
+        let mut file = compress(&data[.. 500000], 6);
+        file.push_all(compress(&data[500000 ..], 6).as_slice());
+        let (out, _) = collect(file.as_slice(), 3, 30000);
+        assert!(out == data);
+    }
+
+    #[test]
+    fn test_errors() {
+        let data = text(40000);
+        let file = compress(data.as_slice(), 6);
+        let len = file.len();
+        let mut bad = file.clone();
+        bad[len - 7] ^= 1;
+        let err = decompress_in_chunks(bad.as_slice(), 3, 20000, |_: &[u8]| true).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::CrcMismatch);
+        assert_eq!(err.position, len - 8);
+        let mut bad = file.clone();
+        bad[len - 1] ^= 1;
+        let err = decompress_in_chunks(bad.as_slice(), 3, 20000, |_: &[u8]| true).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::SizeMismatch);
+        let err = decompress_in_chunks(&file[.. len / 2], 3, 20000, |_: &[u8]| true).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::UnexpectedEof);
+        let err = decompress_in_chunks(file.as_slice(), 3, 20000, |_: &[u8]| false).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::Aborted);
+        let mut garbage = file.clone();
+        garbage.push_all(b"trailing garbage");
+        assert_eq!(decompress_speculative(garbage.as_slice(), 2).unwrap_err().kind,
+                   ErrorKind::BadHeader);
+        assert_eq!(decompress_speculative(&[], 2).unwrap_err().kind, ErrorKind::UnexpectedEof);
+        assert_eq!(decompress_speculative(file.as_slice(), 0).unwrap_err().kind,
+                   ErrorKind::InvalidArgument);
+    }
+}
@@ -0,0 +1,483 @@
+#[doc="
+
+    Module: stream
+
+    This module decompresses gzip data that arrives a piece at a
+    time instead of all at once in a Buf. A Decompressor takes
+    whatever input it is given, and stops when that runs out, when
+    the output buffer it was given is full, or at the end of a gzip
+    member. Input that ends partway through a header, symbol or
+    footer is kept until the rest arrives, so callers never have to
+    offer the same bytes twice. The io adaptors are built on it.
+    decompress_to_block() also stops where each DEFLATE block
+    starts, which is where an index can pick up again.
+
+"]
+use cvec::slice_buf;
+use std::cmp;
+use std::mem;
+
+use crc32::Crc32;
+use error::{GzError, ErrorKind};
+use header;
+use header::{GZHeader, GZIP_FOOTER_LEN, read_u32_le};
+use inflate::{Inflater, InflateStatus};
+
+// how much new input to add to kept bytes at a time
+const CARRY_STEP: usize = 1024;
+
+/// Why Decompressor::decompress stopped
+#[derive(Copy, Clone, PartialEq, Show)]
+pub enum StreamStatus {
+    /// All the input was used, and more is needed
+    NeedInput,
+    /// The output buffer is full
+    OutputFull,
+    /// The gzip member has ended, and its footer checked out
+    MemberEnd
+}
+
+/// Why Decompressor::decompress_to_block stopped
+#[derive(Copy, Clone, PartialEq, Show)]
+pub enum BlockStatus {
+    /// A DEFLATE block is about to begin, either just after the header
+    /// or after the previous block, and all the output before it has been
+    /// written
+    BlockStart,
+    /// For a reason decompress would have stopped for
+    Stream(StreamStatus)
+}
+
+/// Which part of a gzip member comes next
+#[derive(Copy, Clone, PartialEq, Show)]
+enum MemberState {
+    Header,
+    Body,
+    Footer,
+    End
+}
+
+/// Decompresses one gzip member at a time from input that arrives in
+/// pieces
+pub struct Decompressor {
+    state: MemberState,
+    header: Option<GZHeader>,
+    inflater: Inflater,
+    crc: Crc32,
+    out_len: u64,
+    // the CRC and size held in the footer, once it has been read
+    footer: Option<(u32, u32)>,
+    // input that has been used but not decoded, because it ends partway
+    // through a header, symbol or footer
+    carry: Vec<u8>,
+    // bits of the first byte of input that have already been decoded
+    bit: usize,
+    // input bytes decoded so far, for error positions
+    total_in: usize,
+    // a block start has been reached, and not reported yet
+    at_block_start: bool,
+    // false when decompression resumed partway through the member, so
+    // the footer can't be checked
+    check_footer: bool
+}
+
+impl Decompressor {
+    pub fn new() -> Decompressor {
+        Decompressor {
+            state: MemberState::Header,
+            header: None,
+            inflater: block_inflater(&[]),
+            crc: Crc32::new(),
+            out_len: 0,
+            footer: None,
+            carry: Vec::new(),
+            bit: 0,
+            total_in: 0,
+            at_block_start: false,
+            check_footer: true
+        }
+    }
+
+    /// Pick up decompression at the start of a block partway through a
+    /// member, bit_position bits into the gzip stream, where window holds
+    /// the output before that point. Input must start from the byte
+    /// bit_position falls in. The footer of that member is skipped rather
+    /// than checked, since the output before this point is not known.
+    pub fn resume(bit_position: usize, window: &[u8]) -> Decompressor {
+        let mut d = Decompressor::new();
+        d.state = MemberState::Body;
+        d.inflater = block_inflater(window);
+        d.bit = bit_position % 8;
+        d.total_in = bit_position / 8;
+        d.check_footer = false;
+        d
+    }
+
+    /// The number of input bits decoded so far, across all members
+    pub fn bit_position(&self) -> usize {
+        self.total_in * 8 + self.bit
+    }
+
+    /// The most recent output of the current member, as much as back
+    /// pointers can refer to
+    pub fn window(&self) -> &[u8] {
+        self.inflater.window()
+    }
+
+    /// The header of the latest member, once it has been read
+    pub fn header(&self) -> Option<&GZHeader> {
+        self.header.as_ref()
+    }
+
+    /// The CRC32 of the current member's output so far
+    pub fn crc(&self) -> u32 {
+        self.crc.crc()
+    }
+
+    /// The length of the current member's output so far
+    pub fn member_len(&self) -> u64 {
+        self.out_len
+    }
+
+    /// The CRC and size held in the current member's footer, once it has
+    /// been read, whether or not they matched the output
+    pub fn footer(&self) -> Option<(u32, u32)> {
+        self.footer
+    }
+
+    /// The number of input bytes decoded so far, across all members
+    pub fn total_in(&self) -> usize {
+        self.total_in
+    }
+
+    /// Whether the current member has ended
+    pub fn is_member_end(&self) -> bool {
+        self.state == MemberState::End
+    }
+
+    /// Get ready to decompress the member following the one that ended
+    pub fn next_member(&mut self) {
+        self.state = MemberState::Header;
+        self.inflater = block_inflater(&[]);
+        self.crc = Crc32::new();
+        self.out_len = 0;
+        self.footer = None;
+        self.check_footer = true;
+    }
+
+    /// Decompress as much of input into output as possible. Returns how
+    /// many bytes of input were used, how many bytes of output were
+    /// written, and why it stopped. Input after the end of a member is
+    /// not used. NeedInput is only returned once all the output that
+    /// can be decoded so far has been written.
+    pub fn decompress(&mut self, input: &[u8], output: &mut [u8])
+            -> Result<(usize, usize, StreamStatus), GzError> {
+        let (used, written, status) = try!(self.decompress_until(input, output, false));
+        match status {
+            BlockStatus::Stream(status) => Ok((used, written, status)),
+            BlockStatus::BlockStart => unreachable!()
+        }
+    }
+
+    /// As decompress, but also stopping at the start of every DEFLATE
+    /// block, once the output before it has been written
+    pub fn decompress_to_block(&mut self, input: &[u8], output: &mut [u8])
+            -> Result<(usize, usize, BlockStatus), GzError> {
+        self.decompress_until(input, output, true)
+    }
+
+    fn decompress_until(&mut self, input: &[u8], output: &mut [u8], stop_at_blocks: bool)
+            -> Result<(usize, usize, BlockStatus), GzError> {
+        let need_input = BlockStatus::Stream(StreamStatus::NeedInput);
+        let mut used = 0;
+        let mut written = 0;
+        while self.carry.len() > 0 {
+            // finish off the kept bytes, with a little new input
+            let carry_len = self.carry.len();
+            let take = cmp::min(input.len() - used, CARRY_STEP);
+            let mut data = mem::replace(&mut self.carry, Vec::new());
+            data.push_all(&input[used .. used + take]);
+            let (pos, status) = try!(self.run(data.as_slice(), output, &mut written,
+                                              stop_at_blocks));
+            let byte = pos / 8;
+            self.bit = pos % 8;
+            self.total_in += byte;
+            if byte >= carry_len {
+                // got past the kept bytes, so carry on with the input itself
+                used += byte - carry_len;
+            } else if status == need_input {
+                used += take;
+                self.carry = data[byte ..].to_vec();
+                if used == input.len() {
+                    return Ok((used, written, status));
+                }
+            } else {
+                self.carry = data[byte .. carry_len].to_vec();
+            }
+            if status != need_input {
+                return Ok((used, written, status));
+            }
+        }
+
+        let (pos, status) = try!(self.run(&input[used ..], output, &mut written, stop_at_blocks));
+        let byte = pos / 8;
+        self.bit = pos % 8;
+        self.total_in += byte;
+        if status == need_input {
+            self.carry = input[used + byte ..].to_vec();
+            return Ok((input.len(), written, status));
+        }
+        Ok((used + byte, written, status))
+    }
+
+    /// Decompress data, starting self.bit bits in, into output after the
+    /// first written bytes. Returns the bit position just past what was
+    /// decoded, and why it stopped.
+    fn run(&mut self, data: &[u8], output: &mut [u8], written: &mut usize, stop_at_blocks: bool)
+            -> Result<(usize, BlockStatus), GzError> {
+        let mut pos = self.bit;
+        loop {
+            match self.state {
+                MemberState::Header => {
+                    let buffer = match slice_buf(&data[pos / 8 ..]) {
+                        Some(buffer) => buffer,
+                        None => return Ok((pos, BlockStatus::Stream(StreamStatus::NeedInput)))
+                    };
+                    match header::parse_header_partial(&buffer) {
+                        Ok(Some(header)) => {
+                            pos += header.header_len * 8;
+                            self.header = Some(header);
+                            self.state = MemberState::Body;
+                            self.at_block_start = true;
+                        },
+                        Ok(None) => return Ok((pos, BlockStatus::Stream(StreamStatus::NeedInput))),
+                        Err(()) => return Err(self.error(ErrorKind::BadHeader, pos))
+                    }
+                },
+                MemberState::Body => {
+                    // hand over the output that is waiting first
+                    *written += self.take_output(&mut output[*written ..]);
+                    if *written == output.len() {
+                        return Ok((pos, BlockStatus::Stream(StreamStatus::OutputFull)));
+                    }
+                    if self.at_block_start {
+                        self.at_block_start = false;
+                        if stop_at_blocks {
+                            return Ok((pos, BlockStatus::BlockStart));
+                        }
+                    }
+                    if self.inflater.is_done() {
+                        // the footer starts at the next byte
+                        pos = (pos + 7) / 8 * 8;
+                        self.state = MemberState::Footer;
+                        continue;
+                    }
+                    let buffer = match slice_buf(data) {
+                        Some(buffer) => buffer,
+                        None => return Ok((pos, BlockStatus::Stream(StreamStatus::NeedInput)))
+                    };
+                    match self.inflater.inflate_some(&buffer, pos, output.len() - *written) {
+                        Some((next, InflateStatus::NeedInput)) => {
+                            // pass on what was decoded before the input ran out
+                            *written += self.take_output(&mut output[*written ..]);
+                            if *written == output.len() {
+                                return Ok((next, BlockStatus::Stream(StreamStatus::OutputFull)));
+                            }
+                            return Ok((next, BlockStatus::Stream(StreamStatus::NeedInput)));
+                        },
+                        Some((next, InflateStatus::BlockEnd)) => {
+                            pos = next;
+                            self.at_block_start = true;
+                        },
+                        Some((next, _)) => pos = next,
+                        None => return Err(self.error(ErrorKind::CorruptData, pos))
+                    }
+                },
+                MemberState::Footer => {
+                    let start = pos / 8;
+                    if data.len() < start + GZIP_FOOTER_LEN {
+                        return Ok((pos, BlockStatus::Stream(StreamStatus::NeedInput)));
+                    }
+                    let (crc, size) = (read_u32_le(&data[start ..]), read_u32_le(&data[start + 4 ..]));
+                    self.footer = Some((crc, size));
+                    if self.check_footer && crc != self.crc.crc() {
+                        return Err(self.error(ErrorKind::CrcMismatch, pos));
+                    }
+                    if self.check_footer && size != self.out_len as u32 {
+                        return Err(self.error(ErrorKind::SizeMismatch, pos + 32));
+                    }
+                    pos += GZIP_FOOTER_LEN * 8;
+                    self.state = MemberState::End;
+                },
+                MemberState::End => return Ok((pos, BlockStatus::Stream(StreamStatus::MemberEnd)))
+            }
+        }
+    }
+
+    /// Copy waiting output into out, returning how much was copied
+    fn take_output(&mut self, out: &mut [u8]) -> usize {
+        let n = cmp::min(out.len(), self.inflater.output().len());
+        if n > 0 {
+            let chunk = &self.inflater.output()[.. n];
+            for i in 0 .. n {
+                out[i] = chunk[i];
+            }
+            self.crc.update(chunk);
+        }
+        self.inflater.consume(n);
+        self.out_len += n as u64;
+        n
+    }
+
+    /// An error at bit pos of the data being run
+    fn error(&self, kind: ErrorKind, pos: usize) -> GzError {
+        GzError::new(kind, self.total_in + pos / 8)
+    }
+}
+
+/// An Inflater that stops at the end of every block, so the Decompressor
+/// can tell where the next one starts, picking up after window
+fn block_inflater(window: &[u8]) -> Inflater {
+    let mut inflater = Inflater::with_window(window);
+    inflater.stop_at_block_ends(true);
+    inflater
+}
+
+#[cfg(test)]
+mod decompressor_tests {
+    use super::{Decompressor, StreamStatus, BlockStatus};
+    use error::ErrorKind;
+
+    // gzip of EXPECTED named ishmael.txt, a single dynamic block
+    static COMPRESSED: [u8; 223] = [
+        0x1f, 0x8b, 0x08, 0x08, 0x00, 0x00, 0x00, 0x00, 0x02, 0xff, 0x69, 0x73,
+        0x68, 0x6d, 0x61, 0x65, 0x6c, 0x2e, 0x74, 0x78, 0x74, 0x00, 0x35, 0x8f,
+        0x41, 0x72, 0xc4, 0x20, 0x0c, 0x04, 0xef, 0x79, 0xc5, 0x3c, 0x20, 0xf1,
+        0x27, 0xf6, 0xe4, 0x73, 0x5e, 0xa0, 0xd8, 0x5a, 0x43, 0x95, 0x40, 0x2e,
+        0x21, 0xd6, 0xc5, 0xef, 0x57, 0x78, 0x2b, 0x37, 0xa4, 0x69, 0x9a, 0xe1,
+        0x41, 0x22, 0x28, 0x8c, 0xb5, 0xa5, 0x42, 0x2c, 0x0b, 0x7e, 0x35, 0xa6,
+        0xc1, 0x64, 0x0d, 0x74, 0x28, 0x7e, 0x50, 0xf9, 0xc5, 0x86, 0x92, 0xeb,
+        0x8e, 0xa4, 0x17, 0x44, 0xeb, 0x81, 0xd3, 0x78, 0xcb, 0x8d, 0x65, 0x44,
+        0x9e, 0xe8, 0x95, 0x63, 0x25, 0xd9, 0x5d, 0x18, 0x6a, 0xa8, 0x8a, 0xa2,
+        0x95, 0x07, 0x72, 0x45, 0x19, 0x38, 0xbb, 0x35, 0xfe, 0x06, 0xc5, 0xfd,
+        0xaa, 0x9e, 0x26, 0x7b, 0x92, 0x79, 0xde, 0xba, 0x90, 0xc1, 0x35, 0x30,
+        0x67, 0xe3, 0xe6, 0xb3, 0x86, 0x56, 0xb4, 0xa4, 0x16, 0xfc, 0x0a, 0x4f,
+        0xda, 0x8f, 0xe4, 0x71, 0xba, 0xb4, 0xcb, 0x8e, 0x46, 0x59, 0x40, 0x7f,
+        0xda, 0x1d, 0xf4, 0xff, 0xdc, 0xb4, 0x36, 0xe6, 0x60, 0x19, 0x17, 0x85,
+        0x67, 0xdc, 0x72, 0xe8, 0xf3, 0xb3, 0x52, 0x93, 0x7d, 0xc1, 0xea, 0xc8,
+        0xf1, 0x9d, 0x20, 0x46, 0xd8, 0xa2, 0x30, 0x4f, 0x60, 0xb7, 0x7c, 0x37,
+        0xd7, 0xe7, 0x07, 0x6e, 0xa7, 0x30, 0xd7, 0x5b, 0x69, 0x7c, 0x44, 0x3b,
+        0x9f, 0xe9, 0x4c, 0xb6, 0x6c, 0xdb, 0x3d, 0x6b, 0x5d, 0xbe, 0xde, 0x8c,
+        0x6a, 0x9a, 0xf3, 0x32, 0x01, 0x00, 0x00];
+    static EXPECTED: &'static [u8] = b"Call me Ishmael. Some years ago - never mind how long \
+        precisely - having little or no money in my purse, and nothing particular to interest \
+        me on shore, I thought I would sail about a little and see the watery part of the \
+        world. It is a way I have of driving off the spleen and regulating the circulation.\n";
+
+    /// Feed input in pieces of in_step bytes, taking at most out_step
+    /// bytes of output at a time, until the member ends
+    fn run(input: &[u8], in_step: usize, out_step: usize) -> (Vec<u8>, usize) {
+        let mut d = Decompressor::new();
+        let mut out = Vec::new();
+        let mut buf = vec![0u8; out_step];
+        let mut pos = 0;
+        loop {
+            let end = if pos + in_step < input.len() { pos + in_step } else { input.len() };
+            let (used, written, status) = d.decompress(&input[pos .. end], buf.as_mut_slice()).unwrap();
+            pos += used;
+            out.push_all(&buf[.. written]);
+            if status == StreamStatus::MemberEnd {
+                return (out, pos);
+            }
+            assert!(status != StreamStatus::NeedInput || pos < input.len());
+        }
+    }
+
+    #[test]
+    fn test_all_at_once() {
+        let (out, used) = run(&COMPRESSED, COMPRESSED.len(), 4096);
+        assert_eq!(out.as_slice(), EXPECTED);
+        assert_eq!(used, COMPRESSED.len());
+    }
+
+    #[test]
+    fn test_byte_at_a_time() {
+        for &out_step in [1, 7, 4096].iter() {
+            let (out, used) = run(&COMPRESSED, 1, out_step);
+            assert_eq!(out.as_slice(), EXPECTED);
+            assert_eq!(used, COMPRESSED.len());
+        }
+    }
+
+    #[test]
+    fn test_stops_at_member_end() {
+        let mut input = COMPRESSED.to_vec();
+        input.push_all(&COMPRESSED);
+        for &in_step in [1, 5, 100].iter() {
+            let (out, used) = run(input.as_slice(), in_step, 3);
+            assert_eq!(out.as_slice(), EXPECTED);
+            assert_eq!(used, COMPRESSED.len());
+        }
+    }
+
+    #[test]
+    fn test_header() {
+        let mut d = Decompressor::new();
+        let mut buf = [0u8; 8];
+        assert!(d.header().is_none());
+        assert!(!d.is_member_end());
+        d.decompress(&COMPRESSED[.. 30], &mut buf).unwrap();
+        assert_eq!(d.header().unwrap().fname, Some("ishmael.txt".to_string()));
+    }
+
+    #[test]
+    fn test_block_starts() {
+        let mut d = Decompressor::new();
+        let mut buf = [0u8; 4096];
+        let (used, written, status) = d.decompress_to_block(&COMPRESSED, &mut buf).unwrap();
+        assert_eq!((written, status), (0, BlockStatus::BlockStart));
+        // the only block starts just after the header
+        assert_eq!(d.bit_position(), 22 * 8);
+        assert_eq!(d.window().len(), 0);
+        let (_, written, status) = d.decompress(&COMPRESSED[used ..], &mut buf).unwrap();
+        assert_eq!(status, StreamStatus::MemberEnd);
+        assert_eq!(&buf[.. written], EXPECTED);
+        assert_eq!(d.window(), EXPECTED);
+    }
+
+    #[test]
+    fn test_resume() {
+        // a resumed stream does not know its CRC, so skips the footer check
+        let mut input = COMPRESSED.to_vec();
+        input[215] ^= 1;
+        let mut d = Decompressor::resume(22 * 8 + 3, &[]);
+        let mut buf = [0u8; 4096];
+        let err = d.decompress(&input[22 ..], &mut buf).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::CorruptData);
+        assert_eq!(err.position, 22);
+        let mut d = Decompressor::resume(22 * 8, &[]);
+        let (used, written, status) = d.decompress(&input[22 ..], &mut buf).unwrap();
+        assert_eq!(status, StreamStatus::MemberEnd);
+        assert_eq!(used, COMPRESSED.len() - 22);
+        assert_eq!(&buf[.. written], EXPECTED);
+    }
+
+    #[test]
+    fn test_bad_crc() {
+        let mut input = COMPRESSED.to_vec();
+        input[215] ^= 1;
+        let mut d = Decompressor::new();
+        let mut buf = [0u8; 4096];
+        let err = d.decompress(input.as_slice(), &mut buf).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::CrcMismatch);
+        assert_eq!(err.position, 215);
+    }
+
+    #[test]
+    fn test_bad_header() {
+        let mut d = Decompressor::new();
+        let mut buf = [0u8; 16];
+        let err = d.decompress(&[0x1f, 0x8c, 0x08], &mut buf).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::BadHeader);
+    }
+}
@@ -0,0 +1,119 @@
+#[doc="
+
+    Module: testing
+
+    Helpers for the tests of more than one module. COMPRESSED is a
+    small gzip file to decompress, and EXPECTED what it holds.
+    COMMENTED is another, of a run of 'a's, with a comment in
+    its header.
+    text() makes a log of numbered lines to compress, as long as a
+    test needs, and bgzf() splits data into BGZF blocks, for the
+    modules that read that format. system_gunzip() runs the gzip installed on the
+    system over some compressed data, for checking that what is
+    written here can be read by everything else, and system_gzip()
+    compresses with it, for comparing against. Lcg makes the
+    same pseudo-random numbers on every run, for tests that want
+    input without a pattern.
+
+"]
+use bgzf::EOF_MARKER;
+use encoder::compress_with_header;
+use header::{HeaderOptions, ExtraField};
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::thread;
+
+// gzip -9 -n of EXPECTED, a single fixed block
+pub static COMPRESSED: &'static [u8] = &[
+    0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0x0b, 0xc9,
+    0x48, 0x55, 0x28, 0x2c, 0xcd, 0x4c, 0xce, 0x56, 0x48, 0x2a, 0xca, 0x2f,
+    0xcf, 0x53, 0x48, 0xcb, 0xaf, 0x50, 0xc8, 0x2a, 0xcd, 0x2d, 0x28, 0x56,
+    0xc8, 0x2f, 0x4b, 0x2d, 0x52, 0x28, 0x01, 0x4a, 0xe7, 0x24, 0x56, 0x55,
+    0x2a, 0xa4, 0xe4, 0xa7, 0xeb, 0x29, 0x84, 0x10, 0xaf, 0x58, 0x21, 0x31,
+    0x3d, 0x31, 0x33, 0x4f, 0x8f, 0x0b, 0x00, 0x7b, 0x3f, 0x9f, 0x2f, 0x60,
+    0x00, 0x00, 0x00];
+pub static EXPECTED: &'static [u8] = b"The quick brown fox jumps over the lazy dog. \
+    The quick brown fox jumps over the lazy dog again.\n";
+// 63 'a's and a newline, deflated at level 9, with a comment in the
+// header to get past the minimum length
+pub static COMMENTED: &'static [u8] = &[
+    0x1f, 0x8b, 0x08, 0x10, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0x74, 0x68,
+    0x69, 0x73, 0x20, 0x69, 0x73, 0x20, 0x61, 0x20, 0x63, 0x6f, 0x6d, 0x6d,
+    0x65, 0x6e, 0x74, 0x00, 0x4b, 0x4c, 0xa4, 0x08, 0x70, 0x01, 0x00, 0x85,
+    0xdd, 0xd4, 0x53, 0x40, 0x00, 0x00, 0x00];
+
+/// lines lines of text that compresses the way text does. Each starts
+/// with a number, three times the line's, padded to 8 digits, so lines
+/// can be found by it as by a timestamp.
+pub fn text(lines: usize) -> Vec<u8> {
+    let mut data = Vec::new();
+    for i in 0 .. lines {
+        data.push_all(format!("{:08} line {} of the text, with words that repeat {}\n",
+                              i * 3, i, i * 7919 % 10007).as_bytes());
+    }
+    data
+}
+
+/// Compress data into BGZF blocks of at most block_len bytes each,
+/// followed by the EOF marker
+pub fn bgzf(data: &[u8], block_len: usize) -> Vec<u8> {
+    let mut options = HeaderOptions::new();
+    options.extra = Some(vec![ExtraField { id: [0x42, 0x43], data: vec![0, 0] }]);
+    let mut out = Vec::new();
+    for chunk in data.chunks(block_len) {
+        let mut block = compress_with_header(chunk, 6, &options).unwrap();
+        let size = block.len() - 1;
+        block[16] = size as u8;
+        block[17] = (size >> 8) as u8;
+        out.push_all(block.as_slice());
+    }
+    out.push_all(&EOF_MARKER);
+    out
+}
+
+/// A linear congruential generator, the one from the C standard
+pub struct Lcg {
+    state: u32
+}
+
+impl Lcg {
+    pub fn new(seed: u32) -> Lcg {
+        Lcg { state: seed }
+    }
+
+    /// The next number, from 0 to 2^24 - 1
+    pub fn next(&mut self) -> u32 {
+        self.state = self.state.wrapping_mul(1103515245).wrapping_add(12345);
+        self.state >> 8
+    }
+}
+
+/// Decompress data with the system gzip, returning its exit code and output
+pub fn system_gunzip(data: &[u8]) -> (i32, Vec<u8>) {
+    system_gzip_with(&["-dc"], data)
+}
+
+/// Compress data with the system gzip at the given level, without a name
+/// or time in the header
+pub fn system_gzip(data: &[u8], level: u32) -> Vec<u8> {
+    let (code, out) = system_gzip_with(&[format!("-{}", level).as_slice(), "-cn"], data);
+    assert_eq!(code, 0);
+    out
+}
+
+/// Run the system gzip with args over data, returning its exit code and
+/// output
+fn system_gzip_with(args: &[&str], data: &[u8]) -> (i32, Vec<u8>) {
+    let mut child = Command::new("gzip").args(args)
+        .stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped())
+        .spawn().ok().expect("gzip must be installed to run these tests");
+    let mut stdin = child.stdin.take().unwrap();
+    let data = data.to_vec();
+    // write from another thread, so neither side waits on a full pipe
+    let writer = thread::spawn(move || {
+        let _ = stdin.write_all(data.as_slice());
+    });
+    let output = child.wait_with_output().unwrap();
+    let _ = writer.join();
+    (output.status.code().unwrap_or(-1), output.stdout)
+}
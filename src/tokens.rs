@@ -0,0 +1,264 @@
+#[doc="
+
+    Module: tokens
+
+    This module gives the LZ77 tokens of a gzip file rather than
+    the bytes they make. Each literal byte and each length and
+    distance pair is handed to a TokenVisitor as a Token, before
+    it is expanded, along with where it starts in the file, how
+    many bits it took and where its output goes. The visitor is
+    also told where blocks start and end; the bits of a block not
+    spent on its tokens are its header and its end-of-block code.
+    That is all that's needed to see how well a file compressed,
+    and where, for instance as a heatmap of bits spent per byte.
+    visit_tokens() walks the members as inspect() does, with a
+    sink that wraps inspect's own, and fails where inspect() would.
+
+"]
+use error::{GzError, ErrorKind};
+use inspect::{inspect_members, BlockInfo, BlockRecorder, BlockSink};
+use sink::Sink;
+
+/// One LZ77 token
+#[derive(Copy, Clone, PartialEq, Show)]
+pub enum Token {
+    /// A byte of output
+    Literal(u8),
+    /// A copy of length bytes, starting distance bytes back
+    Match { length: u32, distance: u32 }
+}
+
+impl Token {
+    /// The number of bytes of output the token makes
+    pub fn output_len(&self) -> u32 {
+        match *self {
+            Token::Literal(..) => 1,
+            Token::Match { length, .. } => length
+        }
+    }
+}
+
+/// Told about the tokens of a gzip file, in order. Bit positions are
+/// counted from the start of the file, and output positions from the
+/// start of the member's output.
+pub trait TokenVisitor {
+    /// A block of the given member, counting from 0, is starting. Its
+    /// end is not known yet.
+    fn block_start(&mut self, _member: usize, _block: &BlockInfo) {}
+
+    /// A token starting at bit start took bits bits, and makes the output
+    /// from output_pos on
+    fn token(&mut self, token: Token, start: u64, bits: u32, output_pos: u64);
+
+    /// The block that last started has ended. end_of_block_bits is the
+    /// length of its end-of-block code, which stored blocks don't have.
+    fn block_end(&mut self, _block: &BlockInfo, _end_of_block_bits: u32) {}
+}
+
+/// Hand every token of every member of the gzip file in data to visitor.
+/// Fails at the first damage inspect() would report, once the tokens
+/// before it have been visited.
+pub fn visit_tokens<V: TokenVisitor>(data: &[u8], visitor: &mut V) -> Result<(), GzError> {
+    let mut sink = TokenSink {
+        blocks: BlockSink::new(),
+        visitor: visitor,
+        member: 0,
+        token: None,
+        token_start: 0
+    };
+    match inspect_members(data, &mut sink).error {
+        Some(error) => Err(error),
+        None => Ok(())
+    }
+}
+
+/// Records the blocks as inspect() does, and hands them and their tokens
+/// to a visitor
+struct TokenSink<'a, V: 'a> {
+    blocks: BlockSink,
+    visitor: &'a mut V,
+    // which member this is, counting from 0
+    member: usize,
+    // the token given since the last one ended, and where its output starts
+    token: Option<(Token, u64)>,
+    // the bit the next token starts at
+    token_start: u64
+}
+
+impl<'a, V: TokenVisitor> Sink for TokenSink<'a, V> {
+    fn push(&mut self, value: u8) -> Option<()> {
+        self.token = Some((Token::Literal(value), self.blocks.out_len()));
+        self.blocks.push(value)
+    }
+
+    fn copy_back_pointer(&mut self, distance: usize, length: usize) -> Option<()> {
+        let output_pos = self.blocks.out_len();
+        try_opt!(self.blocks.copy_back_pointer(distance, length));
+        let token = Token::Match { length: length as u32, distance: distance as u32 + 1 };
+        self.token = Some((token, output_pos));
+        Some(())
+    }
+
+    fn failure(&self) -> Option<ErrorKind> {
+        None
+    }
+
+    fn dynamic_lengths(&mut self, hclen: u32, code_length_lengths: &[u32],
+                       literal_lengths: &[u32], distance_lengths: &[u32]) {
+        self.blocks.dynamic_lengths(hclen, code_length_lengths, literal_lengths, distance_lengths);
+    }
+
+    fn block_start(&mut self, start: usize, last: bool, block_type: u32, data_start: usize) {
+        self.blocks.block_start(start, last, block_type, data_start);
+        self.token_start = data_start as u64;
+        if let Some(block) = self.blocks.blocks().last() {
+            self.visitor.block_start(self.member, block);
+        }
+    }
+
+    fn block_end(&mut self, end: usize) {
+        self.blocks.block_end(end);
+        let end_of_block_bits = (end as u64 - self.token_start) as u32;
+        if let Some(block) = self.blocks.blocks().last() {
+            self.visitor.block_end(block, end_of_block_bits);
+        }
+    }
+
+    fn token_end(&mut self, end: usize) {
+        if let Some((token, output_pos)) = self.token.take() {
+            let bits = (end as u64 - self.token_start) as u32;
+            self.visitor.token(token, self.token_start, bits, output_pos);
+            self.token_start = end as u64;
+        }
+    }
+}
+
+impl<'a, V: TokenVisitor> BlockRecorder for TokenSink<'a, V> {
+    fn take_blocks(&mut self) -> Vec<BlockInfo> {
+        self.member += 1;
+        self.blocks.take_blocks()
+    }
+}
+
+#[cfg(test)]
+mod tokens_tests {
+    use super::{visit_tokens, Token, TokenVisitor};
+    use encoder::compress;
+    use error::ErrorKind;
+    use gz::decompress;
+    use inspect::BlockInfo;
+    use testing::text;
+
+    /// Rebuilds the output from the tokens, and checks the bits add up
+    struct Rebuild {
+        output: Vec<u8>,
+        tokens: usize,
+        matches: usize,
+        // the next token should start here
+        next_bit: u64,
+        block_start_bit: u64,
+        block_type: u32,
+        member: usize
+    }
+
+    impl TokenVisitor for Rebuild {
+        fn block_start(&mut self, member: usize, block: &BlockInfo) {
+            if member != self.member {
+                self.member = member;
+                self.output.clear();
+            }
+            assert_eq!(block.output_start, self.output.len() as u64);
+            assert_eq!(block.end_bit, None);
+            self.block_start_bit = block.start_bit;
+            self.block_type = block.block_type;
+            self.next_bit = block.data_start_bit;
+        }
+
+        fn token(&mut self, token: Token, start: u64, bits: u32, output_pos: u64) {
+            assert_eq!(start, self.next_bit);
+            assert_eq!(output_pos, self.output.len() as u64);
+            match token {
+                Token::Literal(value) => {
+                    if self.block_type == 0 {
+                        assert_eq!(bits, 8);
+                    } else {
+                        assert!(bits >= 1 && bits <= 15);
+                    }
+                    self.output.push(value);
+                },
+                Token::Match { length, distance } => {
+                    assert!(self.block_type != 0);
+                    // a length code and a distance code at least
+                    assert!(bits >= 2);
+                    assert!(length >= 3 && length <= 258);
+                    assert!(distance >= 1 && distance <= 32768);
+                    for _ in 0 .. length {
+                        let byte = self.output[self.output.len() - distance as usize];
+                        self.output.push(byte);
+                    }
+                    self.matches += 1;
+                }
+            }
+            assert_eq!(self.output.len() as u64 - output_pos, token.output_len() as u64);
+            self.tokens += 1;
+            self.next_bit = start + bits as u64;
+        }
+
+        fn block_end(&mut self, block: &BlockInfo, end_of_block_bits: u32) {
+            assert_eq!(block.start_bit, self.block_start_bit);
+            assert_eq!(block.end_bit, Some(self.next_bit + end_of_block_bits as u64));
+            assert_eq!(block.output_end, self.output.len() as u64);
+            if block.block_type == 0 {
+                assert_eq!(end_of_block_bits, 0);
+            } else {
+                assert!(end_of_block_bits >= 1 && end_of_block_bits <= 15);
+            }
+        }
+    }
+
+    fn rebuild() -> Rebuild {
+        Rebuild { output: Vec::new(), tokens: 0, matches: 0, next_bit: 0, block_start_bit: 0,
+                  block_type: 0, member: 0 }
+    }
+
+    #[test]
+    fn test_rebuild() {
+        for &level in [0, 1, 6, 9].iter() {
+            let contents = text(10000);
+            let data = compress(contents.as_slice(), level);
+            let mut visitor = rebuild();
+            visit_tokens(data.as_slice(), &mut visitor).unwrap();
+            assert_eq!(visitor.output, decompress(data.as_slice()).unwrap());
+            assert_eq!(visitor.output, contents);
+            if level > 0 {
+                assert!(visitor.matches > 0);
+                assert!(visitor.tokens < contents.len() / 2);
+            } else {
+                assert_eq!(visitor.tokens, contents.len());
+            }
+        }
+    }
+
+    #[test]
+    fn test_members() {
+        let mut data = compress(b"first first first first", 6);
+        data.push_all(compress(b"second second second", 6).as_slice());
+        let mut visitor = rebuild();
+        visit_tokens(data.as_slice(), &mut visitor).unwrap();
+        assert_eq!(visitor.member, 1);
+        assert_eq!(visitor.output.as_slice(), b"second second second");
+    }
+
+    #[test]
+    fn test_errors() {
+        let data = compress(text(1000).as_slice(), 6);
+        let mut visitor = rebuild();
+        let error = visit_tokens(&data[.. data.len() / 2], &mut visitor).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::UnexpectedEof);
+        // the tokens before the damage were still visited
+        assert!(visitor.output.len() > 0);
+        let mut visitor = rebuild();
+        assert_eq!(visit_tokens(b"not gzip", &mut visitor).unwrap_err().kind, ErrorKind::BadHeader);
+        assert_eq!(visitor.tokens, 0);
+    }
+}
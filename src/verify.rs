@@ -0,0 +1,187 @@
+#[doc="
+
+    Module: verify
+
+    This module checks gzip files the way gzip -t does, without
+    keeping their contents. verify() walks the members with
+    Members, which inflates each one into the same 32K of output
+    buffer over and over, so its CRC32 and length are worked out
+    as the data goes by, and checks them against its footer.
+    Memory use is the same whatever the size of the file. Rather
+    than a bare error, the result is a VerifyReport with what was
+    checked, and for the first failure, the member it was in, how
+    far into the member's output it came, and what the footer held
+    against what the data gave.
+
+"]
+use std::fmt;
+use std::io;
+use std::io::{Read, BufReader};
+
+use error::{GzError, ErrorKind};
+use members::Members;
+
+/// What verifying a gzip file found
+#[derive(Clone, Show)]
+pub struct VerifyReport {
+    /// The number of members that checked out
+    pub members: usize,
+    /// The number of input bytes read, up to the end of the file or the
+    /// point where the failure was found
+    pub compressed_len: u64,
+    /// The length of the output of all the members, including what was
+    /// decoded of a member that failed
+    pub uncompressed_len: u64,
+    /// The number of bytes after the last member that are not another
+    /// member, which gzip -t warns about but does not fail
+    pub trailing_len: u64,
+    /// The first thing that was wrong, if anything was
+    pub failure: Option<VerifyFailure>
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.failure.is_none()
+    }
+}
+
+/// Where and how verification first failed
+#[derive(Copy, Clone, Show)]
+pub struct VerifyFailure {
+    pub kind: ErrorKind,
+    /// Which member failed, counting from 0
+    pub member: usize,
+    /// Where the failing member starts in the file
+    pub member_offset: u64,
+    /// Where in the file the failure was found
+    pub position: u64,
+    /// How much of the member's output was decoded before it failed
+    pub member_output: u64,
+    /// The CRC32 of that output
+    pub crc: u32,
+    /// The CRC and size held in the member's footer, if it was reached
+    pub footer: Option<(u32, u32)>
+}
+
+impl VerifyFailure {
+    pub fn to_gz_error(&self) -> GzError {
+        GzError::new(self.kind, self.position as usize)
+    }
+}
+
+impl fmt::Display for VerifyFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{} at byte {}, in member {} starting at byte {}, after {} bytes of output",
+                    self.kind.description(), self.position, self.member + 1, self.member_offset,
+                    self.member_output));
+        match self.footer {
+            Some((crc, size)) =>
+                write!(f, "; the footer holds CRC {:08x} and size {}, the data gives {:08x} and {}",
+                       crc, size, self.crc, self.member_output as u32),
+            None => Ok(())
+        }
+    }
+}
+
+/// Check every member of the gzip file read from reader, keeping none of
+/// the output. Problems with the data are given in the report; only a
+/// failure to read is returned as an error.
+pub fn verify<R: Read>(reader: R) -> io::Result<VerifyReport> {
+    let mut report = VerifyReport {
+        members: 0,
+        compressed_len: 0,
+        uncompressed_len: 0,
+        trailing_len: 0,
+        failure: None
+    };
+    let mut members = Members::new(BufReader::new(reader));
+    for member in members.by_ref() {
+        let member = try!(member);
+        report.compressed_len += member.compressed_len;
+        report.uncompressed_len += member.uncompressed_len;
+        if let Some(error) = member.error {
+            report.failure = Some(VerifyFailure {
+                kind: error.kind,
+                member: report.members,
+                member_offset: member.offset,
+                position: error.position as u64,
+                member_output: member.uncompressed_len,
+                crc: member.crc,
+                footer: member.footer
+            });
+            return Ok(report);
+        }
+        report.members += 1;
+    }
+    report.trailing_len = members.trailing_len();
+    Ok(report)
+}
+
+#[cfg(test)]
+mod verify_tests {
+    use super::verify;
+    use encoder::compress;
+    use error::ErrorKind;
+    use testing::text;
+
+    #[test]
+    fn test_good() {
+        let first = text(5000);
+        let mut data = compress(first.as_slice(), 6);
+        data.push_all(compress(b"second", 6).as_slice());
+        let report = verify(data.as_slice()).unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.members, 2);
+        assert_eq!(report.compressed_len, data.len() as u64);
+        assert_eq!(report.uncompressed_len, first.len() as u64 + 6);
+        data.push_all(&[0u8; 10]);
+        let report = verify(data.as_slice()).unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.trailing_len, 10);
+    }
+
+    #[test]
+    fn test_crc_failure() {
+        let contents = text(3000);
+        let first = compress(b"fine", 6);
+        let mut data = first.clone();
+        data.push_all(compress(contents.as_slice(), 6).as_slice());
+        let len = data.len();
+        data[len - 8] ^= 0xff;
+        let report = verify(data.as_slice()).unwrap();
+        assert_eq!(report.members, 1);
+        let failure = report.failure.unwrap();
+        assert_eq!(failure.kind, ErrorKind::CrcMismatch);
+        assert_eq!((failure.member, failure.member_offset), (1, first.len() as u64));
+        assert_eq!(failure.position, len as u64 - 8);
+        assert_eq!(failure.member_output, contents.len() as u64);
+        let (crc, size) = failure.footer.unwrap();
+        assert_eq!(crc ^ 0xff, failure.crc);
+        assert_eq!(size, contents.len() as u32);
+        let message = format!("{}", failure);
+        assert!(message.starts_with(format!("{} at byte {}, in member 2 starting at byte {}",
+                                            ErrorKind::CrcMismatch.description(), len - 8,
+                                            first.len()).as_slice()));
+        assert!(message.ends_with(format!("the data gives {:08x} and {}", failure.crc,
+                                          contents.len()).as_slice()));
+    }
+
+    #[test]
+    fn test_other_failures() {
+        let contents = text(3000);
+        let data = compress(contents.as_slice(), 6);
+        let len = data.len();
+        let failure = verify(&data[.. len - 100]).unwrap().failure.unwrap();
+        assert_eq!(failure.kind, ErrorKind::UnexpectedEof);
+        assert!(failure.member_output > 0 && failure.member_output < contents.len() as u64);
+        assert!(failure.footer.is_none());
+        let mut bad_size = data.clone();
+        bad_size[len - 1] ^= 1;
+        let failure = verify(bad_size.as_slice()).unwrap().failure.unwrap();
+        assert_eq!((failure.kind, failure.position), (ErrorKind::SizeMismatch, len as u64 - 4));
+        let report = verify(Vec::new().as_slice()).unwrap();
+        assert_eq!(report.failure.unwrap().kind, ErrorKind::UnexpectedEof);
+        let report = verify(b"not gzip".as_slice()).unwrap();
+        assert_eq!(report.failure.unwrap().kind, ErrorKind::BadHeader);
+    }
+}
@@ -0,0 +1,140 @@
+#[doc="
+
+    Module: write
+
+    This module provides GzWriteDecoder, for when compressed data
+    is pushed at us rather than read. It accepts gzip data through
+    std::io::Write and writes the decompressed bytes to an inner
+    writer as soon as they can be decoded.
+
+"]
+use std::io;
+use std::io::Write;
+
+use error::{GzError, ErrorKind};
+use header::GZHeader;
+use stream::{Decompressor, StreamStatus};
+
+// how much decompressed data to collect before writing it on
+const OUTPUT_BUF_SIZE: usize = 32768;
+
+/// Decompresses the gzip stream written to it into W. Members that
+/// follow one another are decompressed one after the other.
+pub struct GzWriteDecoder<W> {
+    writer: W,
+    decompressor: Decompressor,
+    buf: Vec<u8>
+}
+
+impl<W: Write> GzWriteDecoder<W> {
+    pub fn new(writer: W) -> GzWriteDecoder<W> {
+        GzWriteDecoder {
+            writer: writer,
+            decompressor: Decompressor::new(),
+            buf: vec![0u8; OUTPUT_BUF_SIZE]
+        }
+    }
+
+    /// The header of the member currently being written, once it has
+    /// been parsed
+    pub fn header(&self) -> Option<&GZHeader> {
+        self.decompressor.header()
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    /// Check that the data written so far ended with a complete member,
+    /// whose footer matched, and give back the inner writer
+    pub fn try_finish(mut self) -> io::Result<W> {
+        if !self.decompressor.is_member_end() {
+            let position = self.decompressor.total_in();
+            return Err(GzError::new(ErrorKind::UnexpectedEof, position).to_io_error());
+        }
+        try!(self.writer.flush());
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write> Write for GzWriteDecoder<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let mut used = 0;
+        while used < data.len() {
+            if self.decompressor.is_member_end() {
+                self.decompressor.next_member();
+            }
+            let (n, written, status) = match self.decompressor.decompress(
+                    &data[used ..], self.buf.as_mut_slice()) {
+                Ok(result) => result,
+                Err(e) => return Err(e.to_io_error())
+            };
+            used += n;
+            try!(self.writer.write_all(&self.buf[.. written]));
+            if status == StreamStatus::NeedInput {
+                break;
+            }
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod gz_write_decoder_tests {
+    use super::GzWriteDecoder;
+    use std::io;
+    use std::io::Write;
+    use testing::{COMPRESSED, EXPECTED};
+
+    fn write_in_pieces(input: &[u8], step: usize) -> io::Result<Vec<u8>> {
+        let mut decoder = GzWriteDecoder::new(Vec::new());
+        for chunk in input.chunks(step) {
+            try!(decoder.write_all(chunk));
+        }
+        decoder.try_finish()
+    }
+
+    #[test]
+    fn test_write() {
+        for &step in [1, 3, 16, 4096].iter() {
+            assert_eq!(write_in_pieces(COMPRESSED, step).unwrap().as_slice(), EXPECTED);
+        }
+    }
+
+    #[test]
+    fn test_output_as_it_arrives() {
+        let mut decoder = GzWriteDecoder::new(Vec::new());
+        decoder.write_all(&COMPRESSED[.. 40]).unwrap();
+        assert!(decoder.get_ref().len() > 0);
+        assert!(EXPECTED.starts_with(decoder.get_ref().as_slice()));
+        assert_eq!(decoder.header().unwrap().os, 3);
+    }
+
+    #[test]
+    fn test_multiple_members() {
+        let mut input = COMPRESSED.to_vec();
+        input.push_all(COMPRESSED);
+        let mut expect = EXPECTED.to_vec();
+        expect.push_all(EXPECTED);
+        assert_eq!(write_in_pieces(input.as_slice(), 7).unwrap(), expect);
+    }
+
+    #[test]
+    fn test_truncated() {
+        let err = write_in_pieces(&COMPRESSED[.. 70], 10).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(write_in_pieces(&[], 10).is_err());
+    }
+
+    #[test]
+    fn test_corrupt() {
+        let mut input = COMPRESSED.to_vec();
+        input[71] ^= 0xff;
+        let err = write_in_pieces(input.as_slice(), 10).unwrap_err();
+        assert_eq!(format!("{}", err), "size does not match decompressed data at byte 71");
+    }
+}